@@ -1,5 +1,5 @@
 use std::{collections::HashMap, io::{Error, ErrorKind}, sync::Arc};
-use actix_web::{body::BoxBody, dev::ServiceResponse, middleware::ErrorHandlerResponse, web, HttpResponse};
+use actix_web::{body::BoxBody, dev::ServiceResponse, http::{header, StatusCode}, middleware::ErrorHandlerResponse, web, HttpResponse};
 use chrono::Utc;
 use log::{error, info};
 use serde::Deserialize;
@@ -65,18 +65,28 @@ impl Handler {
     }
 
     pub fn get_status_ms(&self, code: &u16) -> String {
-        let status = self.status_set.status.get(code).unwrap();
-        status.message.clone()
+        match self.status_set.status.get(code) {
+            Some(status) => status.message.clone(),
+            // jsonに無いステータスは標準の理由句を使う
+            None => StatusCode::from_u16(*code).ok()
+                .and_then(|s| s.canonical_reason())
+                .unwrap_or("Unknown Status")
+                .to_string(),
+        }
     }
 
     pub fn get_status_color(&self, code: &u16) -> String {
-        let status = self.status_set.status.get(code).unwrap();
-        status.color.clone()
+        match self.status_set.status.get(code) {
+            Some(status) => status.color.clone(),
+            None => "#888888".to_string(),
+        }
     }
 
     pub fn get_status_solution(&self, code: &u16) -> Vec<String> {
-        let status = self.status_set.status.get(code).unwrap();
-        status.suggest.clone()
+        match self.status_set.status.get(code) {
+            Some(status) => status.suggest.clone(),
+            None => Vec::new(),
+        }
     }
 
     pub fn generate_page<B>(&self, res: &ServiceResponse<B>) -> HttpResponse<BoxBody> {
@@ -136,14 +146,147 @@ impl Handler {
                 format!("code: {}, message: {}, color: {}, suggestions: {:?}, debug_info: {:?} - Failed to render err template", status_code, status_message, status_color, suggestion_list, debug_info)
             });
 
-        HttpResponse::build(res.status()).body(rendered)
+        let mut response = HttpResponse::build(res.status())
+            .content_type("text/html; charset=utf-8")
+            .body(rendered);
+        // Upload-Offset や Content-Range などプロトコルで必要なヘッダーは残す
+        for (name, value) in res.headers() {
+            if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH && name != header::CONTENT_ENCODING {
+                response.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        response
     }
-    
-    pub fn err_handler<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>, actix_web::Error> {
-        let collection = res.request().app_data::<web::Data<Arc<Collection>>>().unwrap();
-        let response = collection.middleware.status_page.generate_page(&res);
+
+    /// ステータスページにするか ブラウザからのリクエストのみで、API のレスポンスはそのまま返す
+    fn wants_page<B>(res: &ServiceResponse<B>) -> bool {
+        let accepts_html = res.request().headers().get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |v| v.contains("text/html"));
+        let is_json = res.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |v| v.starts_with("application/json") || v.contains("+json"));
+        accepts_html && !is_json && !res.headers().contains_key("Tus-Resumable")
+    }
+
+    pub fn respond<B>(&self, res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>, actix_web::Error> {
+        if !Self::wants_page(&res) {
+            return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+        }
+        let response = self.generate_page(&res);
         Ok(ErrorHandlerResponse::Response(
             res.into_response(response.map_into_right_body()),
         ))
     }
+
+    pub fn err_handler<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>, actix_web::Error> {
+        let collection = res.request().app_data::<web::Data<Arc<Collection>>>().unwrap().clone();
+        collection.middleware.status_page.respond(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix_web::{body::{BoxBody, EitherBody}, dev::ServiceResponse, http::{header, StatusCode}, middleware::ErrorHandlers, test, web, App, HttpResponse};
+    use tera::Tera;
+
+    use super::{Handler, StatusSet};
+
+    fn handler() -> Handler {
+        let mut template = Tera::default();
+        template.add_raw_template("status_page", "<h1>{{ code }} {{ ms }}</h1>").unwrap();
+        Handler {
+            status_set: StatusSet { status: HashMap::new() },
+            template,
+        }
+    }
+
+    async fn call(accept: Option<&str>, uri: &str) -> ServiceResponse<EitherBody<BoxBody>> {
+        let handler = handler();
+        let app = test::init_service(
+            App::new()
+                .wrap(ErrorHandlers::new().default_handler(move |res| handler.respond(res)))
+                .route("/tus/conflict", web::patch().to(|| async {
+                    HttpResponse::Conflict().insert_header(("Tus-Resumable", "1.0.0")).insert_header(("Upload-Offset", "42")).finish()
+                }))
+                .route("/tus/checksum", web::patch().to(|| async {
+                    HttpResponse::build(StatusCode::from_u16(460).unwrap()).insert_header(("Tus-Resumable", "1.0.0")).insert_header(("Upload-Offset", "0")).finish()
+                }))
+                .route("/range", web::get().to(|| async {
+                    HttpResponse::RangeNotSatisfiable().insert_header((header::CONTENT_RANGE, "bytes */10")).finish()
+                }))
+                .route("/precondition", web::get().to(|| async {
+                    HttpResponse::PreconditionFailed().insert_header((header::ETAG, "\"abc\"")).finish()
+                }))
+                .route("/edit", web::patch().to(|| async {
+                    HttpResponse::PreconditionFailed().insert_header((header::ETAG, "\"abc\"")).json(serde_json::json!({ "etag": "abc" }))
+                }))
+                .route("/login", web::post().to(|| async {
+                    HttpResponse::TooManyRequests().insert_header((header::RETRY_AFTER, "60")).body("too many failed logins")
+                })),
+        ).await;
+        let mut req = test::TestRequest::default().uri(uri);
+        req = match uri {
+            "/tus/conflict" | "/tus/checksum" | "/edit" => req.method(actix_web::http::Method::PATCH),
+            "/login" => req.method(actix_web::http::Method::POST),
+            _ => req,
+        };
+        if let Some(accept) = accept {
+            req = req.insert_header((header::ACCEPT, accept));
+        }
+        test::call_service(&app, req.to_request()).await
+    }
+
+    /// ブラウザ向けのページにしても、プロトコルで必要なヘッダーは残る
+    #[actix_web::test]
+    async fn keeps_protocol_headers() {
+        for accept in [None, Some("text/html,application/xhtml+xml,*/*;q=0.8")] {
+            let res = call(accept, "/tus/conflict").await;
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+            assert_eq!(res.headers().get("Upload-Offset").unwrap(), "42");
+            assert_eq!(res.headers().get("Tus-Resumable").unwrap(), "1.0.0");
+
+            let res = call(accept, "/tus/checksum").await;
+            assert_eq!(res.status().as_u16(), 460);
+            assert_eq!(res.headers().get("Upload-Offset").unwrap(), "0");
+            assert_eq!(res.headers().get("Tus-Resumable").unwrap(), "1.0.0");
+
+            let res = call(accept, "/range").await;
+            assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(res.headers().get(header::CONTENT_RANGE).unwrap(), "bytes */10");
+
+            let res = call(accept, "/precondition").await;
+            assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+            assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"abc\"");
+
+            let res = call(accept, "/login").await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "60");
+        }
+    }
+
+    /// API のレスポンスは本文もそのまま返す
+    #[actix_web::test]
+    async fn keeps_api_bodies() {
+        for accept in [None, Some("text/html")] {
+            let res = call(accept, "/edit").await;
+            assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"abc\"");
+            let body = test::read_body(res).await;
+            assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!({ "etag": "abc" }));
+        }
+
+        let body = test::read_body(call(None, "/login").await).await;
+        assert_eq!(&body[..], b"too many failed logins");
+    }
+
+    /// ブラウザからのリクエストはステータスページにする
+    #[actix_web::test]
+    async fn renders_page_for_browsers() {
+        let res = call(Some("text/html"), "/range").await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+        let body = test::read_body(res).await;
+        assert_eq!(&body[..], "<h1>416 Range Not Satisfiable</h1>".as_bytes());
+    }
 }
@@ -1,7 +1,7 @@
 use serde::Deserialize;
 use log::{error, info};
 
-use crate::{actix_middleware::config::MiddlewareConfig, idis_server, utils};
+use crate::{actix_middleware::config::MiddlewareConfig, file_system::config::FileSystemConfig, idis_server, utils};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerConfig<ServiceConfig> {
@@ -21,6 +21,8 @@ pub struct Configuration {
     pub idis_server: ServerConfig<idis_server::actix_server_config::ServiceConfig>,
     pub logger_mode: String,
     pub middleware_config: MiddlewareConfig,
+    pub server_id: u16,
+    pub file_system: FileSystemConfig,
 }
 
 impl Configuration {
@@ -0,0 +1,306 @@
+use std::{collections::HashMap, io::{Error, ErrorKind}, sync::Arc};
+
+use argon2::Argon2;
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tokio::sync::Mutex;
+
+use crate::utils::{custom_serializers_adapters::Hex, password, ruid::{self, RuidGenerator}};
+
+use super::{file_system::FileSystem, provision::Provisioner, resolve};
+
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountConfig {
+    /// false の場合は /signup でアカウントを作れない
+    pub signup: bool,
+    pub min_password_length: usize,
+    /// Argon2id のメモリ (KiB)、反復回数、並列度
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+    /// failure_window 秒の間に max_failures 回失敗したユーザーと接続元は、その間ログインできない 0 で制限しない
+    pub max_failures: u32,
+    pub failure_window: u64,
+}
+
+/// ログインできるユーザー 使用量と account_level は Usage に持つ
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    #[serde_as(as = "Hex")]
+    pub ruid: u128,
+    pub user: String,
+    /// Argon2id (PHC 文字列)
+    pub password: String,
+    pub create_time: i64,
+}
+
+/// 失敗した回数と、数え始めた時刻
+struct Failure {
+    count: u32,
+    since: i64,
+}
+
+pub struct Accounts {
+    config: AccountConfig,
+    argon2: Argon2<'static>,
+    /// 存在しないユーザーでも同じだけ時間をかけるためのハッシュ
+    dummy: String,
+    file_system: Arc<FileSystem>,
+    provisioner: Arc<Provisioner>,
+    ruid: Arc<RuidGenerator>,
+    /// 作成を直列にする ユーザー名の重複を防ぐため
+    lock: Mutex<()>,
+    /// `@<user>` または接続元 -> 失敗
+    failures: std::sync::Mutex<HashMap<String, Failure>>,
+}
+
+impl Accounts {
+    pub fn new(file_system: Arc<FileSystem>, provisioner: Arc<Provisioner>, ruid: Arc<RuidGenerator>) -> Result<Self, Error> {
+        let config = file_system.config.account.clone();
+        let argon2 = password::argon2id(config.memory_cost, config.time_cost, config.parallelism)?;
+        let dummy = password::hash(&argon2, &format!("{:032x}", ruid.generate(ruid::prefix::CACHE_FILE)))?;
+        info!("password hashing: argon2id m={} t={} p={}", config.memory_cost, config.time_cost, config.parallelism);
+
+        Ok(Self {
+            config,
+            argon2,
+            dummy,
+            file_system,
+            provisioner,
+            ruid,
+            lock: Mutex::new(()),
+            failures: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn hash(&self, password: &str) -> Result<String, Error> {
+        let argon2 = self.argon2.clone();
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || password::hash(&argon2, &password)).await.map_err(Error::other)?
+    }
+
+    /// アカウントを作り、ユーザーディレクトリをテンプレートから作る
+    pub async fn signup(&self, user: &str, password: &str) -> Result<Account, Error> {
+        if !self.config.signup {
+            return Err(Error::new(ErrorKind::PermissionDenied, "signup is disabled"));
+        }
+        if !resolve::valid_user_name(user) {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid user name"));
+        }
+        let length = password.chars().count();
+        if length < self.config.min_password_length || length > MAX_PASSWORD_LENGTH {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("password must be {} to {} characters", self.config.min_password_length, MAX_PASSWORD_LENGTH)));
+        }
+
+        let _guard = self.lock.lock().await;
+        // アカウントの無い既存のユーザー (テンプレートから作られたものなど) の名前も使えない
+        if self.file_system.metas.get_account(user).await?.is_some()
+            || self.file_system.metas.ruid_by_user(user).await?.is_some()
+            || self.file_system.metas.resolve(user, "/").await?.is_some()
+        {
+            return Err(Error::new(ErrorKind::AlreadyExists, "user already exists"));
+        }
+
+        let account = Account {
+            ruid: self.ruid.generate(ruid::prefix::USER_ID),
+            user: user.to_string(),
+            password: self.hash(password).await?,
+            create_time: Utc::now().timestamp_millis(),
+        };
+        self.provisioner.provision(user, account.ruid, account.create_time).await?;
+        self.file_system.metas.put_account(&account).await?;
+        info!("account created: @{}", user);
+        Ok(account)
+    }
+
+    /// パスワードを確かめる client は接続元 失敗が続いたユーザーと接続元は断る
+    /// 確かめる前に一回分の失敗として数え、成功した場合に戻す 並行した試行で max_failures を超えないため
+    pub async fn login(&self, user: &str, password: &str, client: Option<&str>) -> Result<Account, Error> {
+        let mut keys = vec![format!("@{}", user)];
+        if let Some(client) = client {
+            keys.push(client.to_string());
+        }
+        if !self.reserve(&keys) {
+            return Err(Error::new(ErrorKind::ResourceBusy, "too many failed logins"));
+        }
+
+        let account = self.file_system.metas.get_account(user).await?;
+        let hash = account.as_ref().map_or_else(|| self.dummy.clone(), |a| a.password.clone());
+        let given = password.chars().take(MAX_PASSWORD_LENGTH).collect::<String>();
+        let verified = tokio::task::spawn_blocking(move || password::verify(&hash, &given)).await.map_err(Error::other)?;
+        let mut account = match account {
+            Some(account) if verified => account,
+            _ => return Err(Error::new(ErrorKind::PermissionDenied, "user or password is wrong")),
+        };
+        self.release(&keys);
+
+        // パラメーターを変えた後は、ログインした時に作り直す
+        if password::needs_rehash(&self.argon2, &account.password) {
+            match self.hash(password).await {
+                Ok(hash) => {
+                    account.password = hash;
+                    if let Err(e) = self.file_system.metas.put_account(&account).await {
+                        error!("Failed to rehash password of @{}: {}", user, e);
+                    }
+                }
+                Err(e) => error!("Failed to rehash password of @{}: {}", user, e),
+            }
+        }
+        Ok(account)
+    }
+
+    fn failures(&self) -> std::sync::MutexGuard<'_, HashMap<String, Failure>> {
+        self.failures.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn window(&self) -> i64 {
+        self.config.failure_window.saturating_mul(1000) as i64
+    }
+
+    /// どれも上限に達していなければ、全てに一回分の失敗を数えて true
+    fn reserve(&self, keys: &[String]) -> bool {
+        if self.config.max_failures == 0 {
+            return true;
+        }
+        let now = Utc::now().timestamp_millis();
+        let window = self.window();
+        let mut failures = self.failures();
+        failures.retain(|_, f| now - f.since < window);
+        if keys.iter().any(|key| failures.get(key).is_some_and(|f| f.count >= self.config.max_failures)) {
+            return false;
+        }
+        for key in keys {
+            let failure = failures.entry(key.clone()).or_insert(Failure { count: 0, since: now });
+            failure.count += 1;
+            if failure.count == self.config.max_failures {
+                warn!("login is blocked for {} seconds: {}", self.config.failure_window, key);
+            }
+        }
+        true
+    }
+
+    /// 成功したログインの分を戻す ユーザーの失敗は消し、接続元は一回分だけ戻す
+    fn release(&self, keys: &[String]) {
+        let mut failures = self.failures();
+        failures.remove(&keys[0]);
+        for key in &keys[1..] {
+            if let Some(failure) = failures.get_mut(key) {
+                failure.count = failure.count.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, sync::Arc};
+
+    use crate::{
+        file_system::{
+            file_system::FileSystem,
+            provision::Provisioner,
+            test_support::{self, Backend, BACKENDS},
+        },
+        utils::{password, ruid::RuidGenerator},
+    };
+
+    use super::Accounts;
+
+    fn accounts(fs: &Arc<FileSystem>) -> Accounts {
+        let ruid = Arc::new(RuidGenerator::new(1));
+        let provisioner = Arc::new(Provisioner::new(Arc::clone(fs), Arc::clone(&ruid)).unwrap());
+        Accounts::new(Arc::clone(fs), provisioner, ruid).unwrap()
+    }
+
+    #[tokio::test]
+    async fn signup_and_login() {
+        for backend in BACKENDS {
+            let (_dir, fs) = test_support::file_system(backend).await;
+            let accounts = accounts(&fs);
+            let account = accounts.signup("alice", "password1").await.unwrap();
+            assert!(account.password.starts_with("$argon2id$"), "{:?}", backend);
+            assert!(fs.get("alice", "/home").await.is_ok(), "{:?}", backend);
+
+            let invalid = [("alice", "password2", ErrorKind::AlreadyExists), ("../bob", "password1", ErrorKind::InvalidInput), ("bob", "short", ErrorKind::InvalidInput)];
+            for (user, password, kind) in invalid {
+                assert_eq!(accounts.signup(user, password).await.unwrap_err().kind(), kind, "{:?} {}", backend, user);
+            }
+
+            assert_eq!(accounts.login("alice", "password1", None).await.unwrap().ruid, account.ruid, "{:?}", backend);
+            assert_eq!(accounts.login("alice", "password2", None).await.unwrap_err().kind(), ErrorKind::PermissionDenied, "{:?}", backend);
+            assert_eq!(accounts.login("nobody", "password1", None).await.unwrap_err().kind(), ErrorKind::PermissionDenied, "{:?}", backend);
+        }
+    }
+
+    /// ユーザーと接続元のどちらかの失敗が続けば、正しいパスワードでも断る
+    #[tokio::test]
+    async fn failures_are_blocked() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let accounts = accounts(&fs);
+        accounts.signup("alice", "password1").await.unwrap();
+        accounts.signup("bob", "password1").await.unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(accounts.login("alice", "wrong", Some("10.0.0.1")).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        }
+        assert_eq!(accounts.login("alice", "password1", Some("10.0.0.2")).await.unwrap_err().kind(), ErrorKind::ResourceBusy);
+        assert_eq!(accounts.login("bob", "password1", Some("10.0.0.1")).await.unwrap_err().kind(), ErrorKind::ResourceBusy);
+        accounts.login("bob", "password1", Some("10.0.0.2")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn signup_disabled_and_rehash() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let mut disabled = accounts(&fs);
+        disabled.config.signup = false;
+        assert_eq!(disabled.signup("alice", "password1").await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        accounts(&fs).signup("alice", "password1").await.unwrap();
+
+        // パラメーターを変えた後のログインで作り直す
+        let mut changed = accounts(&fs);
+        changed.argon2 = password::argon2id(fs.config.account.memory_cost, 2, fs.config.account.parallelism).unwrap();
+        let account = changed.login("alice", "password1", None).await.unwrap();
+        assert!(account.password.contains(",t=2,"), "{}", account.password);
+        assert_eq!(fs.metas.get_account("alice").await.unwrap().unwrap().password, account.password);
+    }
+
+    /// 並行した試行でも max_failures 回より多くはパスワードを確かめない
+    #[tokio::test]
+    async fn parallel_attempts_are_limited() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let accounts = Arc::new(accounts(&fs));
+        accounts.signup("alice", "correct password").await.unwrap();
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let accounts = Arc::clone(&accounts);
+                tokio::spawn(async move { accounts.login("alice", "wrong password", Some("192.0.2.1")).await })
+            })
+            .collect();
+        let mut wrong = 0;
+        for task in tasks {
+            if task.await.unwrap().unwrap_err().kind() == ErrorKind::PermissionDenied {
+                wrong += 1;
+            }
+        }
+        assert_eq!(wrong, 3);
+        assert!(accounts.login("alice", "correct password", Some("192.0.2.2")).await.is_err());
+    }
+
+    /// 成功したログインは接続元の失敗を増やさない
+    #[tokio::test]
+    async fn success_releases_attempt() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let accounts = Arc::new(accounts(&fs));
+        accounts.signup("alice", "correct password").await.unwrap();
+        for _ in 0..5 {
+            accounts.login("alice", "correct password", Some("192.0.2.1")).await.unwrap();
+        }
+        accounts.login("alice", "wrong password", Some("192.0.2.1")).await.unwrap_err();
+        accounts.login("alice", "correct password", Some("192.0.2.1")).await.unwrap();
+    }
+}
@@ -0,0 +1,662 @@
+use std::io::{Error, ErrorKind, Write};
+
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::{write::GzEncoder, Compression};
+use futures::{channel::mpsc, SinkExt, StreamExt};
+
+use super::{file_system::FileSystem, meta::MetaData, path, perm::{Operation, Principal}};
+
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+const TAR_BLOCK: usize = 512;
+/// ustar の size は 11 桁の 8 進数まで
+const TAR_SIZE_LIMIT: u64 = 0o77777777777;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "zip" => Some(Self::Zip),
+            "tar" => Some(Self::Tar),
+            "tar.gz" | "tgz" => Some(Self::TarGz),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::Tar => "tar",
+            Self::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Zip => "application/zip",
+            Self::Tar => "application/x-tar",
+            Self::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// アーカイブに入れるファイルまたはフォルダ
+/// name はアーカイブ内の相対パスで、フォルダは `/` で終わる
+pub struct ArchiveEntry {
+    pub name: String,
+    pub meta: MetaData,
+}
+
+impl FileSystem {
+    /// フォルダ path 以下をアーカイブに入れる順に並べる path 自体の名前が先頭のフォルダになる
+    /// principal が読めないものは入れない (フォルダの場合は中身も)
+    /// 本体の合計が archive_max_size を超える場合は FileTooLarge
+    /// 返したエントリのバイナリは固定されるので、必ず write_archive に渡す
+    pub async fn archive_entries(&self, user: &str, path: &str, principal: &Principal) -> Result<Vec<ArchiveEntry>, Error> {
+        let entries = {
+            let _guard = self.lock.read().await;
+            let root = self.lookup_required(user, path).await?;
+            if !root.is_folder() {
+                return Err(Error::new(ErrorKind::InvalidInput, "not a folder"));
+            }
+            let chain = self.perm_chain(user, path).await?;
+            if !principal.permits(user, path, &chain, Operation::Read) {
+                return Err(Error::new(ErrorKind::PermissionDenied, "folder is not readable"));
+            }
+
+            let mut entries = Vec::new();
+            let mut total: u64 = 0;
+            // フォルダの中身を判定するためにフォルダごとの権限の連なりを持つ
+            let mut stack = vec![(path::name(path).to_string(), root, chain)];
+            while let Some((name, meta, chain)) = stack.pop() {
+                if meta.is_folder() {
+                    let mut children = Vec::with_capacity(meta.links.len());
+                    for id in &meta.links {
+                        match self.metas.get(*id).await? {
+                            Some(child) if principal.permits(user, &child.path, std::iter::once(&child.perm).chain(&chain), Operation::Read) => children.push(child),
+                            _ => {}
+                        }
+                    }
+                    // 名前順に出すため逆順に積む
+                    children.sort_by(|a, b| b.name.cmp(&a.name));
+                    stack.extend(children.into_iter().map(|child| {
+                        let chain = match child.is_folder() {
+                            true => std::iter::once(child.perm.clone()).chain(chain.iter().cloned()).collect(),
+                            false => Vec::new(),
+                        };
+                        (format!("{}/{}", name, child.name), child, chain)
+                    }));
+                    entries.push(ArchiveEntry { name: format!("{}/", name), meta });
+                } else {
+                    total += meta.size;
+                    if self.config.archive_max_size > 0 && total > self.config.archive_max_size {
+                        return Err(Error::new(ErrorKind::FileTooLarge, "archive is too large"));
+                    }
+                    entries.push(ArchiveEntry { name, meta });
+                }
+            }
+            entries
+        };
+
+        // 読み出し中に上書きされても GC されないようにする
+        for (i, entry) in entries.iter().enumerate() {
+            if let Some(blob) = &entry.meta.blob {
+                if let Err(e) = self.pin(blob).await {
+                    for entry in &entries[..i] {
+                        if let Some(blob) = &entry.meta.blob {
+                            self.unpin(blob).await;
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// アーカイブを作りながら tx に流す 一時ファイルは作らない
+    /// 受け取り側が切断した場合は途中でやめる
+    pub async fn write_archive(&self, entries: Vec<ArchiveEntry>, format: ArchiveFormat, mut tx: mpsc::Sender<Result<Bytes, Error>>) {
+        if let Err(e) = self.stream_archive(&entries, format, &mut tx).await {
+            let _ = tx.send(Err(e)).await;
+        }
+        for entry in &entries {
+            if let Some(blob) = &entry.meta.blob {
+                self.unpin(blob).await;
+            }
+        }
+    }
+
+    async fn stream_archive(&self, entries: &[ArchiveEntry], format: ArchiveFormat, tx: &mut mpsc::Sender<Result<Bytes, Error>>) -> Result<(), Error> {
+        let closed = |_| Error::new(ErrorKind::BrokenPipe, "archive receiver is closed");
+        let mut writer = ArchiveWriter::new(format);
+
+        for entry in entries {
+            tx.send(Ok(writer.begin(entry)?)).await.map_err(closed)?;
+            if entry.meta.is_folder() {
+                continue;
+            }
+
+            let mut written: u64 = 0;
+            let mut chunks = match (&entry.meta.inline, &entry.meta.blob) {
+                (Some(data), _) => futures::stream::iter([Ok(Bytes::from(data.clone()))]).boxed(),
+                (None, Some(blob)) => self.blobs.read(blob, 0, entry.meta.size, self.config.streaming_chunk_size),
+                (None, None) => futures::stream::empty().boxed(),
+            };
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+                written += chunk.len() as u64;
+                tx.send(Ok(writer.data(chunk)?)).await.map_err(closed)?;
+            }
+            // ヘッダーに書いた大きさと違うとアーカイブが壊れる
+            if written != entry.meta.size {
+                return Err(Error::new(ErrorKind::InvalidData, format!("size of {} does not match", entry.meta.path)));
+            }
+            tx.send(Ok(writer.end()?)).await.map_err(closed)?;
+        }
+        tx.send(Ok(writer.finish()?)).await.map_err(closed)?;
+        Ok(())
+    }
+}
+
+enum ArchiveWriter {
+    Zip(ZipWriter),
+    Tar(TarWriter),
+    TarGz(TarWriter, GzEncoder<Vec<u8>>),
+}
+
+impl ArchiveWriter {
+    fn new(format: ArchiveFormat) -> Self {
+        match format {
+            ArchiveFormat::Zip => Self::Zip(ZipWriter::default()),
+            ArchiveFormat::Tar => Self::Tar(TarWriter::default()),
+            ArchiveFormat::TarGz => Self::TarGz(TarWriter::default(), GzEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    /// 圧縮する場合は圧縮器に通して、出てきた分だけ返す
+    fn output(&mut self, raw: Vec<u8>) -> Result<Bytes, Error> {
+        match self {
+            Self::Zip(_) | Self::Tar(_) => Ok(Bytes::from(raw)),
+            Self::TarGz(_, gz) => {
+                gz.write_all(&raw)?;
+                Ok(Bytes::from(std::mem::take(gz.get_mut())))
+            }
+        }
+    }
+
+    fn begin(&mut self, entry: &ArchiveEntry) -> Result<Bytes, Error> {
+        let raw = match self {
+            Self::Zip(zip) => zip.begin(entry),
+            Self::Tar(tar) | Self::TarGz(tar, _) => tar.begin(entry),
+        };
+        self.output(raw)
+    }
+
+    fn data(&mut self, chunk: Bytes) -> Result<Bytes, Error> {
+        match self {
+            Self::Zip(zip) => {
+                zip.data(&chunk);
+                Ok(chunk)
+            }
+            Self::Tar(tar) => {
+                tar.data(&chunk);
+                Ok(chunk)
+            }
+            Self::TarGz(tar, _) => {
+                tar.data(&chunk);
+                self.output(chunk.to_vec())
+            }
+        }
+    }
+
+    fn end(&mut self) -> Result<Bytes, Error> {
+        let raw = match self {
+            Self::Zip(zip) => zip.end(),
+            Self::Tar(tar) | Self::TarGz(tar, _) => tar.end(),
+        };
+        self.output(raw)
+    }
+
+    fn finish(&mut self) -> Result<Bytes, Error> {
+        match self {
+            Self::Zip(zip) => Ok(Bytes::from(zip.finish())),
+            Self::Tar(tar) => Ok(Bytes::from(tar.finish())),
+            Self::TarGz(tar, gz) => {
+                gz.write_all(&tar.finish())?;
+                let gz = std::mem::replace(gz, GzEncoder::new(Vec::new(), Compression::default()));
+                Ok(Bytes::from(gz.finish()?))
+            }
+        }
+    }
+}
+
+fn datetime(time_millis: i64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_timestamp_millis(time_millis).unwrap_or_default()
+}
+
+struct ZipEntry {
+    name: Vec<u8>,
+    folder: bool,
+    time: (u16, u16),
+    crc: u32,
+    size: u64,
+    offset: u64,
+    zip64: bool,
+}
+
+/// 無圧縮 (stored) の ZIP を前から順に書く
+/// CRC は書き終わるまで分からないのでデータディスクリプタを使い、正しい値は中央ディレクトリに置く
+/// 名前は UTF-8 フラグ (bit 11) を立てて UTF-8 のまま書く
+#[derive(Default)]
+struct ZipWriter {
+    offset: u64,
+    entries: Vec<ZipEntry>,
+    current: Option<ZipEntry>,
+    hasher: crc32fast::Hasher,
+}
+
+impl ZipWriter {
+    const UTF8: u16 = 1 << 11;
+    /// フォルダは中身が無いのでデータディスクリプタを使わない
+    const DESCRIPTOR: u16 = 1 << 3;
+
+    fn flags(folder: bool) -> u16 {
+        match folder {
+            true => Self::UTF8,
+            false => Self::UTF8 | Self::DESCRIPTOR,
+        }
+    }
+
+    /// UTF-8 フラグを見ない古い展開ソフト向けの Info-ZIP Unicode Path
+    fn unicode_path(name: &[u8]) -> Vec<u8> {
+        if name.is_ascii() {
+            return Vec::new();
+        }
+        let mut extra = Vec::with_capacity(9 + name.len());
+        extra.extend_from_slice(&0x7075u16.to_le_bytes());
+        extra.extend_from_slice(&((5 + name.len()) as u16).to_le_bytes());
+        extra.push(1);
+        extra.extend_from_slice(&crc32fast::hash(name).to_le_bytes());
+        extra.extend_from_slice(name);
+        extra
+    }
+
+    /// DOS の日付と時刻 (1980 年より前は 1980 年にする)
+    fn dos_time(time_millis: i64) -> (u16, u16) {
+        let t = datetime(time_millis);
+        if t.year() < 1980 {
+            return (0, (1 << 5) | 1);
+        }
+        let time = ((t.hour() << 11) | (t.minute() << 5) | (t.second() / 2)) as u16;
+        let date = ((((t.year() - 1980) as u32) << 9) | (t.month() << 5) | t.day()) as u16;
+        (time, date)
+    }
+
+    fn begin(&mut self, entry: &ArchiveEntry) -> Vec<u8> {
+        let folder = entry.meta.is_folder();
+        let zip64 = entry.meta.size >= ZIP64_LIMIT || self.offset >= ZIP64_LIMIT;
+        let current = ZipEntry {
+            name: entry.name.as_bytes().to_vec(),
+            folder,
+            time: Self::dos_time(entry.meta.update_time),
+            crc: 0,
+            size: if folder { 0 } else { entry.meta.size },
+            offset: self.offset,
+            zip64,
+        };
+        self.hasher = crc32fast::Hasher::new();
+
+        let mut header = Vec::with_capacity(30 + current.name.len() + 20);
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+        header.extend_from_slice(&Self::flags(folder).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // stored
+        header.extend_from_slice(&current.time.0.to_le_bytes());
+        header.extend_from_slice(&current.time.1.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes()); // crc はデータディスクリプタに書く (フォルダは 0)
+        let size = if zip64 { u32::MAX } else { 0 };
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        let mut extra = Vec::new();
+        if zip64 {
+            extra.extend_from_slice(&0x0001u16.to_le_bytes());
+            extra.extend_from_slice(&16u16.to_le_bytes());
+            extra.extend_from_slice(&0u64.to_le_bytes());
+            extra.extend_from_slice(&0u64.to_le_bytes());
+        }
+        extra.extend(Self::unicode_path(&current.name));
+        header.extend_from_slice(&(current.name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        header.extend_from_slice(&current.name);
+        header.extend_from_slice(&extra);
+
+        self.offset += header.len() as u64;
+        self.current = Some(current);
+        if folder {
+            self.end_current();
+        }
+        header
+    }
+
+    fn data(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+        self.offset += chunk.len() as u64;
+    }
+
+    fn end_current(&mut self) -> Option<&ZipEntry> {
+        let mut entry = self.current.take()?;
+        entry.crc = std::mem::take(&mut self.hasher).finalize();
+        self.entries.push(entry);
+        self.entries.last()
+    }
+
+    fn end(&mut self) -> Vec<u8> {
+        let entry = match self.end_current() {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&entry.crc.to_le_bytes());
+        if entry.zip64 {
+            descriptor.extend_from_slice(&entry.size.to_le_bytes());
+            descriptor.extend_from_slice(&entry.size.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(entry.size as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(entry.size as u32).to_le_bytes());
+        }
+        self.offset += descriptor.len() as u64;
+        descriptor
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let start = self.offset;
+        let mut out = Vec::new();
+        for entry in &self.entries {
+            let mut extra = Vec::new();
+            if entry.size >= ZIP64_LIMIT || entry.offset >= ZIP64_LIMIT {
+                let mut fields = Vec::new();
+                if entry.size >= ZIP64_LIMIT {
+                    fields.extend_from_slice(&entry.size.to_le_bytes());
+                    fields.extend_from_slice(&entry.size.to_le_bytes());
+                }
+                if entry.offset >= ZIP64_LIMIT {
+                    fields.extend_from_slice(&entry.offset.to_le_bytes());
+                }
+                extra.extend_from_slice(&0x0001u16.to_le_bytes());
+                extra.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+                extra.extend_from_slice(&fields);
+            }
+            extra.extend(Self::unicode_path(&entry.name));
+
+            let size = entry.size.min(ZIP64_LIMIT) as u32;
+            // 上位 8bit の 3 は UNIX、外部属性の上位 16bit は UNIX のモード
+            let (mode, dos) = if entry.folder { (0o040755u32, 0x10u32) } else { (0o100644u32, 0u32) };
+            out.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            out.extend_from_slice(&((3u16 << 8) | 45).to_le_bytes());
+            out.extend_from_slice(&(if entry.zip64 || entry.offset >= ZIP64_LIMIT { 45u16 } else { 20u16 }).to_le_bytes());
+            out.extend_from_slice(&Self::flags(entry.folder).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&entry.time.0.to_le_bytes());
+            out.extend_from_slice(&entry.time.1.to_le_bytes());
+            out.extend_from_slice(&entry.crc.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes()); // comment
+            out.extend_from_slice(&0u16.to_le_bytes()); // disk
+            out.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            out.extend_from_slice(&((mode << 16) | dos).to_le_bytes());
+            out.extend_from_slice(&(entry.offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+            out.extend_from_slice(&entry.name);
+            out.extend_from_slice(&extra);
+        }
+
+        let size = out.len() as u64;
+        let count = self.entries.len() as u64;
+        if count >= 0xFFFF || start >= ZIP64_LIMIT || size >= ZIP64_LIMIT {
+            let record = start + size;
+            out.extend_from_slice(&0x06064b50u32.to_le_bytes());
+            out.extend_from_slice(&44u64.to_le_bytes());
+            out.extend_from_slice(&((3u16 << 8) | 45).to_le_bytes());
+            out.extend_from_slice(&45u16.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&start.to_le_bytes());
+
+            out.extend_from_slice(&0x07064b50u32.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&record.to_le_bytes());
+            out.extend_from_slice(&1u32.to_le_bytes());
+        }
+        out.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+        out.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+        out.extend_from_slice(&(size.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        out.extend_from_slice(&(start.min(ZIP64_LIMIT) as u32).to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+}
+
+/// ustar 形式の tar を前から順に書く
+/// 長い名前、ASCII 以外の名前、大きいファイルは PAX 拡張ヘッダーに書く
+#[derive(Default)]
+struct TarWriter {
+    written: u64,
+}
+
+impl TarWriter {
+    fn header(name: &[u8], size: u64, mode: u32, mtime: i64, kind: u8) -> [u8; TAR_BLOCK] {
+        let mut block = [0u8; TAR_BLOCK];
+        let octal = |field: &mut [u8], value: u64| {
+            let text = format!("{:0width$o}", value, width = field.len() - 1);
+            field[..text.len()].copy_from_slice(text.as_bytes());
+        };
+        let len = name.len().min(100);
+        block[..len].copy_from_slice(&name[..len]);
+        octal(&mut block[100..108], mode as u64);
+        octal(&mut block[108..116], 0);
+        octal(&mut block[116..124], 0);
+        octal(&mut block[124..136], size.min(TAR_SIZE_LIMIT));
+        octal(&mut block[136..148], mtime.max(0) as u64);
+        block[156] = kind;
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+
+        block[148..156].fill(b' ');
+        let checksum: u64 = block.iter().map(|b| *b as u64).sum();
+        let text = format!("{:06o}\0 ", checksum);
+        block[148..156].copy_from_slice(text.as_bytes());
+        block
+    }
+
+    /// PAX のレコード `<len> <key>=<value>\n` (len は自身を含む)
+    fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+        let body = key.len() + value.len() + 3;
+        let mut len = body + body.to_string().len();
+        if len.to_string().len() != body.to_string().len() {
+            len += 1;
+        }
+        let mut record = format!("{} {}=", len, key).into_bytes();
+        record.extend_from_slice(value);
+        record.push(b'\n');
+        record
+    }
+
+    fn padding(size: u64) -> usize {
+        (TAR_BLOCK - (size % TAR_BLOCK as u64) as usize) % TAR_BLOCK
+    }
+
+    fn begin(&mut self, entry: &ArchiveEntry) -> Vec<u8> {
+        let folder = entry.meta.is_folder();
+        let size = if folder { 0 } else { entry.meta.size };
+        let mtime = entry.meta.update_time / 1000;
+        let name = entry.name.as_bytes();
+        let mut out = Vec::new();
+
+        let mut pax = Vec::new();
+        if name.len() > 100 || !entry.name.is_ascii() {
+            pax.extend(Self::pax_record("path", name));
+        }
+        if size > TAR_SIZE_LIMIT {
+            pax.extend(Self::pax_record("size", size.to_string().as_bytes()));
+        }
+        if !pax.is_empty() {
+            out.extend_from_slice(&Self::header(b"././@PaxHeader", pax.len() as u64, 0o644, mtime, b'x'));
+            let padding = Self::padding(pax.len() as u64);
+            out.extend(pax);
+            out.extend(std::iter::repeat_n(0u8, padding));
+        }
+
+        // PAX を読まない展開ソフト向けに ASCII に置き換えた名前を入れておく
+        let fallback: Vec<u8> = entry.name.bytes().map(|b| if b.is_ascii() { b } else { b'_' }).collect();
+        let (mode, kind) = if folder { (0o755, b'5') } else { (0o644, b'0') };
+        out.extend_from_slice(&Self::header(&fallback, size, mode, mtime, kind));
+        self.written = 0;
+        out
+    }
+
+    fn data(&mut self, chunk: &[u8]) {
+        self.written += chunk.len() as u64;
+    }
+
+    fn end(&mut self) -> Vec<u8> {
+        vec![0u8; Self::padding(self.written)]
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        vec![0u8; TAR_BLOCK * 2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Read},
+        sync::Arc,
+    };
+
+    use futures::{channel::mpsc, StreamExt};
+
+    use super::ArchiveFormat;
+    use crate::{
+        file_system::{
+            file_system::FileSystem,
+            perm::Principal,
+            test_support::{self, Backend},
+        },
+        utils::ruid::RuidGenerator,
+    };
+
+    const BOB: u128 = 0xb0b;
+
+    fn bob() -> Principal {
+        Principal { user: Some("bob".to_string()), ids: [BOB].into(), link: None }
+    }
+
+    /// bob から見たアーカイブ secret は読めない
+    async fn archive(fs: &FileSystem, format: ArchiveFormat) -> Vec<u8> {
+        let entries = fs.archive_entries("alice", "/docs", &bob()).await.unwrap();
+        let (tx, rx) = mpsc::channel(4);
+        let (_, chunks) = futures::join!(fs.write_archive(entries, format, tx), rx.collect::<Vec<_>>());
+        chunks.into_iter().flat_map(|chunk| chunk.unwrap().to_vec()).collect()
+    }
+
+    async fn setup(backend: Backend) -> (tempfile::TempDir, Arc<FileSystem>) {
+        let (dir, fs) = test_support::file_system(backend).await;
+        test_support::write(&fs, "alice", "/docs/日本語.txt", "こんにちは、アーカイブの中の世界".as_bytes()).await;
+        test_support::write(&fs, "alice", "/docs/sub/a.txt", b"alpha").await;
+        test_support::write(&fs, "alice", "/docs/secret", b"hidden data").await;
+        fs.create_folder("alice", "/docs/empty").await.unwrap();
+        let mut docs = fs.get("alice", "/docs").await.unwrap();
+        docs.perm.read.allow.insert(BOB);
+        fs.metas.put(&docs).await.unwrap();
+        let mut secret = fs.get("alice", "/docs/secret").await.unwrap();
+        secret.perm.read.deny.insert(BOB);
+        fs.metas.put(&secret).await.unwrap();
+        (dir, fs)
+    }
+
+    #[tokio::test]
+    async fn zip_round_trip() {
+        for backend in test_support::BACKENDS {
+            let (_dir, fs) = setup(backend).await;
+            let data = archive(&fs, ArchiveFormat::Zip).await;
+
+            let mut zip = zip::ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+            let mut names: Vec<String> = zip.file_names().map(str::to_string).collect();
+            names.sort();
+            assert_eq!(names, ["docs/", "docs/empty/", "docs/sub/", "docs/sub/a.txt", "docs/日本語.txt"], "{:?}", backend);
+            assert!(zip.by_name("docs/empty/").unwrap().is_dir());
+
+            let mut text = String::new();
+            zip.by_name("docs/日本語.txt").unwrap().read_to_string(&mut text).unwrap();
+            assert_eq!(text, "こんにちは、アーカイブの中の世界");
+            text.clear();
+            zip.by_name("docs/sub/a.txt").unwrap().read_to_string(&mut text).unwrap();
+            assert_eq!(text, "alpha");
+        }
+    }
+
+    #[tokio::test]
+    async fn tar_round_trip() {
+        for backend in test_support::BACKENDS {
+            let (_dir, fs) = setup(backend).await;
+            let data = archive(&fs, ArchiveFormat::TarGz).await;
+
+            let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(data.as_slice()));
+            let mut files = Vec::new();
+            for entry in tar.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().display().to_string();
+                let mut body = String::new();
+                entry.read_to_string(&mut body).unwrap();
+                files.push((name, entry.header().entry_type().is_dir(), body));
+            }
+            let expected = [
+                ("docs", true, ""),
+                ("docs/empty", true, ""),
+                ("docs/sub", true, ""),
+                ("docs/sub/a.txt", false, "alpha"),
+                ("docs/日本語.txt", false, "こんにちは、アーカイブの中の世界"),
+            ];
+            let files: Vec<_> = files.iter().map(|(n, d, b)| (n.trim_end_matches('/'), *d, b.as_str())).collect();
+            assert_eq!(files, expected, "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn unreadable_root_and_size_limit() {
+        let (_dir, fs) = setup(Backend::Memory).await;
+        let carol = Principal { user: Some("carol".to_string()), ids: [0xca201].into(), link: None };
+        let denied = fs.archive_entries("alice", "/docs", &carol).await.err().unwrap();
+        assert_eq!(denied.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs.archive_entries("alice", "/docs/sub/a.txt", &bob()).await.err().unwrap().kind(), ErrorKind::InvalidInput);
+        // 所有者には全て見える
+        let owner = Principal { user: Some("alice".to_string()), ..Default::default() };
+        assert_eq!(fs.archive_entries("alice", "/docs", &owner).await.unwrap().len(), 6);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_support::config(dir.path(), Backend::Memory);
+        config.archive_max_size = 8;
+        let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap();
+        test_support::write(&fs, "alice", "/docs/a.txt", b"alpha").await;
+        assert!(fs.archive_entries("alice", "/docs", &owner).await.is_ok());
+        test_support::write(&fs, "alice", "/docs/b.txt", b"beta").await;
+        assert_eq!(fs.archive_entries("alice", "/docs", &owner).await.err().unwrap().kind(), ErrorKind::FileTooLarge);
+    }
+}
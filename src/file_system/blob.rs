@@ -0,0 +1,377 @@
+use std::{io::{Error, ErrorKind}, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use log::{error, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use super::file_system::FileSystem;
+
+/// バイナリのキーは内容の sha256 (小文字 hex 64文字)
+/// 同じ内容は一つのバイナリを共有し、参照数はメタデータの blob から数える
+pub fn is_content_key(key: &str) -> bool {
+    key.len() == 64 && key.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// 暗号化したバイナリのキー 内容の sha256 に `u` とユーザー名の sha256 を続ける
+/// 平文の同じ内容や他のユーザーのバイナリとは共有しない
+pub fn sealed_key(user: &str, checksum: &str) -> String {
+    format!("{}u{:x}", checksum, Sha256::digest(user.as_bytes()))
+}
+
+/// キーが表す内容の sha256 内容アドレスでない古いキーは None
+fn content_of(key: &str) -> Option<&str> {
+    match key.split_once('u') {
+        Some((checksum, user)) if is_content_key(checksum) && is_content_key(user) => Some(checksum),
+        Some(_) => None,
+        None => Some(key).filter(|key| is_content_key(key)),
+    }
+}
+
+/// ファイルの本体
+/// 小さいものはメタデータに直接持ち、それ以外は BlobStore に置く
+pub enum Content {
+    Inline(Vec<u8>),
+    Blob(String),
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    pub checked: u64,
+    /// 内容アドレスでない古いキー
+    pub skipped: u64,
+    pub corrupt: Vec<String>,
+}
+
+impl FileSystem {
+    /// バイナリを一時ファイルに書き出して (path, size, sha256) を返す
+    pub async fn save_temp<S, E>(&self, mut stream: S) -> Result<(PathBuf, u64, String), Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let temp_path = self.temp_path();
+        let mut file = File::create(&temp_path).await?;
+        let mut hasher = Sha256::new();
+        let mut size: u64 = 0;
+
+        while let Some(chunk) = stream.next().await {
+            let data = match chunk {
+                Ok(data) => data,
+                Err(e) => {
+                    drop(file);
+                    let _ = tokio::fs::remove_file(&temp_path).await;
+                    return Err(Error::new(ErrorKind::Interrupted, e.to_string()));
+                }
+            };
+            hasher.update(&data);
+            size += data.len() as u64;
+            file.write_all(&data).await?;
+        }
+        file.flush().await?;
+
+        Ok((temp_path, size, format!("{:x}", hasher.finalize())))
+    }
+
+    /// sha256 が checksum の一時ファイルを取り込み、キーを返す
+    /// 同じ内容が既にある場合は一時ファイルを捨てる
+    /// user の暗号化が有効な場合は sealed_key に暗号化して保存する 平文の同じ内容には触らない
+    /// 返したキーは create_file するまで GC されない
+    pub async fn import_binary(&self, user: &str, from: &Path, checksum: &str) -> Result<String, Error> {
+        if !is_content_key(checksum) {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid checksum"));
+        }
+        let ring = self.keyring.as_deref().filter(|ring| ring.enabled_for(user));
+        let key = match ring {
+            Some(_) => sealed_key(user, checksum),
+            None => checksum.to_string(),
+        };
+
+        let mut pins = self.pins.lock().await;
+        let exists = self.blobs.size(&key).await?.is_some();
+        match ring {
+            Some(ring) if !exists || ring.blob_key(&key).await?.is_none() => self.import_sealed(ring, user, &key, from).await?,
+            _ if exists => tokio::fs::remove_file(from).await?,
+            _ => self.blobs.import(&key, from).await?,
+        }
+        *pins.entry(key.clone()).or_insert(0) += 1;
+        Ok(key)
+    }
+
+    /// 一時ファイルを inline_threshold に従ってメタデータ用か BlobStore 用に振り分ける
+    /// 暗号化するユーザーはメタデータに平文を持たないよう常に BlobStore に置く
+    pub async fn import_content(&self, user: &str, from: &Path, size: u64, checksum: &str) -> Result<Content, Error> {
+        let sealed = self.keyring.as_ref().is_some_and(|ring| ring.enabled_for(user));
+        if size > self.config.inline_threshold || sealed {
+            return Ok(Content::Blob(self.import_binary(user, from, checksum).await?));
+        }
+        let data = tokio::fs::read(from).await?;
+        tokio::fs::remove_file(from).await?;
+        Ok(Content::Inline(data))
+    }
+
+    /// 本体を保存して (content, size, sha256) を返す
+    pub async fn save_binary<S, E>(&self, user: &str, stream: S) -> Result<(Content, u64, String), Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let (temp_path, size, checksum) = self.save_temp(stream).await?;
+        match self.import_content(user, &temp_path, size, &checksum).await {
+            Ok(content) => Ok((content, size, checksum)),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// ファイルの本体を全て読み込む 設定ファイルなど小さいもの向け
+    pub async fn read_all(&self, user: &str, path: &str) -> Result<Vec<u8>, Error> {
+        let meta = self.get(user, path).await?;
+        match (meta.inline, meta.blob) {
+            (Some(data), _) => Ok(data),
+            (None, Some(blob)) => self.read_blob(&blob, meta.size).await,
+            (None, None) => Err(Error::new(ErrorKind::InvalidInput, "not a file")),
+        }
+    }
+
+    pub(super) async fn read_blob(&self, key: &str, size: u64) -> Result<Vec<u8>, Error> {
+        let mut stream = self.blobs.read(key, 0, size, self.config.streaming_chunk_size);
+        let mut data = Vec::with_capacity(size as usize);
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
+
+    /// 既にあるバイナリを create_file するまで GC から守る
+    pub(super) async fn pin(&self, key: &str) -> Result<(), Error> {
+        let mut pins = self.pins.lock().await;
+        if self.blobs.size(key).await?.is_none() {
+            return Err(Error::new(ErrorKind::NotFound, "blob is not found"));
+        }
+        *pins.entry(key.to_string()).or_insert(0) += 1;
+        Ok(())
+    }
+
+    pub(super) async fn unpin(&self, key: &str) {
+        let mut pins = self.pins.lock().await;
+        if let Some(count) = pins.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(key);
+            }
+        }
+    }
+
+    /// 固定されておらず、どのメタデータからも参照されていないバイナリを削除する
+    /// pins を取ったまま判定するため、同じ内容のアップロードと競合しない
+    async fn release(&self, key: &str) -> Result<bool, Error> {
+        let pins = self.pins.lock().await;
+        if pins.contains_key(key) || self.metas.count_blob_refs(key).await? > 0 {
+            return Ok(false);
+        }
+        self.blobs.delete(key).await?;
+        Ok(true)
+    }
+
+    pub(super) async fn release_blobs(&self, blobs: impl IntoIterator<Item = String>) {
+        for blob in blobs {
+            if let Err(e) = self.release(&blob).await {
+                error!("Failed to release blob {}: {}", blob, e);
+            }
+        }
+    }
+
+    /// 参照されていないバイナリを全て削除する
+    pub async fn collect_garbage(&self) -> Result<u64, Error> {
+        let mut removed = 0;
+        for key in self.blobs.list().await? {
+            match self.release(&key).await {
+                Ok(true) => removed += 1,
+                Ok(false) => {}
+                Err(e) => error!("Failed to release blob {}: {}", key, e),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// 全てのバイナリを読み直して sha256 がキーの内容と一致するか確かめる
+    pub async fn verify_blobs(&self) -> Result<VerifyReport, Error> {
+        let mut report = VerifyReport::default();
+        for key in self.blobs.list().await? {
+            let checksum = match content_of(&key) {
+                Some(checksum) => checksum.to_string(),
+                None => {
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+            report.checked += 1;
+            match self.hash_blob(&key).await {
+                Ok(Some(digest)) if digest == checksum => {}
+                Ok(None) => {}
+                Ok(Some(_)) => {
+                    warn!("blob {} is corrupted", key);
+                    report.corrupt.push(key);
+                }
+                Err(e) => {
+                    warn!("blob {} can't be read: {}", key, e);
+                    report.corrupt.push(key);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// 削除済みの場合は None
+    async fn hash_blob(&self, key: &str) -> Result<Option<String>, Error> {
+        let size = match self.blobs.size(key).await? {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        let mut stream = self.blobs.read(key, 0, size, self.config.streaming_chunk_size);
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+
+    pub async fn run_blob_gc(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.collect_garbage().await {
+                Ok(0) => {}
+                Ok(count) => info!("removed {} unreferenced blobs", count),
+                Err(e) => error!("Failed to collect garbage blobs: {}", e),
+            }
+        }
+    }
+
+    pub async fn run_blob_verifier(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // 起動直後は走らせない
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match self.verify_blobs().await {
+                Ok(report) if report.corrupt.is_empty() => info!("verified {} blobs", report.checked),
+                Ok(report) => error!("{} of {} blobs are corrupted: {:?}", report.corrupt.len(), report.checked, report.corrupt),
+                Err(e) => error!("Failed to verify blobs: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::stream;
+    use sha2::{Digest, Sha256};
+
+    use crate::file_system::test_support::{self, Backend, BACKENDS};
+
+    use super::{is_content_key, sealed_key, Content};
+
+    /// 同じ内容は一つのバイナリを共有し、最後の参照が消えたときに削除する
+    #[tokio::test]
+    async fn same_content_is_shared() {
+        for backend in BACKENDS {
+            let (_dir, fs) = test_support::file_system(backend).await;
+            // 暗号化したバイナリはユーザーをまたいで共有しない
+            let checksum = format!("{:x}", Sha256::digest(b"same content in two files"));
+            let (other, expected) = match backend {
+                Backend::Encrypted => ("alice", sealed_key("alice", &checksum)),
+                _ => ("bob", checksum),
+            };
+            let a = test_support::write(&fs, "alice", "/a.txt", b"same content in two files").await;
+            let b = test_support::write(&fs, other, "/b.txt", b"same content in two files").await;
+            let key = a.blob.clone().unwrap();
+            assert_eq!(key, expected, "{:?}", backend);
+            assert_eq!(b.blob.as_deref(), Some(key.as_str()), "{:?}", backend);
+            assert_eq!(fs.metas.count_blob_refs(&key).await.unwrap(), 2, "{:?}", backend);
+
+            let (_, entry) = fs.remove("alice", "/a.txt", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+            fs.purge(None, entry.id).await.unwrap();
+            assert_eq!(fs.blobs.size(&key).await.unwrap(), Some(25), "{:?}", backend);
+            let (_, entry) = fs.remove(other, "/b.txt", &fs.principal(Some(other)).await.unwrap(), |_| {}).await.unwrap();
+            fs.purge(None, entry.id).await.unwrap();
+            assert_eq!(fs.blobs.size(&key).await.unwrap(), None, "{:?}", backend);
+        }
+    }
+
+    /// create_file する前のバイナリは GC で消さない
+    #[tokio::test]
+    async fn gc_keeps_pinned_blobs() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/a.txt", b"referenced by a file").await;
+        fs.blobs.put("0123", Bytes::from_static(b"orphan")).await.unwrap();
+        let chunks = stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from_static(b"uploaded but not created"))]);
+        let pending = match fs.save_binary("alice", chunks).await.unwrap() {
+            (Content::Blob(key), _, _) => key,
+            _ => panic!("expected a blob"),
+        };
+
+        assert_eq!(fs.collect_garbage().await.unwrap(), 1);
+        assert_eq!(fs.blobs.size("0123").await.unwrap(), None);
+        assert_eq!(fs.blobs.size(&pending).await.unwrap(), Some(24));
+        assert_eq!(fs.blobs.list().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn verify_finds_corruption() {
+        let (_dir, fs) = test_support::file_system(Backend::Local).await;
+        let good = test_support::write(&fs, "alice", "/good.txt", b"good content over sixteen").await;
+        let bad = test_support::write(&fs, "alice", "/bad.txt", b"bad content over sixteen").await;
+        fs.blobs.put(bad.blob.as_deref().unwrap(), Bytes::from_static(b"broken")).await.unwrap();
+        fs.blobs.put("0123", Bytes::from_static(b"legacy")).await.unwrap();
+
+        let report = fs.verify_blobs().await.unwrap();
+        assert_eq!((report.checked, report.skipped), (2, 1));
+        assert_eq!(report.corrupt, [bad.blob.unwrap()]);
+        assert!(is_content_key(good.blob.as_deref().unwrap()));
+        assert!(!is_content_key("ABC"));
+    }
+
+    /// inline_threshold 以下はメタデータに持ち、上書きで閾値をまたぐと移し替える
+    #[tokio::test]
+    async fn small_files_are_inline() {
+        for backend in [Backend::Memory, Backend::Local] {
+            let (dir, fs) = test_support::file_system(backend).await;
+            let small = test_support::write(&fs, "alice", "/a.txt", b"sixteen bytes!!!").await;
+            assert_eq!((small.inline.as_deref(), small.blob.as_deref()), (Some(&b"sixteen bytes!!!"[..]), None), "{:?}", backend);
+
+            let large = test_support::write(&fs, "alice", "/a.txt", b"seventeen bytes!!").await;
+            let key = large.blob.clone().unwrap();
+            assert_eq!((large.id, large.inline.as_deref()), (small.id, None), "{:?}", backend);
+            assert_eq!(fs.blobs.size(&key).await.unwrap(), Some(17), "{:?}", backend);
+
+            let small = test_support::write(&fs, "alice", "/a.txt", b"small").await;
+            assert_eq!((small.inline.as_deref(), small.blob.as_deref()), (Some(&b"small"[..]), None), "{:?}", backend);
+
+            let temp = dir.path().join("temp");
+            std::fs::write(&temp, b"tiny").unwrap();
+            assert!(matches!(fs.import_content("alice", &temp, 4, &format!("{:x}", Sha256::digest(b"tiny"))).await.unwrap(), Content::Inline(data) if data == b"tiny"));
+            assert!(!temp.exists(), "{:?}", backend);
+        }
+    }
+
+    /// 暗号化するユーザーのファイルは小さくても BlobStore に置く
+    #[tokio::test]
+    async fn encrypted_files_are_not_inline() {
+        let (dir, fs) = test_support::file_system(Backend::Encrypted).await;
+        let small = test_support::write(&fs, "alice", "/a.txt", b"small").await;
+        assert_eq!((small.inline.as_deref(), small.blob.is_some()), (None, true));
+        assert_eq!(fs.read_all("alice", "/a.txt").await.unwrap(), b"small");
+
+        let temp = dir.path().join("temp");
+        std::fs::write(&temp, b"tiny").unwrap();
+        let checksum = format!("{:x}", Sha256::digest(b"tiny"));
+        assert!(matches!(fs.import_content("alice", &temp, 4, &checksum).await.unwrap(), Content::Blob(key) if key == sealed_key("alice", &checksum)));
+    }
+}
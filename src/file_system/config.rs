@@ -0,0 +1,41 @@
+use serde::Deserialize;
+
+use super::{account::AccountConfig, crypto::EncryptionConfig, extract::ExtractConfig, fsck::FsckConfig, lifecycle::LifecycleConfig, preview::PreviewConfig, perm::PermConfig, quota::QuotaConfig, share_link::ShareLinkConfig, store::StoreKind};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileSystemConfig {
+    pub storage_path: String,
+    pub streaming_chunk_size: usize,
+    pub upload_max_size: u64,
+    pub upload_expire: u64,
+    pub upload_sweep_interval: u64,
+    pub trash_retention: u64,
+    pub trash_purge_interval: u64,
+    pub lifecycle: LifecycleConfig,
+    /// 上書きで残す古い版の数 0 で版を残さない
+    pub version_retention_count: usize,
+    /// 古い版を残す秒数 0 で無期限
+    pub version_retention_age: u64,
+    pub blob_gc_interval: u64,
+    pub blob_verify_interval: u64,
+    pub fsck: FsckConfig,
+    /// この大きさ以下のファイルはメタデータに直接保存する 0 で無効
+    pub inline_threshold: u64,
+    /// フォルダをアーカイブでダウンロードするときの本体の合計の上限 0 で無制限
+    pub archive_max_size: u64,
+    pub extract: ExtractConfig,
+    /// ユーザーのディレクトリのテンプレート (template.json のあるディレクトリ)
+    pub template_path: String,
+    /// 起動時に全ユーザーをテンプレートの版に揃える
+    pub provision_on_start: bool,
+    pub quota: QuotaConfig,
+    pub account: AccountConfig,
+    pub perm: PermConfig,
+    pub share_link: ShareLinkConfig,
+    pub encryption: EncryptionConfig,
+    pub preview: PreviewConfig,
+    pub metadata_store: StoreKind,
+    pub blob_store: StoreKind,
+    pub mongodb_uri: String,
+    pub mongodb_database: String,
+}
@@ -0,0 +1,137 @@
+//! 保存先ごとに同じ操作をして、同じ結果になることを確かめる
+//! memory、local (一時ディレクトリ)、暗号化した local の全てで動かす
+
+use std::sync::Arc;
+
+use super::test_support::{self, Backend, BACKENDS};
+
+const SMALL: &[u8] = b"inline";
+const LARGE: &[u8] = b"this body is larger than the inline threshold";
+
+#[tokio::test]
+async fn write_read_and_list() {
+    for backend in BACKENDS {
+        let (_dir, fs) = test_support::file_system(backend).await;
+        let small = test_support::write(&fs, "alice", "/home/docs/small.txt", SMALL).await;
+        let large = test_support::write(&fs, "alice", "/home/docs/large.txt", LARGE).await;
+
+        assert_eq!(small.inline.is_some(), backend != Backend::Encrypted, "{:?}", backend);
+        assert!(large.blob.is_some(), "{:?}", backend);
+        assert_eq!(fs.read_all("alice", "/home/docs/small.txt").await.unwrap(), SMALL, "{:?}", backend);
+        assert_eq!(fs.read_all("alice", "/home/docs/large.txt").await.unwrap(), LARGE, "{:?}", backend);
+
+        let (folder, children) = fs.list("alice", "/home/docs").await.unwrap();
+        assert!(folder.is_folder());
+        let mut names: Vec<_> = children.iter().map(|c| c.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["large.txt", "small.txt"], "{:?}", backend);
+        assert!(fs.get("bob", "/home/docs/small.txt").await.is_err(), "{:?}", backend);
+    }
+}
+
+#[tokio::test]
+async fn overwrite_keeps_versions() {
+    for backend in BACKENDS {
+        let (_dir, fs) = test_support::file_system(backend).await;
+        test_support::write(&fs, "alice", "/home/a.txt", LARGE).await;
+        let meta = test_support::write(&fs, "alice", "/home/a.txt", b"second body, also long enough").await;
+        assert_eq!(meta.version, 2, "{:?}", backend);
+
+        let (_, versions) = fs.versions("alice", "/home/a.txt").await.unwrap();
+        assert_eq!(versions.len(), 1, "{:?}", backend);
+        let old = fs.version("alice", "/home/a.txt", versions[0].version).await.unwrap();
+        assert_eq!(fs.read_version(&old).await.unwrap(), LARGE, "{:?}", backend);
+    }
+}
+
+#[tokio::test]
+async fn blob_refs_follow_metadata() {
+    for backend in BACKENDS {
+        let (_dir, fs) = test_support::file_system(backend).await;
+        let a = test_support::write(&fs, "alice", "/home/a.txt", LARGE).await;
+        let b = test_support::write(&fs, "alice", "/home/b.txt", LARGE).await;
+        let blob = a.blob.clone().unwrap();
+        assert_eq!(b.blob.as_deref(), Some(blob.as_str()), "same content shares a blob: {:?}", backend);
+        assert_eq!(fs.metas.count_blob_refs(&blob).await.unwrap(), 2, "{:?}", backend);
+
+        // ゴミ箱の中も参照に数える
+        let (_, entry) = fs.remove("alice", "/home/a.txt", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        assert_eq!(fs.metas.count_blob_refs(&blob).await.unwrap(), 2, "{:?}", backend);
+        fs.purge(Some("alice"), entry.id).await.unwrap();
+        assert_eq!(fs.metas.count_blob_refs(&blob).await.unwrap(), 1, "{:?}", backend);
+
+        let (_, entry) = fs.remove("alice", "/home/b.txt", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        fs.purge(Some("alice"), entry.id).await.unwrap();
+        assert_eq!(fs.metas.count_blob_refs(&blob).await.unwrap(), 0, "{:?}", backend);
+        fs.collect_garbage().await.unwrap();
+        assert_eq!(fs.blobs.size(&blob).await.unwrap(), None, "{:?}", backend);
+    }
+}
+
+/// 同じ内容を並行して書いても参照数はずれない
+#[tokio::test]
+async fn concurrent_refs() {
+    for backend in BACKENDS {
+        let (_dir, fs) = test_support::file_system(backend).await;
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let fs = Arc::clone(&fs);
+                tokio::spawn(async move { test_support::write(&fs, "alice", &format!("/home/{}.txt", i), LARGE).await })
+            })
+            .collect();
+        let mut blob = None;
+        for task in tasks {
+            blob = task.await.unwrap().blob;
+        }
+        let blob = blob.unwrap();
+        assert_eq!(fs.metas.count_blob_refs(&blob).await.unwrap(), 16, "{:?}", backend);
+
+        for meta in fs.metas.scan().await.unwrap().into_iter().filter(|m| !m.is_folder()) {
+            fs.metas.delete(meta.id).await.unwrap();
+        }
+        assert_eq!(fs.metas.count_blob_refs(&blob).await.unwrap(), 0, "{:?}", backend);
+    }
+}
+
+#[tokio::test]
+async fn move_and_restore() {
+    for backend in BACKENDS {
+        let (_dir, fs) = test_support::file_system(backend).await;
+        test_support::write(&fs, "alice", "/home/src/a.txt", LARGE).await;
+
+        let moved = fs.move_to("alice", "/home/src", "/home/dst").await.unwrap();
+        assert_eq!(moved.path, "/home/dst", "{:?}", backend);
+        assert_eq!(fs.read_all("alice", "/home/dst/a.txt").await.unwrap(), LARGE, "{:?}", backend);
+        assert!(fs.get("alice", "/home/src/a.txt").await.is_err(), "{:?}", backend);
+
+        let (_, entry) = fs.remove("alice", "/home/dst", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        assert!(fs.get("alice", "/home/dst/a.txt").await.is_err(), "{:?}", backend);
+        fs.restore("alice", entry.id).await.unwrap();
+        assert_eq!(fs.read_all("alice", "/home/dst/a.txt").await.unwrap(), LARGE, "{:?}", backend);
+    }
+}
+
+/// 書き込み直後の状態が開き直しても残る (memory 以外)
+#[tokio::test]
+async fn reopen_keeps_data() {
+    for backend in [Backend::Local, Backend::Encrypted] {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(dir.path(), backend);
+        let ruid = Arc::new(crate::utils::ruid::RuidGenerator::new(1));
+        {
+            let fs = super::file_system::FileSystem::new(&config, Arc::clone(&ruid)).await.unwrap();
+            test_support::write(&fs, "alice", "/home/a.txt", LARGE).await;
+        }
+        // sled はバックグラウンドのスレッドが止まるまでファイルのロックを持つ
+        let mut retry = 0;
+        let fs = loop {
+            match super::file_system::FileSystem::new(&config, Arc::clone(&ruid)).await {
+                Ok(fs) => break fs,
+                Err(e) if retry < 50 && e.to_string().contains("could not acquire lock") => retry += 1,
+                Err(e) => panic!("{:?}: {}", backend, e),
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        };
+        assert_eq!(fs.read_all("alice", "/home/a.txt").await.unwrap(), LARGE, "{:?}", backend);
+    }
+}
@@ -0,0 +1,642 @@
+use std::{collections::{BTreeMap, HashSet}, io::{Error, ErrorKind}, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::Duration};
+
+use bytes::Bytes;
+use chacha20poly1305::{aead::{Aead, KeyInit, Payload}, ChaCha20Poly1305, Key, Nonce};
+use chrono::Utc;
+use futures::{stream::{self, BoxStream}, StreamExt};
+use log::{error, info};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tokio::{fs::File, io::{AsyncReadExt, AsyncWriteExt}, sync::Mutex};
+
+use crate::utils::{self, custom_serializers_adapters::Base64};
+
+use super::{file_system::FileSystem, store::{BlobStore, MetadataStore}};
+
+pub const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+type SecretKey = [u8; KEY_LENGTH];
+
+/// 保存時の暗号化
+/// バイナリごとのデータキーをユーザーの鍵で包み、ユーザーの鍵をノードのマスターキーで包む
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EncryptionConfig {
+    /// マスターキーのファイル 空の場合は暗号化しない 無い場合は作成する
+    pub master_key_path: String,
+    /// 全てのユーザーの新しいバイナリを暗号化する
+    pub default: bool,
+    /// default が false の場合に暗号化するユーザー
+    pub users: HashSet<String>,
+    /// 暗号化する単位 (平文のバイト数) Range はこの単位で復号する
+    pub chunk_size: u32,
+    /// ユーザーの鍵を更新する秒数 0 で更新しない
+    pub user_key_rotation: u64,
+    /// マスターキーを更新する秒数 0 で更新しない
+    pub master_key_rotation: u64,
+    pub rotation_check_interval: u64,
+}
+
+/// マスターキーのファイル
+/// 更新しても全てのユーザーの鍵を包み直すまで古い鍵を残す
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyFile {
+    active: u32,
+    keys: BTreeMap<u32, MasterKey>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MasterKey {
+    #[serde_as(as = "Base64")]
+    key: Vec<u8>,
+    created_time: i64,
+}
+
+/// マスターキーで包んだユーザーの鍵
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserKey {
+    pub user: String,
+    pub id: u32,
+    /// 包んだマスターキーの id
+    pub master: u32,
+    #[serde_as(as = "Base64")]
+    pub wrapped: Vec<u8>,
+    /// 新しいバイナリに使う鍵 ユーザーごとに一つ
+    pub active: bool,
+    pub created_time: i64,
+}
+
+/// ユーザーの鍵で包んだバイナリのデータキー
+/// これがあるバイナリは暗号化されている
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobKey {
+    pub blob: String,
+    pub user: String,
+    pub user_key: u32,
+    #[serde_as(as = "Base64")]
+    pub wrapped: Vec<u8>,
+    /// 平文の大きさ
+    pub size: u64,
+    pub chunk_size: u32,
+}
+
+/// 暗号化したがまだ包んでいないデータキー
+/// ユーザーの鍵の更新と重ならないよう、包むのは store_blob_key で self.lock を取ってから
+pub struct SealedBlob {
+    blob: String,
+    data_key: SecretKey,
+    size: u64,
+    chunk_size: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RotationReport {
+    pub master_rotated: bool,
+    pub user_keys: u64,
+    pub blob_keys: u64,
+}
+
+fn crypto_err() -> Error {
+    Error::new(ErrorKind::InvalidData, "decryption failed")
+}
+
+fn random_key() -> SecretKey {
+    let mut key = [0u8; KEY_LENGTH];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+fn to_key(data: &[u8]) -> Result<SecretKey, Error> {
+    data.try_into().map_err(|_| Error::new(ErrorKind::InvalidData, "invalid key length"))
+}
+
+/// nonce || ciphertext
+fn wrap(kek: &SecretKey, key: &SecretKey, aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    let sealed = ChaCha20Poly1305::new(Key::from_slice(kek))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: key, aad })
+        .map_err(|_| Error::other("encryption failed"))?;
+    let mut wrapped = nonce.to_vec();
+    wrapped.extend_from_slice(&sealed);
+    Ok(wrapped)
+}
+
+fn unwrap(kek: &SecretKey, wrapped: &[u8], aad: &[u8]) -> Result<SecretKey, Error> {
+    if wrapped.len() < NONCE_LENGTH {
+        return Err(crypto_err());
+    }
+    let (nonce, sealed) = wrapped.split_at(NONCE_LENGTH);
+    let key = ChaCha20Poly1305::new(Key::from_slice(kek))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+        .map_err(|_| crypto_err())?;
+    to_key(&key)
+}
+
+fn user_key_aad(user: &str, id: u32) -> Vec<u8> {
+    format!("idis user key {} {}", user, id).into_bytes()
+}
+
+fn blob_key_aad(blob: &str) -> Vec<u8> {
+    format!("idis blob key {}", blob).into_bytes()
+}
+
+/// 一つのバイナリの暗号化と復号
+/// チャンクごとに AEAD で封をし、nonce はチャンクの番号と最後のチャンクかどうかから作る
+/// 並べ替えと切り詰めは復号に失敗する
+pub struct BlobCipher {
+    cipher: ChaCha20Poly1305,
+    blob: String,
+    size: u64,
+    chunk_size: u64,
+}
+
+impl BlobCipher {
+    fn new(key: &SecretKey, blob: &str, size: u64, chunk_size: u32) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            blob: blob.to_string(),
+            size,
+            chunk_size: chunk_size.max(1) as u64,
+        }
+    }
+
+    /// 空のバイナリも一つのチャンクを持つ
+    fn chunks(&self) -> u64 {
+        self.size.div_ceil(self.chunk_size).max(1)
+    }
+
+    fn nonce(&self, index: u64) -> [u8; NONCE_LENGTH] {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        nonce[..8].copy_from_slice(&index.to_be_bytes());
+        nonce[NONCE_LENGTH - 1] = (index + 1 == self.chunks()) as u8;
+        nonce
+    }
+
+    /// 暗号文の中のチャンクの位置 (offset, len)
+    fn sealed_range(&self, index: u64) -> (u64, u64) {
+        let plain = self.chunk_size.min(self.size - index * self.chunk_size);
+        (index * (self.chunk_size + TAG_LENGTH as u64), plain + TAG_LENGTH as u64)
+    }
+
+    fn seal(&self, index: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.cipher.encrypt(Nonce::from_slice(&self.nonce(index)), Payload { msg: data, aad: self.blob.as_bytes() })
+            .map_err(|_| Error::other("encryption failed"))
+    }
+
+    fn open(&self, index: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.cipher.decrypt(Nonce::from_slice(&self.nonce(index)), Payload { msg: data, aad: self.blob.as_bytes() })
+            .map_err(|_| crypto_err())
+    }
+
+    /// 平文の start から len バイトを、含まれるチャンクだけ読み出して復号する
+    pub fn decrypt_range(self, inner: Arc<dyn BlobStore>, start: u64, len: u64) -> BoxStream<'static, Result<Bytes, Error>> {
+        if len == 0 {
+            return stream::empty().boxed();
+        }
+        if start + len > self.size {
+            return stream::once(async { Err(Error::new(ErrorKind::UnexpectedEof, "blob is shorter than expected")) }).boxed();
+        }
+        let first = start / self.chunk_size;
+        let last = (start + len - 1) / self.chunk_size;
+        let cipher = Arc::new(self);
+
+        stream::iter(first..=last)
+            .then(move |index| {
+                let inner = Arc::clone(&inner);
+                let cipher = Arc::clone(&cipher);
+                async move {
+                    let (offset, length) = cipher.sealed_range(index);
+                    let mut sealed = Vec::with_capacity(length as usize);
+                    let mut chunks = inner.read(&cipher.blob, offset, length, length as usize);
+                    while let Some(chunk) = chunks.next().await {
+                        sealed.extend_from_slice(&chunk?);
+                    }
+                    let plain = cipher.open(index, &sealed)?;
+
+                    let base = index * cipher.chunk_size;
+                    let from = start.saturating_sub(base) as usize;
+                    let to = ((start + len - base) as usize).min(plain.len());
+                    Ok(Bytes::from(plain).slice(from..to))
+                }
+            })
+            .boxed()
+    }
+}
+
+/// マスターキー、ユーザーの鍵、バイナリのデータキーを扱う
+pub struct KeyRing {
+    config: EncryptionConfig,
+    path: PathBuf,
+    metas: Arc<dyn MetadataStore>,
+    master: RwLock<KeyFile>,
+    /// 鍵の作成と更新を直列にする
+    lock: Mutex<()>,
+}
+
+impl KeyRing {
+    /// master_key_path が空の場合は None
+    pub fn open(config: &EncryptionConfig, metas: Arc<dyn MetadataStore>) -> Result<Option<Self>, Error> {
+        if config.master_key_path.is_empty() {
+            return Ok(None);
+        }
+        let path = utils::fs::get_file_path(&config.master_key_path)?;
+        let master = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let keys = KeyFile {
+                    active: 1,
+                    keys: BTreeMap::from([(1, MasterKey { key: random_key().to_vec(), created_time: Utc::now().timestamp_millis() })]),
+                };
+                save_key_file(&path, &keys)?;
+                info!("created master key: {}", path.display());
+                keys
+            }
+            Err(e) => return Err(e),
+        };
+        let ring = Self {
+            config: config.clone(),
+            path,
+            metas,
+            master: RwLock::new(master),
+            lock: Mutex::new(()),
+        };
+        ring.master_key(ring.active_master()?)?;
+        Ok(Some(ring))
+    }
+
+    pub fn enabled_for(&self, user: &str) -> bool {
+        self.config.default || self.config.users.contains(user)
+    }
+
+    fn active_master(&self) -> Result<u32, Error> {
+        Ok(self.master.read().map_err(|_| Error::other("key ring is poisoned"))?.active)
+    }
+
+    fn master_key(&self, id: u32) -> Result<SecretKey, Error> {
+        let master = self.master.read().map_err(|_| Error::other("key ring is poisoned"))?;
+        match master.keys.get(&id) {
+            Some(key) => to_key(&key.key),
+            None => Err(Error::new(ErrorKind::NotFound, format!("master key {} is not found", id))),
+        }
+    }
+
+    async fn user_key(&self, user: &str, id: u32) -> Result<SecretKey, Error> {
+        let key = self.metas.list_user_keys(Some(user)).await?.into_iter()
+            .find(|k| k.id == id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "user key is not found"))?;
+        unwrap(&self.master_key(key.master)?, &key.wrapped, &user_key_aad(user, id))
+    }
+
+    /// self.lock を取った状態で呼ぶ
+    async fn create_user_key(&self, user: &str, id: u32) -> Result<SecretKey, Error> {
+        let master = self.active_master()?;
+        let key = random_key();
+        self.metas.put_user_key(&UserKey {
+            user: user.to_string(),
+            id,
+            master,
+            wrapped: wrap(&self.master_key(master)?, &key, &user_key_aad(user, id))?,
+            active: true,
+            created_time: Utc::now().timestamp_millis(),
+        }).await?;
+        Ok(key)
+    }
+
+    /// 新しいバイナリに使うユーザーの鍵 無ければ作る self.lock を取った状態で呼ぶ
+    async fn active_user_key(&self, user: &str) -> Result<(u32, SecretKey), Error> {
+        let keys = self.metas.list_user_keys(Some(user)).await?;
+        if let Some(key) = keys.iter().find(|k| k.active) {
+            return Ok((key.id, unwrap(&self.master_key(key.master)?, &key.wrapped, &user_key_aad(user, key.id))?));
+        }
+        let id = keys.iter().map(|k| k.id).max().unwrap_or(0) + 1;
+        Ok((id, self.create_user_key(user, id).await?))
+    }
+
+    pub async fn blob_key(&self, blob: &str) -> Result<Option<BlobKey>, Error> {
+        self.metas.get_blob_key(blob).await
+    }
+
+    /// バイナリを削除した時にデータキーも消す
+    pub async fn forget(&self, blob: &str) -> Result<(), Error> {
+        self.metas.delete_blob_key(blob).await
+    }
+
+    /// 暗号化されていないバイナリは None
+    pub async fn cipher(&self, blob: &str) -> Result<Option<BlobCipher>, Error> {
+        let blob_key = match self.metas.get_blob_key(blob).await? {
+            Some(blob_key) => blob_key,
+            None => return Ok(None),
+        };
+        let user_key = self.user_key(&blob_key.user, blob_key.user_key).await?;
+        let data_key = unwrap(&user_key, &blob_key.wrapped, &blob_key_aad(blob))?;
+        Ok(Some(BlobCipher::new(&data_key, blob, blob_key.size, blob_key.chunk_size)))
+    }
+
+    /// from を暗号化して to に書き出す データキーは store_blob_key で保存する
+    pub async fn seal_file(&self, blob: &str, from: &Path, to: &Path) -> Result<SealedBlob, Error> {
+        let data_key = random_key();
+        let mut input = File::open(from).await?;
+        let size = input.metadata().await?.len();
+        let sealed = SealedBlob { blob: blob.to_string(), data_key, size, chunk_size: self.config.chunk_size.max(1) };
+
+        let cipher = BlobCipher::new(&data_key, blob, size, sealed.chunk_size);
+        let mut output = File::create(to).await?;
+        let mut buffer = vec![0u8; cipher.chunk_size as usize];
+        for index in 0..cipher.chunks() {
+            let len = cipher.chunk_size.min(size - index * cipher.chunk_size) as usize;
+            input.read_exact(&mut buffer[..len]).await?;
+            output.write_all(&cipher.seal(index, &buffer[..len])?).await?;
+        }
+        output.flush().await?;
+        Ok(sealed)
+    }
+
+    /// データキーをユーザーの今の鍵で包んで保存する
+    /// 鍵の更新と同じ lock の中で包むので、更新で消えた鍵で包んだ BlobKey が残ることはない
+    pub async fn store_blob_key(&self, user: &str, sealed: SealedBlob) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        let (user_key_id, user_key) = self.active_user_key(user).await?;
+        self.metas.put_blob_key(&BlobKey {
+            wrapped: wrap(&user_key, &sealed.data_key, &blob_key_aad(&sealed.blob))?,
+            blob: sealed.blob,
+            user: user.to_string(),
+            user_key: user_key_id,
+            size: sealed.size,
+            chunk_size: sealed.chunk_size,
+        }).await
+    }
+
+    /// ユーザーの鍵を新しくし、全てのデータキーを包み直す バイナリ自体は書き換えない
+    pub async fn rotate_user_key(&self, user: &str) -> Result<u64, Error> {
+        let _guard = self.lock.lock().await;
+        let old = self.metas.list_user_keys(Some(user)).await?;
+        let id = old.iter().map(|k| k.id).max().unwrap_or(0) + 1;
+        let new_key = self.create_user_key(user, id).await?;
+        for key in &old {
+            if key.active {
+                self.metas.put_user_key(&UserKey { active: false, ..key.clone() }).await?;
+            }
+        }
+
+        let mut count = 0;
+        for mut blob_key in self.metas.list_blob_keys(Some(user)).await? {
+            let aad = blob_key_aad(&blob_key.blob);
+            let data_key = unwrap(&self.user_key(user, blob_key.user_key).await?, &blob_key.wrapped, &aad)?;
+            blob_key.wrapped = wrap(&new_key, &data_key, &aad)?;
+            blob_key.user_key = id;
+            self.metas.put_blob_key(&blob_key).await?;
+            count += 1;
+        }
+        for key in old {
+            self.metas.delete_user_key(user, key.id).await?;
+        }
+        Ok(count)
+    }
+
+    /// 新しいマスターキーを作り、全てのユーザーの鍵を包み直す
+    pub async fn rotate_master_key(&self) -> Result<u64, Error> {
+        let _guard = self.lock.lock().await;
+        {
+            let mut master = self.master.write().map_err(|_| Error::other("key ring is poisoned"))?;
+            let id = master.keys.keys().max().copied().unwrap_or(0) + 1;
+            master.keys.insert(id, MasterKey { key: random_key().to_vec(), created_time: Utc::now().timestamp_millis() });
+            master.active = id;
+            save_key_file(&self.path, &master)?;
+        }
+        self.rewrap_user_keys().await
+    }
+
+    /// 古いマスターキーで包まれたユーザーの鍵を包み直し、使われなくなったマスターキーを消す
+    /// マスターキーのファイルを手で更新した場合もこれで揃える self.lock を取った状態で呼ぶ
+    async fn rewrap_user_keys(&self) -> Result<u64, Error> {
+        let active = self.active_master()?;
+        let master_key = self.master_key(active)?;
+        let mut count = 0;
+        for mut key in self.metas.list_user_keys(None).await? {
+            if key.master == active {
+                continue;
+            }
+            let aad = user_key_aad(&key.user, key.id);
+            let user_key = unwrap(&self.master_key(key.master)?, &key.wrapped, &aad)?;
+            key.wrapped = wrap(&master_key, &user_key, &aad)?;
+            key.master = active;
+            self.metas.put_user_key(&key).await?;
+            count += 1;
+        }
+
+        let mut master = self.master.write().map_err(|_| Error::other("key ring is poisoned"))?;
+        if master.keys.len() > 1 {
+            master.keys.retain(|id, _| *id == active);
+            save_key_file(&self.path, &master)?;
+        }
+        Ok(count)
+    }
+
+    /// 期限を過ぎた鍵を更新する
+    pub async fn rotate_expired(&self) -> Result<RotationReport, Error> {
+        let now = Utc::now().timestamp_millis();
+        let mut report = RotationReport::default();
+
+        let master_created = {
+            let master = self.master.read().map_err(|_| Error::other("key ring is poisoned"))?;
+            master.keys.get(&master.active).map(|k| k.created_time).unwrap_or(0)
+        };
+        if self.config.master_key_rotation > 0 && now - master_created > (self.config.master_key_rotation * 1000) as i64 {
+            report.user_keys += self.rotate_master_key().await?;
+            report.master_rotated = true;
+        } else {
+            let _guard = self.lock.lock().await;
+            report.user_keys += self.rewrap_user_keys().await?;
+        }
+
+        if self.config.user_key_rotation > 0 {
+            let max_age = (self.config.user_key_rotation * 1000) as i64;
+            for key in self.metas.list_user_keys(None).await? {
+                if key.active && now - key.created_time > max_age {
+                    report.blob_keys += self.rotate_user_key(&key.user).await?;
+                    report.user_keys += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+fn save_key_file(path: &Path, keys: &KeyFile) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension("tmp");
+    let data = serde_json::to_vec_pretty(keys).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::Write::write_all(&mut options.open(&temp)?, &data)?;
+    std::fs::rename(temp, path)
+}
+
+impl FileSystem {
+    /// from を暗号化して key に取り込む pins を取った状態で呼ぶ
+    /// key に BlobKey の無い古いバイナリがある場合は置き換える
+    pub(super) async fn import_sealed(&self, ring: &KeyRing, user: &str, key: &str, from: &Path) -> Result<(), Error> {
+        let sealed_path = self.temp_path();
+        let sealed = match ring.seal_file(key, from, &sealed_path).await {
+            Ok(sealed) => sealed,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&sealed_path).await;
+                return Err(e);
+            }
+        };
+        // 置き換えの間に平文を暗号文として読むことがないよう、BlobKey を先に書く
+        // その間の読み出しは復号に失敗する
+        if let Err(e) = ring.store_blob_key(user, sealed).await {
+            let _ = tokio::fs::remove_file(&sealed_path).await;
+            return Err(e);
+        }
+        if let Err(e) = self.blobs.import(key, &sealed_path).await {
+            let _ = self.metas.delete_blob_key(key).await;
+            let _ = tokio::fs::remove_file(&sealed_path).await;
+            return Err(e);
+        }
+        tokio::fs::remove_file(from).await
+    }
+
+    pub async fn run_key_rotation(self: Arc<Self>, interval: Duration) {
+        let ring = match &self.keyring {
+            Some(ring) => Arc::clone(ring),
+            None => return,
+        };
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match ring.rotate_expired().await {
+                Ok(report) if report.user_keys == 0 && !report.master_rotated => {}
+                Ok(report) => info!("rotated keys: {:?}", report),
+                Err(e) => error!("Failed to rotate keys: {}", e),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use std::sync::Arc;
+
+    use futures::TryStreamExt;
+
+    use crate::{
+        file_system::{blob, file_system::FileSystem, test_support::{self, Backend}},
+        utils::ruid::RuidGenerator,
+    };
+
+    const SECRET: &[u8] = b"a plaintext body that must never reach the disk";
+
+    fn contains(dir: &Path, needle: &[u8]) -> bool {
+        std::fs::read_dir(dir).unwrap().flatten().any(|entry| {
+            let path = entry.path();
+            match path.is_dir() {
+                true => contains(&path, needle),
+                false => std::fs::read(&path).unwrap_or_default().windows(needle.len()).any(|w| w == needle),
+            }
+        })
+    }
+
+    /// 保存先のファイルに平文が残らない
+    #[tokio::test]
+    async fn stored_blob_is_ciphertext() {
+        let (dir, fs) = test_support::file_system(Backend::Encrypted).await;
+        let meta = test_support::write(&fs, "alice", "/home/secret.txt", SECRET).await;
+        assert!(meta.blob.is_some());
+        assert_eq!(fs.read_all("alice", "/home/secret.txt").await.unwrap(), SECRET);
+        assert!(!contains(&dir.path().join("storage"), SECRET));
+    }
+
+    /// 暗号化したユーザーは自分のキーに保存し、同じ内容の平文のバイナリには触らない
+    #[tokio::test]
+    async fn sealed_copy_has_its_own_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_support::config(dir.path(), Backend::Encrypted);
+        config.encryption.default = false;
+        config.encryption.users.insert("alice".to_string());
+        let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap();
+
+        let plain = test_support::write(&fs, "bob", "/home/shared.txt", SECRET).await;
+        let sealed = test_support::write(&fs, "alice", "/home/shared.txt", SECRET).await;
+        let checksum = plain.checksum.clone().unwrap();
+        assert_eq!(plain.blob.as_deref(), Some(checksum.as_str()));
+        assert_eq!(sealed.blob, Some(blob::sealed_key("alice", &checksum)));
+        assert_eq!(sealed.checksum, plain.checksum);
+
+        let ring = fs.keyring.clone().unwrap();
+        assert!(ring.blob_key(&checksum).await.unwrap().is_none());
+        assert!(ring.blob_key(sealed.blob.as_deref().unwrap()).await.unwrap().is_some());
+        assert_eq!(fs.read_all("bob", "/home/shared.txt").await.unwrap(), SECRET);
+        assert_eq!(fs.read_all("alice", "/home/shared.txt").await.unwrap(), SECRET);
+
+        let report = fs.verify_blobs().await.unwrap();
+        assert_eq!((report.checked, report.skipped, report.corrupt.len()), (2, 0, 0));
+    }
+
+    /// Range はチャンクの途中から途中まででも平文を返す
+    #[tokio::test]
+    async fn range_is_decrypted() {
+        let (_dir, fs) = test_support::file_system(Backend::Encrypted).await;
+        let meta = test_support::write(&fs, "alice", "/home/secret.txt", SECRET).await;
+        let key = meta.blob.unwrap();
+        assert_eq!(fs.blobs.size(&key).await.unwrap(), Some(SECRET.len() as u64));
+        for (start, len) in [(0, 5), (3, 9), (12, 1), (7, SECRET.len() as u64 - 7)] {
+            let chunks: Vec<_> = fs.blobs.read(&key, start, len, 4).try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), &SECRET[start as usize..(start + len) as usize], "{}+{}", start, len);
+        }
+    }
+
+    /// 鍵を更新しても包み直すだけで、同じバイナリを復号できる
+    #[tokio::test]
+    async fn rotation_keeps_blobs_readable() {
+        let (dir, fs) = test_support::file_system(Backend::Encrypted).await;
+        test_support::write(&fs, "alice", "/home/a.txt", SECRET).await;
+        test_support::write(&fs, "alice", "/home/b.txt", b"another secret body").await;
+        let ring = fs.keyring.clone().unwrap();
+
+        assert_eq!(ring.rotate_user_key("alice").await.unwrap(), 2);
+        assert_eq!(fs.metas.list_user_keys(Some("alice")).await.unwrap().len(), 1);
+        assert_eq!(ring.rotate_master_key().await.unwrap(), 1);
+        let key_file: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.path().join("master.key")).unwrap()).unwrap();
+        assert_eq!(key_file["keys"].as_object().unwrap().len(), 1);
+
+        assert_eq!(fs.read_all("alice", "/home/a.txt").await.unwrap(), SECRET);
+        assert_eq!(fs.read_all("alice", "/home/b.txt").await.unwrap(), b"another secret body");
+        let report = ring.rotate_expired().await.unwrap();
+        assert_eq!((report.user_keys, report.blob_keys, report.master_rotated), (0, 0, false));
+    }
+
+    /// 暗号化と保存の間にユーザーの鍵を更新しても復号できる
+    #[tokio::test]
+    async fn rotation_between_seal_and_store() {
+        let (dir, fs) = test_support::file_system(Backend::Encrypted).await;
+        test_support::write(&fs, "alice", "/home/first.txt", SECRET).await;
+        let ring = fs.keyring.clone().unwrap();
+
+        let from = dir.path().join("plain");
+        std::fs::write(&from, b"another plaintext body").unwrap();
+        let sealed = ring.seal_file("blob", &from, &dir.path().join("sealed")).await.unwrap();
+        ring.rotate_user_key("alice").await.unwrap();
+        ring.store_blob_key("alice", sealed).await.unwrap();
+
+        assert!(ring.cipher("blob").await.unwrap().is_some());
+        assert_eq!(fs.read_all("alice", "/home/first.txt").await.unwrap(), SECRET);
+    }
+}
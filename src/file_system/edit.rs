@@ -0,0 +1,208 @@
+use std::io::Error;
+
+use chrono::Utc;
+use serde_json::Value;
+
+use super::{file_system::FileSystem, meta::{MetaData, IMMUTABLE_FIELDS, PERM_FIELD}};
+
+pub enum MetaPatch {
+    /// RFC 7396 JSON Merge Patch
+    Merge(Value),
+    /// RFC 6902 JSON Patch
+    Json(json_patch::Patch),
+}
+
+/// 変更できる範囲
+#[derive(Debug, Clone, Copy)]
+pub struct EditRights {
+    /// perm 以外のフィールド (edit の権限)
+    pub fields: bool,
+    /// perm (share の権限)
+    pub perm: bool,
+}
+
+#[derive(Debug)]
+pub enum EditError {
+    /// If-Match のバージョンが一致しない 現在のメタデータを返す
+    VersionMismatch(Box<MetaData>),
+    Immutable(String),
+    Invalid(String),
+    Io(Error),
+}
+
+impl From<Error> for EditError {
+    fn from(e: Error) -> Self {
+        EditError::Io(e)
+    }
+}
+
+impl MetaPatch {
+    /// パッチを適用し、変更できないフィールドが変わっていないか確認する
+    /// rights に無いものは変更できない ファイルとフォルダの種類も変えられない
+    pub fn apply(&self, meta: &MetaData, rights: EditRights) -> Result<MetaData, EditError> {
+        let original = serde_json::to_value(meta).map_err(|e| EditError::Invalid(e.to_string()))?;
+        let mut doc = original.clone();
+
+        match self {
+            MetaPatch::Merge(patch) => json_patch::merge(&mut doc, patch),
+            MetaPatch::Json(patch) => json_patch::patch(&mut doc, &patch.0).map_err(|e| EditError::Invalid(e.to_string()))?,
+        }
+
+        for field in IMMUTABLE_FIELDS {
+            if original.get(field) != doc.get(field) {
+                return Err(EditError::Immutable(field.to_string()));
+            }
+        }
+        if !rights.perm && original.get(PERM_FIELD) != doc.get(PERM_FIELD) {
+            return Err(EditError::Immutable(PERM_FIELD.to_string()));
+        }
+        if !rights.fields {
+            let changed = doc.as_object().into_iter().flatten()
+                .chain(original.as_object().into_iter().flatten())
+                .find(|(field, _)| field.as_str() != PERM_FIELD && original.get(field.as_str()) != doc.get(field.as_str()));
+            if let Some((field, _)) = changed {
+                return Err(EditError::Immutable(field.clone()));
+            }
+        }
+
+        let patched: MetaData = serde_json::from_value(doc).map_err(|e| EditError::Invalid(e.to_string()))?;
+        if patched.is_folder() != meta.is_folder() {
+            return Err(EditError::Immutable("data_type".to_string()));
+        }
+        Ok(patched)
+    }
+}
+
+impl FileSystem {
+    /// version が一致する場合のみメタデータを上書きする
+    pub async fn edit(&self, user: &str, path: &str, version: u64, patch: &MetaPatch, rights: EditRights) -> Result<MetaData, EditError> {
+        let _guard = self.lock.write().await;
+        let meta = self.lookup_required(user, path).await?;
+        if meta.version != version {
+            return Err(EditError::VersionMismatch(Box::new(meta)));
+        }
+
+        let mut patched = patch.apply(&meta, rights)?;
+        patched.version = meta.version + 1;
+        patched.update_time = Utc::now().timestamp_millis();
+        self.metas.put(&patched).await?;
+        Ok(patched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::file_system::{meta::{MetaData, FOLDER_TYPE, IMMUTABLE_FIELDS}, test_support::{self, Backend}};
+
+    use super::{EditError, EditRights, MetaPatch};
+
+    const ALL: EditRights = EditRights { fields: true, perm: true };
+    /// edit の権限だけがある場合
+    const FIELDS: EditRights = EditRights { fields: true, perm: false };
+    const PERM: EditRights = EditRights { fields: false, perm: true };
+
+    fn file() -> MetaData {
+        let mut meta = MetaData::new(0x1100_0000_0000_0000_0000_0000_0000_0001, "alice", "/home/a.txt", "text/plain".to_string(), 1);
+        meta.blob = Some("ab".repeat(32));
+        meta.size = 3;
+        meta.version = 1;
+        meta
+    }
+
+    fn json_patch(value: Value) -> MetaPatch {
+        MetaPatch::Json(serde_json::from_value(value).unwrap())
+    }
+
+    fn rejected(result: Result<MetaData, EditError>) -> Option<String> {
+        match result {
+            Err(EditError::Immutable(field)) => Some(field),
+            _ => None,
+        }
+    }
+
+    /// 変更できないフィールドはどちらのパッチでも、全ての権限があっても変えられない
+    #[test]
+    fn immutable_fields() {
+        let meta = file();
+        for field in IMMUTABLE_FIELDS {
+            let merge = MetaPatch::Merge(json!({ *field: "changed" }));
+            assert_eq!(rejected(merge.apply(&meta, ALL)).as_deref(), Some(*field), "merge {}", field);
+            let replace = json_patch(json!([{ "op": "add", "path": format!("/{}", field), "value": "changed" }]));
+            assert_eq!(rejected(replace.apply(&meta, ALL)).as_deref(), Some(*field), "json {}", field);
+        }
+    }
+
+    /// perm は share の権限がある場合のみ、それ以外は edit の権限がある場合のみ
+    #[test]
+    fn rights_matrix() {
+        let meta = file();
+        let perm = || MetaPatch::Merge(json!({ "perm": { "read": { "allow": ["everyone"] } } }));
+        let about = || MetaPatch::Merge(json!({ "about": "hello" }));
+        let add_perm = || json_patch(json!([{ "op": "add", "path": "/perm", "value": { "edit": { "deny": ["owner"] } } }]));
+
+        assert_eq!(rejected(perm().apply(&meta, FIELDS)).as_deref(), Some("perm"));
+        assert_eq!(rejected(add_perm().apply(&meta, FIELDS)).as_deref(), Some("perm"));
+        assert_eq!(rejected(about().apply(&meta, PERM)).as_deref(), Some("about"));
+
+        assert!(!perm().apply(&meta, PERM).unwrap().perm.read.allow.is_empty());
+        assert!(!add_perm().apply(&meta, ALL).unwrap().perm.edit.deny.is_empty());
+        assert_eq!(about().apply(&meta, FIELDS).unwrap().about, "hello");
+    }
+
+    #[test]
+    fn both_patch_kinds() {
+        let meta = file();
+        let merged = MetaPatch::Merge(json!({ "about": "hello", "event": { "a": 1 } })).apply(&meta, FIELDS).unwrap();
+        assert_eq!((merged.about.as_str(), &merged.event), ("hello", &json!({ "a": 1 })));
+
+        let patched = json_patch(json!([
+            { "op": "replace", "path": "/about", "value": "hi" },
+            { "op": "test", "path": "/data_type", "value": "text/plain" },
+        ])).apply(&meta, FIELDS).unwrap();
+        assert_eq!(patched.about, "hi");
+        assert!(matches!(json_patch(json!([{ "op": "test", "path": "/about", "value": "x" }])).apply(&meta, FIELDS), Err(EditError::Invalid(_))));
+        assert!(matches!(MetaPatch::Merge(json!({ "about": 1 })).apply(&meta, FIELDS), Err(EditError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn version_must_match() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/a.txt", b"abc").await;
+        let about = MetaPatch::Merge(json!({ "about": "hello" }));
+
+        let edited = fs.edit("alice", "/a.txt", 1, &about, FIELDS).await.unwrap();
+        assert_eq!((edited.version, edited.about.as_str()), (2, "hello"));
+        match fs.edit("alice", "/a.txt", 1, &about, FIELDS).await {
+            Err(EditError::VersionMismatch(current)) => assert_eq!(current.version, 2),
+            _ => panic!("stale version was accepted"),
+        }
+        assert_eq!(fs.get("alice", "/a.txt").await.unwrap().version, 2);
+    }
+
+    /// MIME type は変えられるが、ファイルとフォルダは入れ替えられない
+    #[tokio::test]
+    async fn data_type() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/docs/a.txt", b"abc").await;
+
+        let markdown = MetaPatch::Merge(json!({ "data_type": "text/markdown" }));
+        assert_eq!(fs.edit("alice", "/docs/a.txt", 1, &markdown, FIELDS).await.unwrap().data_type, "text/markdown");
+        let folder = MetaPatch::Merge(json!({ "data_type": FOLDER_TYPE }));
+        assert_eq!(rejected(fs.edit("alice", "/docs/a.txt", 2, &folder, FIELDS).await).as_deref(), Some("data_type"));
+        let file = MetaPatch::Merge(json!({ "data_type": "text/plain" }));
+        assert_eq!(rejected(fs.edit("alice", "/docs", 1, &file, FIELDS).await).as_deref(), Some("data_type"));
+    }
+
+    /// 種類の確認は apply の中で行う
+    #[test]
+    fn data_type_in_apply() {
+        let meta = file();
+        assert_eq!(MetaPatch::Merge(json!({ "data_type": "text/markdown" })).apply(&meta, ALL).unwrap().data_type, "text/markdown");
+        assert_eq!(rejected(MetaPatch::Merge(json!({ "data_type": FOLDER_TYPE })).apply(&meta, ALL)).as_deref(), Some("data_type"));
+
+        let folder = MetaData::new(0x1000_0000_0000_0000_0000_0000_0000_0001, "alice", "/home", FOLDER_TYPE.to_string(), 1);
+        assert_eq!(rejected(MetaPatch::Merge(json!({ "data_type": "text/plain" })).apply(&folder, ALL)).as_deref(), Some("data_type"));
+    }
+}
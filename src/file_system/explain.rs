@@ -0,0 +1,209 @@
+use std::{collections::BTreeSet, io::Error};
+
+use serde::Serialize;
+use serde_with::serde_as;
+
+use crate::utils::custom_serializers_adapters::Hex;
+
+use super::{file_system::FileSystem, perm::{decide, Matched, Operation, Principal, PrincipalId}, resolve::{self, Area}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+/// 判定の理由
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// システムのパスは所有者のみ
+    SystemArea,
+    /// 共有リンクの範囲
+    ShareLink,
+    /// 所有者の share は拒否しない
+    OwnerShare,
+    /// 拒否に当たった
+    Denied,
+    /// 許可に当たった
+    Allowed,
+    /// どちらにも当たらない所有者
+    OwnerDefault,
+    /// どちらにも当たらない
+    NoMatch,
+}
+
+/// 一つの階層で見た規則
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct ExplainLevel {
+    pub path: String,
+    #[serde_as(as = "BTreeSet<PrincipalId>")]
+    pub allow: BTreeSet<u128>,
+    #[serde_as(as = "BTreeSet<PrincipalId>")]
+    pub deny: BTreeSet<u128>,
+    /// allow と deny のうち相手に当てはまるもの
+    #[serde_as(as = "BTreeSet<PrincipalId>")]
+    pub matched_allow: BTreeSet<u128>,
+    #[serde_as(as = "BTreeSet<PrincipalId>")]
+    pub matched_deny: BTreeSet<u128>,
+    pub inherit: bool,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct ExplainedPrincipal {
+    pub user: Option<String>,
+    #[serde_as(as = "BTreeSet<PrincipalId>")]
+    pub ids: BTreeSet<u128>,
+    /// 共有リンクの id
+    #[serde_as(as = "Option<Hex>")]
+    pub link: Option<u128>,
+}
+
+/// 権限の判定の説明
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub user: String,
+    pub path: String,
+    pub operation: Operation,
+    /// 既にあるものへの create は edit として判定する
+    pub evaluated: Operation,
+    pub principal: ExplainedPrincipal,
+    /// 対象から親に向かう順 存在しない階層は含めない inherit が false の階層で終わる
+    pub levels: Vec<ExplainLevel>,
+    /// 判定に使われた規則 level は levels の添字
+    pub matched: Option<Matched>,
+    pub decision: Decision,
+    pub reason: Reason,
+}
+
+impl FileSystem {
+    /// principal が user の path に op をできるかを、見た規則と一緒に返す
+    /// authorize と同じ順で判定する
+    pub async fn explain(&self, user: &str, path: &str, principal: &Principal, op: Operation) -> Result<Explanation, Error> {
+        let _guard = self.lock.read().await;
+        let evaluated = self.effective_operation(user, path, op).await?;
+        let levels = self.perm_levels(user, path).await?;
+        let is_owner = principal.is_owner(user);
+        let ids = principal.ids_for(user);
+
+        let matched = decide(levels.iter().map(|(_, perm)| perm), &ids, evaluated);
+        let (decision, reason) = if resolve::area_of(path) == Some(Area::System) && !is_owner {
+            (Decision::Deny, Reason::SystemArea)
+        } else if principal.link.as_ref().is_some_and(|link| link.covers(user, path, evaluated)) {
+            (Decision::Allow, Reason::ShareLink)
+        } else if is_owner && evaluated == Operation::Share {
+            (Decision::Allow, Reason::OwnerShare)
+        } else {
+            match matched {
+                Some(m) if m.deny => (Decision::Deny, Reason::Denied),
+                Some(_) => (Decision::Allow, Reason::Allowed),
+                None if is_owner => (Decision::Allow, Reason::OwnerDefault),
+                None => (Decision::Deny, Reason::NoMatch),
+            }
+        };
+
+        let levels = levels.into_iter()
+            .map(|(path, perm)| {
+                let control = perm.get(evaluated);
+                ExplainLevel {
+                    path,
+                    matched_allow: control.allow.iter().filter(|id| ids.contains(id)).copied().collect(),
+                    matched_deny: control.deny.iter().filter(|id| ids.contains(id)).copied().collect(),
+                    allow: control.allow.clone(),
+                    deny: control.deny.clone(),
+                    inherit: perm.inherit,
+                }
+            })
+            .collect();
+        Ok(Explanation {
+            user: user.to_string(),
+            path: path.to_string(),
+            operation: op,
+            evaluated,
+            principal: ExplainedPrincipal {
+                user: principal.user.clone(),
+                ids: ids.iter().copied().collect(),
+                link: principal.link.as_ref().map(|link| link.id),
+            },
+            levels,
+            matched,
+            decision,
+            reason,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_system::{
+        perm::{Operation, Perm, EVERYONE, OWNER},
+        test_support::{self, Backend},
+    };
+
+    use super::{Decision, Reason};
+
+    const BOB: u128 = (0x2101 << 112) | 2;
+
+    /// explain の結論は authorize と一致する
+    #[tokio::test]
+    async fn explain_agrees_with_authorize() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        fs.metas.bind_user(BOB, "bob").await.unwrap();
+        for path in ["/docs/a.txt", "/docs/private/b.txt", "/docs/closed/c.txt", "/docs/locked.txt", "/etc/x.json"] {
+            test_support::write(&fs, "alice", path, b"data").await;
+        }
+        let rules = [
+            ("/docs", &[BOB][..], &[][..], true),
+            ("/docs/private", &[], &[BOB], true),
+            ("/docs/closed", &[], &[], false),
+            ("/docs/locked.txt", &[EVERYONE], &[OWNER], true),
+        ];
+        for (path, allow, deny, inherit) in rules {
+            let mut meta = fs.get("alice", path).await.unwrap();
+            meta.perm = Perm { inherit, ..Default::default() };
+            for op in [Operation::Read, Operation::Share] {
+                let control = match op {
+                    Operation::Read => &mut meta.perm.read,
+                    _ => &mut meta.perm.share,
+                };
+                control.allow.extend(allow);
+                control.deny.extend(deny);
+            }
+            fs.metas.put(&meta).await.unwrap();
+        }
+
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        let bob = fs.principal(Some("bob")).await.unwrap();
+        let cases = [
+            (&bob, "/docs/a.txt", Operation::Read, Reason::Allowed),
+            (&bob, "/docs/private/b.txt", Operation::Read, Reason::Denied),
+            (&bob, "/docs/closed/c.txt", Operation::Read, Reason::NoMatch),
+            (&bob, "/docs/a.txt", Operation::Delete, Reason::NoMatch),
+            (&alice, "/docs/a.txt", Operation::Delete, Reason::OwnerDefault),
+            (&alice, "/docs/locked.txt", Operation::Read, Reason::Denied),
+            (&alice, "/docs/locked.txt", Operation::Share, Reason::OwnerShare),
+            (&bob, "/docs/locked.txt", Operation::Share, Reason::Allowed),
+        ];
+        for (principal, path, op, reason) in cases {
+            let explanation = fs.explain("alice", path, principal, op).await.unwrap();
+            let authorized = fs.authorize("alice", path, principal, op).await.is_ok();
+            assert_eq!(explanation.reason, reason, "{:?} {} {:?}", principal.user, path, op);
+            assert_eq!(explanation.decision == Decision::Allow, authorized, "{:?} {} {:?}", principal.user, path, op);
+        }
+
+        // inherit が false の階層で終わり、当たった規則の階層を示す
+        let explanation = fs.explain("alice", "/docs/closed/c.txt", &bob, Operation::Read).await.unwrap();
+        assert_eq!(explanation.levels.iter().map(|l| l.path.as_str()).collect::<Vec<_>>(), ["/docs/closed/c.txt", "/docs/closed"]);
+        let explanation = fs.explain("alice", "/docs/private/b.txt", &bob, Operation::Read).await.unwrap();
+        assert_eq!(explanation.matched.map(|m| (m.level, m.id, m.deny)), Some((1, BOB, true)));
+        assert_eq!(explanation.levels[1].matched_deny, [BOB].into());
+
+        let explanation = fs.explain("alice", "/docs/a.txt", &bob, Operation::Create).await.unwrap();
+        assert_eq!((explanation.evaluated, explanation.decision), (Operation::Edit, Decision::Deny));
+        let explanation = fs.explain("alice", "/etc/x.json", &bob, Operation::Read).await.unwrap();
+        assert_eq!((explanation.decision, explanation.reason), (Decision::Deny, Reason::SystemArea));
+    }
+}
@@ -0,0 +1,533 @@
+use std::{fs::File, io::{BufReader, Error, ErrorKind, Read, Write}, path::{Path, PathBuf}, sync::Arc};
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use sha2::{Digest, Sha256};
+
+use crate::utils::{custom_serializers_adapters::Hex, ruid::{self, RuidGenerator}};
+
+use super::{archive::ArchiveFormat, file_system::FileSystem, meta::MetaData, path, perm::{Operation, Principal}};
+
+/// アーカイブの展開の上限 (zip bomb 対策) 0 で無制限
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExtractConfig {
+    /// フォルダを含むエントリの数
+    pub max_entries: usize,
+    /// 展開後の合計のバイト数
+    pub max_size: u64,
+    /// 展開後の合計がアーカイブの大きさの何倍まで
+    pub max_ratio: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtractStatus {
+    Created,
+    /// 既にあったファイルを置き換えた (古い版は残る)
+    Updated,
+    /// 既にあるフォルダ、またはリンクなど扱えない種類
+    Skipped,
+    /// 展開先のフォルダの外を指すなど危険な名前、または作成や上書きが許されていない
+    Rejected,
+    Failed,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtractedEntry {
+    /// アーカイブ内の名前
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub status: ExtractStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<Hex>")]
+    pub id: Option<u128>,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtractReport {
+    pub folder: String,
+    pub created: u64,
+    pub updated: u64,
+    pub skipped: u64,
+    pub rejected: u64,
+    pub failed: u64,
+    pub entries: Vec<ExtractedEntry>,
+}
+
+impl ExtractReport {
+    fn push(&mut self, name: String, path: Option<String>, status: ExtractStatus, id: Option<u128>, size: u64, reason: Option<String>) {
+        match status {
+            ExtractStatus::Created => self.created += 1,
+            ExtractStatus::Updated => self.updated += 1,
+            ExtractStatus::Skipped => self.skipped += 1,
+            ExtractStatus::Rejected => self.rejected += 1,
+            ExtractStatus::Failed => self.failed += 1,
+        }
+        self.entries.push(ExtractedEntry { name, path, status, id, size, reason });
+    }
+}
+
+enum Unpacked {
+    Folder,
+    File { temp: PathBuf, size: u64, checksum: String },
+    Skipped(String),
+    Rejected(String),
+    Failed(String),
+}
+
+/// 展開した内容を一時ファイルに書き出す 同期 I/O なので spawn_blocking で使う
+struct Unpacker {
+    temp_dir: PathBuf,
+    ruid: Arc<RuidGenerator>,
+    folder: String,
+    max_entries: usize,
+    /// 展開後の合計の上限 (max_size と max_ratio の小さい方)
+    limit: Option<u64>,
+    total: u64,
+    entries: Vec<(String, Option<String>, Unpacked)>,
+}
+
+impl Unpacker {
+    /// 名前を展開先のパスにする フォルダの外を指すものは Err
+    fn destination(&self, name: &str) -> Result<String, String> {
+        if name.starts_with('/') {
+            return Err("absolute path is not allowed".to_string());
+        }
+        let dest = path::normalize(&format!("{}/{}", self.folder, name)).map_err(|e| e.to_string())?;
+        match dest.strip_prefix(&self.folder) {
+            Some(rest) if rest.starts_with('/') || self.folder == "/" && dest != "/" => Ok(dest),
+            _ => Err("entry is outside of the folder".to_string()),
+        }
+    }
+
+    fn count(&self) -> Result<(), Error> {
+        if self.max_entries > 0 && self.entries.len() >= self.max_entries {
+            return Err(Error::new(ErrorKind::InvalidData, "archive has too many entries"));
+        }
+        Ok(())
+    }
+
+    /// エントリを一時ファイルに書き出す ヘッダーの大きさは信用せず、実際に読んだ量で上限を確かめる
+    /// 外側の Err は上限超過で展開全体をやめる、内側の Err はこのエントリだけの失敗
+    fn write<R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<Result<(PathBuf, u64, String), Error>, Error> {
+        let temp = self.temp_dir.join(format!("{:032x}", self.ruid.generate(ruid::prefix::CACHE_FILE)));
+        let result = self.copy(reader, &temp);
+        if !matches!(result, Ok(Ok(_))) {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result.map(|r| r.map(|(size, checksum)| (temp, size, checksum)))
+    }
+
+    fn copy<R: Read + ?Sized>(&mut self, reader: &mut R, temp: &Path) -> Result<Result<(u64, String), Error>, Error> {
+        let mut file = match File::create(temp) {
+            Ok(file) => file,
+            Err(e) => return Ok(Err(e)),
+        };
+        let mut hasher = Sha256::new();
+        let mut size: u64 = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Ok(Err(e)),
+            };
+            size += n as u64;
+            self.total += n as u64;
+            if self.limit.is_some_and(|limit| self.total > limit) {
+                return Err(Error::new(ErrorKind::FileTooLarge, "extracted size exceeds the limit"));
+            }
+            hasher.update(&buf[..n]);
+            if let Err(e) = file.write_all(&buf[..n]) {
+                return Ok(Err(e));
+            }
+        }
+        Ok(Ok((size, format!("{:x}", hasher.finalize()))))
+    }
+
+    fn push(&mut self, name: String, folder: bool, reader: Option<&mut dyn Read>) -> Result<(), Error> {
+        let dest = match self.destination(&name) {
+            Ok(dest) => dest,
+            Err(reason) => {
+                self.entries.push((name, None, Unpacked::Rejected(reason)));
+                return Ok(());
+            }
+        };
+        let unpacked = match (folder, reader) {
+            (true, _) => Unpacked::Folder,
+            (false, Some(reader)) => match self.write(reader)? {
+                Ok((temp, size, checksum)) => Unpacked::File { temp, size, checksum },
+                Err(e) => Unpacked::Failed(e.to_string()),
+            },
+            (false, None) => Unpacked::Skipped("unsupported entry type".to_string()),
+        };
+        self.entries.push((name, Some(dest), unpacked));
+        Ok(())
+    }
+
+    fn unpack_zip(&mut self, from: &Path) -> Result<(), Error> {
+        let broken = |e: zip::result::ZipError| Error::new(ErrorKind::InvalidData, e);
+        let mut archive = zip::ZipArchive::new(BufReader::new(File::open(from)?)).map_err(broken)?;
+        if self.max_entries > 0 && archive.len() > self.max_entries {
+            return Err(Error::new(ErrorKind::InvalidData, "archive has too many entries"));
+        }
+        for i in 0..archive.len() {
+            self.count()?;
+            let name = archive.name_for_index(i).unwrap_or_default().to_string();
+            let mut entry = match archive.by_index(i) {
+                Ok(entry) => entry,
+                // 暗号化されたものなど
+                Err(e) => {
+                    self.entries.push((name, None, Unpacked::Failed(e.to_string())));
+                    continue;
+                }
+            };
+            if entry.is_dir() {
+                self.push(name, true, None)?;
+            } else if entry.is_file() {
+                self.push(name, false, Some(&mut entry))?;
+            } else {
+                self.push(name, false, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn unpack_tar(&mut self, from: &Path, gzip: bool) -> Result<(), Error> {
+        let file = BufReader::new(File::open(from)?);
+        let reader: Box<dyn Read> = match gzip {
+            true => Box::new(GzDecoder::new(file)),
+            false => Box::new(file),
+        };
+        let mut archive = tar::Archive::new(reader);
+        // 途中で壊れている場合は以降を読めないので全体を失敗にする
+        let broken = |e: Error| Error::new(ErrorKind::InvalidData, e);
+        for entry in archive.entries().map_err(broken)? {
+            self.count()?;
+            let mut entry = entry.map_err(broken)?;
+            let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => self.push(name, true, None)?,
+                tar::EntryType::Regular | tar::EntryType::Continuous => self.push(name, false, Some(&mut entry))?,
+                _ => self.push(name, false, None)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn unpack(&mut self, from: &Path, format: ArchiveFormat) -> Result<(), Error> {
+        match format {
+            ArchiveFormat::Zip => self.unpack_zip(from),
+            ArchiveFormat::Tar => self.unpack_tar(from, false),
+            ArchiveFormat::TarGz => self.unpack_tar(from, true),
+        }
+    }
+
+    fn cleanup(&self) {
+        for (_, _, unpacked) in &self.entries {
+            if let Unpacked::File { temp, .. } = unpacked {
+                let _ = std::fs::remove_file(temp);
+            }
+        }
+    }
+}
+
+impl FileSystem {
+    /// 一時ファイルのアーカイブをフォルダ path に展開する 一時ファイルは削除する
+    /// 上限を超えた場合は何も作らずに Err、それ以外はエントリごとの結果を返す
+    /// 既にあるファイルは上書きする (古い版は残る)
+    /// エントリごとに principal が作成 (既にある場合は編集) できるか確かめ、できないものは Rejected にする
+    pub async fn extract(&self, user: &str, folder: &str, principal: &Principal, from: PathBuf, format: ArchiveFormat) -> Result<ExtractReport, Error> {
+        let result = self.unpack(user, folder, &from, format).await;
+        let _ = tokio::fs::remove_file(&from).await;
+        let entries = result?;
+
+        let mut report = ExtractReport { folder: folder.to_string(), ..Default::default() };
+        for (name, dest, unpacked) in entries {
+            let unpacked = match &dest {
+                Some(dest) => self.authorize_entry(user, dest, principal, unpacked).await,
+                None => unpacked,
+            };
+            let (status, id, size, reason) = match (&dest, unpacked) {
+                (_, Unpacked::Rejected(reason)) => (ExtractStatus::Rejected, None, 0, Some(reason)),
+                (_, Unpacked::Skipped(reason)) => (ExtractStatus::Skipped, None, 0, Some(reason)),
+                (_, Unpacked::Failed(reason)) => (ExtractStatus::Failed, None, 0, Some(reason)),
+                (Some(dest), Unpacked::Folder) => self.extract_folder(user, dest).await,
+                (Some(dest), Unpacked::File { temp, size, checksum }) => match self.extract_file(user, dest, &temp, size, checksum).await {
+                    Ok(meta) if meta.version > 1 => (ExtractStatus::Updated, Some(meta.id), size, None),
+                    Ok(meta) => (ExtractStatus::Created, Some(meta.id), size, None),
+                    Err(e) => (ExtractStatus::Failed, None, size, Some(e.to_string())),
+                },
+                // 展開先の無いものは Rejected のみ
+                (None, _) => continue,
+            };
+            report.push(name, dest, status, id, size, reason);
+        }
+        Ok(report)
+    }
+
+    /// 展開先を用意して一時ファイルに展開する
+    async fn unpack(&self, user: &str, folder: &str, from: &Path, format: ArchiveFormat) -> Result<Vec<(String, Option<String>, Unpacked)>, Error> {
+        self.create_folder(user, folder).await?;
+
+        let archive_size = tokio::fs::metadata(from).await?.len();
+        let config = &self.config.extract;
+        let limits = [
+            Some(config.max_size).filter(|s| *s > 0),
+            Some(archive_size.saturating_mul(config.max_ratio)).filter(|_| config.max_ratio > 0),
+        ];
+        let mut unpacker = Unpacker {
+            temp_dir: self.root.join("tmp"),
+            ruid: Arc::clone(&self.ruid),
+            folder: folder.to_string(),
+            max_entries: config.max_entries,
+            limit: limits.into_iter().flatten().min(),
+            total: 0,
+            entries: Vec::new(),
+        };
+        let from = from.to_path_buf();
+        let unpacker = tokio::task::spawn_blocking(move || match unpacker.unpack(&from, format) {
+            Ok(()) => Ok(unpacker),
+            Err(e) => {
+                unpacker.cleanup();
+                Err(e)
+            }
+        })
+        .await
+        .map_err(Error::other)??;
+
+        // 展開した合計で先に使用量を確かめ、途中まで作って止まらないようにする
+        if let Err(e) = self.check_quota(user, unpacker.total).await {
+            unpacker.cleanup();
+            return Err(e);
+        }
+        Ok(unpacker.entries)
+    }
+
+    /// 展開先に作成できないエントリは Rejected にして一時ファイルを消す
+    async fn authorize_entry(&self, user: &str, dest: &str, principal: &Principal, unpacked: Unpacked) -> Unpacked {
+        if !matches!(unpacked, Unpacked::Folder | Unpacked::File { .. }) {
+            return unpacked;
+        }
+        let reason = match self.authorize(user, dest, principal, Operation::Create).await {
+            Ok(()) => return unpacked,
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => Unpacked::Rejected(e.to_string()),
+            Err(e) => Unpacked::Failed(e.to_string()),
+        };
+        if let Unpacked::File { temp, .. } = &unpacked {
+            let _ = tokio::fs::remove_file(temp).await;
+        }
+        reason
+    }
+
+    async fn extract_folder(&self, user: &str, path: &str) -> (ExtractStatus, Option<u128>, u64, Option<String>) {
+        let existing = {
+            let _guard = self.lock.read().await;
+            self.lookup(user, path).await
+        };
+        match existing {
+            Ok(Some(meta)) if meta.is_folder() => return (ExtractStatus::Skipped, Some(meta.id), 0, Some("folder already exists".to_string())),
+            Err(e) => return (ExtractStatus::Failed, None, 0, Some(e.to_string())),
+            _ => {}
+        }
+        match self.create_folder(user, path).await {
+            Ok(meta) => (ExtractStatus::Created, Some(meta.id), 0, None),
+            Err(e) => (ExtractStatus::Failed, None, 0, Some(e.to_string())),
+        }
+    }
+
+    async fn extract_file(&self, user: &str, path: &str, temp: &Path, size: u64, checksum: String) -> Result<MetaData, Error> {
+        let content = match self.import_content(user, temp, size, &checksum).await {
+            Ok(content) => content,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(temp).await;
+                return Err(e);
+            }
+        };
+        self.create_file(user, path, content, size, checksum).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Write},
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use bytes::Bytes;
+    use futures::stream;
+
+    use super::ExtractStatus;
+    use crate::{
+        file_system::{
+            archive::ArchiveFormat,
+            file_system::FileSystem,
+            test_support::{self, Backend},
+        },
+        utils::ruid::RuidGenerator,
+    };
+
+    /// (名前, 内容) の ZIP を作る 名前が `/` で終わるものはフォルダ
+    fn zip_file(dir: &Path, entries: &[(&str, &[u8])]) -> PathBuf {
+        let path = dir.join("in.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, data) in entries {
+            match name.strip_suffix('/') {
+                Some(folder) => zip.add_directory(folder, options).unwrap(),
+                None => {
+                    zip.start_file(*name, options).unwrap();
+                    zip.write_all(data).unwrap();
+                }
+            }
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    fn kinds(report: &super::ExtractReport) -> Vec<(&str, ExtractStatus)> {
+        report.entries.iter().map(|e| (e.name.as_str(), e.status)).collect()
+    }
+
+    #[tokio::test]
+    async fn extract_zip() {
+        for backend in test_support::BACKENDS {
+            let (dir, fs) = test_support::file_system(backend).await;
+            let alice = fs.principal(Some("alice")).await.unwrap();
+            test_support::write(&fs, "alice", "/out/a.txt", b"old").await;
+            let from = zip_file(
+                dir.path(),
+                &[
+                    ("a.txt", b"new content of a.txt"),
+                    ("sub/", b""),
+                    ("sub/b.txt", b"beta"),
+                    ("empty/", b""),
+                    ("../evil.txt", b"evil"),
+                ],
+            );
+
+            let report = fs.extract("alice", "/out", &alice, from.clone(), ArchiveFormat::Zip).await.unwrap();
+            assert_eq!(
+                kinds(&report),
+                [
+                    ("a.txt", ExtractStatus::Updated),
+                    ("sub/", ExtractStatus::Created),
+                    ("sub/b.txt", ExtractStatus::Created),
+                    ("empty/", ExtractStatus::Created),
+                    ("../evil.txt", ExtractStatus::Rejected),
+                ],
+                "{:?}",
+                backend
+            );
+            assert_eq!((report.created, report.updated, report.rejected), (3, 1, 1));
+            assert!(!from.exists());
+            assert_eq!(fs.read_all("alice", "/out/a.txt").await.unwrap(), b"new content of a.txt");
+            assert_eq!(fs.read_all("alice", "/out/sub/b.txt").await.unwrap(), b"beta");
+            assert!(fs.get("alice", "/out/empty").await.unwrap().is_folder());
+            assert_eq!(fs.get("alice", "/evil.txt").await.unwrap_err().kind(), ErrorKind::NotFound);
+        }
+    }
+
+    /// リンクは作らず、絶対パスは展開しない
+    #[tokio::test]
+    async fn extract_tar() {
+        let (dir, fs) = test_support::file_system(Backend::Memory).await;
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        let from = dir.path().join("in.tar");
+        let mut tar = tar::Builder::new(std::fs::File::create(&from).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        tar.append_data(&mut header, "日本語.txt", &b"alpha"[..]).unwrap();
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        tar.append_link(&mut link, "link", "/etc/passwd").unwrap();
+        let mut absolute = tar::Header::new_gnu();
+        absolute.set_size(4);
+        absolute.as_old_mut().name[..8].copy_from_slice(b"/abs.txt");
+        absolute.set_cksum();
+        tar.append(&absolute, &b"abso"[..]).unwrap();
+        tar.into_inner().unwrap();
+
+        let report = fs.extract("alice", "/", &alice, from, ArchiveFormat::Tar).await.unwrap();
+        assert_eq!(
+            kinds(&report),
+            [("日本語.txt", ExtractStatus::Created), ("link", ExtractStatus::Skipped), ("/abs.txt", ExtractStatus::Rejected)]
+        );
+        assert_eq!(fs.read_all("alice", "/日本語.txt").await.unwrap(), b"alpha");
+        assert_eq!(fs.get("alice", "/link").await.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    /// 上限を超えた場合は何も作らず、一時ファイルも残さない
+    #[tokio::test]
+    async fn limits_create_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_support::config(dir.path(), Backend::Memory);
+        config.extract.max_entries = 2;
+        config.extract.max_size = 16;
+        let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap();
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        let temp_files = || std::fs::read_dir(fs.root.join("tmp")).unwrap().count();
+
+        let from = zip_file(dir.path(), &[("a", b"1"), ("b", b"2"), ("c", b"3")]);
+        let e = fs.extract("alice", "/many", &alice, from, ArchiveFormat::Zip).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        // 圧縮で小さくなる中身でも展開後の量で数える
+        let from = zip_file(dir.path(), &[("a", b"small"), ("b", &[0u8; 64])]);
+        let e = fs.extract("alice", "/bomb", &alice, from, ArchiveFormat::Zip).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::FileTooLarge);
+        assert!(fs.list("alice", "/bomb").await.unwrap().1.is_empty());
+        assert_eq!(temp_files(), 0);
+
+        config.extract.max_size = 0;
+        config.extract.max_ratio = 1;
+        let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(2))).await.unwrap();
+        let from = zip_file(dir.path(), &[("zeros", &[0u8; 4096])]);
+        let e = fs.extract("alice", "/ratio", &alice, from, ArchiveFormat::Zip).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::FileTooLarge);
+    }
+
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// 作成だけ許されたユーザーは、既にあるファイルを展開で上書きできない
+    #[tokio::test]
+    async fn entries_are_authorized() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let bob = 0x2101_0000_0000_0000_0000_0000_0000_0001;
+        fs.metas.bind_user(bob, "bob").await.unwrap();
+        test_support::write(&fs, "alice", "/home/drop/locked.txt", b"original").await;
+        let mut folder = fs.get("alice", "/home/drop").await.unwrap();
+        folder.perm.create.allow.insert(bob);
+        fs.metas.put(&folder).await.unwrap();
+
+        let archive = tar(&[("new.txt", b"new"), ("locked.txt", b"replaced")]);
+        let (temp, _, _) = fs.save_temp(stream::iter([Ok::<_, std::io::Error>(Bytes::from(archive))])).await.unwrap();
+        let by_bob = fs.principal(Some("bob")).await.unwrap();
+        let report = fs.extract("alice", "/home/drop", &by_bob, temp, ArchiveFormat::Tar).await.unwrap();
+
+        let status = |name: &str| report.entries.iter().find(|e| e.name == name).map(|e| e.status);
+        assert_eq!(status("new.txt"), Some(ExtractStatus::Created));
+        assert_eq!(status("locked.txt"), Some(ExtractStatus::Rejected));
+        assert_eq!(fs.read_all("alice", "/home/drop/locked.txt").await.unwrap(), b"original");
+        assert_eq!(fs.read_all("alice", "/home/drop/new.txt").await.unwrap(), b"new");
+    }
+}
@@ -0,0 +1,265 @@
+use std::{collections::HashMap, io::{Error, ErrorKind}, path::PathBuf, sync::Arc};
+
+use chrono::Utc;
+use log::info;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::utils::{self, ruid::{self, RuidGenerator}};
+
+use super::{blob::Content, config::FileSystemConfig, crypto::KeyRing, group::GroupCache, meta::{MetaData, FOLDER_TYPE}, path, preview::PreviewQueue, store::{self, encrypted::EncryptedBlobStore, BlobStore, MetadataStore}};
+
+pub struct FileSystem {
+    pub config: FileSystemConfig,
+    pub root: PathBuf,
+    pub metas: Arc<dyn MetadataStore>,
+    /// 暗号化が有効な場合は復号を挟んだもの
+    pub blobs: Arc<dyn BlobStore>,
+    /// 暗号化を挟まない BlobStore 暗号文のまま扱う場合に使う
+    pub(super) raw_blobs: Arc<dyn BlobStore>,
+    pub keyring: Option<Arc<KeyRing>>,
+    pub(super) ruid: Arc<RuidGenerator>,
+    /// ツリーを変更する操作は write、読み出しは read を取る
+    /// 移動などで途中の状態が見えないようにするため
+    pub(super) lock: RwLock<()>,
+    /// メタデータに結び付く前のバイナリ (key -> 固定数) GC から守る
+    pub(super) pins: Mutex<HashMap<String, usize>>,
+    pub(super) preview_queue: PreviewQueue,
+    /// 権限グループの変更を直列にする 循環の検査のため
+    pub(super) group_lock: Mutex<()>,
+    pub(super) group_cache: RwLock<GroupCache>,
+}
+
+impl FileSystem {
+    pub async fn new(config: &FileSystemConfig, ruid: Arc<RuidGenerator>) -> Result<Self, Error> {
+        let root = utils::fs::get_file_path(&config.storage_path)?;
+        std::fs::create_dir_all(root.join("tmp"))?;
+        info!("file system storage: {}", root.display());
+        let (metas, mut blobs) = store::open(config, &root).await?;
+        let keyring = KeyRing::open(&config.encryption, Arc::clone(&metas))?.map(Arc::new);
+        let raw_blobs = Arc::clone(&blobs);
+        if let Some(ring) = &keyring {
+            blobs = Arc::new(EncryptedBlobStore::new(blobs, Arc::clone(ring)));
+            info!("encryption at rest is enabled");
+        }
+
+        Ok(Self {
+            config: config.clone(),
+            root,
+            metas,
+            blobs,
+            raw_blobs,
+            keyring,
+            ruid,
+            lock: RwLock::new(()),
+            pins: Mutex::new(HashMap::new()),
+            preview_queue: PreviewQueue::new(),
+            group_lock: Mutex::new(()),
+            group_cache: RwLock::new(GroupCache::default()),
+        })
+    }
+
+    /// バイナリを書き込む前の一時ファイル
+    pub fn temp_path(&self) -> PathBuf {
+        self.root.join("tmp").join(format!("{:032x}", self.ruid.generate(ruid::prefix::CACHE_FILE)))
+    }
+
+    pub(super) async fn lookup(&self, user: &str, path: &str) -> Result<Option<MetaData>, Error> {
+        match self.metas.resolve(user, path).await? {
+            Some(id) => self.metas.get(id).await,
+            None => Ok(None),
+        }
+    }
+
+    pub(super) async fn lookup_required(&self, user: &str, path: &str) -> Result<MetaData, Error> {
+        self.lookup(user, path).await?.ok_or_else(|| Error::new(ErrorKind::NotFound, "file is not found"))
+    }
+
+    pub async fn get(&self, user: &str, path: &str) -> Result<MetaData, Error> {
+        let _guard = self.lock.read().await;
+        self.lookup_required(user, path).await
+    }
+
+    /// ルートフォルダを持つユーザーの一覧
+    pub async fn users(&self) -> Result<Vec<String>, Error> {
+        let mut users: Vec<String> = self.metas.scan().await?.into_iter()
+            .filter(|m| m.path == "/")
+            .map(|m| m.owner)
+            .collect();
+        users.sort();
+        users.dedup();
+        Ok(users)
+    }
+
+    /// フォルダの場合は links の子要素も返す
+    pub async fn list(&self, user: &str, path: &str) -> Result<(MetaData, Vec<MetaData>), Error> {
+        let _guard = self.lock.read().await;
+        let meta = self.lookup_required(user, path).await?;
+        let mut children = Vec::with_capacity(meta.links.len());
+        for id in &meta.links {
+            if let Some(child) = self.metas.get(*id).await? {
+                children.push(child);
+            }
+        }
+        Ok((meta, children))
+    }
+
+    /// 途中のフォルダも含めて作成する 既にある場合はそのフォルダを返す
+    pub async fn create_folder(&self, user: &str, path: &str) -> Result<MetaData, Error> {
+        let _guard = self.lock.write().await;
+        self.ensure_folder(user, path).await
+    }
+
+    /// ファイルのメタデータを作成する 既にファイルがある場合は本体を置き換える
+    /// 置き換えた本体は古い版として残り、保持数と保持期間を超えた版の blob は解放される
+    /// プレビューは作り直すため待ち行列に入れる
+    /// Content::Blob は import_binary で固定したもの 成否にかかわらず固定を外す
+    pub async fn create_file(&self, user: &str, path: &str, content: Content, size: u64, checksum: String) -> Result<MetaData, Error> {
+        match self.create_file_if(user, path, content, size, checksum, |_| true).await? {
+            Some(meta) => Ok(meta),
+            None => Err(Error::other("file was not written")),
+        }
+    }
+
+    /// lock を取った後の既存のメタデータ (無い場合は None) が check を満たす場合だけ create_file する 満たさない場合は None
+    pub(super) async fn create_file_if<C>(&self, user: &str, path: &str, content: Content, size: u64, checksum: String, check: C) -> Result<Option<MetaData>, Error>
+    where
+        C: Fn(Option<&MetaData>) -> bool,
+    {
+        let blob = match &content {
+            Content::Blob(key) => Some(key.clone()),
+            Content::Inline(_) => None,
+        };
+        let result = self.attach_file(user, path, content, size, checksum, check).await;
+        if let Some(blob) = &blob {
+            self.unpin(blob).await;
+        }
+        match result {
+            Ok(Some((meta, released))) => {
+                self.release_blobs(released).await;
+                self.enqueue_preview(&meta);
+                Ok(Some(meta))
+            }
+            Ok(None) => {
+                self.release_blobs(blob).await;
+                Ok(None)
+            }
+            Err(e) => {
+                self.release_blobs(blob).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn attach_file<C>(&self, user: &str, path: &str, content: Content, size: u64, checksum: String, check: C) -> Result<Option<(MetaData, Vec<String>)>, Error>
+    where
+        C: Fn(Option<&MetaData>) -> bool,
+    {
+        let parent = path::parent(path).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "root is not a file"))?;
+        let time = Utc::now().timestamp_millis();
+
+        let _guard = self.lock.write().await;
+        let existing = self.lookup(user, path).await?;
+        if !check(existing.as_ref()) {
+            return Ok(None);
+        }
+        let mut parent_meta = self.ensure_folder(user, parent).await?;
+
+        let (mut meta, previous) = match existing {
+            Some(meta) if meta.is_folder() => return Err(Error::new(ErrorKind::AlreadyExists, "folder already exists")),
+            Some(mut meta) => {
+                let previous = meta.clone();
+                meta.update_time = time;
+                meta.version += 1;
+                (meta, Some(previous))
+            }
+            None => {
+                let data_type = mime_guess::from_path(path).first_or_octet_stream().to_string();
+                (MetaData::new(self.ruid.generate(ruid::prefix_from_extension(path)), user, path, data_type, time), None)
+            }
+        };
+
+        let old_blob = match content {
+            Content::Inline(data) => {
+                meta.inline = Some(data);
+                meta.blob.take()
+            }
+            Content::Blob(key) => {
+                meta.inline = None;
+                meta.blob.replace(key)
+            }
+        };
+        meta.size = size;
+        meta.checksum = Some(checksum);
+        self.charge(user, std::slice::from_ref(&meta), previous.as_slice(), true).await?;
+
+        // 新しい本体より先に古い版を残し、古い blob の参照が途切れないようにする
+        let mut released: Vec<String> = old_blob.into_iter().collect();
+        released.extend(meta.previews.drain(..).map(|p| p.blob));
+        if let Some(previous) = &previous {
+            released.extend(self.archive_version(previous).await?);
+        }
+        self.metas.put(&meta).await?;
+
+        if previous.is_none() {
+            self.metas.attach(user, path, meta.id).await?;
+            parent_meta.links.push(meta.id);
+            self.metas.put(&parent_meta).await?;
+        }
+        Ok(Some((meta, released)))
+    }
+
+    /// lock の write を取った状態で呼ぶ
+    pub(super) async fn ensure_folder(&self, user: &str, path: &str) -> Result<MetaData, Error> {
+        let mut parent: Option<MetaData> = None;
+        let mut current = String::from("/");
+        let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+
+        for i in 0..=parts.len() {
+            if i > 0 {
+                current = path::join(&current, parts[i - 1]);
+            }
+            let meta = match self.lookup(user, &current).await? {
+                Some(meta) if meta.is_folder() => meta,
+                Some(_) => return Err(Error::new(ErrorKind::AlreadyExists, "file exists in place of folder")),
+                None => {
+                    let meta = MetaData::new(self.ruid.generate(ruid::prefix::FOLDER), user, &current, FOLDER_TYPE.to_string(), Utc::now().timestamp_millis());
+                    self.metas.put(&meta).await?;
+                    self.metas.attach(user, &current, meta.id).await?;
+                    if let Some(mut parent) = parent.take() {
+                        parent.links.push(meta.id);
+                        self.metas.put(&parent).await?;
+                    }
+                    meta
+                }
+            };
+            parent = Some(meta);
+        }
+
+        Ok(parent.unwrap())
+    }
+
+    /// id 以下の全ての子孫 (id 自身は含まない)
+    pub(super) async fn subtree(&self, id: u128) -> Result<Vec<MetaData>, Error> {
+        let mut result = Vec::new();
+        let mut stack = match self.metas.get(id).await? {
+            Some(meta) => meta.links.clone(),
+            None => return Ok(result),
+        };
+        while let Some(id) = stack.pop() {
+            if let Some(meta) = self.metas.get(id).await? {
+                stack.extend(meta.links.iter().copied());
+                result.push(meta);
+            }
+        }
+        Ok(result)
+    }
+
+    /// 親フォルダの links から id を外す
+    pub(super) async fn unlink(&self, user: &str, parent: &str, id: u128) -> Result<(), Error> {
+        if let Some(mut parent) = self.lookup(user, parent).await? {
+            parent.links.retain(|l| *l != id);
+            self.metas.put(&parent).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,455 @@
+use std::{collections::{HashMap, HashSet}, io::Error, path::PathBuf, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use futures::StreamExt;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tokio::io::AsyncWriteExt;
+
+use crate::utils::custom_serializers_adapters::Hex;
+
+use super::{file_system::FileSystem, meta::MetaData, path, version::FileVersion};
+
+/// 直す内容 全て false の場合は検査だけ
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct FsckOptions {
+    /// 参照されていないバイナリを quarantine に移す
+    #[serde(default)]
+    pub quarantine: bool,
+    /// フォルダの links から存在しない子を外す
+    #[serde(default)]
+    pub prune: bool,
+    /// メタデータと版の size を実際のバイナリの大きさに直す
+    #[serde(default)]
+    pub recompute: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FsckConfig {
+    /// バックグラウンドで検査する間隔 (秒) 0 で行わない
+    pub interval: u64,
+    pub repair: FsckOptions,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanBlob {
+    pub blob: String,
+    pub size: u64,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobUse {
+    Body,
+    Preview,
+    Version,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct MissingBlob {
+    pub blob: String,
+    #[serde_as(as = "Hex")]
+    pub id: u128,
+    pub owner: String,
+    pub path: String,
+    #[serde(rename = "use")]
+    pub used_as: BlobUse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLink {
+    #[serde_as(as = "Hex")]
+    pub folder: u128,
+    pub path: String,
+    #[serde_as(as = "Hex")]
+    pub child: u128,
+    pub reason: String,
+    pub repaired: bool,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeMismatch {
+    #[serde_as(as = "Hex")]
+    pub id: u128,
+    pub owner: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    pub recorded: u64,
+    pub actual: u64,
+    pub repaired: bool,
+}
+
+/// 検査の結果 種類ごとに分ける
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FsckReport {
+    pub started_time: i64,
+    pub finished_time: i64,
+    pub metas: u64,
+    pub versions: u64,
+    pub blobs: u64,
+    pub orphan_blobs: Vec<OrphanBlob>,
+    pub missing_blobs: Vec<MissingBlob>,
+    pub dead_links: Vec<DeadLink>,
+    pub size_mismatches: Vec<SizeMismatch>,
+    /// 検査や修復が途中で失敗したもの
+    pub errors: Vec<String>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphan_blobs.is_empty() && self.missing_blobs.is_empty() && self.dead_links.is_empty() && self.size_mismatches.is_empty() && self.errors.is_empty()
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} orphan blobs, {} missing blobs, {} dead links, {} size mismatches, {} errors",
+            self.orphan_blobs.len(), self.missing_blobs.len(), self.dead_links.len(), self.size_mismatches.len(), self.errors.len()
+        )
+    }
+}
+
+impl FileSystem {
+    /// 隔離したバイナリの置き場所
+    fn quarantine_path(&self) -> PathBuf {
+        self.root.join("quarantine")
+    }
+
+    /// メタデータとバイナリを突き合わせる
+    /// 稼働中でも使えるよう、修復は一件ずつ lock を取って確かめ直してから行う
+    pub async fn fsck(&self, options: FsckOptions) -> Result<FsckReport, Error> {
+        let mut report = FsckReport { started_time: Utc::now().timestamp_millis(), ..Default::default() };
+
+        let metas = {
+            let _guard = self.lock.read().await;
+            self.metas.scan().await?
+        };
+        let versions = self.metas.list_versions(None).await?;
+        let stored = self.blobs.list().await?;
+        report.metas = metas.len() as u64;
+        report.versions = versions.len() as u64;
+        report.blobs = stored.len() as u64;
+
+        // バイナリの実際の大きさ 暗号化したものは平文の大きさ
+        let mut sizes: HashMap<String, u64> = HashMap::with_capacity(stored.len());
+        for key in stored {
+            match self.blobs.size(&key).await {
+                Ok(Some(size)) => {
+                    sizes.insert(key, size);
+                }
+                Ok(None) => {}
+                Err(e) => report.errors.push(format!("blob {}: {}", key, e)),
+            }
+        }
+
+        let mut referenced: HashSet<&str> = HashSet::new();
+        for meta in &metas {
+            referenced.extend(meta.blobs().map(|b| b.as_str()));
+        }
+        referenced.extend(versions.iter().filter_map(|v| v.blob.as_deref()));
+
+        self.check_blobs(&metas, &versions, &sizes, options, &mut report).await;
+        self.check_links(&metas, options, &mut report).await;
+
+        for (key, size) in &sizes {
+            if referenced.contains(key.as_str()) || !self.is_orphan(key).await.unwrap_or(false) {
+                continue;
+            }
+            let repaired = match options.quarantine {
+                true => match self.quarantine(key).await {
+                    Ok(repaired) => repaired,
+                    Err(e) => {
+                        report.errors.push(format!("quarantine {}: {}", key, e));
+                        false
+                    }
+                },
+                false => false,
+            };
+            report.orphan_blobs.push(OrphanBlob { blob: key.clone(), size: *size, repaired });
+        }
+
+        report.finished_time = Utc::now().timestamp_millis();
+        Ok(report)
+    }
+
+    /// 無いバイナリと大きさの違うものを探す
+    async fn check_blobs(&self, metas: &[MetaData], versions: &[FileVersion], sizes: &HashMap<String, u64>, options: FsckOptions, report: &mut FsckReport) {
+        let by_id: HashMap<u128, &MetaData> = metas.iter().map(|m| (m.id, m)).collect();
+
+        for meta in metas.iter().filter(|m| !m.is_folder()) {
+            let missing = |blob: &String, used_as| MissingBlob { blob: blob.clone(), id: meta.id, owner: meta.owner.clone(), path: meta.path.clone(), used_as, version: None };
+            for preview in &meta.previews {
+                if !sizes.contains_key(&preview.blob) {
+                    report.missing_blobs.push(missing(&preview.blob, BlobUse::Preview));
+                }
+            }
+            let actual = match (&meta.inline, &meta.blob) {
+                (Some(data), _) => data.len() as u64,
+                (None, Some(blob)) => match sizes.get(blob) {
+                    Some(size) => *size,
+                    None => {
+                        report.missing_blobs.push(missing(blob, BlobUse::Body));
+                        continue;
+                    }
+                },
+                (None, None) => 0,
+            };
+            if actual == meta.size {
+                continue;
+            }
+            let repaired = match options.recompute {
+                true => match self.recompute_size(meta.id, meta.size, actual).await {
+                    Ok(repaired) => repaired,
+                    Err(e) => {
+                        report.errors.push(format!("recompute {:032x}: {}", meta.id, e));
+                        false
+                    }
+                },
+                false => false,
+            };
+            report.size_mismatches.push(SizeMismatch { id: meta.id, owner: meta.owner.clone(), path: meta.path.clone(), version: None, recorded: meta.size, actual, repaired });
+        }
+
+        for version in versions {
+            let (owner, path) = by_id.get(&version.file).map_or((String::new(), String::new()), |m| (m.owner.clone(), m.path.clone()));
+            let actual = match (&version.inline, &version.blob) {
+                (Some(data), _) => data.len() as u64,
+                (None, Some(blob)) => match sizes.get(blob) {
+                    Some(size) => *size,
+                    None => {
+                        report.missing_blobs.push(MissingBlob { blob: blob.clone(), id: version.file, owner, path, used_as: BlobUse::Version, version: Some(version.version) });
+                        continue;
+                    }
+                },
+                (None, None) => 0,
+            };
+            if actual == version.size {
+                continue;
+            }
+            let repaired = match options.recompute {
+                true => {
+                    let mut fixed = version.clone();
+                    fixed.size = actual;
+                    match self.metas.put_version(&fixed).await {
+                        Ok(()) => true,
+                        Err(e) => {
+                            report.errors.push(format!("recompute {:032x} version {}: {}", version.file, version.version, e));
+                            false
+                        }
+                    }
+                }
+                false => false,
+            };
+            report.size_mismatches.push(SizeMismatch { id: version.file, owner, path, version: Some(version.version), recorded: version.size, actual, repaired });
+        }
+    }
+
+    /// links の子が無い、パスが親の下に無い、所有者が違う、重複しているものを探す
+    async fn check_links(&self, metas: &[MetaData], options: FsckOptions, report: &mut FsckReport) {
+        let by_id: HashMap<u128, &MetaData> = metas.iter().map(|m| (m.id, m)).collect();
+
+        for folder in metas.iter().filter(|m| m.is_folder()) {
+            let mut seen = HashSet::new();
+            let mut dead = Vec::new();
+            for child in &folder.links {
+                let reason = match by_id.get(child) {
+                    _ if !seen.insert(*child) => "duplicate link",
+                    None => "child does not exist",
+                    Some(meta) if path::parent(&meta.path) != Some(folder.path.as_str()) => "child is not under the folder",
+                    Some(meta) if meta.owner != folder.owner => "child has another owner",
+                    Some(_) => continue,
+                };
+                dead.push((*child, reason));
+            }
+            if dead.is_empty() {
+                continue;
+            }
+
+            let repaired = match options.prune {
+                true => match self.prune_links(folder.id, &folder.links).await {
+                    Ok(repaired) => repaired,
+                    Err(e) => {
+                        report.errors.push(format!("prune {:032x}: {}", folder.id, e));
+                        false
+                    }
+                },
+                false => false,
+            };
+            for (child, reason) in dead {
+                report.dead_links.push(DeadLink { folder: folder.id, path: folder.path.clone(), child, reason: reason.to_string(), repaired });
+            }
+        }
+    }
+
+    /// 検査した時から変わっていなければ size を直し、使用量も合わせる
+    async fn recompute_size(&self, id: u128, recorded: u64, actual: u64) -> Result<bool, Error> {
+        let _guard = self.lock.write().await;
+        let previous = match self.metas.get(id).await? {
+            Some(meta) if meta.size == recorded => meta,
+            _ => return Ok(false),
+        };
+        let mut meta = previous.clone();
+        meta.size = actual;
+        self.metas.put(&meta).await?;
+        self.charge(&meta.owner, std::slice::from_ref(&meta), std::slice::from_ref(&previous), false).await?;
+        Ok(true)
+    }
+
+    /// 検査した時から links が変わっていなければ、今も正しい子だけを残す
+    async fn prune_links(&self, id: u128, links: &[u128]) -> Result<bool, Error> {
+        let _guard = self.lock.write().await;
+        let mut folder = match self.metas.get(id).await? {
+            Some(folder) if folder.links == links => folder,
+            _ => return Ok(false),
+        };
+        let mut kept = Vec::with_capacity(folder.links.len());
+        for child in &folder.links {
+            if kept.contains(child) {
+                continue;
+            }
+            match self.metas.get(*child).await? {
+                Some(meta) if path::parent(&meta.path) == Some(folder.path.as_str()) && meta.owner == folder.owner => kept.push(*child),
+                _ => {}
+            }
+        }
+        folder.links = kept;
+        self.metas.put(&folder).await?;
+        Ok(true)
+    }
+
+    /// 一覧を取った後に結び付いたものを除くため数え直す
+    async fn is_orphan(&self, key: &str) -> Result<bool, Error> {
+        let pins = self.pins.lock().await;
+        Ok(!pins.contains_key(key) && self.metas.count_blob_refs(key).await? == 0)
+    }
+
+    /// 参照が無く固定もされていなければ、暗号化されたままの中身を quarantine に書き出してから削除する
+    /// 暗号化されたものはデータキーも一緒に残す
+    async fn quarantine(&self, key: &str) -> Result<bool, Error> {
+        let pins = self.pins.lock().await;
+        if pins.contains_key(key) || self.metas.count_blob_refs(key).await? > 0 {
+            return Ok(false);
+        }
+        let size = match self.raw_blobs.size(key).await? {
+            Some(size) => size,
+            None => return Ok(false),
+        };
+
+        let dir = self.quarantine_path();
+        tokio::fs::create_dir_all(&dir).await?;
+        let mut file = tokio::fs::File::create(dir.join(key)).await?;
+        let mut stream = self.raw_blobs.read(key, 0, size, self.config.streaming_chunk_size);
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        if let Some(ring) = &self.keyring {
+            if let Some(blob_key) = ring.blob_key(key).await? {
+                let json = serde_json::to_vec_pretty(&blob_key).map_err(Error::other)?;
+                tokio::fs::write(dir.join(format!("{}.key.json", key)), json).await?;
+            }
+        }
+        self.blobs.delete(key).await?;
+        Ok(true)
+    }
+
+    /// 稼働中の検査 結果は storage_path/fsck.json に書き出す
+    pub async fn run_fsck(self: Arc<Self>, interval: Duration, options: FsckOptions) {
+        let mut ticker = tokio::time::interval(interval);
+        // 起動直後は走らせない
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let report = match self.fsck(options).await {
+                Ok(report) => report,
+                Err(e) => {
+                    error!("Failed to check file system: {}", e);
+                    continue;
+                }
+            };
+            match report.is_clean() {
+                true => info!("file system is consistent ({} metas, {} blobs)", report.metas, report.blobs),
+                false => warn!("file system has inconsistencies: {}", report.summary()),
+            }
+            let json = match serde_json::to_vec_pretty(&report) {
+                Ok(json) => json,
+                Err(e) => {
+                    error!("Failed to serialize fsck report: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = tokio::fs::write(self.root.join("fsck.json"), json).await {
+                error!("Failed to write fsck report: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::stream;
+
+    use super::{BlobUse, FsckOptions};
+    use crate::file_system::{
+        blob::Content,
+        test_support::{self, BACKENDS},
+    };
+
+    /// 孤立したバイナリ、無いバイナリ、切れたリンク、大きさの違いを見つけて直す
+    /// create_file する前の固定されたバイナリは孤立として扱わない
+    #[tokio::test]
+    async fn report_and_repair() {
+        for backend in BACKENDS {
+            let (dir, fs) = test_support::file_system(backend).await;
+            let missing = test_support::write(&fs, "alice", "/docs/missing.txt", b"this blob will be deleted").await;
+            let resized = test_support::write(&fs, "alice", "/docs/resized.txt", b"this size will be wrong").await;
+            let chunks = stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from_static(b"uploaded but not created"))]);
+            let pending = match fs.save_binary("alice", chunks).await.unwrap() {
+                (Content::Blob(key), _, _) => key,
+                _ => panic!("expected a blob"),
+            };
+            fs.blobs.put("0123", Bytes::from_static(b"orphan")).await.unwrap();
+            fs.blobs.delete(missing.blob.as_deref().unwrap()).await.unwrap();
+            let mut meta = fs.get("alice", "/docs/resized.txt").await.unwrap();
+            meta.size += 1;
+            fs.metas.put(&meta).await.unwrap();
+            let mut folder = fs.get("alice", "/docs").await.unwrap();
+            folder.links.push(42);
+            fs.metas.put(&folder).await.unwrap();
+
+            let report = fs.fsck(FsckOptions::default()).await.unwrap();
+            let orphans: Vec<_> = report.orphan_blobs.iter().map(|o| (o.blob.as_str(), o.repaired)).collect();
+            assert_eq!(orphans, [("0123", false)], "{:?}", backend);
+            assert_eq!(report.missing_blobs.len(), 1);
+            assert_eq!((report.missing_blobs[0].id, report.missing_blobs[0].used_as), (missing.id, BlobUse::Body));
+            assert_eq!(report.dead_links.len(), 1);
+            assert_eq!((report.dead_links[0].child, report.dead_links[0].repaired), (42, false));
+            assert_eq!(report.size_mismatches.len(), 1);
+            let mismatch = &report.size_mismatches[0];
+            assert_eq!((mismatch.id, mismatch.recorded, mismatch.actual, mismatch.repaired), (resized.id, 24, 23, false));
+            assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+            let repair = FsckOptions { quarantine: true, prune: true, recompute: true };
+            let report = fs.fsck(repair).await.unwrap();
+            assert!(report.orphan_blobs[0].repaired && report.dead_links[0].repaired && report.size_mismatches[0].repaired, "{:?}", backend);
+            assert!(dir.path().join("storage/quarantine/0123").exists());
+            assert_eq!(fs.blobs.size("0123").await.unwrap(), None);
+            assert_eq!(fs.blobs.size(&pending).await.unwrap(), Some(24));
+            assert_eq!(fs.get("alice", "/docs/resized.txt").await.unwrap().size, 23);
+            assert!(!fs.get("alice", "/docs").await.unwrap().links.contains(&42));
+
+            // 無いバイナリは直せないので残る
+            let report = fs.fsck(repair).await.unwrap();
+            assert_eq!(report.missing_blobs.len(), 1);
+            assert!(report.orphan_blobs.is_empty() && report.dead_links.is_empty() && report.size_mismatches.is_empty());
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::{collections::{HashMap, HashSet}, io::{Error, ErrorKind}};
+
+use chrono::Utc;
+use serde::Serialize;
+use serde_with::serde_as;
+
+use crate::utils::{custom_serializers_adapters::Hex, ruid};
+
+use super::{file_system::FileSystem, perm::{is_group, PermGroup}};
+
+pub const MAX_GROUP_NAME_LENGTH: usize = 64;
+
+/// ユーザーの RUID -> 属する権限グループ
+/// 所属が変わるたびに全て捨てるので、ログインし直さなくても次のリクエストから反映される
+#[derive(Default)]
+pub(super) struct GroupCache {
+    /// 捨てるたびに増やす 計算中に捨てられた結果を入れないため
+    generation: u64,
+    users: HashMap<u128, HashSet<u128>>,
+}
+
+/// ユーザーが属する権限グループ
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct Membership {
+    #[serde_as(as = "Hex")]
+    pub id: u128,
+    pub owner: String,
+    pub name: String,
+    /// 直接のメンバーか 入れ子のグループを通してか
+    pub direct: bool,
+}
+
+fn valid_group_name(name: &str) -> bool {
+    !name.trim().is_empty() && name.chars().count() <= MAX_GROUP_NAME_LENGTH && !name.chars().any(char::is_control)
+}
+
+impl FileSystem {
+    /// effective_groups のキャッシュ
+    pub(super) async fn cached_groups(&self, user: u128) -> Result<HashSet<u128>, Error> {
+        let generation = {
+            let cache = self.group_cache.read().await;
+            if let Some(groups) = cache.users.get(&user) {
+                return Ok(groups.clone());
+            }
+            cache.generation
+        };
+        let groups = self.effective_groups(user).await?;
+        let mut cache = self.group_cache.write().await;
+        if cache.generation == generation {
+            cache.users.insert(user, groups.clone());
+        }
+        Ok(groups)
+    }
+
+    async fn invalidate_groups(&self) {
+        let mut cache = self.group_cache.write().await;
+        cache.generation += 1;
+        cache.users.clear();
+    }
+
+    /// `@<user>` またはユーザーか権限グループの RUID (hex)
+    pub async fn resolve_member(&self, spec: &str) -> Result<u128, Error> {
+        if let Some(name) = spec.strip_prefix('@') {
+            return self.metas.ruid_by_user(name).await?.ok_or_else(|| Error::new(ErrorKind::NotFound, "user is not found"));
+        }
+        let hex = spec.trim_start_matches("0x");
+        if hex.len() != 32 {
+            return Err(Error::new(ErrorKind::InvalidInput, "member must be @<id> or RUID"));
+        }
+        u128::from_str_radix(hex, 16).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid RUID"))
+    }
+
+    pub async fn create_group(&self, owner: &str, name: &str) -> Result<PermGroup, Error> {
+        if !valid_group_name(name) {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid group name"));
+        }
+        let group = PermGroup {
+            id: self.ruid.generate(ruid::prefix::PERMISSION_ID),
+            owner: owner.to_string(),
+            name: name.to_string(),
+            members: Vec::new(),
+            create_time: Utc::now().timestamp_millis(),
+        };
+        self.metas.put_group(&group).await?;
+        Ok(group)
+    }
+
+    /// owner が作った権限グループ
+    pub async fn groups(&self, owner: &str) -> Result<Vec<PermGroup>, Error> {
+        let mut groups = self.metas.list_groups(Some(owner)).await?;
+        groups.sort_by_key(|g| g.create_time);
+        Ok(groups)
+    }
+
+    /// owner の権限グループ 他のユーザーのものは無いものとして扱う
+    pub async fn owned_group(&self, owner: &str, id: u128) -> Result<PermGroup, Error> {
+        match self.metas.get_group(id).await? {
+            Some(group) if group.owner == owner => Ok(group),
+            _ => Err(Error::new(ErrorKind::NotFound, "group is not found")),
+        }
+    }
+
+    /// 削除して、それを含むグループからも外す perm に残った RUID は誰にも当たらなくなる
+    pub async fn delete_group(&self, owner: &str, id: u128) -> Result<PermGroup, Error> {
+        let _guard = self.group_lock.lock().await;
+        let group = self.owned_group(owner, id).await?;
+        for mut parent in self.metas.groups_with_member(id).await? {
+            parent.members.retain(|m| *m != id);
+            self.metas.put_group(&parent).await?;
+        }
+        self.metas.delete_group(id).await?;
+        self.invalidate_groups().await;
+        Ok(group)
+    }
+
+    /// member はユーザーまたは他の権限グループ 自分を含むことになるグループは入れられない
+    pub async fn add_member(&self, owner: &str, id: u128, member: u128) -> Result<PermGroup, Error> {
+        let _guard = self.group_lock.lock().await;
+        let mut group = self.owned_group(owner, id).await?;
+        if group.members.contains(&member) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "already a member"));
+        }
+        if is_group(member) {
+            if self.metas.get_group(member).await?.is_none() {
+                return Err(Error::new(ErrorKind::NotFound, "group is not found"));
+            }
+            // id を直接または間接に含むグループを入れると循環する
+            if member == id || self.effective_groups(id).await?.contains(&member) {
+                return Err(Error::new(ErrorKind::InvalidInput, "group would contain itself"));
+            }
+        } else if ruid::prefix_of(member) & 0xFF00 == ruid::prefix::USER_EXAMPLE_ID {
+            if self.metas.user_by_ruid(member).await?.is_none() {
+                return Err(Error::new(ErrorKind::NotFound, "user is not found"));
+            }
+        } else {
+            return Err(Error::new(ErrorKind::InvalidInput, "member must be a user or a group"));
+        }
+
+        group.members.push(member);
+        self.metas.put_group(&group).await?;
+        self.invalidate_groups().await;
+        Ok(group)
+    }
+
+    pub async fn remove_member(&self, owner: &str, id: u128, member: u128) -> Result<PermGroup, Error> {
+        let _guard = self.group_lock.lock().await;
+        let mut group = self.owned_group(owner, id).await?;
+        if !group.members.contains(&member) {
+            return Err(Error::new(ErrorKind::NotFound, "not a member"));
+        }
+        group.members.retain(|m| *m != member);
+        self.metas.put_group(&group).await?;
+        self.invalidate_groups().await;
+        Ok(group)
+    }
+
+    /// user が直接または入れ子で属する権限グループ
+    pub async fn memberships(&self, user: &str) -> Result<Vec<Membership>, Error> {
+        let ruid = match self.metas.ruid_by_user(user).await? {
+            Some(ruid) => ruid,
+            None => return Ok(Vec::new()),
+        };
+        let mut list = Vec::new();
+        for id in self.cached_groups(ruid).await? {
+            if let Some(group) = self.metas.get_group(id).await? {
+                list.push(Membership {
+                    id,
+                    direct: group.members.contains(&ruid),
+                    owner: group.owner,
+                    name: group.name,
+                });
+            }
+        }
+        list.sort_by(|a, b| b.direct.cmp(&a.direct).then_with(|| a.name.cmp(&b.name)));
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use crate::file_system::test_support::{self, Backend};
+
+    const BOB: u128 = (0x2101 << 112) | 2;
+
+    #[tokio::test]
+    async fn members_and_cycles() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        fs.metas.bind_user(BOB, "bob").await.unwrap();
+        let a = fs.create_group("alice", "a").await.unwrap();
+        let b = fs.create_group("alice", "b").await.unwrap();
+        let c = fs.create_group("alice", "c").await.unwrap();
+        assert_eq!(fs.create_group("alice", " ").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        assert_eq!(fs.resolve_member("@bob").await.unwrap(), BOB);
+        assert_eq!(fs.resolve_member(&format!("{:032x}", a.id)).await.unwrap(), a.id);
+        assert_eq!(fs.resolve_member("@carol").await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.resolve_member("bob").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        fs.add_member("alice", a.id, BOB).await.unwrap();
+        fs.add_member("alice", b.id, a.id).await.unwrap();
+        fs.add_member("alice", c.id, b.id).await.unwrap();
+        assert_eq!(fs.add_member("alice", a.id, BOB).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        // c -> b -> a なので a に c や a 自身は入れられない
+        assert_eq!(fs.add_member("alice", a.id, c.id).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.add_member("alice", a.id, a.id).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.add_member("alice", a.id, (0x2101 << 112) | 9).await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.add_member("alice", a.id, 0x1100 << 112).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        // 他のユーザーのグループは無いものとして扱う
+        assert_eq!(fs.add_member("bob", a.id, BOB).await.unwrap_err().kind(), ErrorKind::NotFound);
+
+        let memberships = fs.memberships("bob").await.unwrap();
+        let names: Vec<_> = memberships.iter().map(|m| (m.name.as_str(), m.direct)).collect();
+        assert_eq!(names, [("a", true), ("b", false), ("c", false)]);
+    }
+
+    /// 削除したグループは親からも外す
+    #[tokio::test]
+    async fn delete_removes_from_parents() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let a = fs.create_group("alice", "a").await.unwrap();
+        let b = fs.create_group("alice", "b").await.unwrap();
+        fs.add_member("alice", b.id, a.id).await.unwrap();
+
+        assert_eq!(fs.delete_group("bob", a.id).await.unwrap_err().kind(), ErrorKind::NotFound);
+        fs.delete_group("alice", a.id).await.unwrap();
+        assert!(fs.owned_group("alice", b.id).await.unwrap().members.is_empty());
+        assert_eq!(fs.owned_group("alice", a.id).await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.groups("alice").await.unwrap().iter().map(|g| g.id).collect::<Vec<_>>(), [b.id]);
+    }
+
+    /// 所属が変わると、ログインし直さなくても次の principal から反映される
+    #[tokio::test]
+    async fn cache_is_invalidated() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        fs.metas.bind_user(BOB, "bob").await.unwrap();
+        let a = fs.create_group("alice", "a").await.unwrap();
+        let b = fs.create_group("alice", "b").await.unwrap();
+        assert!(!fs.principal(Some("bob")).await.unwrap().ids.contains(&a.id));
+
+        fs.add_member("alice", a.id, BOB).await.unwrap();
+        fs.add_member("alice", b.id, a.id).await.unwrap();
+        let ids = fs.principal(Some("bob")).await.unwrap().ids;
+        assert!(ids.contains(&a.id) && ids.contains(&b.id));
+
+        fs.remove_member("alice", b.id, a.id).await.unwrap();
+        assert!(!fs.principal(Some("bob")).await.unwrap().ids.contains(&b.id));
+        fs.delete_group("alice", a.id).await.unwrap();
+        assert!(!fs.principal(Some("bob")).await.unwrap().ids.contains(&a.id));
+        assert_eq!(fs.remove_member("alice", b.id, a.id).await.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,505 @@
+use std::{io::{Error, ErrorKind}, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::serde_as;
+use tokio::{io::AsyncWriteExt, time::{Interval, MissedTickBehavior}};
+
+use crate::utils::custom_serializers_adapters::Hex;
+
+use super::{file_system::FileSystem, meta::MetaData, path};
+
+fn default_pointer() -> String {
+    "/logs".to_string()
+}
+
+/// 規則が一致したファイルに行う操作
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LifecycleAction {
+    /// 最後の更新から age を過ぎたファイルをゴミ箱に移す
+    Trash,
+    /// JSON の pointer の配列から time_field が age を過ぎた要素を消す
+    /// 時刻はミリ秒の数値か RFC 3339 の文字列
+    ExpireEntries {
+        #[serde(default = "default_pointer")]
+        pointer: String,
+        time_field: String,
+    },
+    /// age より前に上書きされた版を最も新しい一つにまとめる
+    CollapseVersions,
+}
+
+impl LifecycleAction {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Trash => "trash",
+            Self::ExpireEntries { .. } => "expire_entries",
+            Self::CollapseVersions => "collapse_versions",
+        }
+    }
+}
+
+/// path と data_type の両方を指定した場合は両方に一致するものが対象 フォルダは対象にしない
+#[derive(Debug, Clone, Deserialize)]
+pub struct LifecycleRule {
+    /// 監査ログのファイル名にも使う 英数字と `_` `-` のみ
+    pub name: String,
+    /// ユーザーのディレクトリからのパス そのパスと以下のファイルが対象
+    #[serde(default)]
+    pub path: Option<String>,
+    /// MIME type `image/*` のように種類だけでも指定できる
+    #[serde(default)]
+    pub data_type: Option<String>,
+    /// 秒
+    pub age: u64,
+    pub action: LifecycleAction,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl LifecycleRule {
+    fn valid_name(&self) -> bool {
+        !self.name.is_empty() && self.name.len() <= 64 && self.name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-'))
+    }
+
+    fn matches(&self, meta: &MetaData, prefix: Option<&str>) -> bool {
+        if meta.is_folder() {
+            return false;
+        }
+        if let Some(prefix) = prefix {
+            let under = meta.path == prefix || prefix == "/" || meta.path.starts_with(&format!("{}/", prefix));
+            if !under {
+                return false;
+            }
+        }
+        match self.data_type.as_deref() {
+            Some(pattern) => {
+                let data_type = meta.data_type.split(';').next().unwrap_or("").trim();
+                match pattern.strip_suffix("/*") {
+                    Some(main) => data_type.split('/').next() == Some(main),
+                    None => data_type.eq_ignore_ascii_case(pattern),
+                }
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LifecycleConfig {
+    /// 規則を評価する間隔 (秒) 0 で行わない
+    pub interval: u64,
+    /// 全ての規則を dry-run にする
+    #[serde(default)]
+    pub dry_run: bool,
+    /// 1 秒あたりの操作の数 前面の処理を妨げないよう間隔を空ける 0 で無制限
+    pub max_actions_per_second: u64,
+    /// 1 回の評価で規則ごとに行う操作の数 残りは次の評価に回す 0 で無制限
+    pub max_actions_per_rule: usize,
+    #[serde(default)]
+    pub rules: Vec<LifecycleRule>,
+}
+
+/// 監査ログの一行 (NDJSON)
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleRecord {
+    pub time: i64,
+    pub rule: String,
+    pub action: &'static str,
+    pub owner: String,
+    pub path: String,
+    #[serde_as(as = "Hex")]
+    pub id: u128,
+    pub dry_run: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RuleReport {
+    pub rule: String,
+    pub dry_run: bool,
+    pub matched: u64,
+    pub applied: u64,
+    pub failed: u64,
+    /// max_actions_per_rule で途中でやめた
+    pub limited: bool,
+    pub records: Vec<LifecycleRecord>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LifecycleReport {
+    pub started_time: i64,
+    pub finished_time: i64,
+    pub rules: Vec<RuleReport>,
+}
+
+/// 操作の間隔を空ける
+struct Pace(Option<Interval>);
+
+impl Pace {
+    fn new(per_second: u64) -> Self {
+        match per_second {
+            0 => Self(None),
+            n => {
+                let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / n as f64));
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                Self(Some(interval))
+            }
+        }
+    }
+
+    async fn wait(&mut self) {
+        if let Some(interval) = &mut self.0 {
+            interval.tick().await;
+        }
+    }
+}
+
+/// ミリ秒の数値か RFC 3339 の文字列
+fn entry_time(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => DateTime::parse_from_rfc3339(s).ok().map(|t| t.timestamp_millis()),
+        _ => None,
+    }
+}
+
+impl FileSystem {
+    fn lifecycle_log_path(&self, rule: &str) -> std::path::PathBuf {
+        self.root.join("lifecycle").join(format!("{}.log", rule))
+    }
+
+    /// 全ての規則を一度評価する dry_run の場合は何も変更せずに行う予定の操作を返す
+    pub async fn run_lifecycle_rules(&self, dry_run: bool) -> Result<LifecycleReport, Error> {
+        let config = &self.config.lifecycle;
+        let mut report = LifecycleReport { started_time: Utc::now().timestamp_millis(), ..Default::default() };
+
+        // ゴミ箱の中はパス索引から外れているので除く
+        let mut live = Vec::new();
+        for meta in self.metas.scan().await? {
+            if !meta.is_folder() && self.metas.resolve(&meta.owner, &meta.path).await? == Some(meta.id) {
+                live.push(meta);
+            }
+        }
+
+        let mut pace = Pace::new(config.max_actions_per_second);
+        for rule in &config.rules {
+            if !rule.valid_name() {
+                warn!("lifecycle rule {:?} is skipped: invalid name", rule.name);
+                continue;
+            }
+            let prefix = match rule.path.as_deref().map(path::normalize).transpose() {
+                Ok(prefix) => prefix,
+                Err(e) => {
+                    warn!("lifecycle rule {} is skipped: {}", rule.name, e);
+                    continue;
+                }
+            };
+            let dry_run = dry_run || config.dry_run || rule.dry_run;
+            let cutoff = Utc::now().timestamp_millis() - rule.age.saturating_mul(1000).min(i64::MAX as u64) as i64;
+            let mut rule_report = RuleReport { rule: rule.name.clone(), dry_run, ..Default::default() };
+
+            for meta in live.iter().filter(|m| rule.matches(m, prefix.as_deref())) {
+                rule_report.matched += 1;
+                if config.max_actions_per_rule > 0 && rule_report.applied + rule_report.failed >= config.max_actions_per_rule as u64 {
+                    rule_report.limited = true;
+                    break;
+                }
+                if !dry_run {
+                    pace.wait().await;
+                }
+
+                let (detail, error) = match self.apply_lifecycle(&rule.action, meta, cutoff, dry_run).await {
+                    Ok(None) => continue,
+                    Ok(Some(detail)) => {
+                        rule_report.applied += 1;
+                        (Some(detail), None)
+                    }
+                    Err(e) => {
+                        rule_report.failed += 1;
+                        (None, Some(e.to_string()))
+                    }
+                };
+                let record = LifecycleRecord {
+                    time: Utc::now().timestamp_millis(),
+                    rule: rule.name.clone(),
+                    action: rule.action.kind(),
+                    owner: meta.owner.clone(),
+                    path: meta.path.clone(),
+                    id: meta.id,
+                    dry_run,
+                    detail,
+                    error,
+                };
+                if let Err(e) = self.write_lifecycle_log(&record).await {
+                    error!("Failed to write lifecycle audit log of {}: {}", rule.name, e);
+                }
+                rule_report.records.push(record);
+            }
+            report.rules.push(rule_report);
+        }
+
+        report.finished_time = Utc::now().timestamp_millis();
+        Ok(report)
+    }
+
+    /// 何もすることが無い場合は None
+    async fn apply_lifecycle(&self, action: &LifecycleAction, meta: &MetaData, cutoff: i64, dry_run: bool) -> Result<Option<String>, Error> {
+        match action {
+            LifecycleAction::Trash => {
+                if meta.update_time >= cutoff {
+                    return Ok(None);
+                }
+                if dry_run {
+                    return Ok(Some("would move to trash".to_string()));
+                }
+                // 評価の後に上書きされたものは消さない
+                let removed = self.remove_if(&meta.owner, &meta.path, None, |m| m.id == meta.id && m.update_time < cutoff, |_| {}).await?;
+                Ok(removed.map(|(_, entry)| format!("moved to trash {:032x}", entry.id)))
+            }
+            LifecycleAction::ExpireEntries { pointer, time_field } => self.expire_entries(meta, pointer, time_field, cutoff, dry_run).await,
+            LifecycleAction::CollapseVersions => {
+                let removed = self.collapse_versions(meta.id, cutoff, dry_run).await?;
+                match (removed.is_empty(), dry_run) {
+                    (true, _) => Ok(None),
+                    (false, true) => Ok(Some(format!("would remove versions {:?}", removed))),
+                    (false, false) => Ok(Some(format!("removed versions {:?}", removed))),
+                }
+            }
+        }
+    }
+
+    /// 書き換えた内容は新しい版になる
+    /// 読んだ時から内容が変わっていないことを lock の中で確かめてから書く 変わっていた場合は何もしない
+    async fn expire_entries(&self, meta: &MetaData, pointer: &str, time_field: &str, cutoff: i64, dry_run: bool) -> Result<Option<String>, Error> {
+        let data = match (&meta.inline, &meta.blob) {
+            (Some(data), _) => data.clone(),
+            (None, Some(blob)) => self.read_blob(blob, meta.size).await?,
+            (None, None) => return Ok(None),
+        };
+        let mut json: Value = serde_json::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let entries = match json.pointer_mut(pointer) {
+            Some(Value::Array(entries)) => entries,
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("{} is not an array", pointer))),
+        };
+        let before = entries.len();
+        entries.retain(|entry| entry.get(time_field).and_then(entry_time).is_none_or(|time| time >= cutoff));
+        let removed = before - entries.len();
+        if removed == 0 {
+            return Ok(None);
+        }
+        if dry_run {
+            return Ok(Some(format!("would remove {} entries", removed)));
+        }
+
+        let body = serde_json::to_vec_pretty(&json).map_err(Error::other)?;
+        let (content, size, checksum) = self.save_binary(&meta.owner, futures::stream::iter([Ok::<_, Error>(Bytes::from(body))])).await?;
+        let updated = self.create_file_if(&meta.owner, &meta.path, content, size, checksum, |current| {
+            current.is_some_and(|current| current.id == meta.id && current.checksum == meta.checksum)
+        })
+        .await?;
+        Ok(updated.map(|updated| format!("removed {} entries (version {})", removed, updated.version)))
+    }
+
+    async fn write_lifecycle_log(&self, record: &LifecycleRecord) -> Result<(), Error> {
+        let path = self.lifecycle_log_path(&record.rule);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut line = serde_json::to_vec(record).map_err(Error::other)?;
+        line.push(b'\n');
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+
+    pub async fn run_lifecycle(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // 起動直後は走らせない
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match self.run_lifecycle_rules(false).await {
+                Ok(report) => {
+                    let applied: u64 = report.rules.iter().map(|r| r.applied).sum();
+                    let failed: u64 = report.rules.iter().map(|r| r.failed).sum();
+                    if applied > 0 || failed > 0 {
+                        info!("lifecycle rules applied {} actions, {} failed", applied, failed);
+                    }
+                }
+                Err(e) => error!("Failed to run lifecycle rules: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, sync::Arc, time::Duration};
+
+    use serde_json::json;
+
+    use super::{LifecycleConfig, LifecycleRule};
+    use crate::{
+        file_system::{
+            file_system::FileSystem,
+            meta::MetaData,
+            test_support::{self, Backend},
+        },
+        utils::ruid::RuidGenerator,
+    };
+
+    async fn file_system(dir: &std::path::Path, lifecycle: serde_json::Value) -> FileSystem {
+        let mut config = test_support::config(dir, Backend::Memory);
+        config.lifecycle = serde_json::from_value::<LifecycleConfig>(lifecycle).unwrap();
+        FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap()
+    }
+
+    /// update_time を age 0 の基準より前にする
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    #[test]
+    fn rule_matching() {
+        let rule: LifecycleRule = serde_json::from_value(json!({
+            "name": "images", "path": "/tmp", "data_type": "image/*", "age": 0, "action": { "type": "trash" }
+        }))
+        .unwrap();
+        let file = |path: &str, data_type: &str| MetaData::new(1, "alice", path, data_type.to_string(), 0);
+        assert!(rule.matches(&file("/tmp/a.png", "image/png"), Some("/tmp")));
+        assert!(rule.matches(&file("/tmp/sub/a.jpg", "image/jpeg; q=1"), Some("/tmp")));
+        assert!(!rule.matches(&file("/tmp2/a.png", "image/png"), Some("/tmp")));
+        assert!(!rule.matches(&file("/tmp/a.txt", "text/plain"), Some("/tmp")));
+        assert!(rule.matches(&file("/a.png", "image/png"), Some("/")));
+        assert!(rule.valid_name());
+
+        let rule: LifecycleRule = serde_json::from_value(json!({
+            "name": "../logs", "data_type": "Text/Plain", "age": 0, "action": { "type": "collapse_versions" }
+        }))
+        .unwrap();
+        assert!(rule.matches(&file("/a.txt", "text/plain"), None));
+        assert!(!rule.valid_name());
+    }
+
+    /// dry-run は何も変えずに予定だけを記録する
+    #[tokio::test]
+    async fn trash_and_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = file_system(dir.path(), json!({
+            "interval": 0, "max_actions_per_second": 0, "max_actions_per_rule": 0,
+            "rules": [{ "name": "home_tmp", "path": "/tmp", "age": 0, "action": { "type": "trash" } }]
+        }))
+        .await;
+        test_support::write(&fs, "alice", "/tmp/a.txt", b"alpha").await;
+        test_support::write(&fs, "bob", "/tmp/sub/b.txt", b"beta").await;
+        test_support::write(&fs, "alice", "/keep.txt", b"keep").await;
+        settle().await;
+
+        let report = fs.run_lifecycle_rules(true).await.unwrap();
+        assert_eq!((report.rules[0].matched, report.rules[0].applied, report.rules[0].dry_run), (2, 2, true));
+        assert!(report.rules[0].records.iter().all(|r| r.dry_run && r.detail.as_deref() == Some("would move to trash")));
+        assert!(fs.get("alice", "/tmp/a.txt").await.is_ok());
+        let log = std::fs::read_to_string(dir.path().join("storage/lifecycle/home_tmp.log")).unwrap();
+        assert_eq!(log.lines().count(), 2);
+
+        let report = fs.run_lifecycle_rules(false).await.unwrap();
+        assert_eq!((report.rules[0].applied, report.rules[0].failed), (2, 0));
+        assert_eq!(fs.get("alice", "/tmp/a.txt").await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.get("bob", "/tmp/sub/b.txt").await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.trash_list("bob").await.unwrap().len(), 1);
+        assert!(fs.get("alice", "/keep.txt").await.is_ok());
+
+        // ゴミ箱の中は対象にしない
+        assert_eq!(fs.run_lifecycle_rules(false).await.unwrap().rules[0].matched, 0);
+    }
+
+    #[tokio::test]
+    async fn expire_entries_and_collapse_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = file_system(dir.path(), json!({
+            "interval": 0, "max_actions_per_second": 0, "max_actions_per_rule": 0,
+            "rules": [
+                { "name": "user_log", "path": "/log.json", "age": 1, "action": { "type": "expire_entries", "time_field": "time" } },
+                { "name": "old_versions", "path": "/note.txt", "age": 0, "action": { "type": "collapse_versions" } }
+            ]
+        }))
+        .await;
+        let future = chrono::Utc::now().timestamp_millis() + 60_000;
+        let logs = json!({ "logs": [{ "time": 0 }, { "time": future }, { "time": "2000-01-01T00:00:00Z" }, { "time": "unknown" }] });
+        test_support::write(&fs, "alice", "/log.json", logs.to_string().as_bytes()).await;
+        for text in ["first", "second", "third"] {
+            test_support::write(&fs, "alice", "/note.txt", text.as_bytes()).await;
+        }
+        settle().await;
+
+        let report = fs.run_lifecycle_rules(false).await.unwrap();
+        assert_eq!(report.rules[0].records[0].detail.as_deref(), Some("removed 2 entries (version 2)"));
+        let json: serde_json::Value = serde_json::from_slice(&fs.read_all("alice", "/log.json").await.unwrap()).unwrap();
+        assert_eq!(json["logs"], json!([{ "time": future }, { "time": "unknown" }]));
+        assert_eq!(report.rules[1].records[0].detail.as_deref(), Some("removed versions [1]"));
+        let (_, versions) = fs.versions("alice", "/note.txt").await.unwrap();
+        assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<_>>(), [2]);
+    }
+
+    /// 評価した後に上書きされた内容は書き換えない
+    #[tokio::test]
+    async fn changed_entries_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = file_system(dir.path(), json!({ "interval": 0, "max_actions_per_second": 0, "max_actions_per_rule": 0, "rules": [] })).await;
+        let stale = test_support::write(&fs, "alice", "/log.json", json!({ "logs": [{ "time": 0 }] }).to_string().as_bytes()).await;
+        let current = json!({ "logs": [{ "time": 0 }, { "time": 1 }] }).to_string();
+        test_support::write(&fs, "alice", "/log.json", current.as_bytes()).await;
+
+        assert_eq!(fs.expire_entries(&stale, "/logs", "time", i64::MAX, false).await.unwrap(), None);
+        assert_eq!(fs.read_all("alice", "/log.json").await.unwrap(), current.as_bytes());
+        assert_eq!(fs.get("alice", "/log.json").await.unwrap().version, 2);
+    }
+
+    /// 大きすぎる age は溢れずに何にも当たらない
+    #[tokio::test]
+    async fn huge_age_matches_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = file_system(dir.path(), json!({
+            "interval": 0, "max_actions_per_second": 0, "max_actions_per_rule": 0,
+            "rules": [{ "name": "forever", "age": u64::MAX, "action": { "type": "trash" } }]
+        }))
+        .await;
+        test_support::write(&fs, "alice", "/a.txt", b"alpha").await;
+
+        let report = fs.run_lifecycle_rules(false).await.unwrap();
+        assert_eq!((report.rules[0].matched, report.rules[0].applied, report.rules[0].failed), (1, 0, 0));
+        assert!(fs.get("alice", "/a.txt").await.is_ok());
+    }
+
+    /// 規則ごとの上限を超えた分は次の評価に回し、操作の間隔を空ける
+    #[tokio::test]
+    async fn actions_are_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = file_system(dir.path(), json!({
+            "interval": 0, "max_actions_per_second": 20, "max_actions_per_rule": 2,
+            "rules": [{ "name": "all", "age": 0, "action": { "type": "trash" } }]
+        }))
+        .await;
+        for name in ["a", "b", "c"] {
+            test_support::write(&fs, "alice", &format!("/{}.txt", name), name.as_bytes()).await;
+        }
+        settle().await;
+
+        let started = std::time::Instant::now();
+        let report = fs.run_lifecycle_rules(false).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(45));
+        assert_eq!((report.rules[0].applied, report.rules[0].limited), (2, true));
+        let report = fs.run_lifecycle_rules(false).await.unwrap();
+        assert_eq!((report.rules[0].applied, report.rules[0].limited), (1, false));
+        assert_eq!(fs.trash_list("alice").await.unwrap().len(), 3);
+    }
+}
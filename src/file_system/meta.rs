@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::utils::custom_serializers_adapters::{Base64, Hex};
+
+use super::{perm::Perm, preview::Preview};
+
+pub const FOLDER_TYPE: &str = "application/folder";
+
+/// /edit で変更できないフィールド
+/// name はパスと一致させるため移動/リネームで変更する
+pub const IMMUTABLE_FIELDS: &[&str] = &[
+    "name", "path", "id", "owner", "size", "links", "blob", "inline", "previews", "checksum", "log",
+    "viws", "reaction", "reaction-count", "create_time", "update_time", "version",
+];
+
+/// share の権限がある場合のみ /edit で変更できるフィールド
+pub const PERM_FIELD: &str = "perm";
+
+/// ファイルまたはフォルダのメタデータ
+/// docment/server/system/db/db_format.md を参照
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetaData {
+    pub name: String,
+    pub path: String, // can't edit
+    #[serde_as(as = "Hex")]
+    pub id: u128, // can't edit
+    pub owner: String,
+    pub data_type: String, // MIME type | application/folder
+    pub size: u64,
+    #[serde_as(as = "Vec<Hex>")]
+    pub links: Vec<u128>,
+    pub about: String,
+    pub blob: Option<String>, // BlobStore のキー
+    /// inline_threshold 以下の小さいファイルはメタデータに本体を持つ (blob は None)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<Base64>")]
+    pub inline: Option<Vec<u8>>,
+    /// 本体から作った縮小画像や抜粋 バイナリは BlobStore に置く
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previews: Vec<Preview>,
+    pub checksum: Option<String>, // sha256 hex
+    #[serde_as(as = "HashMap<Hex, _>")]
+    pub log: HashMap<u128, String>,
+    pub event: serde_json::Value,
+    pub viws: u64, // can't edit
+    #[serde_as(as = "HashMap<Hex, Vec<Hex>>")]
+    pub reaction: HashMap<u128, Vec<u128>>, // can't edit
+    #[serde(rename = "reaction-count")]
+    #[serde_as(as = "HashMap<Hex, _>")]
+    pub reaction_count: HashMap<u128, u64>, // can't edit
+    /// 操作ごとの許可と拒否 share の権限がある場合のみ変更できる
+    #[serde(default, skip_serializing_if = "Perm::is_empty")]
+    pub perm: Perm,
+    pub create_time: i64,
+    pub update_time: i64,
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl MetaData {
+    pub fn new(id: u128, owner: &str, path: &str, data_type: String, time: i64) -> Self {
+        Self {
+            name: super::path::name(path).to_string(),
+            path: path.to_string(),
+            id,
+            owner: owner.to_string(),
+            data_type,
+            size: 0,
+            links: Vec::new(),
+            about: String::new(),
+            blob: None,
+            inline: None,
+            previews: Vec::new(),
+            checksum: None,
+            log: HashMap::new(),
+            event: serde_json::Value::Null,
+            viws: 0,
+            reaction: HashMap::new(),
+            reaction_count: HashMap::new(),
+            perm: Perm::default(),
+            create_time: time,
+            update_time: time,
+            version: 1,
+        }
+    }
+
+    pub fn is_folder(&self) -> bool {
+        self.data_type == FOLDER_TYPE
+    }
+
+    /// 参照している全てのバイナリ (本体とプレビュー)
+    pub fn blobs(&self) -> impl Iterator<Item = &String> {
+        self.blob.iter().chain(self.previews.iter().map(|p| &p.blob))
+    }
+
+    /// メタデータの ETag (クォートなし)
+    pub fn etag(&self) -> String {
+        format!("{:032x}-{}", self.id, self.version)
+    }
+}
@@ -0,0 +1,30 @@
+pub mod account;
+pub mod archive;
+pub mod blob;
+pub mod config;
+#[cfg(test)]
+mod conformance;
+pub mod crypto;
+pub mod edit;
+pub mod explain;
+pub mod extract;
+#[allow(clippy::module_inception)]
+pub mod file_system;
+pub mod fsck;
+pub mod group;
+pub mod lifecycle;
+pub mod meta;
+pub mod path;
+pub mod perm;
+pub mod preview;
+pub mod provision;
+pub mod quota;
+pub mod resolve;
+pub mod share_link;
+pub mod store;
+#[cfg(test)]
+pub mod test_support;
+pub mod transfer;
+pub mod trash;
+pub mod upload;
+pub mod version;
@@ -0,0 +1,160 @@
+use std::io::{Error, ErrorKind};
+
+use unicode_normalization::UnicodeNormalization;
+
+/// 正規化後のパスの最大バイト数
+pub const MAX_PATH_LENGTH: usize = 1024;
+/// ルートからの最大の深さ
+pub const MAX_DEPTH: usize = 32;
+/// 一つの名前の最大バイト数
+pub const MAX_NAME_LENGTH: usize = 255;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, message.to_string())
+}
+
+/// パスを `/a/b/c` の形に正規化する
+/// Unicode は NFC に揃え、空要素と `.` は取り除く
+/// `..`、NUL を含む制御文字、バックスラッシュ、長すぎるパスは拒否する
+pub fn normalize(path: &str) -> Result<String, Error> {
+    let path: String = path.nfc().collect();
+    if path.chars().any(|c| c.is_control() || c == '\\') {
+        return Err(invalid("path contains forbidden characters"));
+    }
+
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => return Err(invalid("parent directory reference is not allowed")),
+            p if p.len() > MAX_NAME_LENGTH => return Err(invalid("name is too long")),
+            p => parts.push(p),
+        }
+    }
+    if parts.len() > MAX_DEPTH {
+        return Err(invalid("path is too deep"));
+    }
+
+    let normalized = format!("/{}", parts.join("/"));
+    if normalized.len() > MAX_PATH_LENGTH {
+        return Err(invalid("path is too long"));
+    }
+    Ok(normalized)
+}
+
+/// 一つの名前 (ファイル名やリネーム先) を検証して NFC にする
+pub fn normalize_name(name: &str) -> Result<String, Error> {
+    if name.contains('/') {
+        return Err(invalid("name can't contain '/'"));
+    }
+    let normalized = normalize(name)?;
+    if normalized == "/" {
+        return Err(invalid("name is empty"));
+    }
+    Ok(normalized[1..].to_string())
+}
+
+/// 親フォルダのパス ルートの場合は None
+pub fn parent(path: &str) -> Option<&str> {
+    if path == "/" {
+        return None;
+    }
+    match path.rfind('/') {
+        Some(0) => Some("/"),
+        Some(i) => Some(&path[..i]),
+        None => None,
+    }
+}
+
+/// パスの最後の要素
+pub fn name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or("")
+}
+
+pub fn join(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{join, name, normalize, normalize_name, parent, MAX_DEPTH, MAX_NAME_LENGTH};
+
+    #[test]
+    fn normalized_forms() {
+        assert_eq!(normalize("a//b/./c/").unwrap(), "/a/b/c");
+        assert_eq!(normalize("").unwrap(), "/");
+        assert_eq!(normalize("/e\u{301}").unwrap(), "/\u{e9}");
+        assert_eq!(normalize_name("e\u{301}.txt").unwrap(), "\u{e9}.txt");
+    }
+
+    #[test]
+    fn rejected_paths() {
+        let deep = "/a".repeat(MAX_DEPTH + 1);
+        let long = format!("/{}", "a".repeat(MAX_NAME_LENGTH + 1));
+        for path in ["/a/../b", "/a\\b", "/a\0b", "/a\nb", deep.as_str(), long.as_str()] {
+            assert!(normalize(path).is_err(), "{:?}", path);
+        }
+        for name in ["a/b", "", ".", ".."] {
+            assert!(normalize_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn parts() {
+        assert_eq!((parent("/"), parent("/a"), parent("/a/b")), (None, Some("/"), Some("/a")));
+        assert_eq!((name("/a/b.txt"), name("/")), ("b.txt", ""));
+        assert_eq!((join("/", "a"), join("/a", "b")), ("/a".to_string(), "/a/b".to_string()));
+    }
+
+    /// 走査に使われやすい断片と任意の文字列をつないだパス
+    fn any_path() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            Just("/".to_string()),
+            Just("..".to_string()),
+            Just(".".to_string()),
+            Just("\\".to_string()),
+            Just("\0".to_string()),
+            Just("\n".to_string()),
+            Just("%2e%2e".to_string()),
+            Just("e\u{301}".to_string()),
+            "[a-z]{1,4}",
+            any::<String>(),
+        ];
+        prop::collection::vec(piece, 0..12).prop_map(|pieces| pieces.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn output_is_clean(path in any_path()) {
+            if let Ok(normalized) = normalize(&path) {
+                prop_assert!(normalized.starts_with('/'));
+                prop_assert!(!normalized.chars().any(|c| c.is_control() || c == '\\'));
+                prop_assert!(normalized.split('/').skip(1).all(|part| part != ".." && part != "."));
+                prop_assert!(normalized == "/" || normalized.split('/').skip(1).all(|part| !part.is_empty()));
+                prop_assert_eq!(normalize(&normalized).unwrap(), normalized);
+            }
+        }
+
+        /// 前に付けたフォルダの外を指すことはない
+        #[test]
+        fn never_escapes_prefix(prefix in "(/[a-z]{1,4}){0,3}", rest in any_path()) {
+            let base = normalize(&prefix).unwrap();
+            if let Ok(normalized) = normalize(&format!("{}/{}", base, rest)) {
+                prop_assert!(base == "/" || normalized == base || normalized.starts_with(&format!("{}/", base)), "{} -> {}", rest, normalized);
+            }
+        }
+
+        #[test]
+        fn name_is_one_part(name in any_path()) {
+            if let Ok(normalized) = normalize_name(&name) {
+                prop_assert!(!normalized.is_empty() && !normalized.contains('/') && normalized != "..");
+            }
+        }
+    }
+}
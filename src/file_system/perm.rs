@@ -0,0 +1,667 @@
+use std::{borrow::Cow, collections::{BTreeSet, HashSet, VecDeque}, io::{Error, ErrorKind}};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{serde_as, DeserializeAs, SerializeAs};
+
+use crate::utils::{custom_serializers_adapters::Hex, ruid};
+
+use super::{file_system::FileSystem, meta::MetaData, path, share_link::LinkGrant};
+
+/// 権限を判定する操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Create,
+    Read,
+    Edit,
+    Delete,
+    Reaction,
+    Share,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 6] = [Self::Create, Self::Read, Self::Edit, Self::Delete, Self::Reaction, Self::Share];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Read => "read",
+            Self::Edit => "edit",
+            Self::Delete => "delete",
+            Self::Reaction => "reaction",
+            Self::Share => "share",
+        }
+    }
+}
+
+/// 決まった意味を持つ相手 データベースを引かずに判定する
+/// RUID はプレフィックスのみで残りは 0
+pub const EVERYONE: u128 = (ruid::prefix::EVERYONE_PERMISSION as u128) << 112;
+/// ログインしているユーザー
+pub const AUTHENTICATED: u128 = (ruid::prefix::AUTHENTICATED_PERMISSION as u128) << 112;
+/// ログインしていない訪問者
+pub const GUEST: u128 = (ruid::prefix::GUEST_PERMISSION as u128) << 112;
+/// ファイルの所有者
+pub const OWNER: u128 = (ruid::prefix::OWNER_PERMISSION as u128) << 112;
+/// このサーバーで作られたユーザー
+pub const SAME_NODE: u128 = (ruid::prefix::NODE_PERMISSION as u128) << 112;
+
+pub const WELL_KNOWN: &[(&str, u128)] = &[
+    ("everyone", EVERYONE),
+    ("authenticated", AUTHENTICATED),
+    ("guest", GUEST),
+    ("owner", OWNER),
+    ("same_node", SAME_NODE),
+];
+
+pub fn well_known_name(id: u128) -> Option<&'static str> {
+    WELL_KNOWN.iter().find(|(_, known)| *known == id).map(|(name, _)| *name)
+}
+
+/// 決まった相手は名前、それ以外は 16進数の RUID として扱う
+pub struct PrincipalId;
+
+impl SerializeAs<u128> for PrincipalId {
+    fn serialize_as<S>(source: &u128, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match well_known_name(*source) {
+            Some(name) => serializer.serialize_str(name),
+            None => serializer.serialize_str(&format!("{:032x}", source)),
+        }
+    }
+}
+
+impl<'de> DeserializeAs<'de, u128> for PrincipalId {
+    fn deserialize_as<D>(deserializer: D) -> Result<u128, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        match WELL_KNOWN.iter().find(|(name, _)| *name == s) {
+            Some((_, id)) => Ok(*id),
+            None => u128::from_str_radix(s.trim_start_matches("0x"), 16).map_err(D::Error::custom),
+        }
+    }
+}
+
+/// 許可と拒否の RUID (ユーザー、権限グループまたは決まった相手)
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccessControl {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    #[serde_as(as = "BTreeSet<PrincipalId>")]
+    pub allow: BTreeSet<u128>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    #[serde_as(as = "BTreeSet<PrincipalId>")]
+    pub deny: BTreeSet<u128>,
+}
+
+impl AccessControl {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+fn default_inherit() -> bool {
+    true
+}
+
+fn is_inherit(inherit: &bool) -> bool {
+    *inherit
+}
+
+/// ファイルまたはフォルダの権限
+/// 書かれていない操作は親フォルダの権限に従う
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Perm {
+    #[serde(default, skip_serializing_if = "AccessControl::is_empty")]
+    pub create: AccessControl,
+    #[serde(default, skip_serializing_if = "AccessControl::is_empty")]
+    pub read: AccessControl,
+    #[serde(default, skip_serializing_if = "AccessControl::is_empty")]
+    pub edit: AccessControl,
+    #[serde(default, skip_serializing_if = "AccessControl::is_empty")]
+    pub delete: AccessControl,
+    #[serde(default, skip_serializing_if = "AccessControl::is_empty")]
+    pub reaction: AccessControl,
+    #[serde(default, skip_serializing_if = "AccessControl::is_empty")]
+    pub share: AccessControl,
+    /// false の場合は親フォルダの権限を引き継がない
+    #[serde(default = "default_inherit", skip_serializing_if = "is_inherit")]
+    pub inherit: bool,
+}
+
+impl Default for Perm {
+    fn default() -> Self {
+        Self {
+            create: AccessControl::default(),
+            read: AccessControl::default(),
+            edit: AccessControl::default(),
+            delete: AccessControl::default(),
+            reaction: AccessControl::default(),
+            share: AccessControl::default(),
+            inherit: true,
+        }
+    }
+}
+
+impl Perm {
+    pub fn is_empty(&self) -> bool {
+        self.inherit && Operation::ALL.iter().all(|op| self.get(*op).is_empty())
+    }
+
+    pub fn get(&self, op: Operation) -> &AccessControl {
+        match op {
+            Operation::Create => &self.create,
+            Operation::Read => &self.read,
+            Operation::Edit => &self.edit,
+            Operation::Delete => &self.delete,
+            Operation::Reaction => &self.reaction,
+            Operation::Share => &self.share,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PermConfig {
+    /// 全てのユーザーの権限の判定の説明 (/explain) を見られるユーザー
+    pub admins: BTreeSet<String>,
+}
+
+impl PermConfig {
+    pub fn is_admin(&self, user: Option<&str>) -> bool {
+        user.is_some_and(|user| self.admins.contains(user))
+    }
+}
+
+/// 判定に使われた規則
+#[serde_as]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Matched {
+    /// 対象から何階層上か (対象自身は 0)
+    pub level: usize,
+    #[serde_as(as = "PrincipalId")]
+    pub id: u128,
+    pub deny: bool,
+}
+
+/// chain は対象から親に向かう順の権限
+/// inherit が false の階層より上は見ない
+/// 拒否がどこかにあれば最も近い拒否、なければ最も近い許可を返す どちらもなければ None (拒否)
+pub fn decide<'a, I>(chain: I, ids: &HashSet<u128>, op: Operation) -> Option<Matched>
+where
+    I: IntoIterator<Item = &'a Perm>,
+{
+    let mut allowed = None;
+    for (level, perm) in chain.into_iter().enumerate() {
+        let control = perm.get(op);
+        if let Some(id) = control.deny.iter().find(|id| ids.contains(id)) {
+            return Some(Matched { level, id: *id, deny: true });
+        }
+        if allowed.is_none() {
+            allowed = control.allow.iter()
+                .find(|id| ids.contains(id))
+                .map(|id| Matched { level, id: *id, deny: false });
+        }
+        if !perm.inherit {
+            break;
+        }
+    }
+    allowed
+}
+
+/// 権限グループの RUID (0x22**) 決まった相手は含まない
+pub fn is_group(id: u128) -> bool {
+    ruid::prefix_of(id) & 0xFF00 == ruid::prefix::PERMISSION_ID && well_known_name(id).is_none()
+}
+
+/// 権限グループ members にはユーザーまたは他のグループの RUID を入れる
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PermGroup {
+    #[serde_as(as = "Hex")]
+    pub id: u128,
+    pub owner: String,
+    pub name: String,
+    #[serde_as(as = "Vec<Hex>")]
+    pub members: Vec<u128>,
+    pub create_time: i64,
+}
+
+/// 判定する相手
+/// ids はユーザーの RUID と、直接または間接に属する権限グループ、当てはまる決まった相手 (owner 以外)
+/// link は共有リンクで許された範囲 perm に関係なく許可する
+#[derive(Debug, Clone, Default)]
+pub struct Principal {
+    pub user: Option<String>,
+    pub ids: HashSet<u128>,
+    pub link: Option<LinkGrant>,
+}
+
+impl Principal {
+    pub fn is_owner(&self, owner: &str) -> bool {
+        self.user.as_deref() == Some(owner)
+    }
+
+    /// 所有者の場合は owner を足し、owner 以外の決まった相手を除いた ids
+    /// `read: deny [everyone]` のような非公開の設定で所有者自身が締め出されないようにする
+    pub fn ids_for(&self, owner: &str) -> Cow<'_, HashSet<u128>> {
+        match self.is_owner(owner) {
+            true => {
+                let mut ids: HashSet<u128> = self.ids.iter().copied().filter(|id| well_known_name(*id).is_none()).collect();
+                ids.insert(OWNER);
+                Cow::Owned(ids)
+            }
+            false => Cow::Borrowed(&self.ids),
+        }
+    }
+
+    /// 所有者は拒否に当たらなければ許可、それ以外は許可に当たった場合のみ許可
+    /// 所有者に当たる拒否は owner、本人の RUID、属する権限グループのみ
+    /// 権限を直せなくならないように、所有者の share は拒否しない
+    pub fn allowed<'a, I>(&self, owner: &str, chain: I, op: Operation) -> bool
+    where
+        I: IntoIterator<Item = &'a Perm>,
+    {
+        let is_owner = self.is_owner(owner);
+        if is_owner && op == Operation::Share {
+            return true;
+        }
+        match decide(chain, &self.ids_for(owner), op) {
+            Some(m) => !m.deny,
+            None => is_owner,
+        }
+    }
+
+    /// 共有リンクの範囲か、perm で許可されている
+    pub fn permits<'a, I>(&self, owner: &str, path: &str, chain: I, op: Operation) -> bool
+    where
+        I: IntoIterator<Item = &'a Perm>,
+    {
+        self.link.as_ref().is_some_and(|link| link.covers(owner, path, op)) || self.allowed(owner, chain, op)
+    }
+}
+
+impl FileSystem {
+    /// ユーザー名から判定する相手を作る None の場合は訪問者
+    pub async fn principal(&self, user: Option<&str>) -> Result<Principal, Error> {
+        let mut principal = Principal { user: user.map(|u| u.to_string()), ..Default::default() };
+        match user {
+            Some(user) => {
+                if let Some(ruid) = self.metas.ruid_by_user(user).await? {
+                    principal.ids = self.cached_groups(ruid).await?;
+                    principal.ids.insert(ruid);
+                    if ruid::server_id_of(ruid) == self.ruid.server_id() {
+                        principal.ids.insert(SAME_NODE);
+                    }
+                }
+                principal.ids.insert(AUTHENTICATED);
+            }
+            None => {
+                principal.ids.insert(GUEST);
+            }
+        }
+        principal.ids.insert(EVERYONE);
+        Ok(principal)
+    }
+
+    /// id が直接または入れ子で属する権限グループ 循環していても止まる
+    pub async fn effective_groups(&self, id: u128) -> Result<HashSet<u128>, Error> {
+        let mut groups = HashSet::new();
+        let mut queue = VecDeque::from([id]);
+        while let Some(member) = queue.pop_front() {
+            for group in self.metas.groups_with_member(member).await? {
+                if group.id != id && groups.insert(group.id) {
+                    queue.push_back(group.id);
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    /// path から親に向かってパスと権限を集める 存在しない部分は飛ばす
+    /// inherit が false の階層で止める
+    pub(super) async fn perm_levels(&self, user: &str, path: &str) -> Result<Vec<(String, Perm)>, Error> {
+        let mut levels = Vec::new();
+        let mut current = Some(path);
+        while let Some(p) = current {
+            if let Some(meta) = self.lookup(user, p).await? {
+                let inherit = meta.perm.inherit;
+                levels.push((meta.path, meta.perm));
+                if !inherit {
+                    break;
+                }
+            }
+            current = path::parent(p);
+        }
+        Ok(levels)
+    }
+
+    pub(super) async fn perm_chain(&self, user: &str, path: &str) -> Result<Vec<Perm>, Error> {
+        Ok(self.perm_levels(user, path).await?.into_iter().map(|(_, perm)| perm).collect())
+    }
+
+    /// フォルダ path の子要素のうち principal が op をできるものだけ残す
+    pub async fn filter_children(&self, user: &str, path: &str, principal: &Principal, children: Vec<MetaData>, op: Operation) -> Result<Vec<MetaData>, Error> {
+        let chain = self.perm_chain(user, path).await?;
+        Ok(children.into_iter()
+            .filter(|child| principal.permits(user, &child.path, std::iter::once(&child.perm).chain(&chain), op))
+            .collect())
+    }
+
+    /// 既にあるものへの create は edit として判定する
+    pub(super) async fn effective_operation(&self, user: &str, path: &str, op: Operation) -> Result<Operation, Error> {
+        match op {
+            Operation::Create if self.metas.resolve(user, path).await?.is_some() => Ok(Operation::Edit),
+            op => Ok(op),
+        }
+    }
+
+    /// principal が path に op をできるか確かめる
+    pub async fn authorize(&self, user: &str, path: &str, principal: &Principal, op: Operation) -> Result<(), Error> {
+        let _guard = self.lock.read().await;
+        let op = self.effective_operation(user, path, op).await?;
+        let chain = self.perm_chain(user, path).await?;
+        if principal.permits(user, path, &chain, op) {
+            return Ok(());
+        }
+        Err(Error::new(ErrorKind::PermissionDenied, format!("{} is not allowed", op.as_str())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeSet, HashSet, VecDeque},
+        io::ErrorKind,
+    };
+
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use crate::file_system::{file_system::FileSystem, test_support::{self, Backend, BACKENDS}};
+
+    use super::{decide, is_group, AccessControl, Matched, Operation, Perm, PermGroup, Principal, AUTHENTICATED, EVERYONE, GUEST, OWNER, SAME_NODE};
+
+    const ALICE: u128 = (0x2101 << 112) | 1;
+    const BOB: u128 = (0x2101 << 112) | 2;
+
+    fn group(n: u128) -> u128 {
+        (0x2200 << 112) | n
+    }
+
+    async fn can_read(fs: &FileSystem, principal: &Principal, path: &str) -> bool {
+        fs.authorize("alice", path, principal, Operation::Read).await.is_ok()
+    }
+
+    fn perm(read_allow: &[u128], read_deny: &[u128], inherit: bool) -> Perm {
+        let mut perm = Perm { inherit, ..Default::default() };
+        perm.read.allow.extend(read_allow);
+        perm.read.deny.extend(read_deny);
+        perm
+    }
+
+    /// 拒否はどの階層でも許可より強く、inherit が false の階層より上は見ない
+    #[test]
+    fn deny_wins_and_inherit_stops() {
+        let ids: HashSet<u128> = [BOB, group(1)].into();
+        let read = |chain: &[Perm]| decide(chain, &ids, Operation::Read);
+
+        assert_eq!(read(&[]), None);
+        assert_eq!(read(&[perm(&[], &[], true), perm(&[BOB], &[], true)]), Some(Matched { level: 1, id: BOB, deny: false }));
+        assert_eq!(read(&[perm(&[BOB], &[], true), perm(&[], &[group(1)], true)]), Some(Matched { level: 1, id: group(1), deny: true }));
+        assert_eq!(read(&[perm(&[], &[BOB], true), perm(&[], &[group(1)], true)]), Some(Matched { level: 0, id: BOB, deny: true }));
+        assert_eq!(read(&[perm(&[BOB], &[], false), perm(&[], &[BOB], true)]), Some(Matched { level: 0, id: BOB, deny: false }));
+        assert_eq!(read(&[perm(&[], &[], false), perm(&[BOB], &[], true)]), None);
+        assert_eq!(decide(&[perm(&[BOB], &[], true)], &ids, Operation::Edit), None);
+        assert!(!Principal::default().allowed("alice", &[perm(&[BOB], &[], true)], Operation::Read));
+    }
+
+    /// 入れ子のグループは循環していても全て集める
+    #[tokio::test]
+    async fn nested_groups() {
+        for backend in BACKENDS {
+            let (_dir, fs) = test_support::file_system(backend).await;
+            fs.metas.bind_user(BOB, "bob").await.unwrap();
+            let put = |id: u128, members: Vec<u128>| PermGroup { id, owner: "alice".to_string(), name: format!("g{}", id as u8), members, create_time: 0 };
+            fs.metas.put_group(&put(group(1), vec![BOB, group(3)])).await.unwrap();
+            fs.metas.put_group(&put(group(2), vec![group(1)])).await.unwrap();
+            fs.metas.put_group(&put(group(3), vec![group(2)])).await.unwrap();
+            fs.metas.put_group(&put(group(4), vec![ALICE])).await.unwrap();
+
+            let principal = fs.principal(Some("bob")).await.unwrap();
+            assert_eq!(principal.ids, [BOB, group(1), group(2), group(3), AUTHENTICATED, EVERYONE].into(), "{:?}", backend);
+            assert_eq!(fs.metas.get_group(group(2)).await.unwrap().unwrap().members, [group(1)]);
+
+            fs.metas.delete_group(group(1)).await.unwrap();
+            assert!(fs.effective_groups(BOB).await.unwrap().is_empty());
+            assert_eq!(fs.principal(None).await.unwrap().ids, [GUEST, EVERYONE].into());
+        }
+    }
+
+    #[tokio::test]
+    async fn authorize_and_filter() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        fs.metas.bind_user(BOB, "bob").await.unwrap();
+        test_support::write(&fs, "alice", "/docs/a.txt", b"alpha").await;
+        test_support::write(&fs, "alice", "/docs/private/b.txt", b"beta").await;
+        test_support::write(&fs, "alice", "/docs/closed/c.txt", b"gamma").await;
+        let mut docs = fs.get("alice", "/docs").await.unwrap();
+        docs.perm = perm(&[BOB], &[], true);
+        docs.perm.create.allow.insert(BOB);
+        fs.metas.put(&docs).await.unwrap();
+        let mut private = fs.get("alice", "/docs/private").await.unwrap();
+        private.perm = perm(&[], &[BOB], true);
+        fs.metas.put(&private).await.unwrap();
+        let mut closed = fs.get("alice", "/docs/closed").await.unwrap();
+        closed.perm = perm(&[], &[], false);
+        fs.metas.put(&closed).await.unwrap();
+
+        let bob = fs.principal(Some("bob")).await.unwrap();
+        let denied = |r: Result<(), std::io::Error>| r.unwrap_err().kind() == ErrorKind::PermissionDenied;
+        assert!(fs.authorize("alice", "/docs/a.txt", &bob, Operation::Read).await.is_ok());
+        assert!(denied(fs.authorize("alice", "/docs/private/b.txt", &bob, Operation::Read).await));
+        assert!(denied(fs.authorize("alice", "/docs/closed/c.txt", &bob, Operation::Read).await));
+        assert!(denied(fs.authorize("alice", "/docs/a.txt", &bob, Operation::Delete).await));
+        // 既にあるものへの create は edit として判定する
+        assert!(fs.authorize("alice", "/docs/new.txt", &bob, Operation::Create).await.is_ok());
+        assert!(denied(fs.authorize("alice", "/docs/a.txt", &bob, Operation::Create).await));
+        // 所有者は自分に当たる拒否が無ければできる
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        assert!(fs.authorize("alice", "/docs/private/b.txt", &alice, Operation::Delete).await.is_ok());
+
+        let (_, children) = fs.list("alice", "/docs").await.unwrap();
+        let visible = fs.filter_children("alice", "/docs", &bob, children, Operation::Read).await.unwrap();
+        assert_eq!(visible.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), ["a.txt"]);
+    }
+
+    /// 決まった相手は名前で書き、それ以外は 16 進数
+    #[test]
+    fn well_known_names() {
+        let control: AccessControl = serde_json::from_value(json!({ "allow": ["everyone", format!("{:032x}", group(1))], "deny": ["guest"] })).unwrap();
+        assert_eq!(control.allow, [EVERYONE, group(1)].into());
+        assert_eq!(control.deny, [GUEST].into());
+        assert_eq!(serde_json::to_value(&control).unwrap(), json!({ "allow": [format!("{:032x}", group(1)), "everyone"], "deny": ["guest"] }));
+        assert!(serde_json::from_value::<AccessControl>(json!({ "allow": ["nobody"] })).is_err());
+        assert!(is_group(group(1)));
+        assert!(!is_group(EVERYONE) && !is_group(OWNER) && !is_group(BOB));
+    }
+
+    /// 所有者も拒否には従うが、権限を直せるよう share は常にできる
+    #[test]
+    fn owner_rules() {
+        let alice = Principal { user: Some("alice".to_string()), ids: [ALICE, EVERYONE].into(), link: None };
+        let bob = Principal { user: Some("bob".to_string()), ids: [BOB, EVERYONE].into(), link: None };
+        let mut owner_only = Perm::default();
+        owner_only.read.allow.insert(OWNER);
+        assert!(alice.allowed("alice", &[Perm::default()], Operation::Read));
+        assert!(alice.allowed("alice", &[owner_only.clone()], Operation::Read));
+        assert!(!bob.allowed("alice", &[owner_only], Operation::Read));
+
+        let mut denied = perm(&[], &[ALICE], true);
+        denied.share.deny.insert(ALICE);
+        assert!(!alice.allowed("alice", &[denied.clone()], Operation::Read));
+        assert!(alice.allowed("alice", &[denied.clone()], Operation::Share));
+        assert!(!bob.allowed("alice", &[denied], Operation::Share));
+    }
+
+    /// ログインしているか、同じサーバーで作られたユーザーか
+    #[tokio::test]
+    async fn well_known_principals() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let local = fs.ruid.generate(crate::utils::ruid::prefix::USER_ID);
+        fs.metas.bind_user(local, "carol").await.unwrap();
+        fs.metas.bind_user(BOB, "bob").await.unwrap();
+        test_support::write(&fs, "alice", "/members.txt", b"members").await;
+        test_support::write(&fs, "alice", "/public.txt", b"public").await;
+        test_support::write(&fs, "alice", "/local.txt", b"local").await;
+        let rules = [
+            ("/members.txt", perm(&[AUTHENTICATED], &[], true)),
+            ("/public.txt", perm(&[EVERYONE], &[AUTHENTICATED], true)),
+            ("/local.txt", perm(&[SAME_NODE], &[], true)),
+        ];
+        for (path, perm) in rules {
+            let mut meta = fs.get("alice", path).await.unwrap();
+            meta.perm = perm;
+            fs.metas.put(&meta).await.unwrap();
+        }
+
+        let guest = fs.principal(None).await.unwrap();
+        let bob = fs.principal(Some("bob")).await.unwrap();
+        let carol = fs.principal(Some("carol")).await.unwrap();
+        assert!(!can_read(&fs, &guest, "/members.txt").await && can_read(&fs, &bob, "/members.txt").await);
+        assert!(can_read(&fs, &guest, "/public.txt").await && !can_read(&fs, &bob, "/public.txt").await);
+        assert!(!can_read(&fs, &bob, "/local.txt").await && can_read(&fs, &carol, "/local.txt").await);
+    }
+
+    fn user(name: &str, ruid: u128) -> Principal {
+        Principal { user: Some(name.to_string()), ids: HashSet::from([ruid, AUTHENTICATED, EVERYONE]), link: None }
+    }
+
+    fn guest() -> Principal {
+        Principal { user: None, ids: HashSet::from([GUEST, EVERYONE]), link: None }
+    }
+
+    fn perm_for(op: Operation, allow: &[u128], deny: &[u128]) -> Perm {
+        let control = AccessControl { allow: allow.iter().copied().collect(), deny: deny.iter().copied().collect() };
+        let mut perm = Perm::default();
+        match op {
+            Operation::Create => perm.create = control,
+            Operation::Read => perm.read = control,
+            Operation::Edit => perm.edit = control,
+            Operation::Delete => perm.delete = control,
+            Operation::Reaction => perm.reaction = control,
+            Operation::Share => perm.share = control,
+        }
+        perm
+    }
+
+    /// everyone への拒否で非公開にしても所有者は締め出されない
+    #[test]
+    fn everyone_deny_keeps_owner() {
+        let chain = [perm_for(Operation::Read, &[], &[EVERYONE])];
+        assert!(user("alice", ALICE).allowed("alice", &chain, Operation::Read));
+        assert!(!user("bob", BOB).allowed("alice", &chain, Operation::Read));
+        assert!(!guest().allowed("alice", &chain, Operation::Read));
+
+        let chain = [perm_for(Operation::Edit, &[], &[AUTHENTICATED])];
+        assert!(user("alice", ALICE).allowed("alice", &chain, Operation::Edit));
+    }
+
+    /// owner、本人、属するグループへの拒否は所有者にも当たる share は除く
+    #[test]
+    fn owner_deny_applies() {
+        for id in [OWNER, ALICE] {
+            let chain = [perm_for(Operation::Delete, &[], &[id])];
+            assert!(!user("alice", ALICE).allowed("alice", &chain, Operation::Delete));
+        }
+        let chain = [perm_for(Operation::Share, &[], &[OWNER])];
+        assert!(user("alice", ALICE).allowed("alice", &chain, Operation::Share));
+    }
+
+    #[test]
+    fn guest_and_authenticated() {
+        let chain = [perm_for(Operation::Read, &[GUEST], &[])];
+        assert!(guest().allowed("alice", &chain, Operation::Read));
+        assert!(!user("bob", BOB).allowed("alice", &chain, Operation::Read));
+
+        let chain = [perm_for(Operation::Read, &[EVERYONE], &[AUTHENTICATED])];
+        assert!(guest().allowed("alice", &chain, Operation::Read));
+        assert!(!user("bob", BOB).allowed("alice", &chain, Operation::Read));
+        assert!(user("alice", ALICE).allowed("alice", &chain, Operation::Read));
+    }
+
+    /// 小さな相手の集合から選んだ許可と拒否
+    fn any_perm() -> impl Strategy<Value = Perm> {
+        (prop::collection::btree_set(1u128..6, 0..3), prop::collection::btree_set(1u128..6, 0..2), any::<bool>())
+            .prop_map(|(allow, deny, inherit)| Perm { read: AccessControl { allow, deny }, inherit, ..Perm::default() })
+    }
+
+    /// inherit で切れるまでの階層
+    fn visible(chain: &[Perm]) -> &[Perm] {
+        match chain.iter().position(|p| !p.inherit) {
+            Some(i) => &chain[..=i],
+            None => chain,
+        }
+    }
+
+    proptest! {
+        /// 見える階層のどこかに当たる拒否があれば最も近い拒否、なければ最も近い許可
+        #[test]
+        fn nearest_deny_wins(chain in prop::collection::vec(any_perm(), 0..6), ids in prop::collection::hash_set(1u128..6, 0..4)) {
+            let levels = visible(&chain);
+            let hits = |deny: bool| levels.iter().position(|p| {
+                let set = if deny { &p.read.deny } else { &p.read.allow };
+                set.iter().any(|id| ids.contains(id))
+            });
+            let matched = decide(&chain, &ids, Operation::Read);
+            match (hits(true), hits(false)) {
+                (Some(level), _) => prop_assert_eq!(matched.map(|m| (m.level, m.deny)), Some((level, true))),
+                (None, Some(level)) => prop_assert_eq!(matched.map(|m| (m.level, m.deny)), Some((level, false))),
+                (None, None) => prop_assert_eq!(matched, None),
+            }
+            if let Some(m) = matched {
+                prop_assert!(ids.contains(&m.id));
+            }
+        }
+
+        /// inherit が false の階層より上は結果に影響しない
+        #[test]
+        fn inherit_false_cuts_chain(chain in prop::collection::vec(any_perm(), 1..6), above in prop::collection::vec(any_perm(), 0..4), ids in prop::collection::hash_set(1u128..6, 0..4)) {
+            let mut cut = chain.clone();
+            cut.last_mut().unwrap().inherit = false;
+            let mut longer = cut.clone();
+            longer.extend(above);
+            prop_assert_eq!(decide(&longer, &ids, Operation::Read), decide(&cut, &ids, Operation::Read));
+        }
+
+        /// 入れ子や循環があっても、たどれる全てのグループに属する
+        #[test]
+        fn groups_are_reachable(edges in prop::collection::vec((0usize..7, 1usize..7), 0..12)) {
+            // 0 はユーザー、1.. はグループ (member, group)
+            let id = |i: usize| if i == 0 { ALICE } else { group(i as u128) };
+            let mut expected = BTreeSet::new();
+            let mut queue = VecDeque::from([0]);
+            while let Some(member) = queue.pop_front() {
+                for (_, group) in edges.iter().filter(|(m, _)| *m == member) {
+                    if expected.insert(id(*group)) {
+                        queue.push_back(*group);
+                    }
+                }
+            }
+
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            let groups = runtime.block_on(async {
+                let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+                for group in 1..7 {
+                    let members = edges.iter().filter(|(_, g)| *g == group).map(|(m, _)| id(*m)).collect();
+                    fs.metas.put_group(&PermGroup { id: id(group), owner: "alice".to_string(), name: group.to_string(), members, create_time: 0 }).await.unwrap();
+                }
+                fs.effective_groups(id(0)).await.unwrap()
+            });
+            prop_assert_eq!(groups.into_iter().collect::<BTreeSet<_>>(), expected);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::{collections::VecDeque, io::{Cursor, Error, ErrorKind}, sync::{Arc, Mutex}};
+
+use image::{DynamicImage, ImageFormat};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
+
+use crate::utils::ruid::{self, prefix};
+
+use super::{file_system::FileSystem, meta::MetaData};
+
+pub const SVG_TYPE: &str = "image/svg+xml";
+
+/// 本体から作った縮小画像または抜粋
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preview {
+    /// 縮小画像は長辺のピクセル数、抜粋は 0
+    pub dimension: u32,
+    pub data_type: String,
+    pub size: u64,
+    pub blob: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PreviewConfig {
+    /// 縮小画像の長辺 (ピクセル)
+    pub sizes: Vec<u32>,
+    /// テキストの抜粋の文字数 0 で作らない
+    pub excerpt_length: usize,
+    /// これより大きいファイルは処理しない
+    pub max_source_size: u64,
+    /// 待ちの上限 超えた分は捨てる
+    pub queue_size: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Image(ImageFormat),
+    Excerpt,
+}
+
+/// RUID のプレフィックスで決める SVG はそのまま縮小できるので作らない
+fn kind_of(meta: &MetaData) -> Option<Kind> {
+    match ruid::prefix_of(meta.id) {
+        0x1600 => Some(Kind::Image(ImageFormat::Jpeg)),
+        0x1601 => Some(Kind::Image(ImageFormat::Png)),
+        0x1602 => Some(Kind::Image(ImageFormat::Gif)),
+        0x1613 => Some(Kind::Image(ImageFormat::WebP)),
+        p if p & 0xFF00 == prefix::TEXT_FILE => Some(Kind::Excerpt),
+        _ => None,
+    }
+}
+
+/// 要求した大きさ以上で最も小さいもの 無ければ最も大きいもの
+pub fn pick(previews: &[Preview], size: u32) -> Option<&Preview> {
+    previews.iter()
+        .filter(|p| p.dimension >= size)
+        .min_by_key(|p| p.dimension)
+        .or_else(|| previews.iter().max_by_key(|p| p.dimension))
+}
+
+struct Job {
+    id: u128,
+    checksum: String,
+}
+
+/// プレビューを作る待ち行列
+/// 生成はアップロードとは別に行い、失敗してもログに残すだけ
+pub struct PreviewQueue {
+    jobs: Mutex<VecDeque<Job>>,
+    notify: Notify,
+}
+
+impl PreviewQueue {
+    pub fn new() -> Self {
+        Self {
+            jobs: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+        }
+    }
+}
+
+impl FileSystem {
+    /// プレビューを作れるファイルであれば待ち行列に入れる
+    pub(super) fn enqueue_preview(&self, meta: &MetaData) {
+        let checksum = match (&meta.checksum, kind_of(meta)) {
+            (Some(checksum), Some(_)) if meta.size <= self.config.preview.max_source_size => checksum.clone(),
+            _ => return,
+        };
+        let mut jobs = match self.preview_queue.jobs.lock() {
+            Ok(jobs) => jobs,
+            Err(_) => return,
+        };
+        if jobs.len() >= self.config.preview.queue_size {
+            warn!("preview queue is full, skipped {:032x}", meta.id);
+            return;
+        }
+        jobs.push_back(Job { id: meta.id, checksum });
+        drop(jobs);
+        self.preview_queue.notify.notify_one();
+    }
+
+    pub async fn run_preview_worker(self: Arc<Self>) {
+        loop {
+            let job = self.preview_queue.jobs.lock().ok().and_then(|mut jobs| jobs.pop_front());
+            match job {
+                Some(job) => {
+                    if let Err(e) = self.generate_previews(job.id, &job.checksum).await {
+                        warn!("Failed to generate preview of {:032x}: {}", job.id, e);
+                    }
+                }
+                None => self.preview_queue.notify.notified().await,
+            }
+        }
+    }
+
+    /// 本体が checksum のままであればプレビューを作って付け替える
+    async fn generate_previews(&self, id: u128, checksum: &str) -> Result<(), Error> {
+        let meta = match self.metas.get(id).await? {
+            Some(meta) if meta.checksum.as_deref() == Some(checksum) => meta,
+            _ => return Ok(()),
+        };
+        let kind = match kind_of(&meta) {
+            Some(kind) => kind,
+            None => return Ok(()),
+        };
+        let data = match (meta.inline, &meta.blob) {
+            (Some(data), _) => data,
+            (None, Some(blob)) => self.read_blob(blob, meta.size).await?,
+            (None, None) => return Ok(()),
+        };
+
+        let sizes = self.config.preview.sizes.clone();
+        let excerpt_length = self.config.preview.excerpt_length;
+        let rendered = tokio::task::spawn_blocking(move || render(kind, &data, &sizes, excerpt_length))
+            .await
+            .map_err(Error::other)??;
+
+        let mut previews = Vec::with_capacity(rendered.len());
+        let mut result = Ok(Vec::new());
+        for (dimension, data_type, body) in rendered {
+            match self.import_preview(&meta.owner, &body).await {
+                Ok(blob) => previews.push(Preview { dimension, data_type, size: body.len() as u64, blob }),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if result.is_ok() {
+            result = self.attach_previews(id, checksum, previews.clone()).await;
+        }
+        let mut released = Vec::new();
+        for preview in previews {
+            self.unpin(&preview.blob).await;
+            released.push(preview.blob);
+        }
+        match result {
+            Ok(old) => {
+                self.release_blobs(old).await;
+                Ok(())
+            }
+            Err(e) => {
+                self.release_blobs(released).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// プレビューの本体を取り込む 返したキーは固定される
+    async fn import_preview(&self, user: &str, body: &[u8]) -> Result<String, Error> {
+        let checksum = format!("{:x}", Sha256::digest(body));
+        let temp_path = self.temp_path();
+        tokio::fs::write(&temp_path, body).await?;
+        match self.import_binary(user, &temp_path, &checksum).await {
+            Ok(key) => Ok(key),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// 生成中に本体が変わっていなければ付け替え、解放候補の blob を返す
+    /// 変わっていた場合は作ったものを返す (次の生成に任せる)
+    async fn attach_previews(&self, id: u128, checksum: &str, previews: Vec<Preview>) -> Result<Vec<String>, Error> {
+        let _guard = self.lock.write().await;
+        let mut meta = match self.metas.get(id).await? {
+            Some(meta) if meta.checksum.as_deref() == Some(checksum) => meta,
+            _ => return Ok(previews.into_iter().map(|p| p.blob).collect()),
+        };
+        let old = std::mem::replace(&mut meta.previews, previews);
+        self.metas.put(&meta).await?;
+        Ok(old.into_iter().map(|p| p.blob).collect())
+    }
+}
+
+/// (dimension, data_type, body)
+fn render(kind: Kind, data: &[u8], sizes: &[u32], excerpt_length: usize) -> Result<Vec<(u32, String, Vec<u8>)>, Error> {
+    match kind {
+        Kind::Excerpt => Ok(excerpt(data, excerpt_length)
+            .map(|text| vec![(0, "text/plain; charset=utf-8".to_string(), text.into_bytes())])
+            .unwrap_or_default()),
+        Kind::Image(format) => {
+            let image = image::load_from_memory_with_format(data, format)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            // JPEG はそのまま、それ以外は透過を保つため PNG にする
+            let (output, data_type) = match format {
+                ImageFormat::Jpeg => (ImageFormat::Jpeg, "image/jpeg"),
+                _ => (ImageFormat::Png, "image/png"),
+            };
+
+            let mut rendered = Vec::new();
+            for &size in sizes.iter().filter(|s| **s > 0) {
+                let thumbnail = match image.width().max(image.height()) <= size {
+                    true => image.clone(),
+                    false => image.thumbnail(size, size),
+                };
+                let thumbnail = match output {
+                    ImageFormat::Jpeg => DynamicImage::ImageRgb8(thumbnail.to_rgb8()),
+                    _ => thumbnail,
+                };
+                let mut body = Cursor::new(Vec::new());
+                thumbnail.write_to(&mut body, output).map_err(Error::other)?;
+                rendered.push((size, data_type.to_string(), body.into_inner()));
+            }
+            Ok(rendered)
+        }
+    }
+}
+
+/// UTF-8 として読める先頭 length 文字 読めない場合は None
+fn excerpt(data: &[u8], length: usize) -> Option<String> {
+    if length == 0 {
+        return None;
+    }
+    let head = &data[..data.len().min(length * 4)];
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // 途中で切れた文字は捨てる
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let text: String = text.chars().take(length).collect();
+    match text.trim() {
+        "" => None,
+        text => Some(text.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use image::{ImageFormat, RgbaImage};
+    use tempfile::TempDir;
+
+    use crate::{file_system::{file_system::FileSystem, meta::MetaData, test_support::{self, Backend}}, utils::ruid::RuidGenerator};
+
+    use super::{excerpt, kind_of, pick, Kind, Preview};
+
+    fn preview(dimension: u32) -> Preview {
+        Preview { dimension, data_type: "image/png".to_string(), size: 1, blob: format!("{}", dimension) }
+    }
+
+    fn meta(prefix: u16) -> MetaData {
+        MetaData::new((prefix as u128) << 112 | 1, "alice", "/a", String::new(), 0)
+    }
+
+    async fn file_system() -> (TempDir, Arc<FileSystem>) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_support::config(dir.path(), Backend::Memory);
+        config.preview.sizes = vec![4, 16];
+        config.preview.excerpt_length = 5;
+        config.preview.max_source_size = 1 << 20;
+        config.preview.queue_size = 8;
+        let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap();
+        (dir, Arc::new(fs))
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut body = Cursor::new(Vec::new());
+        RgbaImage::new(width, height).write_to(&mut body, ImageFormat::Png).unwrap();
+        body.into_inner()
+    }
+
+    #[test]
+    fn pick_smallest_large_enough() {
+        let previews = [preview(64), preview(256), preview(128)];
+        assert_eq!(pick(&previews, 100).unwrap().dimension, 128);
+        assert_eq!(pick(&previews, 64).unwrap().dimension, 64);
+        assert_eq!(pick(&previews, 1).unwrap().dimension, 64);
+        assert_eq!(pick(&previews, 1000).unwrap().dimension, 256);
+        assert!(pick(&[], 1).is_none());
+    }
+
+    /// 文字数で切り、途中で切れたマルチバイト文字は捨てる
+    #[test]
+    fn excerpt_keeps_utf8() {
+        assert_eq!(excerpt("こんにちは世界".as_bytes(), 5).as_deref(), Some("こんにちは"));
+        assert_eq!(excerpt(b"  ab  ", 10).as_deref(), Some("ab"));
+        // 先頭 length * 4 バイトで切れた文字
+        let cut = [b"a".repeat(7), "あ".as_bytes().to_vec()].concat();
+        assert_eq!(excerpt(&cut, 2).as_deref(), Some("aa"));
+        assert_eq!(excerpt(&cut, 3).as_deref(), Some("aaa"));
+        assert_eq!(excerpt(&[b'a', 0xff, b'b'], 5), None);
+        assert_eq!(excerpt(b"   ", 5), None);
+        assert_eq!(excerpt(b"abc", 0), None);
+    }
+
+    #[test]
+    fn kinds() {
+        assert!(matches!(kind_of(&meta(0x1601)), Some(Kind::Image(ImageFormat::Png))));
+        assert!(matches!(kind_of(&meta(0x1600)), Some(Kind::Image(ImageFormat::Jpeg))));
+        assert!(matches!(kind_of(&meta(0x1100)), Some(Kind::Excerpt)));
+        assert!(matches!(kind_of(&meta(0x1101)), Some(Kind::Excerpt)));
+        assert!(kind_of(&meta(0x1603)).is_none());
+        assert!(kind_of(&meta(0x2100)).is_none());
+    }
+
+    #[tokio::test]
+    async fn thumbnails_and_excerpt() {
+        let (_dir, fs) = file_system().await;
+        let image = test_support::write(&fs, "alice", "/a.png", &png(32, 8)).await;
+        fs.generate_previews(image.id, image.checksum.as_deref().unwrap()).await.unwrap();
+        let previews = fs.get("alice", "/a.png").await.unwrap().previews;
+        assert_eq!(previews.iter().map(|p| p.dimension).collect::<Vec<_>>(), [4, 16]);
+        assert!(previews.iter().all(|p| p.data_type == "image/png"));
+
+        let text = test_support::write(&fs, "alice", "/a.txt", "テキストの抜粋".as_bytes()).await;
+        fs.generate_previews(text.id, text.checksum.as_deref().unwrap()).await.unwrap();
+        let previews = fs.get("alice", "/a.txt").await.unwrap().previews;
+        assert_eq!(fs.read_blob(&previews[0].blob, previews[0].size).await.unwrap(), "テキストの".as_bytes());
+    }
+
+    /// 壊れた画像でもアップロードしたファイルはそのまま残る
+    #[tokio::test]
+    async fn failed_preview_keeps_upload() {
+        let (_dir, fs) = file_system().await;
+        let body = b"this is not a png image at all";
+        let meta = test_support::write(&fs, "alice", "/broken.png", body).await;
+        assert!(fs.generate_previews(meta.id, meta.checksum.as_deref().unwrap()).await.is_err());
+
+        let after = fs.get("alice", "/broken.png").await.unwrap();
+        assert_eq!((after.version, after.previews.len()), (meta.version, 0));
+        assert_eq!(fs.read_all("alice", "/broken.png").await.unwrap(), body);
+        assert_eq!(fs.blobs.list().await.unwrap(), [meta.blob.unwrap()]);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{collections::HashMap, io::{Error, ErrorKind}, path::PathBuf, sync::Arc};
+
+use bytes::Bytes;
+use chrono::{TimeZone, Utc};
+use futures::stream;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::utils::{self, custom_serializers_adapters::Hex, ruid::{self, RuidGenerator}};
+
+use super::{file_system::FileSystem, meta::MetaData};
+
+/// 適用したテンプレートの版と変数を記録するファイル
+pub const STATE_PATH: &str = "/etc/template.json";
+/// ユーザーの記録 (fs_template/user_ruid/info.json) `/home` の外なので API からは見えない
+pub const INFO_PATH: &str = "/info.json";
+
+/// fs_template/template.json
+#[derive(Debug, Clone, Deserialize)]
+pub struct Template {
+    pub version: u64,
+    pub folders: Vec<String>,
+    pub files: Vec<TemplateFile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TemplateFile {
+    pub path: String,
+    /// fs_template からの相対パス
+    pub source: String,
+    /// このファイルが追加または変更された版
+    #[serde(default)]
+    pub since: u64,
+    /// 古い版のユーザーを移行する時に既存のファイルを置き換える
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+/// ユーザーごとのテンプレートの状態 STATE_PATH に保存する
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisionState {
+    pub version: u64,
+    pub user_id: String,
+    #[serde_as(as = "Hex")]
+    pub ruid: u128,
+    pub created_time: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProvisionReport {
+    pub user: String,
+    pub from_version: Option<u64>,
+    pub version: u64,
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+}
+
+pub struct Provisioner {
+    dir: PathBuf,
+    template: Template,
+    file_system: Arc<FileSystem>,
+    ruid: Arc<RuidGenerator>,
+}
+
+impl Provisioner {
+    pub fn new(file_system: Arc<FileSystem>, ruid: Arc<RuidGenerator>) -> Result<Self, Error> {
+        let dir = utils::fs::get_file_path(&file_system.config.template_path)?;
+        let manifest = std::fs::read_to_string(dir.join("template.json"))?;
+        let template: Template = serde_json::from_str(&manifest).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        info!("user template version {} loaded: {}", template.version, dir.display());
+
+        Ok(Self {
+            dir,
+            template,
+            file_system,
+            ruid,
+        })
+    }
+
+    /// アカウント作成時に呼ぶ 既にある場合は repair と同じ
+    pub async fn provision(&self, user: &str, user_ruid: u128, created_time: i64) -> Result<ProvisionReport, Error> {
+        let state = match self.load_state(user).await? {
+            Some(state) => state,
+            None => ProvisionState {
+                version: 0,
+                user_id: user.to_string(),
+                ruid: user_ruid,
+                created_time,
+            },
+        };
+        self.apply(user, state).await
+    }
+
+    /// 足りないフォルダとファイルを作り直し、古い版であれば移行する
+    /// 状態が無いユーザーはルートフォルダの作成時刻と、既に割り当てた RUID を使う
+    pub async fn repair(&self, user: &str) -> Result<ProvisionReport, Error> {
+        let state = match self.load_state(user).await? {
+            Some(state) => state,
+            None => {
+                let created_time = match self.file_system.get(user, "/").await {
+                    Ok(root) => root.create_time,
+                    Err(_) => Utc::now().timestamp_millis(),
+                };
+                let ruid = match self.file_system.metas.ruid_by_user(user).await? {
+                    Some(ruid) => ruid,
+                    None => self.ruid.generate(ruid::prefix::USER_ID),
+                };
+                ProvisionState {
+                    version: 0,
+                    user_id: user.to_string(),
+                    ruid,
+                    created_time,
+                }
+            }
+        };
+        self.apply(user, state).await
+    }
+
+    /// 全てのユーザーを現在のテンプレートの版に揃える
+    pub async fn migrate_all(&self) -> Result<Vec<ProvisionReport>, Error> {
+        let mut reports = Vec::new();
+        for user in self.file_system.users().await? {
+            match self.repair(&user).await {
+                Ok(report) => reports.push(report),
+                Err(e) => error!("Failed to provision user {}: {}", user, e),
+            }
+        }
+        Ok(reports)
+    }
+
+    /// 壊れた状態は作り直さずに Err にする 版や RUID を取り違えないため
+    async fn load_state(&self, user: &str) -> Result<Option<ProvisionState>, Error> {
+        match self.file_system.read_all(user, STATE_PATH).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{} of {} is broken: {}", STATE_PATH, user, e))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn apply(&self, user: &str, mut state: ProvisionState) -> Result<ProvisionReport, Error> {
+        self.file_system.metas.bind_user(state.ruid, user).await?;
+        let mut report = ProvisionReport {
+            user: user.to_string(),
+            from_version: if state.version == 0 { None } else { Some(state.version) },
+            version: self.template.version,
+            ..Default::default()
+        };
+
+        for folder in &self.template.folders {
+            if self.file_system.get(user, folder).await.is_err() {
+                self.file_system.create_folder(user, folder).await?;
+                report.created.push(folder.clone());
+            }
+        }
+
+        let vars = variables(&state, self.file_system.config.quota.default_level);
+        for file in &self.template.files {
+            let exists = self.file_system.get(user, &file.path).await.is_ok();
+            let outdated = file.overwrite && state.version != 0 && file.since > state.version;
+            if exists && !outdated {
+                continue;
+            }
+
+            let source = tokio::fs::read_to_string(self.dir.join(&file.source)).await?;
+            self.write(user, &file.path, substitute(&source, &vars).into_bytes()).await?;
+            if exists {
+                report.updated.push(file.path.clone());
+            } else {
+                report.created.push(file.path.clone());
+            }
+        }
+
+        if state.version != self.template.version || !report.created.is_empty() || !report.updated.is_empty() {
+            state.version = self.template.version;
+            let data = serde_json::to_vec_pretty(&state).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            self.write(user, STATE_PATH, data).await?;
+        }
+        self.file_system.sync_account_level(user).await?;
+        if !report.created.is_empty() || !report.updated.is_empty() {
+            info!("provisioned user {}: created {:?}, updated {:?}", user, report.created, report.updated);
+        }
+        Ok(report)
+    }
+
+    async fn write(&self, user: &str, path: &str, data: Vec<u8>) -> Result<MetaData, Error> {
+        let chunk = stream::iter([Ok::<_, Error>(Bytes::from(data))]);
+        let (content, size, checksum) = self.file_system.save_binary(user, chunk).await?;
+        self.file_system.create_file(user, path, content, size, checksum).await
+    }
+}
+
+fn variables(state: &ProvisionState, account_level: u64) -> HashMap<&'static str, String> {
+    let created = Utc.timestamp_millis_opt(state.created_time).single().unwrap_or_else(Utc::now);
+    HashMap::from([
+        ("username", state.user_id.clone()),
+        ("user_id", format!("@{}", state.user_id)),
+        ("ruid", format!("{:032x}", state.ruid)),
+        ("created_time", created.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        ("account_level", account_level.to_string()),
+    ])
+}
+
+/// `{{name}}` を置き換える テンプレートは JSON なので値はエスケープする
+fn substitute(source: &str, vars: &HashMap<&'static str, String>) -> String {
+    let mut result = source.to_string();
+    for (name, value) in vars {
+        let escaped = serde_json::to_string(value).unwrap_or_default();
+        result = result.replace(&format!("{{{{{}}}}}", name), &escaped[1..escaped.len() - 1]);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::ErrorKind, sync::Arc};
+
+    use serde_json::{json, Value};
+
+    use crate::{file_system::{file_system::FileSystem, test_support::{self, Backend}}, utils::ruid::RuidGenerator};
+
+    use super::{substitute, Provisioner, STATE_PATH};
+
+    fn provisioner(fs: &Arc<FileSystem>) -> Provisioner {
+        Provisioner::new(Arc::clone(fs), Arc::new(RuidGenerator::new(1))).unwrap()
+    }
+
+    async fn about(fs: &FileSystem, user: &str) -> Value {
+        serde_json::from_slice(&fs.read_all(user, "/home/about_user.json").await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn provision_and_repair() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let provisioner = provisioner(&fs);
+        let report = provisioner.provision("alice", 0xab, 0).await.unwrap();
+        assert_eq!((report.from_version, report.version, report.created.len()), (None, 2, 9));
+        let about = about(&fs, "alice").await;
+        assert_eq!((&about["username"], &about["user_id"]), (&json!("alice"), &json!("@alice")));
+        assert_eq!((&about["ruid"], &about["created_time"]), (&json!(format!("{:032x}", 0xab)), &json!("1970-01-01T00:00:00Z")));
+
+        // 何度実行しても同じ 消えたものだけを作り直す
+        assert!(provisioner.repair("alice").await.unwrap().created.is_empty());
+        let (_, entry) = fs.remove("alice", "/var", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        fs.purge(None, entry.id).await.unwrap();
+        assert_eq!(provisioner.repair("alice").await.unwrap().created, ["/var", "/var/user_log.json"]);
+        assert!(fs.get("alice", STATE_PATH).await.is_ok());
+    }
+
+    /// 版を上げると overwrite のファイルだけを置き換える
+    #[tokio::test]
+    async fn migrate_to_new_version() {
+        let (dir, fs) = test_support::file_system(Backend::Memory).await;
+        provisioner(&fs).provision("alice", 1, 0).await.unwrap();
+        fs.create_folder("alice", "/home/keep").await.unwrap();
+
+        let template = dir.path().join("template");
+        std::fs::create_dir_all(template.join("user")).unwrap();
+        std::fs::write(template.join("user/about.json"), r#"{ "username": "{{username}}", "v": 2 }"#).unwrap();
+        std::fs::write(template.join("user/log.json"), "[]").unwrap();
+        std::fs::write(template.join("template.json"), json!({
+            "version": 3,
+            "folders": ["/home"],
+            "files": [
+                { "path": "/home/about_user.json", "source": "user/about.json", "since": 3, "overwrite": true },
+                { "path": "/var/user_log.json", "source": "user/log.json", "since": 1, "overwrite": true },
+            ],
+        }).to_string()).unwrap();
+        let manifest = std::fs::read_to_string(template.join("template.json")).unwrap();
+        let migrated = Provisioner { template: serde_json::from_str(&manifest).unwrap(), dir: template, ..provisioner(&fs) };
+
+        let reports = migrated.migrate_all().await.unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!((reports[0].from_version, reports[0].updated.as_slice()), (Some(2), &["/home/about_user.json".to_string()][..]));
+        assert_eq!(about(&fs, "alice").await, json!({ "username": "alice", "v": 2 }));
+        assert!(fs.get("alice", "/home/keep").await.is_ok());
+        assert!(migrated.migrate_all().await.unwrap()[0].updated.is_empty());
+    }
+
+    #[test]
+    fn values_are_escaped() {
+        let vars = HashMap::from([("username", "a\"b".to_string())]);
+        assert_eq!(substitute(r#"{"u": "{{username}}", "x": "{{other}}"}"#, &vars), r#"{"u": "a\"b", "x": "{{other}}"}"#);
+    }
+
+    #[tokio::test]
+    async fn creates_user_record() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let provisioner = provisioner(&fs);
+        let report = provisioner.provision("alice", 0x2100_0000_0000_0000_0000_0000_0000_0001, 0).await.unwrap();
+        assert!(report.created.iter().any(|p| p == "/info.json"));
+
+        let info: serde_json::Value = serde_json::from_slice(&fs.read_all("alice", "/info.json").await.unwrap()).unwrap();
+        assert_eq!(info["user_id"], "@alice");
+        assert_eq!(info["ruid"], "21000000000000000000000000000001");
+        assert_eq!(info["account_level"], 0);
+        assert!(provisioner.repair("alice").await.unwrap().created.is_empty());
+    }
+
+    /// 状態のファイルが無くても、既に割り当てた RUID を使い続ける
+    #[tokio::test]
+    async fn repair_keeps_ruid() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let ruid = 0x2100_0000_0000_0000_0000_0000_0000_0002;
+        fs.metas.bind_user(ruid, "alice").await.unwrap();
+        let provisioner = provisioner(&fs);
+
+        provisioner.repair("alice").await.unwrap();
+        assert_eq!(fs.metas.ruid_by_user("alice").await.unwrap(), Some(ruid));
+        let info: serde_json::Value = serde_json::from_slice(&fs.read_all("alice", "/info.json").await.unwrap()).unwrap();
+        assert_eq!(info["ruid"], format!("{:032x}", ruid));
+    }
+
+    #[tokio::test]
+    async fn broken_state_is_error() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", STATE_PATH, b"{ broken").await;
+        let provisioner = provisioner(&fs);
+        assert_eq!(provisioner.repair("alice").await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
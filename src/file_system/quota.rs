@@ -0,0 +1,310 @@
+use std::{collections::{BTreeMap, HashMap}, io::{Error, ErrorKind}, sync::Arc, time::Duration};
+
+use log::{error, warn};
+use serde_json::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::ruid;
+
+use super::{file_system::FileSystem, meta::MetaData, provision::INFO_PATH};
+
+/// ユーザーの使用量 ゴミ箱の中のファイルも完全に削除されるまで含む
+/// 同じ内容のバイナリを共有していても、それぞれのファイルの大きさで数える
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub user: String,
+    pub account_level: u64,
+    pub used: u64,
+    pub files: u64,
+    /// RUID のファイルの種類ごとの使用量
+    pub by_type: BTreeMap<String, u64>,
+}
+
+impl Usage {
+    fn apply(&mut self, meta: &MetaData, add: bool) {
+        if meta.is_folder() {
+            return;
+        }
+        let by_type = self.by_type.entry(ruid::file_type_name(ruid::prefix_of(meta.id)).to_string()).or_insert(0);
+        if add {
+            self.used += meta.size;
+            self.files += 1;
+            *by_type += meta.size;
+        } else {
+            self.used = self.used.saturating_sub(meta.size);
+            self.files = self.files.saturating_sub(1);
+            *by_type = by_type.saturating_sub(meta.size);
+        }
+        self.by_type.retain(|_, size| *size > 0);
+    }
+}
+
+/// account_level ごとの上限 (バイト) と、ユーザーごとの上書き
+/// 上限が決まらない場合は無制限
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QuotaConfig {
+    pub default_level: u64,
+    pub levels: HashMap<u64, u64>,
+    pub overrides: HashMap<String, u64>,
+    pub reconcile_interval: u64,
+}
+
+impl QuotaConfig {
+    pub fn limit(&self, usage: &Usage) -> Option<u64> {
+        self.overrides.get(&usage.user)
+            .or_else(|| self.levels.get(&usage.account_level))
+            .copied()
+    }
+}
+
+impl FileSystem {
+    pub async fn usage(&self, user: &str) -> Result<Usage, Error> {
+        Ok(self.metas.get_usage(user).await?.unwrap_or_else(|| Usage {
+            user: user.to_string(),
+            account_level: self.config.quota.default_level,
+            ..Default::default()
+        }))
+    }
+
+    /// ユーザーの記録 (INFO_PATH) の account_level を書き換えて使用量にも反映する
+    /// 記録が無いユーザーは使用量のみ変える
+    pub async fn set_account_level(&self, user: &str, level: u64) -> Result<Usage, Error> {
+        match self.read_all(user, INFO_PATH).await {
+            Ok(data) => {
+                let mut info: Value = serde_json::from_slice(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                let record = info.as_object_mut().ok_or_else(|| Error::new(ErrorKind::InvalidData, "user record is not an object"))?;
+                record.insert("account_level".to_string(), Value::from(level));
+                let data = serde_json::to_vec_pretty(&info).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                let chunk = futures::stream::iter([Ok::<_, Error>(bytes::Bytes::from(data))]);
+                let (content, size, checksum) = self.save_binary(user, chunk).await?;
+                self.create_file(user, INFO_PATH, content, size, checksum).await?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let _guard = self.lock.write().await;
+        let mut usage = self.usage(user).await?;
+        usage.account_level = level;
+        self.metas.put_usage(&usage).await?;
+        Ok(usage)
+    }
+
+    /// ユーザーの記録の account_level を使用量に反映する 記録が無ければ変えない
+    pub async fn sync_account_level(&self, user: &str) -> Result<Usage, Error> {
+        let _guard = self.lock.write().await;
+        let mut usage = self.usage(user).await?;
+        if let Some(level) = self.recorded_level(user).await {
+            if usage.account_level != level || self.metas.get_usage(user).await?.is_none() {
+                usage.account_level = level;
+                self.metas.put_usage(&usage).await?;
+            }
+        }
+        Ok(usage)
+    }
+
+    /// ユーザーの記録の account_level lock を取った状態で呼ぶ
+    /// 読めない記録は警告して None にする 一人の記録のために他のユーザーの処理を止めないため
+    async fn recorded_level(&self, user: &str) -> Option<u64> {
+        let result = match self.lookup(user, INFO_PATH).await {
+            Ok(Some(meta)) => match (meta.inline, meta.blob) {
+                (Some(data), _) => Ok(data),
+                (None, Some(blob)) => self.read_blob(&blob, meta.size).await,
+                (None, None) => return None,
+            },
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        };
+        let level = result.and_then(|data| serde_json::from_slice::<Value>(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e)))
+            .map(|info| info.get("account_level").and_then(Value::as_u64));
+        match level {
+            Ok(level) => level,
+            Err(e) => {
+                warn!("Failed to read {} of {}: {}", INFO_PATH, user, e);
+                None
+            }
+        }
+    }
+
+    /// 書き込む前に上限を確かめる 大きさが分かっている場合に先に断るため
+    pub async fn check_quota(&self, user: &str, additional: u64) -> Result<(), Error> {
+        let usage = self.usage(user).await?;
+        match self.config.quota.limit(&usage) {
+            Some(limit) if usage.used.saturating_add(additional) > limit => Err(quota_exceeded()),
+            _ => Ok(()),
+        }
+    }
+
+    /// 使用量を増減する lock の write を取った状態で呼ぶ
+    /// enforce の場合は増えた結果が上限を超えると QuotaExceeded で何も変更しない
+    pub(super) async fn charge(&self, user: &str, added: &[MetaData], removed: &[MetaData], enforce: bool) -> Result<(), Error> {
+        let mut usage = self.usage(user).await?;
+        let before = usage.used;
+        for meta in removed {
+            usage.apply(meta, false);
+        }
+        for meta in added {
+            usage.apply(meta, true);
+        }
+
+        if enforce && usage.used > before {
+            if let Some(limit) = self.config.quota.limit(&usage) {
+                if usage.used > limit {
+                    return Err(quota_exceeded());
+                }
+            }
+        }
+        self.metas.put_usage(&usage).await
+    }
+
+    /// メタデータから使用量を数え直し、ずれていれば直す account_level はユーザーの記録に揃える
+    pub async fn reconcile_usage(&self) -> Result<Vec<Usage>, Error> {
+        let _guard = self.lock.write().await;
+        let mut actual: HashMap<String, Usage> = HashMap::new();
+        for usage in self.metas.list_usage().await? {
+            let mut empty = usage.clone();
+            empty.used = 0;
+            empty.files = 0;
+            empty.by_type.clear();
+            actual.insert(usage.user.clone(), empty);
+        }
+        for meta in self.metas.scan().await? {
+            let usage = actual.entry(meta.owner.clone()).or_insert_with(|| Usage {
+                user: meta.owner.clone(),
+                account_level: self.config.quota.default_level,
+                ..Default::default()
+            });
+            usage.apply(&meta, true);
+        }
+        for usage in actual.values_mut() {
+            if let Some(level) = self.recorded_level(&usage.user).await {
+                usage.account_level = level;
+            }
+        }
+
+        let mut fixed = Vec::new();
+        for usage in actual.into_values() {
+            if self.metas.get_usage(&usage.user).await?.as_ref() != Some(&usage) {
+                self.metas.put_usage(&usage).await?;
+                fixed.push(usage);
+            }
+        }
+        Ok(fixed)
+    }
+
+    pub async fn run_usage_reconciler(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.reconcile_usage().await {
+                Ok(fixed) if fixed.is_empty() => {}
+                Ok(fixed) => warn!("usage of {} users drifted and was fixed", fixed.len()),
+                Err(e) => error!("Failed to reconcile usage: {}", e),
+            }
+        }
+    }
+}
+
+fn quota_exceeded() -> Error {
+    Error::new(ErrorKind::QuotaExceeded, "storage quota exceeded")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, sync::Arc};
+
+    use bytes::Bytes;
+    use futures::stream;
+    use tempfile::TempDir;
+
+    use crate::{
+        file_system::{
+            file_system::FileSystem,
+            provision::{Provisioner, INFO_PATH},
+            test_support::{self, Backend},
+        },
+        utils::ruid::RuidGenerator,
+    };
+
+    /// level 0 は 64 バイト、bob だけ 128 バイト
+    async fn file_system() -> (TempDir, Arc<FileSystem>) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_support::config(dir.path(), Backend::Memory);
+        config.quota.levels.insert(0, 64);
+        config.quota.overrides.insert("bob".to_string(), 128);
+        let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap();
+        (dir, Arc::new(fs))
+    }
+
+    #[tokio::test]
+    async fn writes_are_limited() {
+        let (_dir, fs) = file_system().await;
+        test_support::write(&fs, "alice", "/a.txt", &[0; 40]).await;
+        assert_eq!(fs.check_quota("alice", 30).await.unwrap_err().kind(), ErrorKind::QuotaExceeded);
+        assert!(fs.check_quota("bob", 100).await.is_ok());
+
+        let chunks = stream::iter([Ok::<_, std::io::Error>(Bytes::from(vec![0; 30]))]);
+        let (content, size, checksum) = fs.save_binary("alice", chunks).await.unwrap();
+        assert_eq!(fs.create_file("alice", "/b.txt", content, size, checksum).await.unwrap_err().kind(), ErrorKind::QuotaExceeded);
+        assert!(fs.get("alice", "/b.txt").await.is_err());
+
+        // 上書きは差分で数える
+        test_support::write(&fs, "alice", "/a.txt", &[1; 60]).await;
+        let usage = fs.usage("alice").await.unwrap();
+        assert_eq!((usage.used, usage.files), (60, 1));
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        assert_eq!(fs.copy_to("alice", "/a.txt", "alice", "/c.txt", &alice).await.unwrap_err().kind(), ErrorKind::QuotaExceeded);
+        fs.copy_to("alice", "/a.txt", "bob", "/c.txt", &alice).await.unwrap();
+        assert_eq!(fs.usage("bob").await.unwrap().used, 60);
+    }
+
+    /// ゴミ箱の中は完全に削除されるまで数える
+    #[tokio::test]
+    async fn trash_counts_until_purged() {
+        let (_dir, fs) = file_system().await;
+        test_support::write(&fs, "alice", "/docs/a.txt", &[0; 40]).await;
+        let (_, entry) = fs.remove("alice", "/docs", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        assert_eq!(fs.usage("alice").await.unwrap().used, 40);
+        fs.purge(None, entry.id).await.unwrap();
+        let usage = fs.usage("alice").await.unwrap();
+        assert_eq!((usage.used, usage.files), (0, 0));
+        assert!(usage.by_type.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconcile_fixes_drift() {
+        let (_dir, fs) = file_system().await;
+        test_support::write(&fs, "alice", "/a.txt", &[0; 40]).await;
+        let mut drifted = fs.usage("alice").await.unwrap();
+        drifted.used = 1;
+        fs.metas.put_usage(&drifted).await.unwrap();
+
+        let fixed = fs.reconcile_usage().await.unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert_eq!(fs.usage("alice").await.unwrap().used, 40);
+        assert!(fs.reconcile_usage().await.unwrap().is_empty());
+
+        assert_eq!(fs.set_account_level("alice", 2).await.unwrap().account_level, 2);
+        assert!(fs.check_quota("alice", 1 << 30).await.is_ok());
+    }
+
+    /// account_level はユーザーの記録から読み、書き換えると記録にも残る
+    #[tokio::test]
+    async fn level_follows_user_record() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let provisioner = Provisioner::new(Arc::clone(&fs), Arc::new(RuidGenerator::new(1))).unwrap();
+        provisioner.provision("alice", 0x2100_0000_0000_0000_0000_0000_0000_0001, 0).await.unwrap();
+        assert_eq!(fs.usage("alice").await.unwrap().account_level, 0);
+
+        assert_eq!(fs.set_account_level("alice", 3).await.unwrap().account_level, 3);
+        let info: serde_json::Value = serde_json::from_slice(&fs.read_all("alice", INFO_PATH).await.unwrap()).unwrap();
+        assert_eq!(info["account_level"], 3);
+        assert_eq!(info["user_id"], "@alice");
+
+        // 記録を直接変えた場合は数え直しで揃う
+        let record = br#"{ "user_id": "@alice", "account_level": 5 }"#;
+        test_support::write(&fs, "alice", INFO_PATH, record).await;
+        fs.reconcile_usage().await.unwrap();
+        assert_eq!(fs.usage("alice").await.unwrap().account_level, 5);
+    }
+}
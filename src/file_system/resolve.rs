@@ -0,0 +1,160 @@
+use std::io::{Error, ErrorKind};
+
+use serde::Serialize;
+
+use super::{file_system::FileSystem, path};
+
+/// 公開されるユーザーのファイル
+pub const HOME: &str = "/home";
+/// 所有者だけが扱えるシステムのパス
+pub const SYSTEM_ROOTS: &[&str] = &["/etc", "/var", "/agent"];
+
+pub const MAX_USER_NAME_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Area {
+    Home,
+    System,
+}
+
+/// URL の `<user>/<path>` を解決した結果
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    pub user: String,
+    pub path: String,
+    pub area: Area,
+}
+
+impl Resolved {
+    /// `/home` `/etc` などの領域そのもの 移動や削除はできない
+    pub fn is_area_root(&self) -> bool {
+        self.path == HOME || SYSTEM_ROOTS.contains(&self.path.as_str())
+    }
+
+    /// システムのパスは所有者のみ
+    /// 公開領域の操作ごとの権限はメタデータの perm で判定する
+    pub fn check(&self, requester: Option<&str>) -> Result<(), Error> {
+        match self.area {
+            Area::Home => Ok(()),
+            Area::System if requester == Some(self.user.as_str()) => Ok(()),
+            Area::System => Err(Error::new(ErrorKind::PermissionDenied, "system path is only for the owner")),
+        }
+    }
+}
+
+/// ユーザー名は英数字と `_` `-` `.` のみ
+pub fn valid_user_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_USER_NAME_LENGTH
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'))
+        && !name.starts_with('.')
+}
+
+/// 正規化済みのパスの領域 どちらでもない場合は None
+pub fn area_of(path: &str) -> Option<Area> {
+    let under = |root: &str| path == root || path.starts_with(&format!("{}/", root));
+    if SYSTEM_ROOTS.iter().any(|root| under(root)) {
+        return Some(Area::System);
+    }
+    if under(HOME) {
+        return Some(Area::Home);
+    }
+    None
+}
+
+/// URL のパスを実際のパスに変換する
+/// `/home` とシステムのパスはそのまま、それ以外は `/home` 以下に転送する
+pub fn view_path(raw: &str) -> Result<(String, Area), Error> {
+    let normalized = path::normalize(raw)?;
+    if let Some(area) = area_of(&normalized) {
+        return Ok((normalized, area));
+    }
+    let path = match normalized.as_str() {
+        "/" => HOME.to_string(),
+        p => format!("{}{}", HOME, p),
+    };
+    if path.len() > path::MAX_PATH_LENGTH {
+        return Err(Error::new(ErrorKind::InvalidInput, "path is too long"));
+    }
+    Ok((path, Area::Home))
+}
+
+impl FileSystem {
+    /// `@<user>` または RUID (hex) からユーザーを解決する
+    pub async fn resolve_user(&self, spec: &str) -> Result<String, Error> {
+        if let Some(name) = spec.strip_prefix('@') {
+            if valid_user_name(name) {
+                return Ok(name.to_string());
+            }
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid user name"));
+        }
+
+        let hex = spec.trim_start_matches("0x");
+        if hex.len() != 32 {
+            return Err(Error::new(ErrorKind::InvalidInput, "user must be @<id> or RUID"));
+        }
+        let ruid = u128::from_str_radix(hex, 16).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid RUID"))?;
+        self.metas.user_by_ruid(ruid).await?.ok_or_else(|| Error::new(ErrorKind::NotFound, "user is not found"))
+    }
+
+    pub async fn resolve(&self, user: &str, raw_path: &str) -> Result<Resolved, Error> {
+        let user = self.resolve_user(user).await?;
+        let (path, area) = view_path(raw_path)?;
+        Ok(Resolved { user, path, area })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use crate::file_system::test_support::{self, Backend};
+
+    use super::{valid_user_name, view_path, Area};
+
+    #[test]
+    fn paths_are_mapped_to_home() {
+        let cases = [
+            ("/", "/home", Area::Home),
+            ("/docs/a.txt", "/home/docs/a.txt", Area::Home),
+            ("/home/a.txt", "/home/a.txt", Area::Home),
+            ("/homework", "/home/homework", Area::Home),
+            ("/etc/x.json", "/etc/x.json", Area::System),
+            ("//var/./log", "/var/log", Area::System),
+            ("/agent", "/agent", Area::System),
+        ];
+        for (raw, path, area) in cases {
+            assert_eq!(view_path(raw).unwrap(), (path.to_string(), area), "{}", raw);
+        }
+        assert_eq!(view_path("/a/../etc").unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn user_names() {
+        for name in ["alice", "a.b-c_d", "A1"] {
+            assert!(valid_user_name(name), "{}", name);
+        }
+        for name in ["", ".hidden", "a/b", "a b", &"a".repeat(33)] {
+            assert!(!valid_user_name(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_users_and_areas() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        fs.metas.bind_user(0xab, "alice").await.unwrap();
+
+        let resolved = fs.resolve(&format!("{:032x}", 0xab), "/etc/a.json").await.unwrap();
+        assert_eq!((resolved.user.as_str(), resolved.path.as_str()), ("alice", "/etc/a.json"));
+        assert!(resolved.check(Some("alice")).is_ok());
+        assert_eq!(resolved.check(Some("bob")).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(resolved.check(None).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(fs.resolve("@alice", "/etc").await.unwrap().is_area_root());
+        assert!(fs.resolve("@bob", "/a.txt").await.unwrap().check(None).is_ok());
+
+        assert_eq!(fs.resolve_user(&format!("{:032x}", 0xcd)).await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.resolve_user("@a/b").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.resolve_user("alice").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
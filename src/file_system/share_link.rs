@@ -0,0 +1,433 @@
+use std::{collections::{BTreeSet, HashMap}, io::{Error, ErrorKind}, sync::Arc};
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::info;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use sha2::Sha256;
+use tokio::sync::Mutex;
+
+use crate::utils::{self, custom_serializers_adapters::Hex, password, ruid::{self, RuidGenerator}};
+
+use super::{file_system::FileSystem, perm::{Operation, Principal}, resolve::{self, Area}};
+
+const KEY_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ShareLinkConfig {
+    /// 署名に使うノードの鍵のファイル 空の場合は `<storage_path>/share_link.key`
+    pub key_path: String,
+    /// 期限を指定しなかった場合の秒数 0 で無期限
+    pub default_expire: u64,
+    /// 期限の上限の秒数 0 で無制限
+    pub max_expire: u64,
+    /// 同じ相手がこの秒数の間に使った分は一回と数える Range や子のファイルで回数を使い切らないため
+    pub visit_window: u64,
+}
+
+/// 共有リンク トークンは署名と期限だけで確かめ、取り消しとパスワードと回数のために記録を残す
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    #[serde_as(as = "Hex")]
+    pub id: u128,
+    pub user: String,
+    /// このパスとその下が対象
+    pub path: String,
+    pub ops: BTreeSet<Operation>,
+    pub creator: Option<String>,
+    pub create_time: i64,
+    pub expire_time: Option<i64>,
+    /// パスワードの Argon2id (PHC 文字列) 一覧では返さない
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub protected: bool,
+    pub max_uses: Option<u64>,
+    #[serde(default)]
+    pub uses: u64,
+}
+
+impl ShareLink {
+    /// 一覧用 パスワードは含めない
+    pub fn summary(&self) -> Self {
+        Self {
+            password: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expire_time.is_some_and(|t| t <= now)
+    }
+}
+
+/// トークンに署名して入れる内容
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    #[serde_as(as = "Hex")]
+    id: u128,
+    user: String,
+    path: String,
+    ops: BTreeSet<Operation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+}
+
+/// 共有リンクで許された範囲
+#[derive(Debug, Clone)]
+pub struct LinkGrant {
+    pub id: u128,
+    pub user: String,
+    pub path: String,
+    pub ops: BTreeSet<Operation>,
+}
+
+impl LinkGrant {
+    pub fn covers(&self, user: &str, path: &str, op: Operation) -> bool {
+        self.user == user
+            && self.ops.contains(&op)
+            && (path == self.path || path.starts_with(&format!("{}/", self.path)))
+    }
+}
+
+/// 作成時の指定
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewShareLink {
+    pub ops: BTreeSet<Operation>,
+    /// 秒数 省略した場合は default_expire
+    pub expire: Option<u64>,
+    pub password: Option<String>,
+    pub max_uses: Option<u64>,
+}
+
+fn invalid_link() -> Error {
+    Error::new(ErrorKind::PermissionDenied, "share link is invalid")
+}
+
+pub struct ShareLinks {
+    config: ShareLinkConfig,
+    key: [u8; KEY_LENGTH],
+    file_system: Arc<FileSystem>,
+    ruid: Arc<RuidGenerator>,
+    /// 使用回数の更新を直列にする
+    lock: Mutex<()>,
+    /// (リンク, 相手) -> 数えた時刻
+    visits: std::sync::Mutex<HashMap<(u128, String), i64>>,
+}
+
+impl ShareLinks {
+    pub fn new(file_system: Arc<FileSystem>, ruid: Arc<RuidGenerator>) -> Result<Self, Error> {
+        let config = file_system.config.share_link.clone();
+        let path = match config.key_path.as_str() {
+            "" => file_system.root.join("share_link.key"),
+            p => utils::fs::get_file_path(p)?,
+        };
+        let key = match std::fs::read(&path) {
+            Ok(data) => data.try_into().map_err(|_| Error::new(ErrorKind::InvalidData, "share link key must be 32 bytes"))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mut key = [0u8; KEY_LENGTH];
+                rand::thread_rng().fill_bytes(&mut key);
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(0o600);
+                }
+                std::io::Write::write_all(&mut options.open(&path)?, &key)?;
+                info!("created share link key: {}", path.display());
+                key
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            config,
+            key,
+            file_system,
+            ruid,
+            lock: Mutex::new(()),
+            visits: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length")
+    }
+
+    fn sign(&self, link: &ShareLink) -> Result<String, Error> {
+        let claims = Claims {
+            id: link.id,
+            user: link.user.clone(),
+            path: link.path.clone(),
+            ops: link.ops.clone(),
+            exp: link.expire_time,
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).map_err(|e| Error::new(ErrorKind::InvalidData, e))?);
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        Ok(format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())))
+    }
+
+    /// 署名と期限だけを確かめる データベースは引かない
+    fn open(&self, token: &str) -> Result<Claims, Error> {
+        let (payload, signature) = token.split_once('.').ok_or_else(invalid_link)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid_link())?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| invalid_link())?;
+
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid_link())?;
+        let claims: Claims = serde_json::from_slice(&payload).map_err(|_| invalid_link())?;
+        if claims.exp.is_some_and(|exp| exp <= Utc::now().timestamp_millis()) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "share link is expired"));
+        }
+        Ok(claims)
+    }
+
+    /// user の path に共有リンクを作り、記録とトークンを返す
+    /// share は含められない 作る人が perm で許されていない操作も含められない
+    pub async fn create(&self, user: &str, path: &str, creator: &Principal, new: NewShareLink) -> Result<(ShareLink, String), Error> {
+        if new.ops.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "ops is empty"));
+        }
+        if new.ops.contains(&Operation::Share) {
+            return Err(Error::new(ErrorKind::InvalidInput, "share can't be given by a link"));
+        }
+        if resolve::area_of(path) != Some(Area::Home) {
+            return Err(Error::new(ErrorKind::InvalidInput, "only public paths can be shared"));
+        }
+        // 共有リンクで得た権限は渡せない
+        let chain = self.file_system.perm_chain(user, path).await?;
+        if let Some(op) = new.ops.iter().find(|op| !creator.allowed(user, &chain, **op)) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not allowed for the creator", op.as_str())));
+        }
+        let expire = match (new.expire.unwrap_or(self.config.default_expire), self.config.max_expire) {
+            (0, 0) => None,
+            (0, max) => Some(max),
+            (expire, 0) => Some(expire),
+            (expire, max) => Some(expire.min(max)),
+        };
+        let password = match new.password.filter(|p| !p.is_empty()) {
+            Some(given) => Some(tokio::task::spawn_blocking(move || password::hash(&Argon2::default(), &given)).await.map_err(Error::other)??),
+            None => None,
+        };
+
+        let now = Utc::now().timestamp_millis();
+        let link = ShareLink {
+            id: self.ruid.generate(ruid::prefix::SHARE_LINK),
+            user: user.to_string(),
+            path: path.to_string(),
+            ops: new.ops,
+            creator: creator.user.clone(),
+            create_time: now,
+            expire_time: expire.map(|e| now.saturating_add(e.saturating_mul(1000) as i64)),
+            protected: password.is_some(),
+            password,
+            max_uses: new.max_uses,
+            uses: 0,
+        };
+        let token = self.sign(&link)?;
+        self.file_system.metas.put_link(&link).await?;
+        Ok((link, token))
+    }
+
+    /// トークンを確かめて使う visitor (セッションや接続元) ごとに visit_window の間は一回と数える
+    /// 取り消し、パスワード、回数は記録で確かめる 既に数えた相手は使い切った後も続けて使える
+    pub async fn redeem(&self, token: &str, password: Option<&str>, visitor: &str) -> Result<LinkGrant, Error> {
+        let claims = self.open(token)?;
+        let link = self.file_system.metas.get_link(claims.id).await?
+            .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "share link is revoked"))?;
+        if let Some(hash) = link.password.clone() {
+            let given = password.unwrap_or("").to_string();
+            let verified = tokio::task::spawn_blocking(move || password::verify(&hash, &given)).await.map_err(Error::other)?;
+            if !verified {
+                return Err(Error::new(ErrorKind::PermissionDenied, "password is wrong"));
+            }
+        }
+
+        let _guard = self.lock.lock().await;
+        let mut link = self.file_system.metas.get_link(claims.id).await?
+            .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "share link is revoked"))?;
+        let now = Utc::now().timestamp_millis();
+        let key = (link.id, visitor.to_string());
+        let counted = {
+            let window = self.config.visit_window.saturating_mul(1000) as i64;
+            let mut visits = self.visits.lock().unwrap_or_else(|e| e.into_inner());
+            visits.retain(|_, since| now - *since < window);
+            visits.contains_key(&key)
+        };
+        if !counted {
+            if link.max_uses.is_some_and(|max| link.uses >= max) {
+                return Err(Error::new(ErrorKind::PermissionDenied, "share link is used up"));
+            }
+            link.uses += 1;
+            self.file_system.metas.put_link(&link).await?;
+            self.visits.lock().unwrap_or_else(|e| e.into_inner()).insert(key, now);
+        }
+        Ok(LinkGrant {
+            id: link.id,
+            user: link.user,
+            path: link.path,
+            ops: link.ops,
+        })
+    }
+
+    /// user の共有リンク 期限切れのものは消す
+    pub async fn list(&self, user: &str) -> Result<Vec<ShareLink>, Error> {
+        let now = Utc::now().timestamp_millis();
+        let mut links = Vec::new();
+        for link in self.file_system.metas.list_links(Some(user)).await? {
+            if link.is_expired(now) {
+                self.file_system.metas.delete_link(link.id).await?;
+                continue;
+            }
+            links.push(link.summary());
+        }
+        links.sort_by_key(|l| l.create_time);
+        Ok(links)
+    }
+
+    /// 取り消す 以後そのトークンは使えない
+    pub async fn revoke(&self, user: &str, id: u128) -> Result<ShareLink, Error> {
+        let _guard = self.lock.lock().await;
+        let link = match self.file_system.metas.get_link(id).await? {
+            Some(link) if link.user == user => link,
+            _ => return Err(Error::new(ErrorKind::NotFound, "share link is not found")),
+        };
+        self.file_system.metas.delete_link(id).await?;
+        Ok(link.summary())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, sync::Arc};
+
+    use chrono::Utc;
+
+    use crate::{
+        file_system::{
+            perm::{Operation, Principal},
+            test_support::{self, Backend},
+        },
+        utils::ruid::RuidGenerator,
+    };
+
+    use super::{NewShareLink, ShareLinks};
+
+    fn new(ops: &[Operation]) -> NewShareLink {
+        NewShareLink { ops: ops.iter().copied().collect(), expire: None, password: None, max_uses: None }
+    }
+
+    async fn share_links() -> (tempfile::TempDir, ShareLinks, Principal) {
+        let (dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/home/docs/a.txt", b"alpha").await;
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        (dir, ShareLinks::new(fs, Arc::new(RuidGenerator::new(1))).unwrap(), alice)
+    }
+
+    #[tokio::test]
+    async fn grant_covers_path_and_ops() {
+        let (_dir, links, alice) = share_links().await;
+        let (link, token) = links.create("alice", "/home/docs", &alice, new(&[Operation::Read])).await.unwrap();
+        assert_eq!((link.uses, link.expire_time, link.protected), (0, None, false));
+
+        let grant = links.redeem(&token, None, "client:a").await.unwrap();
+        assert_eq!(grant.id, link.id);
+        assert!(grant.covers("alice", "/home/docs", Operation::Read));
+        assert!(grant.covers("alice", "/home/docs/a.txt", Operation::Read));
+        assert!(!grant.covers("alice", "/home/docs2", Operation::Read));
+        assert!(!grant.covers("alice", "/home/docs/a.txt", Operation::Edit));
+        assert!(!grant.covers("bob", "/home/docs", Operation::Read));
+
+        let invalid: [(&[Operation], &str); 3] = [(&[], "/home/docs"), (&[Operation::Read, Operation::Share], "/home/docs"), (&[Operation::Read], "/etc/config.json")];
+        for (ops, path) in invalid {
+            assert_eq!(links.create("alice", path, &alice, new(ops)).await.unwrap_err().kind(), ErrorKind::InvalidInput, "{:?} {}", ops, path);
+        }
+    }
+
+    /// 書き換えたトークンと期限切れのトークンは記録を引く前に断る
+    #[tokio::test]
+    async fn signature_and_expiry() {
+        let (_dir, links, alice) = share_links().await;
+        let (mut link, token) = links.create("alice", "/home/docs", &alice, new(&[Operation::Read])).await.unwrap();
+        let (payload, signature) = token.split_once('.').unwrap();
+        let forged = format!("{}x.{}", payload, signature);
+        assert_eq!(links.redeem(&forged, None, "client:a").await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(links.redeem("not a token", None, "client:a").await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        link.expire_time = Some(Utc::now().timestamp_millis() - 1);
+        let expired = links.sign(&link).unwrap();
+        assert_eq!(links.redeem(&expired, None, "client:a").await.unwrap_err().to_string(), "share link is expired");
+    }
+
+    #[tokio::test]
+    async fn password_uses_and_revoke() {
+        let (_dir, links, alice) = share_links().await;
+        let protected = NewShareLink { password: Some("secret".to_string()), max_uses: Some(2), ..new(&[Operation::Read]) };
+        let (link, token) = links.create("alice", "/home/docs", &alice, protected).await.unwrap();
+        assert!(link.protected && link.summary().password.is_none());
+
+        assert!(links.redeem(&token, None, "client:a").await.is_err());
+        assert!(links.redeem(&token, Some("wrong"), "client:a").await.is_err());
+        links.redeem(&token, Some("secret"), "client:a").await.unwrap();
+        links.redeem(&token, Some("secret"), "client:b").await.unwrap();
+        assert_eq!(links.redeem(&token, Some("secret"), "client:c").await.unwrap_err().to_string(), "share link is used up");
+        assert_eq!(links.list("alice").await.unwrap()[0].uses, 2);
+
+        let (other, token) = links.create("alice", "/home/docs/a.txt", &alice, new(&[Operation::Read])).await.unwrap();
+        assert_eq!(links.revoke("bob", other.id).await.unwrap_err().kind(), ErrorKind::NotFound);
+        links.revoke("alice", other.id).await.unwrap();
+        assert_eq!(links.redeem(&token, None, "client:a").await.unwrap_err().to_string(), "share link is revoked");
+        assert_eq!(links.list("alice").await.unwrap().len(), 1);
+    }
+
+    /// share を許された他のユーザーは、自分に許されていない操作をリンクに含められない
+    #[tokio::test]
+    async fn creator_can_only_share_own_rights() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let bob = 0x2101_0000_0000_0000_0000_0000_0000_0001;
+        fs.metas.bind_user(bob, "bob").await.unwrap();
+        let mut meta = test_support::write(&fs, "alice", "/home/doc.txt", b"shared body that is long").await;
+        meta.perm.read.allow.insert(bob);
+        meta.perm.share.allow.insert(bob);
+        fs.metas.put(&meta).await.unwrap();
+        let links = ShareLinks::new(Arc::clone(&fs), Arc::new(RuidGenerator::new(1))).unwrap();
+
+        let by_bob = fs.principal(Some("bob")).await.unwrap();
+        assert!(links.create("alice", "/home/doc.txt", &by_bob, new(&[Operation::Read])).await.is_ok());
+        for ops in [&[Operation::Read, Operation::Edit][..], &[Operation::Delete], &[Operation::Create]] {
+            let e = links.create("alice", "/home/doc.txt", &by_bob, new(ops)).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{:?}", ops);
+        }
+
+        let by_alice = fs.principal(Some("alice")).await.unwrap();
+        assert!(links.create("alice", "/home/doc.txt", &by_alice, new(&[Operation::Edit, Operation::Delete])).await.is_ok());
+    }
+
+    /// 同じ相手の続けてのアクセスは一回と数え、使い切った後も続けて使える
+    #[tokio::test]
+    async fn counts_once_per_visitor() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/home/doc.txt", b"shared body that is long").await;
+        let links = ShareLinks::new(Arc::clone(&fs), Arc::new(RuidGenerator::new(1))).unwrap();
+        let by_alice = fs.principal(Some("alice")).await.unwrap();
+        let new = NewShareLink { max_uses: Some(1), ..new(&[Operation::Read]) };
+        let (link, token) = links.create("alice", "/home/doc.txt", &by_alice, new).await.unwrap();
+
+        for _ in 0..3 {
+            assert!(links.redeem(&token, None, "client:a").await.is_ok());
+        }
+        let e = links.redeem(&token, None, "client:b").await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs.metas.get_link(link.id).await.unwrap().map(|l| l.uses), Some(1));
+        assert!(links.redeem("broken", None, "client:a").await.is_err());
+    }
+}
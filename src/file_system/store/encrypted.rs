@@ -0,0 +1,66 @@
+use std::{io::Error, path::Path, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::{self, BoxStream}, StreamExt};
+
+use crate::file_system::crypto::KeyRing;
+
+use super::BlobStore;
+
+/// 暗号化したバイナリを透過的に復号する BlobStore
+/// BlobKey のあるバイナリは暗号文として扱い、無いものはそのまま読み出す
+/// 書き込みは暗号化済みのものを受け取る (FileSystem::import_sealed)
+pub struct EncryptedBlobStore {
+    inner: Arc<dyn BlobStore>,
+    ring: Arc<KeyRing>,
+}
+
+impl EncryptedBlobStore {
+    pub fn new(inner: Arc<dyn BlobStore>, ring: Arc<KeyRing>) -> Self {
+        Self { inner, ring }
+    }
+}
+
+#[async_trait]
+impl BlobStore for EncryptedBlobStore {
+    async fn import(&self, key: &str, from: &Path) -> Result<(), Error> {
+        self.inner.import(key, from).await
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), Error> {
+        self.inner.put(key, data).await
+    }
+
+    fn read(&self, key: &str, start: u64, len: u64, chunk_size: usize) -> BoxStream<'static, Result<Bytes, Error>> {
+        let inner = Arc::clone(&self.inner);
+        let ring = Arc::clone(&self.ring);
+        let key = key.to_string();
+        stream::once(async move {
+            match ring.cipher(&key).await {
+                Ok(Some(cipher)) => cipher.decrypt_range(inner, start, len),
+                Ok(None) => inner.read(&key, start, len, chunk_size),
+                Err(e) => stream::once(async move { Err(e) }).boxed(),
+            }
+        })
+        .flatten()
+        .boxed()
+    }
+
+    /// 暗号化したものは平文の大きさ
+    async fn size(&self, key: &str) -> Result<Option<u64>, Error> {
+        match self.inner.size(key).await? {
+            Some(size) => Ok(Some(self.ring.blob_key(key).await?.map_or(size, |k| k.size))),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.inner.delete(key).await?;
+        self.ring.forget(key).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        self.inner.list().await
+    }
+}
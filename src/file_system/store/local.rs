@@ -0,0 +1,554 @@
+use std::{io::{Error, ErrorKind, SeekFrom}, path::{Path, PathBuf}};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::{self, BoxStream}, StreamExt};
+use sled::{transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionalTree}, Transactional};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}};
+
+use crate::file_system::{account::Account, crypto::{BlobKey, UserKey}, meta::MetaData, perm::PermGroup, quota::Usage, share_link::ShareLink, trash::TrashEntry, version::FileVersion};
+
+use super::{BlobStore, MetadataStore};
+
+fn sled_err(e: sled::Error) -> Error {
+    Error::other(e)
+}
+
+fn json_err(e: serde_json::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+fn tx_err(e: TransactionError<Error>) -> Error {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => sled_err(e),
+    }
+}
+
+fn tx_json_err(e: serde_json::Error) -> ConflictableTransactionError<Error> {
+    ConflictableTransactionError::Abort(json_err(e))
+}
+
+/// トランザクションの中で参照数を増減する 本体の書き込みと同時に反映する
+fn add_ref_tx<'a>(refs: &TransactionalTree, blobs: impl IntoIterator<Item = &'a str>, delta: i64) -> ConflictableTransactionResult<(), Error> {
+    for blob in blobs {
+        let count = refs.get(blob.as_bytes())?.and_then(|v| v.as_ref().try_into().ok()).map(u64::from_be_bytes).unwrap_or(0);
+        match (count as i64 + delta).max(0) as u64 {
+            0 => {
+                refs.remove(blob.as_bytes())?;
+            }
+            n => {
+                refs.insert(blob.as_bytes(), &n.to_be_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn path_key(user: &str, path: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(user.len() + path.len() + 1);
+    key.extend_from_slice(user.as_bytes());
+    key.push(0);
+    key.extend_from_slice(path.as_bytes());
+    key
+}
+
+/// user \0 id
+fn user_key_key(user: &str, id: u32) -> Vec<u8> {
+    let mut key = path_key(user, "");
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn version_key(file: u128, version: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(24);
+    key.extend_from_slice(&file.to_be_bytes());
+    key.extend_from_slice(&version.to_be_bytes());
+    key
+}
+
+/// sled による組み込みのメタデータ
+/// metas / paths / trash / versions / groups / links / accounts のツリーに JSON で保存し、users に RUID とユーザー名の対応、usage に使用量を持つ
+/// user_keys / blob_keys は暗号化の鍵
+/// refs はバイナリの参照数で、metas と versions の書き込みと同じトランザクションで増減する
+pub struct LocalMetadataStore {
+    metas: sled::Tree,
+    paths: sled::Tree,
+    trash: sled::Tree,
+    versions: sled::Tree,
+    refs: sled::Tree,
+    users: sled::Tree,
+    accounts: sled::Tree,
+    usage: sled::Tree,
+    groups: sled::Tree,
+    links: sled::Tree,
+    user_keys: sled::Tree,
+    blob_keys: sled::Tree,
+}
+
+impl LocalMetadataStore {
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        let db = sled::open(dir).map_err(sled_err)?;
+        Ok(Self {
+            metas: db.open_tree("metas").map_err(sled_err)?,
+            paths: db.open_tree("paths").map_err(sled_err)?,
+            trash: db.open_tree("trash").map_err(sled_err)?,
+            versions: db.open_tree("versions").map_err(sled_err)?,
+            refs: db.open_tree("refs").map_err(sled_err)?,
+            users: db.open_tree("users").map_err(sled_err)?,
+            accounts: db.open_tree("accounts").map_err(sled_err)?,
+            usage: db.open_tree("usage").map_err(sled_err)?,
+            groups: db.open_tree("groups").map_err(sled_err)?,
+            links: db.open_tree("links").map_err(sled_err)?,
+            user_keys: db.open_tree("user_keys").map_err(sled_err)?,
+            blob_keys: db.open_tree("blob_keys").map_err(sled_err)?,
+        })
+    }
+
+    /// 置き換えたメタデータが参照していたバイナリ
+    fn decode_blobs(value: Option<sled::IVec>) -> ConflictableTransactionResult<Vec<String>, Error> {
+        match value {
+            Some(v) => Ok(serde_json::from_slice::<MetaData>(&v).map_err(tx_json_err)?.blobs().cloned().collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn decode_version_blob(value: Option<sled::IVec>) -> ConflictableTransactionResult<Option<String>, Error> {
+        match value {
+            Some(v) => Ok(serde_json::from_slice::<FileVersion>(&v).map_err(tx_json_err)?.blob),
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl MetadataStore for LocalMetadataStore {
+    async fn get(&self, id: u128) -> Result<Option<MetaData>, Error> {
+        match self.metas.get(id.to_be_bytes()).map_err(sled_err)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v).map_err(json_err)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&self, meta: &MetaData) -> Result<(), Error> {
+        let value = serde_json::to_vec(meta).map_err(json_err)?;
+        (&self.metas, &self.refs).transaction(|(metas, refs)| {
+            let old = metas.insert(&meta.id.to_be_bytes(), value.as_slice())?;
+            add_ref_tx(refs, Self::decode_blobs(old)?.iter().map(String::as_str), -1)?;
+            add_ref_tx(refs, meta.blobs().map(String::as_str), 1)
+        }).map_err(tx_err)
+    }
+
+    async fn delete(&self, id: u128) -> Result<(), Error> {
+        (&self.metas, &self.refs).transaction(|(metas, refs)| {
+            let old = metas.remove(&id.to_be_bytes())?;
+            add_ref_tx(refs, Self::decode_blobs(old)?.iter().map(String::as_str), -1)
+        }).map_err(tx_err)
+    }
+
+    async fn scan(&self) -> Result<Vec<MetaData>, Error> {
+        self.metas.iter()
+            .map(|r| {
+                let (_, v) = r.map_err(sled_err)?;
+                serde_json::from_slice(&v).map_err(json_err)
+            })
+            .collect()
+    }
+
+    async fn count_blob_refs(&self, blob: &str) -> Result<u64, Error> {
+        match self.refs.get(blob.as_bytes()).map_err(sled_err)? {
+            Some(v) => {
+                let bytes: [u8; 8] = v.as_ref().try_into().map_err(|_| Error::new(ErrorKind::InvalidData, "broken reference count"))?;
+                Ok(u64::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    async fn resolve(&self, user: &str, path: &str) -> Result<Option<u128>, Error> {
+        match self.paths.get(path_key(user, path)).map_err(sled_err)? {
+            Some(v) => {
+                let bytes: [u8; 16] = v.as_ref().try_into().map_err(|_| Error::new(ErrorKind::InvalidData, "broken path index"))?;
+                Ok(Some(u128::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn attach(&self, user: &str, path: &str, id: u128) -> Result<(), Error> {
+        self.paths.insert(path_key(user, path), &id.to_be_bytes()).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn detach(&self, user: &str, path: &str) -> Result<(), Error> {
+        self.paths.remove(path_key(user, path)).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn get_trash(&self, id: u128) -> Result<Option<TrashEntry>, Error> {
+        match self.trash.get(id.to_be_bytes()).map_err(sled_err)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v).map_err(json_err)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_trash(&self, entry: &TrashEntry) -> Result<(), Error> {
+        let value = serde_json::to_vec(entry).map_err(json_err)?;
+        self.trash.insert(entry.id.to_be_bytes(), value).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn delete_trash(&self, id: u128) -> Result<(), Error> {
+        self.trash.remove(id.to_be_bytes()).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn list_trash(&self, user: Option<&str>) -> Result<Vec<TrashEntry>, Error> {
+        let mut list = Vec::new();
+        for r in self.trash.iter() {
+            let (_, v) = r.map_err(sled_err)?;
+            let entry: TrashEntry = serde_json::from_slice(&v).map_err(json_err)?;
+            if user.is_none_or(|u| entry.user == u) {
+                list.push(entry);
+            }
+        }
+        Ok(list)
+    }
+
+    async fn put_version(&self, version: &FileVersion) -> Result<(), Error> {
+        let value = serde_json::to_vec(version).map_err(json_err)?;
+        let key = version_key(version.file, version.version);
+        (&self.versions, &self.refs).transaction(|(versions, refs)| {
+            let old = versions.insert(key.as_slice(), value.as_slice())?;
+            add_ref_tx(refs, Self::decode_version_blob(old)?.as_deref(), -1)?;
+            add_ref_tx(refs, version.blob.as_deref(), 1)
+        }).map_err(tx_err)
+    }
+
+    async fn delete_version(&self, file: u128, version: u64) -> Result<(), Error> {
+        let key = version_key(file, version);
+        (&self.versions, &self.refs).transaction(|(versions, refs)| {
+            let old = versions.remove(key.as_slice())?;
+            add_ref_tx(refs, Self::decode_version_blob(old)?.as_deref(), -1)
+        }).map_err(tx_err)
+    }
+
+    async fn list_versions(&self, file: Option<u128>) -> Result<Vec<FileVersion>, Error> {
+        let iter = match file {
+            Some(file) => self.versions.scan_prefix(file.to_be_bytes()),
+            None => self.versions.iter(),
+        };
+        iter.map(|r| {
+                let (_, v) = r.map_err(sled_err)?;
+                serde_json::from_slice(&v).map_err(json_err)
+            })
+            .collect()
+    }
+
+    async fn bind_user(&self, ruid: u128, user: &str) -> Result<(), Error> {
+        self.users.insert(ruid.to_be_bytes(), user.as_bytes()).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn user_by_ruid(&self, ruid: u128) -> Result<Option<String>, Error> {
+        match self.users.get(ruid.to_be_bytes()).map_err(sled_err)? {
+            Some(v) => Ok(Some(String::from_utf8(v.to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn ruid_by_user(&self, user: &str) -> Result<Option<u128>, Error> {
+        for r in self.users.iter() {
+            let (k, v) = r.map_err(sled_err)?;
+            if v.as_ref() == user.as_bytes() {
+                let ruid = k.as_ref().try_into().map_err(|_| Error::new(ErrorKind::InvalidData, "broken user index"))?;
+                return Ok(Some(u128::from_be_bytes(ruid)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn get_group(&self, id: u128) -> Result<Option<PermGroup>, Error> {
+        match self.groups.get(id.to_be_bytes()).map_err(sled_err)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v).map_err(json_err)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_group(&self, group: &PermGroup) -> Result<(), Error> {
+        let value = serde_json::to_vec(group).map_err(json_err)?;
+        self.groups.insert(group.id.to_be_bytes(), value).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn delete_group(&self, id: u128) -> Result<(), Error> {
+        self.groups.remove(id.to_be_bytes()).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn groups_with_member(&self, member: u128) -> Result<Vec<PermGroup>, Error> {
+        let mut list = Vec::new();
+        for r in self.groups.iter() {
+            let (_, v) = r.map_err(sled_err)?;
+            let group: PermGroup = serde_json::from_slice(&v).map_err(json_err)?;
+            if group.members.contains(&member) {
+                list.push(group);
+            }
+        }
+        Ok(list)
+    }
+
+    async fn list_groups(&self, owner: Option<&str>) -> Result<Vec<PermGroup>, Error> {
+        let mut list = Vec::new();
+        for r in self.groups.iter() {
+            let (_, v) = r.map_err(sled_err)?;
+            let group: PermGroup = serde_json::from_slice(&v).map_err(json_err)?;
+            if owner.is_none_or(|o| group.owner == o) {
+                list.push(group);
+            }
+        }
+        Ok(list)
+    }
+
+    async fn get_link(&self, id: u128) -> Result<Option<ShareLink>, Error> {
+        match self.links.get(id.to_be_bytes()).map_err(sled_err)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v).map_err(json_err)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_link(&self, link: &ShareLink) -> Result<(), Error> {
+        let value = serde_json::to_vec(link).map_err(json_err)?;
+        self.links.insert(link.id.to_be_bytes(), value).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn delete_link(&self, id: u128) -> Result<(), Error> {
+        self.links.remove(id.to_be_bytes()).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn list_links(&self, user: Option<&str>) -> Result<Vec<ShareLink>, Error> {
+        let mut list = Vec::new();
+        for r in self.links.iter() {
+            let (_, v) = r.map_err(sled_err)?;
+            let link: ShareLink = serde_json::from_slice(&v).map_err(json_err)?;
+            if user.is_none_or(|u| link.user == u) {
+                list.push(link);
+            }
+        }
+        Ok(list)
+    }
+
+    async fn get_account(&self, user: &str) -> Result<Option<Account>, Error> {
+        match self.accounts.get(user.as_bytes()).map_err(sled_err)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v).map_err(json_err)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_account(&self, account: &Account) -> Result<(), Error> {
+        let value = serde_json::to_vec(account).map_err(json_err)?;
+        self.accounts.insert(account.user.as_bytes(), value).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn get_usage(&self, user: &str) -> Result<Option<Usage>, Error> {
+        match self.usage.get(user.as_bytes()).map_err(sled_err)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v).map_err(json_err)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_usage(&self, usage: &Usage) -> Result<(), Error> {
+        let value = serde_json::to_vec(usage).map_err(json_err)?;
+        self.usage.insert(usage.user.as_bytes(), value).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn list_usage(&self) -> Result<Vec<Usage>, Error> {
+        self.usage.iter()
+            .map(|r| {
+                let (_, v) = r.map_err(sled_err)?;
+                serde_json::from_slice(&v).map_err(json_err)
+            })
+            .collect()
+    }
+
+    async fn put_user_key(&self, key: &UserKey) -> Result<(), Error> {
+        let value = serde_json::to_vec(key).map_err(json_err)?;
+        self.user_keys.insert(user_key_key(&key.user, key.id), value).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn delete_user_key(&self, user: &str, id: u32) -> Result<(), Error> {
+        self.user_keys.remove(user_key_key(user, id)).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn list_user_keys(&self, user: Option<&str>) -> Result<Vec<UserKey>, Error> {
+        let iter = match user {
+            Some(user) => self.user_keys.scan_prefix(path_key(user, "")),
+            None => self.user_keys.iter(),
+        };
+        iter.map(|r| {
+                let (_, v) = r.map_err(sled_err)?;
+                serde_json::from_slice(&v).map_err(json_err)
+            })
+            .collect()
+    }
+
+    async fn get_blob_key(&self, blob: &str) -> Result<Option<BlobKey>, Error> {
+        match self.blob_keys.get(blob.as_bytes()).map_err(sled_err)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v).map_err(json_err)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_blob_key(&self, key: &BlobKey) -> Result<(), Error> {
+        let value = serde_json::to_vec(key).map_err(json_err)?;
+        self.blob_keys.insert(key.blob.as_bytes(), value).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn delete_blob_key(&self, blob: &str) -> Result<(), Error> {
+        self.blob_keys.remove(blob.as_bytes()).map_err(sled_err)?;
+        Ok(())
+    }
+
+    async fn list_blob_keys(&self, user: Option<&str>) -> Result<Vec<BlobKey>, Error> {
+        let mut list = Vec::new();
+        for r in self.blob_keys.iter() {
+            let (_, v) = r.map_err(sled_err)?;
+            let key: BlobKey = serde_json::from_slice(&v).map_err(json_err)?;
+            if user.is_none_or(|u| key.user == u) {
+                list.push(key);
+            }
+        }
+        Ok(list)
+    }
+}
+
+/// OS のファイルシステムにバイナリを保存する
+/// キーの先頭4文字で `ab/cd/<key>` に分散させる
+pub struct LocalBlobStore {
+    dir: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        if key.len() < 4 || !key.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid blob key"));
+        }
+        Ok(self.dir.join(&key[0..2]).join(&key[2..4]).join(key))
+    }
+
+    async fn prepare(&self, key: &str) -> Result<PathBuf, Error> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(path)
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn import(&self, key: &str, from: &Path) -> Result<(), Error> {
+        let path = self.prepare(key).await?;
+        if tokio::fs::rename(from, &path).await.is_err() {
+            // 別のデバイスの場合はコピーする
+            tokio::fs::copy(from, &path).await?;
+            tokio::fs::remove_file(from).await?;
+        }
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), Error> {
+        let path = self.prepare(key).await?;
+        tokio::fs::write(path, data).await
+    }
+
+    fn read(&self, key: &str, start: u64, len: u64, chunk_size: usize) -> BoxStream<'static, Result<Bytes, Error>> {
+        match self.path(key) {
+            Ok(path) => range_stream(path, start, len, chunk_size).boxed(),
+            Err(e) => stream::once(async { Err(e) }).boxed(),
+        }
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, Error> {
+        match tokio::fs::metadata(self.path(key)?).await {
+            Ok(meta) => Ok(Some(meta.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut stack = vec![self.dir.clone()];
+        while let Some(dir) = stack.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() {
+                    stack.push(entry.path());
+                } else if let Some(name) = entry.file_name().to_str() {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// start から len バイトを chunk_size ごとに読み出す
+pub fn range_stream(file_path: PathBuf, start: u64, len: u64, chunk_size: usize) -> impl futures::Stream<Item = Result<Bytes, Error>> {
+    let chunk_size = chunk_size.max(1);
+    stream::unfold((None::<File>, file_path, len), move |(file, file_path, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+
+        let mut file = match file {
+            Some(file) => file,
+            None => {
+                let opened = async {
+                    let mut file = File::open(&file_path).await?;
+                    file.seek(SeekFrom::Start(start)).await?;
+                    Ok::<_, Error>(file)
+                };
+                match opened.await {
+                    Ok(file) => file,
+                    Err(e) => return Some((Err(e), (None, file_path, 0))),
+                }
+            }
+        };
+
+        let mut buffer = vec![0; chunk_size.min(remaining as usize)];
+        match file.read(&mut buffer).await {
+            Ok(0) => Some((
+                Err(Error::new(ErrorKind::UnexpectedEof, "file is shorter than expected")),
+                (None, file_path, 0),
+            )),
+            Ok(n) => {
+                buffer.truncate(n);
+                Some((Ok(Bytes::from(buffer)), (Some(file), file_path, remaining - n as u64)))
+            }
+            Err(e) => Some((Err(e), (None, file_path, 0))),
+        }
+    })
+}
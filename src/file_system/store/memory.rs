@@ -0,0 +1,335 @@
+use std::{collections::HashMap, io::{Error, ErrorKind}, path::Path, sync::{RwLock, RwLockReadGuard, RwLockWriteGuard}};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::{self, BoxStream}, StreamExt};
+
+use crate::file_system::{account::Account, crypto::{BlobKey, UserKey}, meta::MetaData, perm::PermGroup, quota::Usage, share_link::ShareLink, trash::TrashEntry, version::FileVersion};
+
+use super::{BlobStore, MetadataStore};
+
+fn poisoned() -> Error {
+    Error::other("memory store is poisoned")
+}
+
+#[derive(Default)]
+struct Tables {
+    metas: HashMap<u128, MetaData>,
+    paths: HashMap<(String, String), u128>,
+    trash: HashMap<u128, TrashEntry>,
+    versions: HashMap<(u128, u64), FileVersion>,
+    /// blob -> 参照しているメタデータと版の数
+    refs: HashMap<String, u64>,
+    users: HashMap<u128, String>,
+    accounts: HashMap<String, Account>,
+    usage: HashMap<String, Usage>,
+    groups: HashMap<u128, PermGroup>,
+    links: HashMap<u128, ShareLink>,
+    user_keys: HashMap<(String, u32), UserKey>,
+    blob_keys: HashMap<String, BlobKey>,
+}
+
+impl Tables {
+    fn add_ref<'a>(&mut self, blobs: impl IntoIterator<Item = &'a String>, delta: i64) {
+        for blob in blobs {
+            let count = self.refs.entry(blob.clone()).or_insert(0);
+            *count = (*count as i64 + delta).max(0) as u64;
+            if *count == 0 {
+                self.refs.remove(blob);
+            }
+        }
+    }
+}
+
+/// プロセス内だけのメタデータ 再起動で消える
+pub struct MemoryMetadataStore {
+    tables: RwLock<Tables>,
+}
+
+impl MemoryMetadataStore {
+    pub fn new() -> Self {
+        Self {
+            tables: RwLock::new(Tables::default()),
+        }
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, Tables>, Error> {
+        self.tables.read().map_err(|_| poisoned())
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, Tables>, Error> {
+        self.tables.write().map_err(|_| poisoned())
+    }
+}
+
+#[async_trait]
+impl MetadataStore for MemoryMetadataStore {
+    async fn get(&self, id: u128) -> Result<Option<MetaData>, Error> {
+        Ok(self.read()?.metas.get(&id).cloned())
+    }
+
+    async fn put(&self, meta: &MetaData) -> Result<(), Error> {
+        let mut tables = self.write()?;
+        let old = tables.metas.insert(meta.id, meta.clone());
+        if let Some(old) = &old {
+            tables.add_ref(old.blobs(), -1);
+        }
+        tables.add_ref(meta.blobs(), 1);
+        Ok(())
+    }
+
+    async fn delete(&self, id: u128) -> Result<(), Error> {
+        let mut tables = self.write()?;
+        let old = tables.metas.remove(&id);
+        if let Some(old) = &old {
+            tables.add_ref(old.blobs(), -1);
+        }
+        Ok(())
+    }
+
+    async fn scan(&self) -> Result<Vec<MetaData>, Error> {
+        Ok(self.read()?.metas.values().cloned().collect())
+    }
+
+    async fn count_blob_refs(&self, blob: &str) -> Result<u64, Error> {
+        Ok(self.read()?.refs.get(blob).copied().unwrap_or(0))
+    }
+
+    async fn resolve(&self, user: &str, path: &str) -> Result<Option<u128>, Error> {
+        Ok(self.read()?.paths.get(&(user.to_string(), path.to_string())).copied())
+    }
+
+    async fn attach(&self, user: &str, path: &str, id: u128) -> Result<(), Error> {
+        self.write()?.paths.insert((user.to_string(), path.to_string()), id);
+        Ok(())
+    }
+
+    async fn detach(&self, user: &str, path: &str) -> Result<(), Error> {
+        self.write()?.paths.remove(&(user.to_string(), path.to_string()));
+        Ok(())
+    }
+
+    async fn get_trash(&self, id: u128) -> Result<Option<TrashEntry>, Error> {
+        Ok(self.read()?.trash.get(&id).cloned())
+    }
+
+    async fn put_trash(&self, entry: &TrashEntry) -> Result<(), Error> {
+        self.write()?.trash.insert(entry.id, entry.clone());
+        Ok(())
+    }
+
+    async fn delete_trash(&self, id: u128) -> Result<(), Error> {
+        self.write()?.trash.remove(&id);
+        Ok(())
+    }
+
+    async fn list_trash(&self, user: Option<&str>) -> Result<Vec<TrashEntry>, Error> {
+        Ok(self.read()?.trash.values()
+            .filter(|t| user.is_none_or(|u| t.user == u))
+            .cloned()
+            .collect())
+    }
+
+    async fn put_version(&self, version: &FileVersion) -> Result<(), Error> {
+        let mut tables = self.write()?;
+        let old = tables.versions.insert((version.file, version.version), version.clone());
+        tables.add_ref(old.as_ref().and_then(|v| v.blob.as_ref()), -1);
+        tables.add_ref(version.blob.as_ref(), 1);
+        Ok(())
+    }
+
+    async fn delete_version(&self, file: u128, version: u64) -> Result<(), Error> {
+        let mut tables = self.write()?;
+        let old = tables.versions.remove(&(file, version));
+        tables.add_ref(old.as_ref().and_then(|v| v.blob.as_ref()), -1);
+        Ok(())
+    }
+
+    async fn list_versions(&self, file: Option<u128>) -> Result<Vec<FileVersion>, Error> {
+        Ok(self.read()?.versions.values()
+            .filter(|v| file.is_none_or(|f| v.file == f))
+            .cloned()
+            .collect())
+    }
+
+    async fn bind_user(&self, ruid: u128, user: &str) -> Result<(), Error> {
+        self.write()?.users.insert(ruid, user.to_string());
+        Ok(())
+    }
+
+    async fn user_by_ruid(&self, ruid: u128) -> Result<Option<String>, Error> {
+        Ok(self.read()?.users.get(&ruid).cloned())
+    }
+
+    async fn ruid_by_user(&self, user: &str) -> Result<Option<u128>, Error> {
+        Ok(self.read()?.users.iter().find(|(_, u)| u.as_str() == user).map(|(ruid, _)| *ruid))
+    }
+
+    async fn get_group(&self, id: u128) -> Result<Option<PermGroup>, Error> {
+        Ok(self.read()?.groups.get(&id).cloned())
+    }
+
+    async fn put_group(&self, group: &PermGroup) -> Result<(), Error> {
+        self.write()?.groups.insert(group.id, group.clone());
+        Ok(())
+    }
+
+    async fn delete_group(&self, id: u128) -> Result<(), Error> {
+        self.write()?.groups.remove(&id);
+        Ok(())
+    }
+
+    async fn groups_with_member(&self, member: u128) -> Result<Vec<PermGroup>, Error> {
+        Ok(self.read()?.groups.values()
+            .filter(|g| g.members.contains(&member))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_groups(&self, owner: Option<&str>) -> Result<Vec<PermGroup>, Error> {
+        Ok(self.read()?.groups.values()
+            .filter(|g| owner.is_none_or(|o| g.owner == o))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_link(&self, id: u128) -> Result<Option<ShareLink>, Error> {
+        Ok(self.read()?.links.get(&id).cloned())
+    }
+
+    async fn put_link(&self, link: &ShareLink) -> Result<(), Error> {
+        self.write()?.links.insert(link.id, link.clone());
+        Ok(())
+    }
+
+    async fn delete_link(&self, id: u128) -> Result<(), Error> {
+        self.write()?.links.remove(&id);
+        Ok(())
+    }
+
+    async fn list_links(&self, user: Option<&str>) -> Result<Vec<ShareLink>, Error> {
+        Ok(self.read()?.links.values()
+            .filter(|l| user.is_none_or(|u| l.user == u))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_account(&self, user: &str) -> Result<Option<Account>, Error> {
+        Ok(self.read()?.accounts.get(user).cloned())
+    }
+
+    async fn put_account(&self, account: &Account) -> Result<(), Error> {
+        self.write()?.accounts.insert(account.user.clone(), account.clone());
+        Ok(())
+    }
+
+    async fn get_usage(&self, user: &str) -> Result<Option<Usage>, Error> {
+        Ok(self.read()?.usage.get(user).cloned())
+    }
+
+    async fn put_usage(&self, usage: &Usage) -> Result<(), Error> {
+        self.write()?.usage.insert(usage.user.clone(), usage.clone());
+        Ok(())
+    }
+
+    async fn list_usage(&self) -> Result<Vec<Usage>, Error> {
+        Ok(self.read()?.usage.values().cloned().collect())
+    }
+
+    async fn put_user_key(&self, key: &UserKey) -> Result<(), Error> {
+        self.write()?.user_keys.insert((key.user.clone(), key.id), key.clone());
+        Ok(())
+    }
+
+    async fn delete_user_key(&self, user: &str, id: u32) -> Result<(), Error> {
+        self.write()?.user_keys.remove(&(user.to_string(), id));
+        Ok(())
+    }
+
+    async fn list_user_keys(&self, user: Option<&str>) -> Result<Vec<UserKey>, Error> {
+        Ok(self.read()?.user_keys.values()
+            .filter(|k| user.is_none_or(|u| k.user == u))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_blob_key(&self, blob: &str) -> Result<Option<BlobKey>, Error> {
+        Ok(self.read()?.blob_keys.get(blob).cloned())
+    }
+
+    async fn put_blob_key(&self, key: &BlobKey) -> Result<(), Error> {
+        self.write()?.blob_keys.insert(key.blob.clone(), key.clone());
+        Ok(())
+    }
+
+    async fn delete_blob_key(&self, blob: &str) -> Result<(), Error> {
+        self.write()?.blob_keys.remove(blob);
+        Ok(())
+    }
+
+    async fn list_blob_keys(&self, user: Option<&str>) -> Result<Vec<BlobKey>, Error> {
+        Ok(self.read()?.blob_keys.values()
+            .filter(|k| user.is_none_or(|u| k.user == u))
+            .cloned()
+            .collect())
+    }
+}
+
+/// プロセス内だけのバイナリ 再起動で消える
+pub struct MemoryBlobStore {
+    blobs: RwLock<HashMap<String, Bytes>>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Self {
+            blobs: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for MemoryBlobStore {
+    async fn import(&self, key: &str, from: &Path) -> Result<(), Error> {
+        let data = tokio::fs::read(from).await?;
+        tokio::fs::remove_file(from).await?;
+        self.put(key, Bytes::from(data)).await
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), Error> {
+        self.blobs.write().map_err(|_| poisoned())?.insert(key.to_string(), data);
+        Ok(())
+    }
+
+    fn read(&self, key: &str, start: u64, len: u64, chunk_size: usize) -> BoxStream<'static, Result<Bytes, Error>> {
+        let data = match self.blobs.read() {
+            Ok(blobs) => blobs.get(key).cloned(),
+            Err(_) => return stream::once(async { Err(poisoned()) }).boxed(),
+        };
+        let data = match data {
+            Some(data) if start + len <= data.len() as u64 => data.slice(start as usize..(start + len) as usize),
+            Some(_) => return stream::once(async { Err(Error::new(ErrorKind::UnexpectedEof, "blob is shorter than expected")) }).boxed(),
+            None => return stream::once(async { Err(Error::new(ErrorKind::NotFound, "blob is not found")) }).boxed(),
+        };
+
+        let chunk_size = chunk_size.max(1);
+        let chunks: Vec<Result<Bytes, Error>> = (0..data.len())
+            .step_by(chunk_size)
+            .map(|i| Ok(data.slice(i..(i + chunk_size).min(data.len()))))
+            .collect();
+        stream::iter(chunks).boxed()
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, Error> {
+        Ok(self.blobs.read().map_err(|_| poisoned())?.get(key).map(|b| b.len() as u64))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.blobs.write().map_err(|_| poisoned())?.remove(key);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(self.blobs.read().map_err(|_| poisoned())?.keys().cloned().collect())
+    }
+}
@@ -0,0 +1,201 @@
+use std::{io::Error, path::Path, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use log::info;
+use serde::Deserialize;
+
+use super::{account::Account, config::FileSystemConfig, crypto::{BlobKey, UserKey}, meta::MetaData, perm::PermGroup, quota::Usage, share_link::ShareLink, trash::TrashEntry, version::FileVersion};
+
+pub mod encrypted;
+pub mod local;
+pub mod memory;
+pub mod mongo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Memory,
+    Local,
+    Mongodb,
+}
+
+/// メタデータの保存先
+/// メタデータ本体 (id -> MetaData)、パス索引 ((user, path) -> id)、ゴミ箱、ファイルの古い版、ユーザーの RUID 索引とアカウントと使用量、権限グループ、共有リンク、暗号化の鍵を持つ
+/// ゴミ箱に入ったメタデータはパス索引から外れるだけで本体は残る
+#[async_trait]
+pub trait MetadataStore: Send + Sync {
+    async fn get(&self, id: u128) -> Result<Option<MetaData>, Error>;
+    async fn put(&self, meta: &MetaData) -> Result<(), Error>;
+    async fn delete(&self, id: u128) -> Result<(), Error>;
+    async fn scan(&self) -> Result<Vec<MetaData>, Error>;
+    /// blob を参照しているメタデータと古い版の数 (ゴミ箱の中も含む)
+    async fn count_blob_refs(&self, blob: &str) -> Result<u64, Error>;
+
+    async fn resolve(&self, user: &str, path: &str) -> Result<Option<u128>, Error>;
+    async fn attach(&self, user: &str, path: &str, id: u128) -> Result<(), Error>;
+    async fn detach(&self, user: &str, path: &str) -> Result<(), Error>;
+
+    async fn get_trash(&self, id: u128) -> Result<Option<TrashEntry>, Error>;
+    async fn put_trash(&self, entry: &TrashEntry) -> Result<(), Error>;
+    async fn delete_trash(&self, id: u128) -> Result<(), Error>;
+    async fn list_trash(&self, user: Option<&str>) -> Result<Vec<TrashEntry>, Error>;
+
+    async fn put_version(&self, version: &FileVersion) -> Result<(), Error>;
+    async fn delete_version(&self, file: u128, version: u64) -> Result<(), Error>;
+    /// file が None の場合は全てのファイルの版
+    async fn list_versions(&self, file: Option<u128>) -> Result<Vec<FileVersion>, Error>;
+
+    /// ユーザーの RUID とユーザー名の対応
+    async fn bind_user(&self, ruid: u128, user: &str) -> Result<(), Error>;
+    async fn user_by_ruid(&self, ruid: u128) -> Result<Option<String>, Error>;
+    async fn ruid_by_user(&self, user: &str) -> Result<Option<u128>, Error>;
+
+    /// ログインできるユーザー
+    async fn get_account(&self, user: &str) -> Result<Option<Account>, Error>;
+    async fn put_account(&self, account: &Account) -> Result<(), Error>;
+
+    /// 権限グループ (0x22**)
+    async fn get_group(&self, id: u128) -> Result<Option<PermGroup>, Error>;
+    async fn put_group(&self, group: &PermGroup) -> Result<(), Error>;
+    async fn delete_group(&self, id: u128) -> Result<(), Error>;
+    /// member を直接含むグループ
+    async fn groups_with_member(&self, member: u128) -> Result<Vec<PermGroup>, Error>;
+    async fn list_groups(&self, owner: Option<&str>) -> Result<Vec<PermGroup>, Error>;
+
+    async fn get_link(&self, id: u128) -> Result<Option<ShareLink>, Error>;
+    async fn put_link(&self, link: &ShareLink) -> Result<(), Error>;
+    async fn delete_link(&self, id: u128) -> Result<(), Error>;
+    async fn list_links(&self, user: Option<&str>) -> Result<Vec<ShareLink>, Error>;
+
+    async fn get_usage(&self, user: &str) -> Result<Option<Usage>, Error>;
+    async fn put_usage(&self, usage: &Usage) -> Result<(), Error>;
+    async fn list_usage(&self) -> Result<Vec<Usage>, Error>;
+
+    /// マスターキーで包んだユーザーの鍵
+    async fn put_user_key(&self, key: &UserKey) -> Result<(), Error>;
+    async fn delete_user_key(&self, user: &str, id: u32) -> Result<(), Error>;
+    async fn list_user_keys(&self, user: Option<&str>) -> Result<Vec<UserKey>, Error>;
+
+    /// 暗号化したバイナリのデータキー
+    async fn get_blob_key(&self, blob: &str) -> Result<Option<BlobKey>, Error>;
+    async fn put_blob_key(&self, key: &BlobKey) -> Result<(), Error>;
+    async fn delete_blob_key(&self, blob: &str) -> Result<(), Error>;
+    async fn list_blob_keys(&self, user: Option<&str>) -> Result<Vec<BlobKey>, Error>;
+}
+
+/// バイナリの保存先
+/// バイナリは書き込み後に変更しない 上書きは別のキーに書いて付け替える
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// 一時ファイルを取り込む 取り込んだ後の一時ファイルは残らない
+    async fn import(&self, key: &str, from: &Path) -> Result<(), Error>;
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), Error>;
+    /// start から len バイトを chunk_size ごとに読み出す
+    fn read(&self, key: &str, start: u64, len: u64, chunk_size: usize) -> BoxStream<'static, Result<Bytes, Error>>;
+    async fn size(&self, key: &str) -> Result<Option<u64>, Error>;
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+    async fn list(&self) -> Result<Vec<String>, Error>;
+}
+
+pub async fn open(config: &FileSystemConfig, root: &Path) -> Result<(Arc<dyn MetadataStore>, Arc<dyn BlobStore>), Error> {
+    let metas: Arc<dyn MetadataStore> = match config.metadata_store {
+        StoreKind::Memory => Arc::new(memory::MemoryMetadataStore::new()),
+        StoreKind::Local => Arc::new(local::LocalMetadataStore::open(&root.join("meta"))?),
+        StoreKind::Mongodb => Arc::new(mongo::MongoMetadataStore::connect(&config.mongodb_uri, &config.mongodb_database).await?),
+    };
+
+    let blobs: Arc<dyn BlobStore> = match config.blob_store {
+        StoreKind::Memory => Arc::new(memory::MemoryBlobStore::new()),
+        StoreKind::Local => Arc::new(local::LocalBlobStore::open(&root.join("blob"))?),
+        StoreKind::Mongodb => return Err(Error::new(std::io::ErrorKind::InvalidInput, "mongodb can't be used as blob store")),
+    };
+
+    info!("metadata store: {:?}, blob store: {:?}", config.metadata_store, config.blob_store);
+    Ok((metas, blobs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+
+    use crate::{file_system::{file_system::FileSystem, meta::MetaData, test_support::{self, BACKENDS}, trash::TrashEntry}, utils::ruid::RuidGenerator};
+
+    #[tokio::test]
+    async fn metadata_store() {
+        for backend in BACKENDS {
+            let (_dir, fs) = test_support::file_system(backend).await;
+            let metas = &fs.metas;
+            let mut meta = MetaData::new(1, "alice", "/a.txt", "text/plain".to_string(), 1);
+            meta.blob = Some("abcd01".to_string());
+            metas.put(&meta).await.unwrap();
+            metas.attach("alice", "/a.txt", meta.id).await.unwrap();
+            assert_eq!(metas.get(1).await.unwrap().unwrap().path, "/a.txt", "{:?}", backend);
+            assert_eq!(metas.resolve("alice", "/a.txt").await.unwrap(), Some(1), "{:?}", backend);
+            assert_eq!(metas.resolve("bob", "/a.txt").await.unwrap(), None, "{:?}", backend);
+            assert_eq!(metas.count_blob_refs("abcd01").await.unwrap(), 1, "{:?}", backend);
+
+            // ゴミ箱に入れてもパス索引から外れるだけで本体は残る
+            metas.detach("alice", "/a.txt").await.unwrap();
+            metas.put_trash(&TrashEntry { id: 1, user: "alice".to_string(), path: "/a.txt".to_string(), count: 1, size: 0, deleted_time: 0, expire_time: 0 }).await.unwrap();
+            assert_eq!(metas.resolve("alice", "/a.txt").await.unwrap(), None, "{:?}", backend);
+            assert_eq!(metas.count_blob_refs("abcd01").await.unwrap(), 1, "{:?}", backend);
+            assert_eq!(metas.list_trash(Some("alice")).await.unwrap().len(), 1, "{:?}", backend);
+            assert!(metas.list_trash(Some("bob")).await.unwrap().is_empty(), "{:?}", backend);
+
+            metas.delete_trash(1).await.unwrap();
+            metas.delete(1).await.unwrap();
+            assert!(metas.get_trash(1).await.unwrap().is_none(), "{:?}", backend);
+            assert!(metas.scan().await.unwrap().is_empty(), "{:?}", backend);
+
+            metas.bind_user(0xab, "alice").await.unwrap();
+            assert_eq!(metas.user_by_ruid(0xab).await.unwrap().as_deref(), Some("alice"), "{:?}", backend);
+            assert_eq!(metas.user_by_ruid(0xcd).await.unwrap(), None, "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn blob_store() {
+        for backend in BACKENDS {
+            let (dir, fs) = test_support::file_system(backend).await;
+            let blobs = &fs.blobs;
+            blobs.put("abcd01", Bytes::from_static(b"hello world")).await.unwrap();
+            let temp = dir.path().join("temp");
+            std::fs::write(&temp, b"imported").unwrap();
+            blobs.import("abcd02", &temp).await.unwrap();
+            assert!(!temp.exists(), "{:?}", backend);
+
+            let chunks: Vec<Bytes> = blobs.read("abcd01", 3, 5, 2).try_collect().await.unwrap();
+            assert_eq!(chunks, [&b"lo"[..], b" w", b"o"], "{:?}", backend);
+            assert_eq!(blobs.size("abcd02").await.unwrap(), Some(8), "{:?}", backend);
+            let mut keys = blobs.list().await.unwrap();
+            keys.sort();
+            assert_eq!(keys, ["abcd01", "abcd02"], "{:?}", backend);
+
+            blobs.delete("abcd01").await.unwrap();
+            blobs.delete("abcd01").await.unwrap();
+            assert_eq!(blobs.size("abcd01").await.unwrap(), None, "{:?}", backend);
+            assert!(blobs.read("abcd01", 0, 1, 1).try_collect::<Vec<_>>().await.is_err(), "{:?}", backend);
+        }
+    }
+
+    /// local は開き直しても内容が残る
+    #[tokio::test]
+    async fn local_store_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_support::config(dir.path(), test_support::Backend::Local);
+        let meta = {
+            let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap();
+            test_support::write(&fs, "alice", "/docs/a.txt", b"alpha is longer than 16").await
+        };
+
+        let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap();
+        let reopened = fs.get("alice", "/docs/a.txt").await.unwrap();
+        assert_eq!((reopened.id, reopened.size), (meta.id, 23));
+        assert_eq!(fs.blobs.size(reopened.blob.as_deref().unwrap()).await.unwrap(), Some(23));
+    }
+}
@@ -0,0 +1,300 @@
+use std::io::{Error, ErrorKind};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::info;
+use mongodb::{bson::{doc, Document}, options::{IndexOptions, ReplaceOptions, UpdateOptions}, Client, Collection, IndexModel};
+
+use crate::file_system::{account::Account, crypto::{BlobKey, UserKey}, meta::MetaData, perm::PermGroup, quota::Usage, share_link::ShareLink, trash::TrashEntry, version::FileVersion};
+
+use super::MetadataStore;
+
+fn mongo_err(e: mongodb::error::Error) -> Error {
+    Error::other(e)
+}
+
+fn hex(id: u128) -> String {
+    format!("{:032x}", id)
+}
+
+/// MongoDB のメタデータ
+/// `meta` / `path` / `trash` / `version` / `user` / `account` / `usage` / `group` / `share_link` / `user_key` / `blob_key` コレクションを使う
+pub struct MongoMetadataStore {
+    metas: Collection<MetaData>,
+    paths: Collection<Document>,
+    trash: Collection<TrashEntry>,
+    versions: Collection<FileVersion>,
+    users: Collection<Document>,
+    accounts: Collection<Account>,
+    usage: Collection<Usage>,
+    groups: Collection<PermGroup>,
+    links: Collection<ShareLink>,
+    user_keys: Collection<UserKey>,
+    blob_keys: Collection<BlobKey>,
+}
+
+impl MongoMetadataStore {
+    pub async fn connect(uri: &str, database: &str) -> Result<Self, Error> {
+        let client = Client::with_uri_str(uri).await.map_err(mongo_err)?;
+        let db = client.database(database);
+        let store = Self {
+            metas: db.collection("meta"),
+            paths: db.collection("path"),
+            trash: db.collection("trash"),
+            versions: db.collection("version"),
+            users: db.collection("user"),
+            accounts: db.collection("account"),
+            usage: db.collection("usage"),
+            groups: db.collection("group"),
+            links: db.collection("share_link"),
+            user_keys: db.collection("user_key"),
+            blob_keys: db.collection("blob_key"),
+        };
+
+        let unique = IndexOptions::builder().unique(true).build();
+        store.metas.create_index(IndexModel::builder().keys(doc! { "id": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.metas.create_index(IndexModel::builder().keys(doc! { "blob": 1 }).build(), None).await.map_err(mongo_err)?;
+        store.metas.create_index(IndexModel::builder().keys(doc! { "previews.blob": 1 }).build(), None).await.map_err(mongo_err)?;
+        store.paths.create_index(IndexModel::builder().keys(doc! { "user": 1, "path": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.trash.create_index(IndexModel::builder().keys(doc! { "id": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.versions.create_index(IndexModel::builder().keys(doc! { "file": 1, "version": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.versions.create_index(IndexModel::builder().keys(doc! { "blob": 1 }).build(), None).await.map_err(mongo_err)?;
+        store.users.create_index(IndexModel::builder().keys(doc! { "ruid": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.accounts.create_index(IndexModel::builder().keys(doc! { "user": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.usage.create_index(IndexModel::builder().keys(doc! { "user": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.users.create_index(IndexModel::builder().keys(doc! { "user": 1 }).build(), None).await.map_err(mongo_err)?;
+        store.groups.create_index(IndexModel::builder().keys(doc! { "id": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.groups.create_index(IndexModel::builder().keys(doc! { "members": 1 }).build(), None).await.map_err(mongo_err)?;
+        store.groups.create_index(IndexModel::builder().keys(doc! { "owner": 1 }).build(), None).await.map_err(mongo_err)?;
+        store.links.create_index(IndexModel::builder().keys(doc! { "id": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.links.create_index(IndexModel::builder().keys(doc! { "user": 1 }).build(), None).await.map_err(mongo_err)?;
+        store.user_keys.create_index(IndexModel::builder().keys(doc! { "user": 1, "id": 1 }).options(unique.clone()).build(), None).await.map_err(mongo_err)?;
+        store.blob_keys.create_index(IndexModel::builder().keys(doc! { "blob": 1 }).options(unique).build(), None).await.map_err(mongo_err)?;
+        store.blob_keys.create_index(IndexModel::builder().keys(doc! { "user": 1 }).build(), None).await.map_err(mongo_err)?;
+        info!("connected to mongodb: {}", database);
+
+        Ok(store)
+    }
+}
+
+#[async_trait]
+impl MetadataStore for MongoMetadataStore {
+    async fn get(&self, id: u128) -> Result<Option<MetaData>, Error> {
+        self.metas.find_one(doc! { "id": hex(id) }, None).await.map_err(mongo_err)
+    }
+
+    async fn put(&self, meta: &MetaData) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.metas.replace_one(doc! { "id": hex(meta.id) }, meta, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: u128) -> Result<(), Error> {
+        self.metas.delete_one(doc! { "id": hex(id) }, None).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn scan(&self) -> Result<Vec<MetaData>, Error> {
+        self.metas.find(None, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+
+    async fn count_blob_refs(&self, blob: &str) -> Result<u64, Error> {
+        let metas = self.metas.count_documents(doc! { "$or": [{ "blob": blob }, { "previews.blob": blob }] }, None).await.map_err(mongo_err)?;
+        let versions = self.versions.count_documents(doc! { "blob": blob }, None).await.map_err(mongo_err)?;
+        Ok(metas + versions)
+    }
+
+    async fn resolve(&self, user: &str, path: &str) -> Result<Option<u128>, Error> {
+        let found = self.paths.find_one(doc! { "user": user, "path": path }, None).await.map_err(mongo_err)?;
+        match found.as_ref().and_then(|d| d.get_str("id").ok()) {
+            Some(id) => u128::from_str_radix(id, 16)
+                .map(Some)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "broken path index")),
+            None => Ok(None),
+        }
+    }
+
+    async fn attach(&self, user: &str, path: &str, id: u128) -> Result<(), Error> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.paths.update_one(
+            doc! { "user": user, "path": path },
+            doc! { "$set": { "id": hex(id) } },
+            options,
+        ).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn detach(&self, user: &str, path: &str) -> Result<(), Error> {
+        self.paths.delete_one(doc! { "user": user, "path": path }, None).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn get_trash(&self, id: u128) -> Result<Option<TrashEntry>, Error> {
+        self.trash.find_one(doc! { "id": hex(id) }, None).await.map_err(mongo_err)
+    }
+
+    async fn put_trash(&self, entry: &TrashEntry) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.trash.replace_one(doc! { "id": hex(entry.id) }, entry, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn delete_trash(&self, id: u128) -> Result<(), Error> {
+        self.trash.delete_one(doc! { "id": hex(id) }, None).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn list_trash(&self, user: Option<&str>) -> Result<Vec<TrashEntry>, Error> {
+        let filter = user.map(|u| doc! { "user": u });
+        self.trash.find(filter, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+
+    async fn put_version(&self, version: &FileVersion) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.versions.replace_one(doc! { "file": hex(version.file), "version": version.version as i64 }, version, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn delete_version(&self, file: u128, version: u64) -> Result<(), Error> {
+        self.versions.delete_one(doc! { "file": hex(file), "version": version as i64 }, None).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn list_versions(&self, file: Option<u128>) -> Result<Vec<FileVersion>, Error> {
+        let filter = file.map(|f| doc! { "file": hex(f) });
+        self.versions.find(filter, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+
+    async fn bind_user(&self, ruid: u128, user: &str) -> Result<(), Error> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.users.update_one(
+            doc! { "ruid": hex(ruid) },
+            doc! { "$set": { "user": user } },
+            options,
+        ).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn user_by_ruid(&self, ruid: u128) -> Result<Option<String>, Error> {
+        let found = self.users.find_one(doc! { "ruid": hex(ruid) }, None).await.map_err(mongo_err)?;
+        Ok(found.and_then(|d| d.get_str("user").ok().map(|u| u.to_string())))
+    }
+
+    async fn ruid_by_user(&self, user: &str) -> Result<Option<u128>, Error> {
+        let found = self.users.find_one(doc! { "user": user }, None).await.map_err(mongo_err)?;
+        Ok(found.and_then(|d| d.get_str("ruid").ok().and_then(|r| u128::from_str_radix(r, 16).ok())))
+    }
+
+    async fn get_group(&self, id: u128) -> Result<Option<PermGroup>, Error> {
+        self.groups.find_one(doc! { "id": hex(id) }, None).await.map_err(mongo_err)
+    }
+
+    async fn put_group(&self, group: &PermGroup) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.groups.replace_one(doc! { "id": hex(group.id) }, group, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn delete_group(&self, id: u128) -> Result<(), Error> {
+        self.groups.delete_one(doc! { "id": hex(id) }, None).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn groups_with_member(&self, member: u128) -> Result<Vec<PermGroup>, Error> {
+        self.groups.find(doc! { "members": hex(member) }, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+
+    async fn list_groups(&self, owner: Option<&str>) -> Result<Vec<PermGroup>, Error> {
+        let filter = owner.map(|o| doc! { "owner": o });
+        self.groups.find(filter, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+
+    async fn get_link(&self, id: u128) -> Result<Option<ShareLink>, Error> {
+        self.links.find_one(doc! { "id": hex(id) }, None).await.map_err(mongo_err)
+    }
+
+    async fn put_link(&self, link: &ShareLink) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.links.replace_one(doc! { "id": hex(link.id) }, link, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn delete_link(&self, id: u128) -> Result<(), Error> {
+        self.links.delete_one(doc! { "id": hex(id) }, None).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn list_links(&self, user: Option<&str>) -> Result<Vec<ShareLink>, Error> {
+        let filter = user.map(|u| doc! { "user": u });
+        self.links.find(filter, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+
+    async fn get_account(&self, user: &str) -> Result<Option<Account>, Error> {
+        self.accounts.find_one(doc! { "user": user }, None).await.map_err(mongo_err)
+    }
+
+    async fn put_account(&self, account: &Account) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.accounts.replace_one(doc! { "user": &account.user }, account, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn get_usage(&self, user: &str) -> Result<Option<Usage>, Error> {
+        self.usage.find_one(doc! { "user": user }, None).await.map_err(mongo_err)
+    }
+
+    async fn put_usage(&self, usage: &Usage) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.usage.replace_one(doc! { "user": &usage.user }, usage, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn list_usage(&self) -> Result<Vec<Usage>, Error> {
+        self.usage.find(None, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+
+    async fn put_user_key(&self, key: &UserKey) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.user_keys.replace_one(doc! { "user": &key.user, "id": key.id }, key, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn delete_user_key(&self, user: &str, id: u32) -> Result<(), Error> {
+        self.user_keys.delete_one(doc! { "user": user, "id": id }, None).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn list_user_keys(&self, user: Option<&str>) -> Result<Vec<UserKey>, Error> {
+        let filter = user.map(|u| doc! { "user": u });
+        self.user_keys.find(filter, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+
+    async fn get_blob_key(&self, blob: &str) -> Result<Option<BlobKey>, Error> {
+        self.blob_keys.find_one(doc! { "blob": blob }, None).await.map_err(mongo_err)
+    }
+
+    async fn put_blob_key(&self, key: &BlobKey) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.blob_keys.replace_one(doc! { "blob": &key.blob }, key, options).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn delete_blob_key(&self, blob: &str) -> Result<(), Error> {
+        self.blob_keys.delete_one(doc! { "blob": blob }, None).await.map_err(mongo_err)?;
+        Ok(())
+    }
+
+    async fn list_blob_keys(&self, user: Option<&str>) -> Result<Vec<BlobKey>, Error> {
+        let filter = user.map(|u| doc! { "user": u });
+        self.blob_keys.find(filter, None).await.map_err(mongo_err)?
+            .try_collect().await.map_err(mongo_err)
+    }
+}
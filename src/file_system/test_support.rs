@@ -0,0 +1,84 @@
+use std::{path::Path, sync::Arc};
+
+use bytes::Bytes;
+use futures::stream;
+use serde_json::json;
+use tempfile::TempDir;
+
+use crate::utils::ruid::RuidGenerator;
+
+use super::{config::FileSystemConfig, file_system::FileSystem, meta::MetaData};
+
+/// テストで使う保存先の組み合わせ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Memory,
+    Local,
+    /// local に暗号化を挟んだもの
+    Encrypted,
+}
+
+pub const BACKENDS: [Backend; 3] = [Backend::Memory, Backend::Local, Backend::Encrypted];
+
+/// dir の下だけを使う設定 バックグラウンドの処理は全て止める
+pub fn config(dir: &Path, backend: Backend) -> FileSystemConfig {
+    let store = match backend {
+        Backend::Memory => "memory",
+        Backend::Local | Backend::Encrypted => "local",
+    };
+    let master_key_path = match backend {
+        Backend::Encrypted => dir.join("master.key").display().to_string(),
+        _ => String::new(),
+    };
+    serde_json::from_value(json!({
+        "storage_path": dir.join("storage").display().to_string(),
+        "streaming_chunk_size": 7,
+        "upload_max_size": 1 << 20,
+        "upload_expire": 60,
+        "upload_sweep_interval": 0,
+        "trash_retention": 60,
+        "trash_purge_interval": 0,
+        "version_retention_count": 3,
+        "version_retention_age": 0,
+        "blob_gc_interval": 0,
+        "blob_verify_interval": 0,
+        "inline_threshold": 16,
+        "template_path": concat!(env!("CARGO_MANIFEST_DIR"), "/fs_template"),
+        "provision_on_start": false,
+        "archive_max_size": 0,
+        "extract": { "max_entries": 100, "max_size": 1 << 20, "max_ratio": 0 },
+        "lifecycle": { "interval": 0, "max_actions_per_second": 0, "max_actions_per_rule": 0 },
+        "fsck": { "interval": 0, "repair": {} },
+        "perm": { "admins": ["root"] },
+        "share_link": { "key_path": "", "default_expire": 0, "max_expire": 0, "visit_window": 60 },
+        "account": {
+            "signup": true, "min_password_length": 8, "memory_cost": 256, "time_cost": 1, "parallelism": 1,
+            "max_failures": 3, "failure_window": 60
+        },
+        "quota": { "default_level": 0, "levels": {}, "overrides": {}, "reconcile_interval": 0 },
+        "encryption": {
+            "master_key_path": master_key_path, "default": true, "users": [], "chunk_size": 5,
+            "user_key_rotation": 0, "master_key_rotation": 0, "rotation_check_interval": 0
+        },
+        "preview": { "sizes": [], "excerpt_length": 0, "max_source_size": 0, "queue_size": 0 },
+        "metadata_store": store,
+        "blob_store": store,
+        "mongodb_uri": "",
+        "mongodb_database": "",
+    })).expect("test config")
+}
+
+/// 一時ディレクトリに作ったファイルシステム ディレクトリは TempDir を捨てると消える
+pub async fn file_system(backend: Backend) -> (TempDir, Arc<FileSystem>) {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let config = config(dir.path(), backend);
+    let file_system = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.expect("file system");
+    (dir, Arc::new(file_system))
+}
+
+/// path に data を書き込む 途中のフォルダも作る
+pub async fn write(file_system: &FileSystem, user: &str, path: &str, data: &[u8]) -> MetaData {
+    let chunks = stream::iter(data.chunks(3).map(|c| Ok::<_, std::io::Error>(Bytes::copy_from_slice(c))).collect::<Vec<_>>());
+    let (blob, size, checksum) = file_system.save_binary(user, chunks).await.expect("save binary");
+    file_system.create_file(user, path, blob, size, checksum).await.expect("create file")
+}
@@ -0,0 +1,277 @@
+use std::{collections::HashMap, io::{Error, ErrorKind}};
+
+use chrono::Utc;
+use log::error;
+
+use crate::utils::ruid;
+
+use super::{file_system::FileSystem, meta::MetaData, path, perm::{Operation, Principal}};
+
+/// `base` 以下のパスを `to` 以下に置き換える
+fn rebase(path: &str, base: &str, to: &str) -> String {
+    match path.strip_prefix(base) {
+        Some("") => to.to_string(),
+        Some(rest) => format!("{}{}", to, rest),
+        None => path.to_string(),
+    }
+}
+
+fn is_inside(path: &str, base: &str) -> bool {
+    path == base || base == "/" || path.starts_with(&format!("{}/", base))
+}
+
+impl FileSystem {
+    /// ユーザー内でファイルまたはフォルダを移動する (リネームを含む)
+    /// lock の中で行うため途中の状態は見えない
+    /// 移動先を先に用意し、元の親から外すのは最後 途中で失敗した場合は元に戻す
+    pub async fn move_to(&self, user: &str, from: &str, to: &str) -> Result<MetaData, Error> {
+        let from_parent = path::parent(from).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "root can't be moved"))?;
+        let to_parent = path::parent(to).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "root can't be replaced"))?;
+        if is_inside(to, from) {
+            return Err(Error::new(ErrorKind::InvalidInput, "can't move a folder into itself"));
+        }
+
+        let _guard = self.lock.write().await;
+        let original = self.lookup_required(user, from).await?;
+        if self.metas.resolve(user, to).await?.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "destination already exists"));
+        }
+        let new_parent = self.ensure_folder(user, to_parent).await?;
+        let mut originals = self.subtree(original.id).await?;
+        originals.push(original.clone());
+
+        match self.relink(user, from, to, from_parent, &originals, new_parent.clone()).await {
+            Ok(meta) => Ok(meta),
+            Err(e) => {
+                if let Err(e) = self.restore_links(user, from, to, &originals, &new_parent).await {
+                    error!("Failed to roll back moving {} to {}: {}", from, to, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// originals (中身、最後に移動するもの自体) のパスを付け替え、親のリンクを移す
+    async fn relink(&self, user: &str, from: &str, to: &str, from_parent: &str, originals: &[MetaData], mut new_parent: MetaData) -> Result<MetaData, Error> {
+        let (original, children) = originals.split_last().ok_or_else(|| Error::new(ErrorKind::NotFound, "nothing to move"))?;
+        for child in children {
+            let new_path = rebase(&child.path, from, to);
+            self.metas.detach(user, &child.path).await?;
+            self.metas.attach(user, &new_path, child.id).await?;
+            self.metas.put(&MetaData { path: new_path, ..child.clone() }).await?;
+        }
+
+        let mut meta = original.clone();
+        self.metas.detach(user, from).await?;
+        self.metas.attach(user, to, meta.id).await?;
+        meta.path = to.to_string();
+        meta.name = path::name(to).to_string();
+        meta.update_time = Utc::now().timestamp_millis();
+        meta.version += 1;
+        self.metas.put(&meta).await?;
+
+        // 同じフォルダの中のリネームはリンクを変えない
+        if new_parent.path != from_parent {
+            new_parent.links.push(meta.id);
+            self.metas.put(&new_parent).await?;
+            self.unlink(user, from_parent, meta.id).await?;
+        }
+        Ok(meta)
+    }
+
+    /// relink の途中で失敗した場合に元のパスとリンクに戻す
+    async fn restore_links(&self, user: &str, from: &str, to: &str, originals: &[MetaData], new_parent: &MetaData) -> Result<(), Error> {
+        for original in originals {
+            self.metas.detach(user, &rebase(&original.path, from, to)).await?;
+            self.metas.attach(user, &original.path, original.id).await?;
+            self.metas.put(original).await?;
+        }
+        self.metas.put(new_parent).await
+    }
+
+    /// ファイルまたはフォルダを複製する ユーザーをまたいでもよい
+    /// バイナリは共有し、上書きされた時点で別のバイナリになる (copy-on-write)
+    /// principal が読めないものは複製しない (フォルダの場合は中身も)
+    pub async fn copy_to(&self, from_user: &str, from: &str, to_user: &str, to: &str, principal: &Principal) -> Result<MetaData, Error> {
+        let to_parent = path::parent(to).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "root can't be replaced"))?;
+        if from_user == to_user && is_inside(to, from) {
+            return Err(Error::new(ErrorKind::InvalidInput, "can't copy a folder into itself"));
+        }
+
+        let _guard = self.lock.write().await;
+        let source = self.lookup_required(from_user, from).await?;
+        let chain = self.perm_chain(from_user, from).await?;
+        if !principal.permits(from_user, from, &chain, Operation::Read) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "source is not readable"));
+        }
+        if self.metas.resolve(to_user, to).await?.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "destination already exists"));
+        }
+
+        // archive_entries と同じくフォルダごとの権限の連なりで中身を判定する
+        let mut sources = Vec::new();
+        let mut stack = vec![(source.clone(), chain)];
+        while let Some((meta, chain)) = stack.pop() {
+            for id in &meta.links {
+                match self.metas.get(*id).await? {
+                    Some(child) if principal.permits(from_user, &child.path, std::iter::once(&child.perm).chain(&chain), Operation::Read) => {
+                        let chain = match child.is_folder() {
+                            true => std::iter::once(child.perm.clone()).chain(chain.iter().cloned()).collect(),
+                            false => Vec::new(),
+                        };
+                        stack.push((child, chain));
+                    }
+                    _ => {}
+                }
+            }
+            sources.push(meta);
+        }
+        let mut parent = self.ensure_folder(to_user, to_parent).await?;
+
+        // 元の id から新しい id への対応
+        let new_ids: HashMap<u128, u128> = sources.iter()
+            .map(|m| (m.id, self.ruid.generate(ruid::prefix_of(m.id))))
+            .collect();
+
+        let time = Utc::now().timestamp_millis();
+        let copies: Vec<MetaData> = sources.iter().map(|original| {
+            let mut copy = MetaData::new(new_ids[&original.id], to_user, &rebase(&original.path, from, to), original.data_type.clone(), time);
+            copy.size = original.size;
+            copy.about = original.about.clone();
+            copy.blob = original.blob.clone();
+            copy.inline = original.inline.clone();
+            copy.checksum = original.checksum.clone();
+            copy.event = original.event.clone();
+            copy.links = original.links.iter().filter_map(|l| new_ids.get(l).copied()).collect();
+            copy
+        }).collect();
+
+        // バイナリは共有しても複製先のユーザーの使用量に数える
+        self.charge(to_user, &copies, &[], true).await?;
+        for copy in &copies {
+            self.metas.put(copy).await?;
+            self.metas.attach(to_user, &copy.path, copy.id).await?;
+        }
+
+        let root = new_ids[&source.id];
+        parent.links.push(root);
+        self.metas.put(&parent).await?;
+
+        self.metas.get(root).await?.ok_or_else(|| Error::other("copied metadata is lost"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use crate::file_system::{perm::Principal, test_support::{self, Backend}};
+
+    const BOB: u128 = 0xb0b;
+
+    #[tokio::test]
+    async fn move_folder() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let a = test_support::write(&fs, "alice", "/src/a.txt", b"alpha").await;
+        let b = test_support::write(&fs, "alice", "/src/sub/b.txt", b"beta").await;
+
+        let moved = fs.move_to("alice", "/src", "/dst/moved").await.unwrap();
+        assert_eq!((moved.name.as_str(), moved.path.as_str(), moved.version), ("moved", "/dst/moved", 2));
+        assert_eq!(fs.get("alice", "/dst/moved/a.txt").await.unwrap().id, a.id);
+        assert_eq!(fs.get("alice", "/dst/moved/sub/b.txt").await.unwrap().path, "/dst/moved/sub/b.txt");
+        assert_eq!(fs.get("alice", "/dst/moved/sub/b.txt").await.unwrap().id, b.id);
+        assert_eq!(fs.get("alice", "/src/a.txt").await.unwrap_err().kind(), ErrorKind::NotFound);
+
+        let (_, root) = fs.list("alice", "/").await.unwrap();
+        assert!(!root.iter().any(|m| m.id == moved.id));
+        assert!(fs.get("alice", "/dst").await.unwrap().links.contains(&moved.id));
+    }
+
+    #[tokio::test]
+    async fn invalid_moves() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/src/a.txt", b"alpha").await;
+        test_support::write(&fs, "alice", "/b.txt", b"beta").await;
+
+        assert_eq!(fs.move_to("alice", "/src", "/src/inner").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.move_to("alice", "/src/a.txt", "/b.txt").await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs.move_to("alice", "/missing", "/c.txt").await.unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.move_to("alice", "/", "/c").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    /// 複製はバイナリを共有し、上書きしても元のファイルは変わらない
+    #[tokio::test]
+    async fn copy_on_write() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let a = test_support::write(&fs, "alice", "/src/a.txt", b"alpha is longer than 16").await;
+        test_support::write(&fs, "alice", "/src/sub/b.txt", b"beta").await;
+        let alice = fs.principal(Some("alice")).await.unwrap();
+
+        let copy = fs.copy_to("alice", "/src", "bob", "/copied", &alice).await.unwrap();
+        assert_eq!(copy.owner, "bob");
+        let copied = fs.get("bob", "/copied/a.txt").await.unwrap();
+        assert_ne!(copied.id, a.id);
+        assert_eq!((copied.blob.as_deref(), copied.checksum.as_deref()), (a.blob.as_deref(), a.checksum.as_deref()));
+        let sub = fs.get("bob", "/copied/sub").await.unwrap();
+        assert!(copy.links.contains(&sub.id));
+        assert!(sub.links.contains(&fs.get("bob", "/copied/sub/b.txt").await.unwrap().id));
+
+        let replaced = test_support::write(&fs, "bob", "/copied/a.txt", b"changed").await;
+        assert_ne!(replaced.blob, a.blob);
+        assert_eq!(fs.blobs.size(a.blob.as_deref().unwrap()).await.unwrap(), Some(23));
+        assert_eq!(fs.get("alice", "/src/a.txt").await.unwrap().blob, a.blob);
+
+        assert_eq!(fs.copy_to("alice", "/src", "alice", "/src/again", &alice).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.copy_to("alice", "/src", "bob", "/copied", &alice).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+    }
+
+    /// 読めない子要素は複製しない
+    #[tokio::test]
+    async fn copy_skips_unreadable() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/src/a.txt", b"alpha").await;
+        test_support::write(&fs, "alice", "/src/secret/b.txt", b"beta").await;
+        test_support::write(&fs, "alice", "/src/c.txt", b"gamma").await;
+        let mut src = fs.get("alice", "/src").await.unwrap();
+        src.perm.read.allow.insert(BOB);
+        fs.metas.put(&src).await.unwrap();
+        for path in ["/src/secret", "/src/c.txt"] {
+            let mut denied = fs.get("alice", path).await.unwrap();
+            denied.perm.read.deny.insert(BOB);
+            fs.metas.put(&denied).await.unwrap();
+        }
+
+        let bob = Principal { user: Some("bob".to_string()), ids: [BOB].into(), link: None };
+        let copy = fs.copy_to("alice", "/src", "bob", "/copied", &bob).await.unwrap();
+        assert_eq!(copy.links, [fs.get("bob", "/copied/a.txt").await.unwrap().id]);
+        for path in ["/copied/secret", "/copied/secret/b.txt", "/copied/c.txt"] {
+            assert_eq!(fs.get("bob", path).await.unwrap_err().kind(), ErrorKind::NotFound, "{}", path);
+        }
+        assert_eq!(fs.copy_to("alice", "/src/c.txt", "bob", "/c.txt", &bob).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    /// 移動先が使えない場合は元の場所から外さない
+    #[tokio::test]
+    async fn invalid_destination_keeps_source() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let a = test_support::write(&fs, "alice", "/home/src/a.txt", b"body").await;
+        test_support::write(&fs, "alice", "/home/file.txt", b"body").await;
+
+        assert!(fs.move_to("alice", "/home/src", "/home/file.txt/dst").await.is_err());
+        let (_, children) = fs.list("alice", "/home").await.unwrap();
+        assert!(children.iter().any(|c| c.path == "/home/src"));
+        assert_eq!(fs.get("alice", "/home/src/a.txt").await.unwrap().id, a.id);
+    }
+
+    /// 同じフォルダの中のリネームで親のリンクが重複したり消えたりしない
+    #[tokio::test]
+    async fn rename_keeps_parent_link() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let a = test_support::write(&fs, "alice", "/home/a.txt", b"body").await;
+        fs.move_to("alice", "/home/a.txt", "/home/b.txt").await.unwrap();
+
+        let home = fs.get("alice", "/home").await.unwrap();
+        assert_eq!(home.links.iter().filter(|l| **l == a.id).count(), 1);
+        assert_eq!(fs.get("alice", "/home/b.txt").await.unwrap().id, a.id);
+    }
+}
@@ -0,0 +1,343 @@
+use std::{io::{Error, ErrorKind}, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::utils::custom_serializers_adapters::Hex;
+
+use super::{file_system::FileSystem, meta::MetaData, path, perm::{Operation, Principal}};
+
+/// ゴミ箱に入ったファイルまたはフォルダ
+/// id は削除したメタデータの id で、子要素はツリーから外した状態で保持する
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    #[serde_as(as = "Hex")]
+    pub id: u128,
+    pub user: String,
+    pub path: String,
+    pub count: u64,
+    pub size: u64,
+    pub deleted_time: i64,
+    pub expire_time: i64,
+}
+
+/// 再帰削除の進捗
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoveProgress {
+    pub path: String,
+    pub done: u64,
+    pub total: u64,
+}
+
+impl FileSystem {
+    /// ファイルまたはフォルダをゴミ箱に移動する
+    /// principal が中身を一つでも削除できない場合は何も変えずに PermissionDenied
+    /// フォルダの場合は子要素ごとに progress が呼ばれる
+    pub async fn remove<F>(&self, user: &str, path: &str, principal: &Principal, progress: F) -> Result<(MetaData, TrashEntry), Error>
+    where
+        F: Fn(RemoveProgress),
+    {
+        match self.remove_if(user, path, Some(principal), |_| true, progress).await? {
+            Some(removed) => Ok(removed),
+            None => Err(Error::new(ErrorKind::NotFound, "file is not found")),
+        }
+    }
+
+    /// lock を取った後のメタデータが check を満たす場合だけゴミ箱に移動する 満たさない場合は None
+    /// principal が None の場合は権限を確かめない (ライフサイクルなどサーバー自身の削除)
+    /// 途中で失敗した場合はツリーに戻す
+    pub(super) async fn remove_if<C, F>(&self, user: &str, path: &str, principal: Option<&Principal>, check: C, progress: F) -> Result<Option<(MetaData, TrashEntry)>, Error>
+    where
+        C: Fn(&MetaData) -> bool,
+        F: Fn(RemoveProgress),
+    {
+        let parent = path::parent(path).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "root can't be removed"))?;
+        let _guard = self.lock.write().await;
+
+        let meta = self.lookup_required(user, path).await?;
+        if !check(&meta) {
+            return Ok(None);
+        }
+        if let Some(principal) = principal {
+            self.check_removable(user, &meta, principal).await?;
+        }
+        let descendants = self.subtree(meta.id).await?;
+
+        let time = Utc::now().timestamp_millis();
+        let entry = TrashEntry {
+            id: meta.id,
+            user: user.to_string(),
+            path: meta.path.clone(),
+            count: descendants.len() as u64 + 1,
+            size: meta.size + descendants.iter().map(|d| d.size).sum::<u64>(),
+            deleted_time: time,
+            expire_time: time + (self.config.trash_retention * 1000) as i64,
+        };
+
+        match self.detach_tree(user, parent, &meta, &descendants, &entry, &progress).await {
+            Ok(()) => Ok(Some((meta, entry))),
+            Err(e) => {
+                if let Err(e) = self.reattach_tree(user, parent, &meta, &descendants).await {
+                    error!("Failed to roll back removing {}: {}", path, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// meta とその中身を全て principal が削除できるか確かめる
+    async fn check_removable(&self, user: &str, meta: &MetaData, principal: &Principal) -> Result<(), Error> {
+        let chain = self.perm_chain(user, &meta.path).await?;
+        if !principal.permits(user, &meta.path, &chain, Operation::Delete) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "delete is not allowed"));
+        }
+        let mut stack = vec![(meta.clone(), chain)];
+        while let Some((folder, chain)) = stack.pop() {
+            for id in &folder.links {
+                if let Some(child) = self.metas.get(*id).await? {
+                    let chain: Vec<_> = std::iter::once(child.perm.clone()).chain(chain.iter().cloned()).collect();
+                    if !principal.permits(user, &child.path, &chain, Operation::Delete) {
+                        return Err(Error::new(ErrorKind::PermissionDenied, format!("{} can't be removed", child.path)));
+                    }
+                    if child.is_folder() {
+                        stack.push((child, chain));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// ツリーから切り離し、ゴミ箱の記録を書く 以降親からは辿れない
+    async fn detach_tree<F>(&self, user: &str, parent: &str, meta: &MetaData, descendants: &[MetaData], entry: &TrashEntry, progress: &F) -> Result<(), Error>
+    where
+        F: Fn(RemoveProgress),
+    {
+        self.metas.detach(user, &meta.path).await?;
+        self.unlink(user, parent, meta.id).await?;
+
+        // 子要素のパスを一つずつ外す
+        progress(RemoveProgress { path: meta.path.clone(), done: 1, total: entry.count });
+        for (i, child) in descendants.iter().enumerate() {
+            self.metas.detach(user, &child.path).await?;
+            progress(RemoveProgress { path: child.path.clone(), done: i as u64 + 2, total: entry.count });
+        }
+        self.metas.put_trash(entry).await
+    }
+
+    /// detach_tree の途中で失敗した場合に元のパスとリンクに戻す
+    async fn reattach_tree(&self, user: &str, parent: &str, meta: &MetaData, descendants: &[MetaData]) -> Result<(), Error> {
+        for m in std::iter::once(meta).chain(descendants) {
+            self.metas.attach(user, &m.path, m.id).await?;
+        }
+        if let Some(mut parent) = self.lookup(user, parent).await? {
+            if !parent.links.contains(&meta.id) {
+                parent.links.push(meta.id);
+                self.metas.put(&parent).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn trash_list(&self, user: &str) -> Result<Vec<TrashEntry>, Error> {
+        let mut list = self.metas.list_trash(Some(user)).await?;
+        list.sort_by_key(|t| std::cmp::Reverse(t.deleted_time));
+        Ok(list)
+    }
+
+    /// ゴミ箱から元のパスに戻す 元のパスが使われている場合は AlreadyExists
+    pub async fn restore(&self, user: &str, id: u128) -> Result<MetaData, Error> {
+        let _guard = self.lock.write().await;
+        let entry = match self.metas.get_trash(id).await? {
+            Some(entry) if entry.user == user => entry,
+            _ => return Err(Error::new(ErrorKind::NotFound, "trash entry is not found")),
+        };
+        let meta = self.metas.get(id).await?.ok_or_else(|| Error::new(ErrorKind::NotFound, "trashed metadata is lost"))?;
+
+        let mut restored = vec![meta.clone()];
+        restored.extend(self.subtree(id).await?);
+        for m in &restored {
+            if self.metas.resolve(user, &m.path).await?.is_some() {
+                return Err(Error::new(ErrorKind::AlreadyExists, "path is already in use"));
+            }
+        }
+
+        let parent = path::parent(&entry.path).unwrap_or("/").to_string();
+        let mut parent_meta = self.ensure_folder(user, &parent).await?;
+        parent_meta.links.push(id);
+        self.metas.put(&parent_meta).await?;
+        for m in &restored {
+            self.metas.attach(user, &m.path, m.id).await?;
+        }
+        self.metas.delete_trash(id).await?;
+
+        Ok(meta)
+    }
+
+    /// ゴミ箱から完全に削除し、参照されなくなったバイナリを解放する
+    pub async fn purge(&self, user: Option<&str>, id: u128) -> Result<TrashEntry, Error> {
+        let (entry, blobs) = {
+            let _guard = self.lock.write().await;
+            let entry = match self.metas.get_trash(id).await? {
+                Some(entry) if user.is_none_or(|u| entry.user == u) => entry,
+                _ => return Err(Error::new(ErrorKind::NotFound, "trash entry is not found")),
+            };
+
+            let mut removed = self.subtree(id).await?;
+            removed.extend(self.metas.get(id).await?);
+            self.charge(&entry.user, &[], &removed, false).await?;
+            let mut blobs = Vec::new();
+            for meta in removed {
+                blobs.extend(self.drop_versions(meta.id).await?);
+                self.metas.delete(meta.id).await?;
+                blobs.extend(meta.blobs().cloned());
+            }
+            self.metas.delete_trash(id).await?;
+            (entry, blobs)
+        };
+
+        self.release_blobs(blobs).await;
+        Ok(entry)
+    }
+
+    /// 保持期間を過ぎたゴミ箱の中身を削除する
+    pub async fn purge_expired(&self) -> usize {
+        let now = Utc::now().timestamp_millis();
+        let expired: Vec<u128> = match self.metas.list_trash(None).await {
+            Ok(list) => list.iter().filter(|t| t.expire_time < now).map(|t| t.id).collect(),
+            Err(e) => {
+                error!("Failed to list trash: {}", e);
+                return 0;
+            }
+        };
+
+        let mut count = 0;
+        for id in expired {
+            match self.purge(None, id).await {
+                Ok(_) => count += 1,
+                Err(e) => error!("Failed to purge trash entry {:032x}: {}", id, e),
+            }
+        }
+        count
+    }
+
+    pub async fn run_purger(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let count = self.purge_expired().await;
+            if count > 0 {
+                info!("purged {} expired trash entries", count);
+            }
+            match self.prune_expired_versions().await {
+                Ok(0) => {}
+                Ok(count) => info!("pruned {} expired file versions", count),
+                Err(e) => error!("Failed to prune file versions: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, sync::Mutex};
+
+    use crate::file_system::{perm::Principal, test_support::{self, Backend}};
+
+    const BOB: u128 = 0xb0b;
+
+    #[tokio::test]
+    async fn remove_and_restore() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/docs/a.txt", b"alpha").await;
+        test_support::write(&fs, "alice", "/docs/sub/b.txt", b"beta").await;
+
+        let seen = Mutex::new(Vec::new());
+        let (meta, entry) = fs.remove("alice", "/docs", &fs.principal(Some("alice")).await.unwrap(), |p| seen.lock().unwrap().push((p.done, p.total))).await.unwrap();
+        assert_eq!(entry.id, meta.id);
+        assert_eq!((entry.count, entry.size), (4, 9));
+        assert_eq!(seen.into_inner().unwrap(), [(1, 4), (2, 4), (3, 4), (4, 4)]);
+        for path in ["/docs", "/docs/a.txt", "/docs/sub/b.txt"] {
+            assert_eq!(fs.get("alice", path).await.unwrap_err().kind(), ErrorKind::NotFound, "{}", path);
+        }
+        assert!(!fs.list("alice", "/").await.unwrap().1.iter().any(|m| m.id == meta.id));
+        assert_eq!(fs.trash_list("alice").await.unwrap().len(), 1);
+        assert!(fs.trash_list("bob").await.unwrap().is_empty());
+
+        // 他のユーザーからは戻せない
+        assert_eq!(fs.restore("bob", entry.id).await.unwrap_err().kind(), ErrorKind::NotFound);
+        fs.restore("alice", entry.id).await.unwrap();
+        assert_eq!(fs.get("alice", "/docs/sub/b.txt").await.unwrap().size, 4);
+        assert!(fs.trash_list("alice").await.unwrap().is_empty());
+    }
+
+    /// 中身に削除できないものがあれば何も変えない
+    #[tokio::test]
+    async fn denied_descendant_keeps_tree() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/docs/a.txt", b"alpha").await;
+        test_support::write(&fs, "alice", "/docs/keep/b.txt", b"beta").await;
+        let mut docs = fs.get("alice", "/docs").await.unwrap();
+        docs.perm.delete.allow.insert(BOB);
+        fs.metas.put(&docs).await.unwrap();
+        let mut keep = fs.get("alice", "/docs/keep").await.unwrap();
+        keep.perm.delete.deny.insert(BOB);
+        fs.metas.put(&keep).await.unwrap();
+
+        let bob = Principal { user: Some("bob".to_string()), ids: [BOB].into(), link: None };
+        assert_eq!(fs.remove("alice", "/docs", &bob, |_| {}).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        for path in ["/docs", "/docs/a.txt", "/docs/keep", "/docs/keep/b.txt"] {
+            assert!(fs.get("alice", path).await.is_ok(), "{}", path);
+        }
+        assert!(fs.list("alice", "/").await.unwrap().1.iter().any(|m| m.id == docs.id));
+        assert!(fs.trash_list("alice").await.unwrap().is_empty());
+
+        fs.remove("alice", "/docs/a.txt", &bob, |_| {}).await.unwrap();
+        assert_eq!(fs.get("alice", "/docs/a.txt").await.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn restore_needs_a_free_path() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/a.txt", b"first").await;
+        let (_, entry) = fs.remove("alice", "/a.txt", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        test_support::write(&fs, "alice", "/a.txt", b"second").await;
+
+        assert_eq!(fs.restore("alice", entry.id).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs.trash_list("alice").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn purge_releases_blobs() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        let meta = test_support::write(&fs, "alice", "/docs/a.txt", b"alpha is longer than 16").await;
+        let blob = meta.blob.unwrap();
+        let (_, entry) = fs.remove("alice", "/docs", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        assert_eq!(fs.blobs.size(&blob).await.unwrap(), Some(23));
+
+        assert_eq!(fs.purge(Some("bob"), entry.id).await.unwrap_err().kind(), ErrorKind::NotFound);
+        fs.purge(Some("alice"), entry.id).await.unwrap();
+        assert_eq!(fs.blobs.size(&blob).await.unwrap(), None);
+        assert_eq!(fs.restore("alice", entry.id).await.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_purged() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/old.txt", b"old").await;
+        test_support::write(&fs, "alice", "/new.txt", b"new").await;
+        let (_, old) = fs.remove("alice", "/old.txt", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        fs.remove("alice", "/new.txt", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+        let mut expired = fs.metas.get_trash(old.id).await.unwrap().unwrap();
+        expired.expire_time = 0;
+        fs.metas.put_trash(&expired).await.unwrap();
+
+        assert_eq!(fs.purge_expired().await, 1);
+        let left = fs.trash_list("alice").await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].path, "/new.txt");
+    }
+}
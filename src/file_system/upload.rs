@@ -0,0 +1,480 @@
+use std::{collections::HashMap, fmt, io::{Error, ErrorKind, SeekFrom}, path::PathBuf, sync::{Arc, RwLock}, time::Duration};
+
+use bytes::Bytes;
+use chrono::Utc;
+use futures::{Stream, StreamExt};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use sha2::{Digest, Sha256};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}, sync::Mutex};
+
+use crate::utils::{custom_serializers_adapters::Hex, ruid::{self, RuidGenerator}};
+
+use super::{file_system::FileSystem, meta::MetaData, perm::{Operation, Principal}};
+
+/// 再開可能アップロードのセッション
+/// `<storage>/upload/<id>.json` に保存し、本体は `<id>.part` に追記していく
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    #[serde_as(as = "Hex")]
+    pub id: u128,
+    pub user: String,
+    pub path: String,
+    pub length: u64,
+    pub offset: u64,
+    pub checksum: Option<String>, // sha256 hex 全体のチェックサム
+    /// 作成したユーザーと共有リンク 続きの操作はこれと同じ相手のみ
+    #[serde(default)]
+    pub requester: Option<String>,
+    #[serde_as(as = "Option<Hex>")]
+    #[serde(default)]
+    pub link: Option<u128>,
+    pub create_time: i64,
+    pub expire_time: i64,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    NotFound,
+    Locked,
+    OffsetMismatch(u64),
+    TooLarge,
+    ChecksumMismatch,
+    Io(Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::NotFound => write!(f, "upload session is not found"),
+            UploadError::Locked => write!(f, "upload session is in use"),
+            UploadError::OffsetMismatch(offset) => write!(f, "upload offset mismatch, current offset is {}", offset),
+            UploadError::TooLarge => write!(f, "upload exceeds declared length"),
+            UploadError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            UploadError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<Error> for UploadError {
+    fn from(e: Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+/// PATCH の結果
+pub enum Appended {
+    Partial(UploadSession),
+    Finished(UploadSession, Box<MetaData>),
+}
+
+pub struct ResumableUpload {
+    pub max_size: u64,
+    pub expire: Duration,
+    dir: PathBuf,
+    file_system: Arc<FileSystem>,
+    ruid: Arc<RuidGenerator>,
+    sessions: RwLock<HashMap<u128, Arc<Mutex<UploadSession>>>>,
+}
+
+impl UploadSession {
+    /// 作成した相手と同じか ログインしているユーザーと共有リンクの両方で比べる
+    pub fn is_created_by(&self, principal: &Principal) -> bool {
+        self.requester == principal.user && self.link == principal.link.as_ref().map(|l| l.id)
+    }
+}
+
+impl ResumableUpload {
+    pub fn new(file_system: Arc<FileSystem>, ruid: Arc<RuidGenerator>) -> Result<Self, Error> {
+        let dir = file_system.root.join("upload");
+        std::fs::create_dir_all(&dir)?;
+
+        // 再起動前のセッションを読み込む
+        let mut sessions = HashMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.path().extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match std::fs::read_to_string(entry.path()).map(|s| serde_json::from_str::<UploadSession>(&s)) {
+                Ok(Ok(session)) => {
+                    sessions.insert(session.id, Arc::new(Mutex::new(session)));
+                }
+                _ => error!("Failed to load upload session: {}", entry.path().display()),
+            }
+        }
+        info!("loaded {} upload sessions", sessions.len());
+
+        Ok(Self {
+            max_size: file_system.config.upload_max_size,
+            expire: Duration::from_secs(file_system.config.upload_expire),
+            dir,
+            file_system,
+            ruid,
+            sessions: RwLock::new(sessions),
+        })
+    }
+
+    fn part_path(&self, id: u128) -> PathBuf {
+        self.dir.join(format!("{:032x}.part", id))
+    }
+
+    fn session_path(&self, id: u128) -> PathBuf {
+        self.dir.join(format!("{:032x}.json", id))
+    }
+
+    async fn store(&self, session: &UploadSession) -> Result<(), Error> {
+        let json = serde_json::to_vec(session).map_err(Error::other)?;
+        tokio::fs::write(self.session_path(session.id), json).await
+    }
+
+    fn lookup(&self, id: u128) -> Option<Arc<Mutex<UploadSession>>> {
+        self.sessions.read().ok()?.get(&id).cloned()
+    }
+
+    /// 作成した相手以外には無いものとして扱う
+    fn lookup_for(&self, id: u128, principal: &Principal) -> Result<Arc<Mutex<UploadSession>>, UploadError> {
+        let lock = self.lookup(id).ok_or(UploadError::NotFound)?;
+        let owned = match lock.try_lock() {
+            Ok(session) => session.is_created_by(principal),
+            Err(_) => return Err(UploadError::Locked),
+        };
+        match owned {
+            true => Ok(lock),
+            false => Err(UploadError::NotFound),
+        }
+    }
+
+    /// 権限は呼び出し側で確かめておく principal は続きの操作をできる相手
+    pub async fn create(&self, user: &str, path: &str, length: u64, checksum: Option<String>, principal: &Principal) -> Result<UploadSession, UploadError> {
+        if length > self.max_size {
+            return Err(UploadError::TooLarge);
+        }
+        // 全体の大きさが分かっているので受け取る前に断る
+        self.file_system.check_quota(user, length).await?;
+        let time = Utc::now().timestamp_millis();
+        let session = UploadSession {
+            id: self.ruid.generate(ruid::prefix::CACHE_FILE),
+            user: user.to_string(),
+            path: path.to_string(),
+            length,
+            offset: 0,
+            checksum: checksum.map(|c| c.to_ascii_lowercase()),
+            requester: principal.user.clone(),
+            link: principal.link.as_ref().map(|l| l.id),
+            create_time: time,
+            expire_time: time + self.expire.as_millis() as i64,
+        };
+
+        File::create(self.part_path(session.id)).await?;
+        self.store(&session).await?;
+        self.sessions.write()
+            .map_err(|_| Error::other("upload sessions are poisoned"))?
+            .insert(session.id, Arc::new(Mutex::new(session.clone())));
+        Ok(session)
+    }
+
+    pub async fn get(&self, id: u128, principal: &Principal) -> Result<UploadSession, UploadError> {
+        let session = self.lookup_for(id, principal)?;
+        let session = session.try_lock().map_err(|_| UploadError::Locked)?;
+        Ok(session.clone())
+    }
+
+    /// offset からチャンクを追記する
+    /// chunk_checksum が指定された場合はチャンクの sha256 を検証し、不一致ならチャンクを破棄する
+    /// 全体を受け取ったら全体のチェックサムを検証し、権限を確かめ直してメタデータを作成する
+    pub async fn append<S, E>(&self, id: u128, principal: &Principal, offset: u64, mut stream: S, chunk_checksum: Option<Vec<u8>>) -> Result<Appended, UploadError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: fmt::Display,
+    {
+        let lock = self.lookup_for(id, principal)?;
+        let mut session = lock.try_lock().map_err(|_| UploadError::Locked)?;
+        if session.offset != offset {
+            return Err(UploadError::OffsetMismatch(session.offset));
+        }
+
+        let mut file = OpenOptions::new().write(true).open(self.part_path(id)).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut hasher = Sha256::new();
+        let mut written: u64 = 0;
+        let mut result = Ok(());
+
+        while let Some(chunk) = stream.next().await {
+            let data = match chunk {
+                Ok(data) => data,
+                Err(e) => {
+                    result = Err(UploadError::Io(Error::new(ErrorKind::Interrupted, e.to_string())));
+                    break;
+                }
+            };
+            if offset + written + data.len() as u64 > session.length {
+                result = Err(UploadError::TooLarge);
+                break;
+            }
+            hasher.update(&data);
+            file.write_all(&data).await?;
+            written += data.len() as u64;
+        }
+        file.flush().await?;
+
+        if let Some(expected) = chunk_checksum {
+            if result.is_ok() && hasher.finalize().as_slice() != expected.as_slice() {
+                result = Err(UploadError::ChecksumMismatch);
+            }
+            // チェックサム付きのチャンクは完全に受け取れた場合のみ有効
+            if result.is_err() {
+                written = 0;
+            }
+        } else if matches!(result, Err(UploadError::TooLarge)) {
+            written = 0;
+        }
+        file.set_len(offset + written).await?;
+
+        // 途中で切断されても受け取れた分は保持する
+        session.offset = offset + written;
+        session.expire_time = Utc::now().timestamp_millis() + self.expire.as_millis() as i64;
+        self.store(&session).await?;
+        result?;
+
+        if session.offset < session.length {
+            return Ok(Appended::Partial(session.clone()));
+        }
+
+        let finished = session.clone();
+        let meta = self.finish(&finished, principal).await;
+        // 成功でも失敗でもセッションは終了
+        self.remove(id).await;
+        Ok(Appended::Finished(finished, Box::new(meta?)))
+    }
+
+    async fn finish(&self, session: &UploadSession, principal: &Principal) -> Result<MetaData, UploadError> {
+        // 作成した後に perm が変わっているかもしれない 既にあるファイルは edit になる
+        self.file_system.authorize(&session.user, &session.path, principal, Operation::Create).await?;
+        let part_path = self.part_path(session.id);
+
+        let mut file = File::open(&part_path).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; self.file_system.config.streaming_chunk_size.max(4096)];
+        loop {
+            let n = file.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        let digest = format!("{:x}", hasher.finalize());
+
+        if let Some(expected) = &session.checksum {
+            if *expected != digest {
+                error!("Upload {:032x} checksum mismatch: expected {}, got {}", session.id, expected, digest);
+                return Err(UploadError::ChecksumMismatch);
+            }
+        }
+
+        let content = self.file_system.import_content(&session.user, &part_path, session.length, &digest).await?;
+        Ok(self.file_system.create_file(&session.user, &session.path, content, session.length, digest).await?)
+    }
+
+    /// 作成した相手のみ中止できる
+    pub async fn terminate(&self, id: u128, principal: &Principal) -> Result<(), UploadError> {
+        let lock = self.lookup_for(id, principal)?;
+        let _session = lock.try_lock().map_err(|_| UploadError::Locked)?;
+        self.remove(id).await;
+        Ok(())
+    }
+
+    pub async fn remove(&self, id: u128) -> bool {
+        let removed = match self.sessions.write() {
+            Ok(mut sessions) => sessions.remove(&id).is_some(),
+            Err(_) => false,
+        };
+        let _ = tokio::fs::remove_file(self.part_path(id)).await;
+        let _ = tokio::fs::remove_file(self.session_path(id)).await;
+        removed
+    }
+
+    /// 期限切れのセッションを削除する
+    pub async fn sweep(&self) -> usize {
+        let now = Utc::now().timestamp_millis();
+        let candidates: Vec<(u128, Arc<Mutex<UploadSession>>)> = match self.sessions.read() {
+            Ok(sessions) => sessions.iter().map(|(id, s)| (*id, Arc::clone(s))).collect(),
+            Err(_) => return 0,
+        };
+
+        let mut count = 0;
+        for (id, session) in candidates {
+            // 使用中のセッションは対象外
+            let expired = match session.try_lock() {
+                Ok(session) => session.expire_time < now,
+                Err(_) => false,
+            };
+            if expired && self.remove(id).await {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub async fn run_sweeper(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let count = self.sweep().await;
+            if count > 0 {
+                info!("swept {} expired upload sessions", count);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, sync::Arc};
+
+    use bytes::Bytes;
+    use futures::{stream, Stream};
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+
+    use crate::{
+        file_system::{
+            file_system::FileSystem,
+            perm::{Operation, Principal},
+            share_link::LinkGrant,
+            test_support::{self, Backend},
+        },
+        utils::ruid::RuidGenerator,
+    };
+
+    use super::{Appended, ResumableUpload, UploadError, UploadSession};
+
+    fn chunk(data: &[u8]) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Unpin {
+        stream::iter(vec![Ok(Bytes::copy_from_slice(data))])
+    }
+
+    async fn upload() -> (TempDir, Arc<FileSystem>, ResumableUpload) {
+        let (dir, fs) = test_support::file_system(Backend::Memory).await;
+        let upload = ResumableUpload::new(Arc::clone(&fs), Arc::new(RuidGenerator::new(1))).unwrap();
+        (dir, fs, upload)
+    }
+
+    #[tokio::test]
+    async fn resumes_from_offset() {
+        let (_dir, fs, upload) = upload().await;
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        let checksum = format!("{:x}", Sha256::digest(b"helloworld"));
+        let session = upload.create("alice", "/docs/a.txt", 10, Some(checksum.to_ascii_uppercase()), &alice).await.unwrap();
+
+        match upload.append(session.id, &alice, 0, chunk(b"hello"), None).await {
+            Ok(Appended::Partial(session)) => assert_eq!(session.offset, 5),
+            _ => panic!("expected a partial upload"),
+        }
+        assert!(matches!(upload.append(session.id, &alice, 3, chunk(b"loworld"), None).await, Err(UploadError::OffsetMismatch(5))));
+        let meta = match upload.append(session.id, &alice, 5, chunk(b"world"), None).await {
+            Ok(Appended::Finished(_, meta)) => meta,
+            _ => panic!("expected a finished upload"),
+        };
+        assert_eq!(meta.size, 10);
+        assert_eq!(meta.checksum.as_deref(), Some(checksum.as_str()));
+        assert_eq!(fs.get("alice", "/docs/a.txt").await.unwrap().id, meta.id);
+        assert!(matches!(upload.get(session.id, &alice).await, Err(UploadError::NotFound)));
+    }
+
+    /// チェックサムが合わないチャンクや長さを超えるチャンクは書き込まない
+    #[tokio::test]
+    async fn rejected_chunk_is_discarded() {
+        let (_dir, fs, upload) = upload().await;
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        let session = upload.create("alice", "/a.txt", 10, None, &alice).await.unwrap();
+
+        let wrong = Sha256::digest(b"other").to_vec();
+        assert!(matches!(upload.append(session.id, &alice, 0, chunk(b"hello"), Some(wrong)).await, Err(UploadError::ChecksumMismatch)));
+        assert_eq!(upload.get(session.id, &alice).await.unwrap().offset, 0);
+
+        assert!(matches!(upload.append(session.id, &alice, 0, chunk(b"hello world!"), None).await, Err(UploadError::TooLarge)));
+        assert_eq!(upload.get(session.id, &alice).await.unwrap().offset, 0);
+
+        let right = Sha256::digest(b"hello").to_vec();
+        assert!(matches!(upload.append(session.id, &alice, 0, chunk(b"hello"), Some(right)).await, Ok(Appended::Partial(_))));
+        assert_eq!(upload.get(session.id, &alice).await.unwrap().offset, 5);
+    }
+
+    #[tokio::test]
+    async fn whole_checksum_mismatch_creates_nothing() {
+        let (_dir, fs, upload) = upload().await;
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        let session = upload.create("alice", "/a.txt", 5, Some("00".repeat(32)), &alice).await.unwrap();
+
+        assert!(matches!(upload.append(session.id, &alice, 0, chunk(b"hello"), None).await, Err(UploadError::ChecksumMismatch)));
+        assert!(matches!(upload.get(session.id, &alice).await, Err(UploadError::NotFound)));
+        assert!(fs.get("alice", "/a.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn declared_length_is_limited() {
+        let (_dir, fs, upload) = upload().await;
+        let alice = fs.principal(Some("alice")).await.unwrap();
+        assert!(matches!(upload.create("alice", "/a.txt", (1 << 20) + 1, None, &alice).await, Err(UploadError::TooLarge)));
+    }
+
+    fn session(requester: Option<&str>, link: Option<u128>) -> UploadSession {
+        UploadSession {
+            id: 1,
+            user: "alice".to_string(),
+            path: "/a.txt".to_string(),
+            length: 1,
+            offset: 0,
+            checksum: None,
+            requester: requester.map(|u| u.to_string()),
+            link,
+            create_time: 0,
+            expire_time: 0,
+        }
+    }
+
+    fn principal(user: Option<&str>, link: Option<u128>) -> Principal {
+        Principal {
+            user: user.map(|u| u.to_string()),
+            link: link.map(|id| LinkGrant { id, user: "alice".to_string(), path: "/".to_string(), ops: BTreeSet::from([Operation::Create]) }),
+            ..Default::default()
+        }
+    }
+
+    /// 作った相手以外には見えず、最後に権限を確かめ直す
+    #[tokio::test]
+    async fn session_is_bound_to_creator() {
+        let (_dir, fs, upload) = upload().await;
+        fs.create_folder("alice", "/docs").await.unwrap();
+        let mut docs = fs.get("alice", "/docs").await.unwrap();
+        docs.perm.create.allow.insert(0xb0b);
+        fs.metas.put(&docs).await.unwrap();
+        let bob = Principal { user: Some("bob".to_string()), ids: [0xb0b].into(), link: None };
+        let carol = Principal { user: Some("carol".to_string()), ids: [0xca201].into(), link: None };
+
+        let session = upload.create("alice", "/docs/a.txt", 10, None, &bob).await.unwrap();
+        assert!(matches!(upload.get(session.id, &carol).await, Err(UploadError::NotFound)));
+        assert!(matches!(upload.append(session.id, &carol, 0, chunk(b"hello"), None).await, Err(UploadError::NotFound)));
+        assert!(matches!(upload.append(session.id, &bob, 0, chunk(b"hello"), None).await, Ok(Appended::Partial(_))));
+
+        docs.perm.create.allow.clear();
+        fs.metas.put(&docs).await.unwrap();
+        assert!(upload.append(session.id, &bob, 5, chunk(b"world"), None).await.is_err());
+        assert!(fs.get("alice", "/docs/a.txt").await.is_err());
+    }
+
+    #[test]
+    fn only_creator_continues() {
+        let by_bob = session(Some("bob"), None);
+        assert!(by_bob.is_created_by(&principal(Some("bob"), None)));
+        assert!(!by_bob.is_created_by(&principal(Some("carol"), None)));
+        assert!(!by_bob.is_created_by(&principal(None, None)));
+
+        let by_link = session(None, Some(7));
+        assert!(by_link.is_created_by(&principal(None, Some(7))));
+        assert!(!by_link.is_created_by(&principal(None, Some(8))));
+        assert!(!by_link.is_created_by(&principal(None, None)));
+    }
+}
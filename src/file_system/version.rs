@@ -0,0 +1,288 @@
+use std::{collections::HashMap, io::{Error, ErrorKind}};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::utils::custom_serializers_adapters::{Base64, Hex};
+
+use super::{blob::Content, file_system::FileSystem, meta::MetaData};
+
+/// 上書きされる前のファイルの本体
+/// バイナリは内容アドレスなので、同じ内容の版は同じ blob を共有する
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileVersion {
+    #[serde_as(as = "Hex")]
+    pub file: u128,
+    /// 上書きされた時点の MetaData.version
+    pub version: u64,
+    pub data_type: String,
+    pub size: u64,
+    pub checksum: Option<String>,
+    pub blob: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<Base64>")]
+    pub inline: Option<Vec<u8>>,
+    /// この版の内容が書き込まれた時刻
+    pub update_time: i64,
+    /// 上書きされた時刻
+    pub archived_time: i64,
+}
+
+impl FileVersion {
+    pub fn from_meta(meta: &MetaData, archived_time: i64) -> Self {
+        Self {
+            file: meta.id,
+            version: meta.version,
+            data_type: meta.data_type.clone(),
+            size: meta.size,
+            checksum: meta.checksum.clone(),
+            blob: meta.blob.clone(),
+            inline: meta.inline.clone(),
+            update_time: meta.update_time,
+            archived_time,
+        }
+    }
+
+    /// 一覧用 本体は含めない
+    pub fn summary(&self) -> Self {
+        Self {
+            inline: None,
+            ..self.clone()
+        }
+    }
+}
+
+impl FileSystem {
+    /// 上書きの直前に呼ぶ lock の write を取った状態で呼ぶ
+    /// 保持数と保持期間を超えた版は削除し、解放候補の blob を返す
+    pub(super) async fn archive_version(&self, previous: &MetaData) -> Result<Vec<String>, Error> {
+        if self.config.version_retention_count == 0 || (previous.blob.is_none() && previous.inline.is_none()) {
+            return Ok(Vec::new());
+        }
+        self.metas.put_version(&FileVersion::from_meta(previous, Utc::now().timestamp_millis())).await?;
+        let removed = self.prune_versions(self.metas.list_versions(Some(previous.id)).await?).await?;
+        Ok(removed.into_iter().filter_map(|v| v.blob).collect())
+    }
+
+    /// 一つのファイルの版のうち、保持数と保持期間を超えたものを削除して返す
+    async fn prune_versions(&self, mut versions: Vec<FileVersion>) -> Result<Vec<FileVersion>, Error> {
+        versions.sort_by_key(|v| std::cmp::Reverse(v.version));
+
+        let now = Utc::now().timestamp_millis();
+        let max_age = (self.config.version_retention_age * 1000) as i64;
+        let mut removed = Vec::new();
+        for (i, version) in versions.into_iter().enumerate() {
+            let expired = max_age > 0 && now - version.archived_time > max_age;
+            if i >= self.config.version_retention_count || expired {
+                self.metas.delete_version(version.file, version.version).await?;
+                removed.push(version);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// 全てのファイルの版を保持数と保持期間に従って削除する 削除した版の数を返す
+    pub async fn prune_expired_versions(&self) -> Result<usize, Error> {
+        let (count, blobs) = {
+            let _guard = self.lock.write().await;
+            let mut by_file: HashMap<u128, Vec<FileVersion>> = HashMap::new();
+            for version in self.metas.list_versions(None).await? {
+                by_file.entry(version.file).or_default().push(version);
+            }
+
+            let mut count = 0;
+            let mut blobs = Vec::new();
+            for versions in by_file.into_values() {
+                let removed = self.prune_versions(versions).await?;
+                count += removed.len();
+                blobs.extend(removed.into_iter().filter_map(|v| v.blob));
+            }
+            (count, blobs)
+        };
+
+        self.release_blobs(blobs).await;
+        Ok(count)
+    }
+
+    /// cutoff より前に上書きされた版を最も新しい一つにまとめ、削除した版の番号を返す
+    /// dry_run の場合は削除せずに番号だけ返す
+    pub(super) async fn collapse_versions(&self, file: u128, cutoff: i64, dry_run: bool) -> Result<Vec<u64>, Error> {
+        let removed = {
+            let _guard = self.lock.write().await;
+            let mut old: Vec<FileVersion> = self.metas.list_versions(Some(file)).await?
+                .into_iter()
+                .filter(|v| v.archived_time < cutoff)
+                .collect();
+            old.sort_by_key(|v| std::cmp::Reverse(v.version));
+            let removed: Vec<FileVersion> = old.into_iter().skip(1).collect();
+            if !dry_run {
+                for version in &removed {
+                    self.metas.delete_version(file, version.version).await?;
+                }
+            }
+            removed
+        };
+
+        let numbers = removed.iter().map(|v| v.version).collect();
+        if !dry_run {
+            self.release_blobs(removed.into_iter().filter_map(|v| v.blob)).await;
+        }
+        Ok(numbers)
+    }
+
+    /// ファイルを完全に削除する時に全ての版を削除する
+    pub(super) async fn drop_versions(&self, file: u128) -> Result<Vec<String>, Error> {
+        let mut released = Vec::new();
+        for version in self.metas.list_versions(Some(file)).await? {
+            self.metas.delete_version(file, version.version).await?;
+            released.extend(version.blob);
+        }
+        Ok(released)
+    }
+
+    /// 新しい順の版の一覧 現在の内容は含まない
+    pub async fn versions(&self, user: &str, path: &str) -> Result<(MetaData, Vec<FileVersion>), Error> {
+        let _guard = self.lock.read().await;
+        let meta = self.lookup_required(user, path).await?;
+        if meta.is_folder() {
+            return Err(Error::new(ErrorKind::InvalidInput, "folder has no versions"));
+        }
+        let mut versions = self.metas.list_versions(Some(meta.id)).await?;
+        versions.sort_by_key(|v| std::cmp::Reverse(v.version));
+        Ok((meta, versions))
+    }
+
+    /// version が現在の版の場合は現在の内容を返す
+    pub async fn version(&self, user: &str, path: &str, version: u64) -> Result<FileVersion, Error> {
+        let (meta, versions) = self.versions(user, path).await?;
+        if meta.version == version {
+            return Ok(FileVersion::from_meta(&meta, meta.update_time));
+        }
+        versions.into_iter()
+            .find(|v| v.version == version)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "version is not found"))
+    }
+
+    /// 版の内容を全て読み込む
+    pub async fn read_version(&self, version: &FileVersion) -> Result<Vec<u8>, Error> {
+        match (&version.inline, &version.blob) {
+            (Some(data), _) => Ok(data.clone()),
+            (None, Some(blob)) => self.read_blob(blob, version.size).await,
+            (None, None) => Err(Error::new(ErrorKind::NotFound, "version has no content")),
+        }
+    }
+
+    /// 古い版の内容で上書きする 現在の内容は新しい版として残る
+    pub async fn restore_version(&self, user: &str, path: &str, version: u64) -> Result<MetaData, Error> {
+        let target = self.version(user, path, version).await?;
+        let checksum = target.checksum.clone().unwrap_or_default();
+        let content = match (target.inline, target.blob) {
+            (Some(data), _) => Content::Inline(data),
+            (None, Some(blob)) => {
+                self.pin(&blob).await?;
+                Content::Blob(blob)
+            }
+            (None, None) => return Err(Error::new(ErrorKind::NotFound, "version has no content")),
+        };
+        self.create_file(user, path, content, target.size, checksum).await
+    }
+
+    /// 二つの版の JSON の差分 (JSON Patch)
+    pub async fn diff_versions(&self, user: &str, path: &str, from: u64, to: u64) -> Result<json_patch::Patch, Error> {
+        let parse = |data: Vec<u8>| serde_json::from_slice::<serde_json::Value>(&data)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "version is not JSON"));
+        let left = parse(self.read_version(&self.version(user, path, from).await?).await?)?;
+        let right = parse(self.read_version(&self.version(user, path, to).await?).await?)?;
+        Ok(json_patch::diff(&left, &right))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, sync::Arc};
+
+    use chrono::Utc;
+    use serde_json::json;
+
+    use crate::{file_system::{file_system::FileSystem, test_support::{self, Backend}}, utils::ruid::RuidGenerator};
+
+    const LONG: &[u8] = b"version one is longer than 16";
+
+    #[tokio::test]
+    async fn retention_by_count() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/a.txt", LONG).await;
+        for i in 2..=6 {
+            test_support::write(&fs, "alice", "/a.txt", format!("v{}", i).as_bytes()).await;
+        }
+
+        let (meta, versions) = fs.versions("alice", "/a.txt").await.unwrap();
+        assert_eq!(meta.version, 6);
+        assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<_>>(), [5, 4, 3]);
+        // 保持数を超えた版のバイナリは解放される
+        assert!(fs.blobs.list().await.unwrap().is_empty());
+        assert_eq!(fs.versions("alice", "/").await.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn retention_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_support::config(dir.path(), Backend::Memory);
+        config.version_retention_age = 60;
+        let fs = FileSystem::new(&config, Arc::new(RuidGenerator::new(1))).await.unwrap();
+        test_support::write(&fs, "alice", "/a.txt", LONG).await;
+        test_support::write(&fs, "alice", "/a.txt", b"v2").await;
+        test_support::write(&fs, "alice", "/a.txt", b"v3").await;
+
+        let (_, versions) = fs.versions("alice", "/a.txt").await.unwrap();
+        let mut old = versions.into_iter().find(|v| v.version == 1).unwrap();
+        old.archived_time = Utc::now().timestamp_millis() - 61_000;
+        fs.metas.put_version(&old).await.unwrap();
+
+        assert_eq!(fs.prune_expired_versions().await.unwrap(), 1);
+        let (_, versions) = fs.versions("alice", "/a.txt").await.unwrap();
+        assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<_>>(), [2]);
+        assert!(fs.blobs.list().await.unwrap().is_empty());
+        assert_eq!(fs.prune_expired_versions().await.unwrap(), 0);
+    }
+
+    /// 戻した内容は新しい版になり、戻す前の内容は古い版として残る
+    #[tokio::test]
+    async fn restore_keeps_current() {
+        for backend in test_support::BACKENDS {
+            let (_dir, fs) = test_support::file_system(backend).await;
+            test_support::write(&fs, "alice", "/a.txt", LONG).await;
+            test_support::write(&fs, "alice", "/a.txt", b"two").await;
+
+            let restored = fs.restore_version("alice", "/a.txt", 1).await.unwrap();
+            assert_eq!((restored.version, restored.size), (3, LONG.len() as u64), "{:?}", backend);
+            assert_eq!(fs.read_all("alice", "/a.txt").await.unwrap(), LONG, "{:?}", backend);
+            let two = fs.version("alice", "/a.txt", 2).await.unwrap();
+            assert_eq!(fs.read_version(&two).await.unwrap(), b"two", "{:?}", backend);
+            assert_eq!(fs.version("alice", "/a.txt", 9).await.unwrap_err().kind(), ErrorKind::NotFound, "{:?}", backend);
+
+            // 版が参照しているバイナリはファイルを消すまで残る
+            let key = restored.blob.clone().unwrap();
+            let (_, entry) = fs.remove("alice", "/a.txt", &fs.principal(Some("alice")).await.unwrap(), |_| {}).await.unwrap();
+            fs.purge(None, entry.id).await.unwrap();
+            assert!(fs.metas.list_versions(Some(restored.id)).await.unwrap().is_empty(), "{:?}", backend);
+            assert_eq!(fs.blobs.size(&key).await.unwrap(), None, "{:?}", backend);
+        }
+    }
+
+    #[tokio::test]
+    async fn diff_json_versions() {
+        let (_dir, fs) = test_support::file_system(Backend::Memory).await;
+        test_support::write(&fs, "alice", "/a.json", br#"{ "a": 1, "b": [1, 2] }"#).await;
+        test_support::write(&fs, "alice", "/a.json", br#"{ "a": 2, "b": [1, 2] }"#).await;
+        test_support::write(&fs, "alice", "/b.txt", b"text").await;
+        test_support::write(&fs, "alice", "/b.txt", b"more").await;
+
+        let patch = fs.diff_versions("alice", "/a.json", 1, 2).await.unwrap();
+        assert_eq!(serde_json::to_value(patch).unwrap(), json!([{ "op": "replace", "path": "/a", "value": 2 }]));
+        assert!(fs.diff_versions("alice", "/a.json", 2, 2).await.unwrap().0.is_empty());
+        assert_eq!(fs.diff_versions("alice", "/b.txt", 1, 2).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
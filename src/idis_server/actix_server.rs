@@ -6,7 +6,7 @@ use log::error;
 
 use crate::{actix_middleware::status_page, config::ServerConfig, server::server_trait::WkServer, share::collection::Collection, utils};
 
-use super::actix_server_config::ServiceConfig;
+use super::{actix_server_config::ServiceConfig, api};
 
 pub struct IndexServer {
     pub config: ServerConfig<ServiceConfig>,
@@ -30,6 +30,7 @@ impl IndexServer {
                 .app_data(share_clone.clone())
                 .wrap(custom_logger)
                 .wrap(middleware::ErrorHandlers::new().default_handler(status_page::middleware::Handler::err_handler))
+                .configure(api::config)
                 // 他のミドルウェアやデータをここに追加可能
         })
         .bind(self.config.server_bind.clone())?
@@ -1,7 +1,13 @@
-use std::str;
+use std::{net::IpAddr, str};
 
 use serde::Deserialize;
 
+use super::session::SessionConfig;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServiceConfig {
-}
\ No newline at end of file
+    pub session: SessionConfig,
+    /// この接続元からのリクエストだけ `Forwarded` / `X-Forwarded-For` の接続元を信用する
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
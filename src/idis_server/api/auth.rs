@@ -0,0 +1,373 @@
+use std::{io::{Error, ErrorKind}, sync::Arc};
+
+use actix_web::{http::header, web, Either, HttpMessage, HttpRequest, HttpResponse};
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use tera::{Context, Tera};
+
+use crate::{file_system::account::Account, idis_server::session::{SessionData, SessionUser}, share::collection::Collection};
+
+use super::{client_addr, err_response};
+
+const LOGIN_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="ja">
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><title>ログイン - IDIS</title></head>
+<body>
+<h1>ログイン</h1>
+{% if message %}<p role="alert">{{ message }}</p>{% endif %}
+<form method="post" action="/login">
+<input type="hidden" name="next" value="{{ next }}">
+<p><label>ユーザー名 <input name="user" autocomplete="username" required></label></p>
+<p><label>パスワード <input name="password" type="password" autocomplete="current-password" required></label></p>
+<p><button type="submit">ログイン</button></p>
+</form>
+<p><a href="/signup?next={{ next | urlencode_strict }}">アカウントを作成</a></p>
+</body>
+</html>
+"#;
+
+const SIGNUP_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="ja">
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><title>サインアップ - IDIS</title></head>
+<body>
+<h1>サインアップ</h1>
+{% if message %}<p role="alert">{{ message }}</p>{% endif %}
+<form method="post" action="/signup">
+<input type="hidden" name="next" value="{{ next }}">
+<p><label>ユーザー名 <input name="user" autocomplete="username" pattern="[A-Za-z0-9_][A-Za-z0-9_.\-]*" maxlength="32" required></label></p>
+<p><label>パスワード <input name="password" type="password" autocomplete="new-password" required></label></p>
+<p><button type="submit">作成</button></p>
+</form>
+<p><a href="/login?next={{ next | urlencode_strict }}">ログイン</a></p>
+</body>
+</html>
+"#;
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    /// `@` は付けても付けなくてもよい
+    pub user: String,
+    pub password: String,
+    /// フォームの場合の移動先
+    pub next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    pub next: Option<String>,
+    pub error: Option<String>,
+}
+
+/// フォームのエラーの表示 任意の文字列を表示しないようにコードで渡す
+fn error_message(code: &str) -> Option<&'static str> {
+    match code {
+        "credentials" => Some("ユーザー名またはパスワードが違います"),
+        "blocked" => Some("失敗が続いたため、しばらくログインできません"),
+        "exists" => Some("そのユーザー名は使われています"),
+        "invalid" => Some("ユーザー名またはパスワードが条件を満たしていません"),
+        "disabled" => Some("アカウントの作成は受け付けていません"),
+        "full" => Some("これ以上のユーザーではログインできません。どれかのユーザーをログアウトしてください"),
+        _ => None,
+    }
+}
+
+fn error_code(e: &Error) -> &'static str {
+    match e.kind() {
+        ErrorKind::PermissionDenied => "credentials",
+        ErrorKind::ResourceBusy => "blocked",
+        ErrorKind::AlreadyExists => "exists",
+        ErrorKind::InvalidInput => "invalid",
+        _ => "",
+    }
+}
+
+/// 同じサイトのパスのみ 他のサイトへ移動させない
+fn safe_next(next: Option<&str>) -> Option<String> {
+    next.filter(|n| n.starts_with('/') && !n.starts_with("//") && !n.contains('\\'))
+        .map(|n| n.to_string())
+}
+
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther().insert_header((header::LOCATION, location.to_string())).finish()
+}
+
+fn render(template: &str, query: &PageQuery) -> HttpResponse {
+    let mut context = Context::new();
+    context.insert("next", &safe_next(query.next.as_deref()).unwrap_or_default());
+    context.insert("message", &query.error.as_deref().and_then(error_message));
+    match Tera::one_off(template, &context, true) {
+        Ok(page) => HttpResponse::Ok().content_type("text/html; charset=utf-8").body(page),
+        Err(e) => {
+            error!("Failed to render auth page: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// セッションに加えて今のユーザーにし、cookie を返す
+/// 既に他のユーザーでログインしているセッションにはユーザーを足す
+fn signed_in(req: &HttpRequest, collection: &Collection, account: &Account, form: bool, next: Option<String>, page: &str) -> HttpResponse {
+    let user = SessionUser { ruid: account.ruid, user: account.user.clone() };
+    let token = match collection.sessions.login(collection.sessions.token(req).as_deref(), user.clone()) {
+        Ok(token) => token,
+        Err(_) if form => return redirect(&format!("{}?error=full&next={}", page, encode(&next.unwrap_or_default()))),
+        Err(e) => return err_response(&e),
+    };
+    let cookie = collection.sessions.cookie(&token);
+    match form {
+        true => {
+            let mut res = redirect(&next.unwrap_or_else(|| format!("/ls/@{}", user.user)));
+            let _ = res.add_cookie(&cookie);
+            res
+        }
+        false if page == "/signup" => HttpResponse::Created().cookie(cookie).json(user),
+        false => HttpResponse::Ok().cookie(cookie).json(user),
+    }
+}
+
+/// `/login` -> HTML
+pub async fn login_page(query: web::Query<PageQuery>) -> HttpResponse {
+    render(LOGIN_PAGE, &query)
+}
+
+/// `/signup` -> HTML
+pub async fn signup_page(query: web::Query<PageQuery>) -> HttpResponse {
+    render(SIGNUP_PAGE, &query)
+}
+
+/// `POST /login` JSON または フォーム
+/// JSON は `{ "ruid": ..., "user": ... }` を返し、失敗は 401 (続いた場合は 429)
+/// フォームは `next` (省略した場合は `/ls/@<user>`) に、失敗はログインページに戻す
+pub async fn login(req: HttpRequest, body: Either<web::Json<Credentials>, web::Form<Credentials>>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let (credentials, form) = match body {
+        Either::Left(json) => (json.into_inner(), false),
+        Either::Right(form) => (form.into_inner(), true),
+    };
+    let name = credentials.user.trim_start_matches('@');
+    let next = safe_next(credentials.next.as_deref());
+
+    match collection.accounts.login(name, &credentials.password, client_addr(&req, &collection).as_deref()).await {
+        Ok(account) => signed_in(&req, &collection, &account, form, next, "/login"),
+        Err(e) if form => redirect(&format!("/login?error={}&next={}", error_code(&e), encode(&next.unwrap_or_default()))),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => HttpResponse::Unauthorized().finish(),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `POST /signup` JSON または フォーム
+/// 作成したユーザーでログインする JSON は 201 `{ "ruid": ..., "user": ... }`
+pub async fn signup(req: HttpRequest, body: Either<web::Json<Credentials>, web::Form<Credentials>>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let (credentials, form) = match body {
+        Either::Left(json) => (json.into_inner(), false),
+        Either::Right(form) => (form.into_inner(), true),
+    };
+    let name = credentials.user.trim_start_matches('@');
+    let next = safe_next(credentials.next.as_deref());
+
+    match collection.accounts.signup(name, &credentials.password).await {
+        Ok(account) => signed_in(&req, &collection, &account, form, next, "/signup"),
+        Err(e) if form => {
+            let code = match e.kind() {
+                ErrorKind::PermissionDenied => "disabled",
+                _ => error_code(&e),
+            };
+            redirect(&format!("/signup?error={}&next={}", code, encode(&next.unwrap_or_default())))
+        }
+        Err(e) => err_response(&e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LogoutQuery {
+    /// `@<user>` または RUID 省略した場合は今のユーザー
+    pub user: Option<String>,
+    #[serde(default)]
+    pub all: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SwitchRequest {
+    /// `@<user>` または RUID
+    pub user: String,
+}
+
+/// ログインしているユーザーの一覧と今のユーザー
+fn session_json(data: Option<&SessionData>) -> serde_json::Value {
+    json!({
+        "users": data.map_or(&[][..], |d| &d.users[..]),
+        "active": data.and_then(|d| d.active_user()),
+    })
+}
+
+/// `POST /logout?user=<@user | RUID>&all=true` -> JSON
+/// ユーザー (省略した場合は今のユーザー、`all` の場合は全員) をセッションから外し、残ったセッションを返す
+/// フォームの場合はログインページに移動する
+pub async fn logout(req: HttpRequest, query: web::Query<LogoutQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let remaining = match collection.sessions.token(&req) {
+        Some(token) if query.all => {
+            collection.sessions.logout_all(&token);
+            None
+        }
+        Some(token) => match collection.sessions.logout(&token, query.user.as_deref()) {
+            Ok(remaining) => remaining,
+            Err(e) => return err_response(&e),
+        },
+        None => None,
+    };
+
+    let form = req.content_type() == "application/x-www-form-urlencoded";
+    let mut res = match form {
+        true => redirect("/login"),
+        false => HttpResponse::Ok().json(session_json(remaining.as_ref())),
+    };
+    if remaining.is_none() {
+        let _ = res.add_cookie(&collection.sessions.removal_cookie());
+    }
+    res
+}
+
+/// `/session` -> JSON
+/// `{ "users": [...], "active": ... }` ログインしていない場合は users が空
+pub async fn session(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let data = collection.sessions.token(&req).and_then(|token| collection.sessions.get(&token));
+    HttpResponse::Ok().json(session_json(data.as_ref()))
+}
+
+/// `POST /session/switch` -> JSON
+/// `{ "user": "@user" }` セッションにログインしているユーザーに切り替える
+pub async fn switch(req: HttpRequest, body: Either<web::Json<SwitchRequest>, web::Form<SwitchRequest>>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let (spec, form) = match body {
+        Either::Left(json) => (json.into_inner().user, false),
+        Either::Right(form) => (form.into_inner().user, true),
+    };
+    let token = match collection.sessions.token(&req) {
+        Some(token) => token,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match collection.sessions.switch(&token, &spec) {
+        Ok(data) if form => redirect(&data.active_user().map_or_else(|| "/".to_string(), |u| format!("/ls/@{}", u.user))),
+        Ok(data) => HttpResponse::Ok().json(session_json(Some(&data))),
+        Err(e) => err_response(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{body::BoxBody, cookie::Cookie, dev::ServiceResponse, http::{header, StatusCode}, test::{self, TestRequest}};
+    use serde_json::{json, Value};
+
+    use crate::{file_system::test_support::Backend, idis_server::api::test_support};
+
+    fn session(res: &ServiceResponse<BoxBody>) -> Cookie<'static> {
+        res.response().cookies().find(|c| c.name() == "idis_session").expect("session cookie").into_owned()
+    }
+
+    #[test]
+    fn next_stays_on_site() {
+        assert_eq!(super::safe_next(Some("/ls/@alice?x=1")).as_deref(), Some("/ls/@alice?x=1"));
+        for next in ["https://example.com/", "//example.com/", "/\\example.com", "ls"] {
+            assert_eq!(super::safe_next(Some(next)), None, "{}", next);
+        }
+        assert_eq!(super::encode("/ls/@alice?x=日"), "/ls/%40alice%3Fx%3D%E6%97%A5");
+    }
+
+    /// ログインのたびにトークンを作り直し、ログアウトで使えなくなる
+    #[actix_web::test]
+    async fn signup_login_and_logout() {
+        let (_dir, collection) = test_support::collection(Backend::Memory).await;
+        let credentials = json!({ "user": "@alice", "password": "password1" });
+        let res = test_support::call(&collection, TestRequest::post().uri("/signup").set_json(&credentials)).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let first = session(&res);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["user"], "alice");
+
+        let about = || TestRequest::get().uri("/get/@alice/about_user.json");
+        assert_eq!(test_support::call(&collection, about()).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test_support::call(&collection, about().cookie(first.clone())).await.status(), StatusCode::OK);
+
+        let res = test_support::call(&collection, TestRequest::post().uri("/login").cookie(first.clone()).set_json(&credentials)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let second = session(&res);
+        assert_ne!(first.value(), second.value());
+        assert_eq!(test_support::call(&collection, about().cookie(first)).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(test_support::call(&collection, about().cookie(second.clone())).await.status(), StatusCode::OK);
+
+        let res = test_support::call(&collection, TestRequest::post().uri("/logout").cookie(second.clone())).await;
+        assert_eq!(session(&res).value(), "");
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body, json!({ "users": [], "active": null }));
+        assert_eq!(test_support::call(&collection, about().cookie(second)).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// 一つのセッションに複数のユーザーでログインし、切り替えやリクエストごとの選択ができる
+    #[actix_web::test]
+    async fn multiple_users() {
+        let (_dir, collection) = test_support::collection(Backend::Memory).await;
+        let alice = json!({ "user": "alice", "password": "password1" });
+        let res = test_support::call(&collection, TestRequest::post().uri("/signup").set_json(&alice)).await;
+        let cookie = session(&res);
+        let res = test_support::call(&collection, TestRequest::post().uri("/signup").cookie(cookie).set_json(json!({ "user": "bob", "password": "password1" }))).await;
+        let cookie = session(&res);
+
+        let res = test_support::call(&collection, TestRequest::get().uri("/session").cookie(cookie.clone())).await;
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!((body["users"].as_array().unwrap().len(), &body["active"]["user"]), (2, &json!("bob")));
+
+        let about = |user: &str| TestRequest::get().uri(&format!("/get/@{}/about_user.json", user)).cookie(cookie.clone());
+        assert_eq!(test_support::call(&collection, about("alice")).await.status(), StatusCode::NOT_FOUND);
+        let req = about("alice").insert_header(("X-Session-User", "@alice"));
+        assert_eq!(test_support::call(&collection, req).await.status(), StatusCode::OK);
+        let req = about("carol").insert_header(("X-Session-User", "@carol"));
+        assert_eq!(test_support::call(&collection, req).await.status(), StatusCode::NOT_FOUND);
+
+        // 切り替えではトークンは変わらない
+        let res = test_support::call(&collection, TestRequest::post().uri("/session/switch").cookie(cookie.clone()).set_json(json!({ "user": "@alice" }))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.response().cookies().next().is_none());
+        assert_eq!(test_support::call(&collection, about("alice")).await.status(), StatusCode::OK);
+        let res = test_support::call(&collection, TestRequest::post().uri("/session/switch").cookie(cookie.clone()).set_json(json!({ "user": "@carol" }))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // 一人を外してもセッションは残り、all で全員を外す
+        let res = test_support::call(&collection, TestRequest::post().uri("/logout?user=@bob").cookie(cookie.clone())).await;
+        assert!(res.response().cookies().next().is_none());
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["active"]["user"], "alice");
+        let res = test_support::call(&collection, TestRequest::post().uri("/logout?all=true").cookie(cookie.clone())).await;
+        assert_eq!(session(&res).value(), "");
+        assert_eq!(test_support::call(&collection, about("alice")).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn failures() {
+        let (_dir, collection) = test_support::collection(Backend::Memory).await;
+        let credentials = json!({ "user": "alice", "password": "password1" });
+        test_support::call(&collection, TestRequest::post().uri("/signup").set_json(&credentials)).await;
+
+        let wrong = json!({ "user": "alice", "password": "wrong" });
+        let res = test_support::call(&collection, TestRequest::post().uri("/login").set_json(&wrong)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = test_support::call(&collection, TestRequest::post().uri("/signup").set_json(&credentials)).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // フォームはコードを付けてページに戻し、外のサイトには移動しない
+        let form = [("user", "alice"), ("password", "wrong"), ("next", "//example.com/")];
+        let res = test_support::call(&collection, TestRequest::post().uri("/login").set_form(form)).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(header::LOCATION).unwrap(), "/login?error=credentials&next=");
+    }
+}
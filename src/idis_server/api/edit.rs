@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use actix_web::{http::{header, StatusCode}, web, HttpRequest, HttpResponse};
+use log::debug;
+use serde_json::json;
+
+use crate::{file_system::{edit::{EditError, EditRights, MetaPatch}, perm::Operation, resolve::Resolved}, share::collection::Collection};
+
+use super::{authorize, err_response, resolve_target};
+
+/// `If-Match: "<id>-<version>"` からバージョンを取り出す
+fn if_match_version(req: &HttpRequest, id: u128) -> Option<u64> {
+    let value = req.headers().get(header::IF_MATCH)?.to_str().ok()?.trim();
+    let tag = value.strip_prefix('"')?.strip_suffix('"')?;
+    let (tag_id, version) = tag.split_once('-')?;
+    if u128::from_str_radix(tag_id, 16).ok()? != id {
+        return None;
+    }
+    version.parse().ok()
+}
+
+/// `PATCH /edit/<@user | RUID>/<path>` -> JSON
+/// Content-Type が `application/json-patch+json` の場合は JSON Patch、それ以外は JSON Merge Patch
+/// 更新を失わないように `If-Match` が必須
+pub async fn edit(req: HttpRequest, body: web::Bytes, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let resolved = match resolve_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    // perm は share、それ以外は edit の権限で変更できる
+    let edit = authorize(&req, &collection, &resolved, Operation::Edit).await;
+    let share = authorize(&req, &collection, &resolved, Operation::Share).await.is_ok();
+    let rights = match edit {
+        Ok(()) => EditRights { fields: true, perm: share },
+        Err(_) if share => EditRights { fields: false, perm: true },
+        Err(e) => return err_response(&e),
+    };
+    let Resolved { user, path, .. } = resolved;
+
+    let current = match collection.file_system.get(&user, &path).await {
+        Ok(meta) => meta,
+        Err(e) => return err_response(&e),
+    };
+    if req.headers().get(header::IF_MATCH).is_none() {
+        return HttpResponse::build(StatusCode::PRECONDITION_REQUIRED)
+            .insert_header((header::ETAG, format!("\"{}\"", current.etag())))
+            .finish();
+    }
+    let version = match if_match_version(&req, current.id) {
+        Some(version) => version,
+        None => return HttpResponse::PreconditionFailed()
+            .insert_header((header::ETAG, format!("\"{}\"", current.etag())))
+            .finish(),
+    };
+
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let patch = if content_type.starts_with("application/json-patch+json") {
+        match serde_json::from_slice(&body) {
+            Ok(patch) => MetaPatch::Json(patch),
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+        }
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(patch) => MetaPatch::Merge(patch),
+            Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+        }
+    };
+
+    match collection.file_system.edit(&user, &path, version, &patch, rights).await {
+        Ok(meta) => HttpResponse::Ok()
+            .insert_header((header::ETAG, format!("\"{}\"", meta.etag())))
+            .json(meta),
+        Err(EditError::VersionMismatch(meta)) => HttpResponse::PreconditionFailed()
+            .insert_header((header::ETAG, format!("\"{}\"", meta.etag())))
+            .finish(),
+        Err(EditError::Immutable(field)) => {
+            debug!("Rejected edit of immutable field {} on {}", field, path);
+            HttpResponse::BadRequest().json(json!({ "error": format!("{} can't be edited", field) }))
+        }
+        Err(EditError::Invalid(e)) => HttpResponse::BadRequest().json(json!({ "error": e })),
+        Err(EditError::Io(e)) => err_response(&e),
+    }
+}
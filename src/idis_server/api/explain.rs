@@ -0,0 +1,56 @@
+use std::{io::{Error, ErrorKind}, sync::Arc};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::{file_system::{perm::{Operation, Principal}, resolve::Resolved}, share::collection::Collection};
+
+use super::{err_response, principal, requester, resolve_target};
+
+#[derive(Deserialize)]
+pub struct ExplainQuery {
+    /// 省略した場合は read
+    pub op: Option<Operation>,
+    /// `@<user>` `RUID` `guest` 省略した場合はリクエストしたユーザー
+    #[serde(rename = "as")]
+    pub as_user: Option<String>,
+}
+
+/// `as` で指定された相手 共有リンクは含めない
+async fn principal_as(collection: &Collection, spec: &str) -> Result<Principal, Error> {
+    match spec {
+        "guest" => collection.file_system.principal(None).await,
+        spec => {
+            let user = collection.file_system.resolve_user(spec).await?;
+            collection.file_system.principal(Some(&user)).await
+        }
+    }
+}
+
+/// `/explain/<@user | RUID>/<path>?op=<op>&as=<@user | RUID | guest>` -> JSON
+/// 各階層で見た規則と、当たった規則、許可か拒否かとその理由
+/// 所有者と `perm.admins` のユーザーのみ
+pub async fn explain(req: HttpRequest, query: web::Query<ExplainQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match resolve_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let requester = requester(&req);
+    if requester.as_deref() != Some(user.as_str()) && !collection.file_system.config.perm.is_admin(requester.as_deref()) {
+        return err_response(&Error::new(ErrorKind::PermissionDenied, "only for the owner or admins"));
+    }
+
+    let subject = match query.as_user.as_deref() {
+        Some(spec) => principal_as(&collection, spec).await,
+        None => principal(&req, &collection).await,
+    };
+    let subject = match subject {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.explain(&user, &path, &subject, query.op.unwrap_or(Operation::Read)).await {
+        Ok(explanation) => HttpResponse::Ok().json(explanation),
+        Err(e) => err_response(&e),
+    }
+}
@@ -0,0 +1,119 @@
+use std::{io::{Error, ErrorKind}, sync::Arc};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::channel::mpsc;
+use serde::Deserialize;
+
+use crate::{file_system::{archive::ArchiveFormat, perm::Operation, preview, resolve::Resolved}, share::collection::Collection};
+
+use super::{err_response, principal, stream::{content_disposition, FileStream}, target};
+
+/// アーカイブを書く側と送る側の間に溜めるチャンクの数
+const ARCHIVE_BUFFER: usize = 8;
+
+#[derive(Deserialize)]
+pub struct GetQuery {
+    /// フォルダを `zip` `tar` `tar.gz` のいずれかにまとめて返す
+    pub archive: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ViwQuery {
+    /// プレビューの長辺 (ピクセル) テキストは抜粋を返す
+    pub size: Option<u32>,
+}
+
+/// `/get/<@user | RUID>/<path>` -> BinaryStream 強制ダウンロード
+/// `?archive=zip|tar|tar.gz` の場合はフォルダをアーカイブにして流す
+pub async fn get(req: HttpRequest, query: web::Query<GetQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    match query.archive.as_deref() {
+        Some(format) => match ArchiveFormat::parse(format) {
+            Some(format) => archive(req, collection, format).await,
+            None => err_response(&Error::new(ErrorKind::InvalidInput, "archive must be zip, tar or tar.gz")),
+        },
+        None => send(req, collection, false, None).await,
+    }
+}
+
+/// 一時ファイルを作らずに書きながら送る
+/// 読めないファイルやフォルダは黙って省く
+async fn archive(req: HttpRequest, collection: web::Data<Arc<Collection>>, format: ArchiveFormat) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Read).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+    let entries = match collection.file_system.archive_entries(&user, &path, &principal).await {
+        Ok(entries) => entries,
+        Err(e) => return err_response(&e),
+    };
+    let name = match entries.first() {
+        Some(root) => root.meta.name.clone(),
+        None => return err_response(&Error::new(ErrorKind::NotFound, "folder is not found")),
+    };
+
+    let (tx, rx) = mpsc::channel(ARCHIVE_BUFFER);
+    let file_system = Arc::clone(&collection.file_system);
+    actix_web::rt::spawn(async move {
+        file_system.write_archive(entries, format, tx).await;
+    });
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(("Content-Disposition", content_disposition("attachment", &format!("{}.{}", name, format.extension()))))
+        .insert_header(("Cache-Control", "private, no-cache"))
+        .streaming(rx)
+}
+
+/// `/viw/<@user | RUID>/<path>?size=<px>` -> BinaryStream
+pub async fn viw(req: HttpRequest, query: web::Query<ViwQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    send(req, collection, true, query.size).await
+}
+
+async fn send(req: HttpRequest, collection: web::Data<Arc<Collection>>, inline: bool, size: Option<u32>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Read).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    let meta = match collection.file_system.get(&user, &path).await {
+        Ok(meta) => meta,
+        Err(e) => return err_response(&e),
+    };
+    let chunk_size = collection.file_system.config.streaming_chunk_size;
+    if let Some(size) = size.filter(|_| meta.data_type != preview::SVG_TYPE) {
+        // プレビューは作り直すまで変わらないので内容のキーを ETag にする
+        return match preview::pick(&meta.previews, size) {
+            Some(p) => FileStream::new(Arc::clone(&collection.file_system.blobs), &p.blob, p.size, chunk_size)
+                .file_name(&meta.name)
+                .content_type(&p.data_type)
+                .inline(true)
+                .last_modified(meta.update_time)
+                .cache_control("private, no-cache")
+                .etag(&p.blob)
+                .send(&req)
+                .await,
+            None => err_response(&Error::new(ErrorKind::NotFound, "preview is not ready")),
+        };
+    }
+    let stream = match (meta.is_folder(), &meta.inline, &meta.blob) {
+        (false, Some(data), _) => FileStream::from_bytes(Bytes::from(data.clone()), chunk_size),
+        (false, None, Some(blob)) => FileStream::new(Arc::clone(&collection.file_system.blobs), blob, meta.size, chunk_size),
+        _ => return err_response(&Error::new(ErrorKind::InvalidInput, "not a file")),
+    };
+
+    let mut stream = stream
+        .file_name(&meta.name)
+        .content_type(&meta.data_type)
+        .inline(inline)
+        .last_modified(meta.update_time)
+        .cache_control("private, no-cache");
+    if let Some(checksum) = &meta.checksum {
+        stream = stream.etag(checksum);
+    }
+    stream.send(&req).await
+}
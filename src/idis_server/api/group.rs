@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::{file_system::resolve::Resolved, share::collection::Collection};
+
+use super::{err_response, owner_target, requester};
+
+#[derive(Deserialize)]
+pub struct NewGroup {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewMember {
+    /// `@<user>` またはユーザーか権限グループの RUID
+    pub member: String,
+}
+
+fn group_id(req: &HttpRequest) -> Option<u128> {
+    req.match_info().get("id").and_then(|id| u128::from_str_radix(id, 16).ok())
+}
+
+/// `/groups/<@user | RUID>` -> JSON
+/// 作った権限グループの一覧 所有者のみ
+pub async fn list(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.groups(&user).await {
+        Ok(groups) => HttpResponse::Ok().json(groups),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `POST /groups/<@user | RUID>` -> JSON
+/// `{ "name": "..." }`
+pub async fn create(req: HttpRequest, body: web::Json<NewGroup>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.create_group(&user, &body.name).await {
+        Ok(group) => HttpResponse::Created().json(group),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `/groups/<@user | RUID>/<id>` -> JSON
+pub async fn get(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let id = match group_id(&req) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match collection.file_system.owned_group(&user, id).await {
+        Ok(group) => HttpResponse::Ok().json(group),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `DELETE /groups/<@user | RUID>/<id>` -> JSON
+pub async fn delete(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let id = match group_id(&req) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match collection.file_system.delete_group(&user, id).await {
+        Ok(group) => HttpResponse::Ok().json(group),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `POST /groups/<@user | RUID>/<id>/members` -> JSON
+/// `{ "member": "@user" }` 他のグループを入れる場合は RUID
+pub async fn add_member(req: HttpRequest, body: web::Json<NewMember>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let id = match group_id(&req) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let member = match collection.file_system.resolve_member(&body.member).await {
+        Ok(m) => m,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.add_member(&user, id, member).await {
+        Ok(group) => HttpResponse::Ok().json(group),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `DELETE /groups/<@user | RUID>/<id>/members/<@user | RUID>` -> JSON
+pub async fn remove_member(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let id = match group_id(&req) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let member = match collection.file_system.resolve_member(req.match_info().get("member").unwrap_or("")).await {
+        Ok(m) => m,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.remove_member(&user, id, member).await {
+        Ok(group) => HttpResponse::Ok().json(group),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `/memberships/<@user | RUID>` -> JSON
+/// 直接または入れ子で属する権限グループ 本人と `perm.admins` のユーザーのみ
+pub async fn memberships(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let user = match collection.file_system.resolve_user(req.match_info().get("user").unwrap_or("")).await {
+        Ok(u) => u,
+        Err(e) => return err_response(&e),
+    };
+    let requester = requester(&req);
+    if requester.as_deref() != Some(user.as_str()) && !collection.file_system.config.perm.is_admin(requester.as_deref()) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    match collection.file_system.memberships(&user).await {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(e) => err_response(&e),
+    }
+}
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use serde_json::json;
+
+use crate::{file_system::{perm::Operation, resolve::Resolved}, share::collection::Collection};
+
+use super::{err_response, principal, target};
+
+/// `/ls/<@user | RUID>/<path>` -> JSON
+pub async fn ls(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Read).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+    let listed = match collection.file_system.list(&user, &path).await {
+        Ok((meta, children)) => collection.file_system.filter_children(&user, &path, &principal, children, Operation::Read).await
+            .map(|children| (meta, children)),
+        Err(e) => Err(e),
+    };
+    match listed {
+        Ok((meta, children)) => HttpResponse::Ok()
+            .insert_header((header::ETAG, format!("\"{}\"", meta.etag())))
+            .json(json!({
+                "meta": meta,
+                "links": children,
+            })),
+        Err(e) => err_response(&e),
+    }
+}
@@ -0,0 +1,190 @@
+use std::{io::{Error, ErrorKind}, net::SocketAddr, sync::Arc};
+
+use actix_web::{http::StatusCode, web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use serde::Deserialize;
+
+use crate::{file_system::{perm::{Operation, Principal}, resolve::Resolved}, share::collection::Collection};
+
+pub mod auth;
+pub mod edit;
+pub mod explain;
+pub mod get;
+pub mod group;
+pub mod ls;
+pub mod resumable;
+pub mod rm;
+pub mod share;
+pub mod stream;
+#[cfg(test)]
+pub mod test_support;
+pub mod transfer;
+pub mod upload;
+pub mod usage;
+pub mod version;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(web::resource("/login").route(web::get().to(auth::login_page)).route(web::post().to(auth::login)))
+        .service(web::resource("/signup").route(web::get().to(auth::signup_page)).route(web::post().to(auth::signup)))
+        .service(web::resource("/logout").route(web::post().to(auth::logout)))
+        .service(web::resource("/session").route(web::get().to(auth::session)))
+        .service(web::resource("/session/switch").route(web::post().to(auth::switch)))
+        .service(web::resource(["/ls/{user}", "/ls/{user}/{path:.*}"]).route(web::get().to(ls::ls)))
+        .service(web::resource(["/upload/{user}", "/upload/{user}/{path:.*}"]).route(web::post().to(upload::upload)))
+        .service(web::resource("/get/{user}/{path:.*}").route(web::get().to(get::get)).route(web::head().to(get::get)))
+        .service(web::resource("/viw/{user}/{path:.*}").route(web::get().to(get::viw)).route(web::head().to(get::viw)))
+        .service(web::resource(["/edit/{user}", "/edit/{user}/{path:.*}"]).route(web::patch().to(edit::edit)).route(web::post().to(edit::edit)))
+        .service(web::resource("/rm/{user}/{path:.*}").route(web::post().to(rm::rm)).route(web::delete().to(rm::rm)))
+        .service(web::resource("/mv/{user}/{path:.*}").route(web::post().to(transfer::mv)))
+        .service(web::resource("/cp/{user}/{path:.*}").route(web::post().to(transfer::cp)))
+        .service(web::resource("/trash/{user}").route(web::get().to(rm::trash_list)))
+        .service(web::resource("/trash/{user}/{id}").route(web::delete().to(rm::purge)))
+        .service(web::resource("/restore/{user}/{id}").route(web::post().to(rm::restore)))
+        .service(web::resource("/history/{user}/{path:.*}").route(web::get().to(version::history)))
+        .service(web::resource("/version/{user}/{path:.*}").route(web::get().to(version::get)).route(web::head().to(version::get)).route(web::post().to(version::restore)))
+        .service(web::resource("/diff/{user}/{path:.*}").route(web::get().to(version::diff)))
+        .service(web::resource("/usage/{user}").route(web::get().to(usage::usage)))
+        .service(web::resource("/share/{user}/{path:.*}").route(web::post().to(share::create)))
+        .service(web::resource("/links/{user}").route(web::get().to(share::list)))
+        .service(web::resource("/links/{user}/{id}").route(web::delete().to(share::revoke)))
+        .service(web::resource("/groups/{user}").route(web::get().to(group::list)).route(web::post().to(group::create)))
+        .service(web::resource("/groups/{user}/{id}").route(web::get().to(group::get)).route(web::delete().to(group::delete)))
+        .service(web::resource("/groups/{user}/{id}/members").route(web::post().to(group::add_member)))
+        .service(web::resource("/groups/{user}/{id}/members/{member}").route(web::delete().to(group::remove_member)))
+        .service(web::resource("/memberships/{user}").route(web::get().to(group::memberships)))
+        .service(web::resource(["/explain/{user}", "/explain/{user}/{path:.*}"]).route(web::get().to(explain::explain)))
+        .configure(resumable::config);
+}
+
+/// リクエストごとにセッションの中のユーザーを選ぶヘッダー `@<user>` または RUID
+pub const SESSION_USER_HEADER: &str = "X-Session-User";
+
+/// リクエストしたユーザー セッションの今のユーザー ログインしていない場合は None
+/// `X-Session-User` がある場合はそのユーザー セッションにログインしていないユーザーは None
+pub fn requester(req: &HttpRequest) -> Option<String> {
+    let collection = req.app_data::<web::Data<Arc<Collection>>>()?;
+    let token = collection.sessions.token(req)?;
+    match req.headers().get(SESSION_USER_HEADER) {
+        Some(spec) => collection.sessions.impersonate(&token, spec.to_str().ok()?).map(|u| u.user),
+        None => collection.sessions.current(&token).map(|u| u.user),
+    }
+}
+
+#[derive(Deserialize)]
+struct ShareQuery {
+    share: Option<String>,
+}
+
+/// `?share=<token>` または `X-Share-Token` の共有リンク
+fn share_token(req: &HttpRequest) -> Option<String> {
+    if let Some(token) = req.headers().get("X-Share-Token").and_then(|v| v.to_str().ok()) {
+        return Some(token.to_string());
+    }
+    web::Query::<ShareQuery>::from_query(req.query_string()).ok()?.into_inner().share
+}
+
+/// 接続元の IP アドレス
+/// `trusted_proxies` からの接続の場合のみ `Forwarded` / `X-Forwarded-For` を使う ヘッダーは偽れるため
+pub fn client_addr(req: &HttpRequest, collection: &Collection) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    if !collection.config.idis_server.service_config.trusted_proxies.contains(&peer) {
+        return Some(peer.to_string());
+    }
+    let addr = req.connection_info().realip_remote_addr()?.to_string();
+    Some(addr.parse::<SocketAddr>().map(|a| a.ip().to_string()).unwrap_or(addr))
+}
+
+/// 共有リンクの使用回数を数える単位 ログインしている場合はセッション、それ以外は接続元と User-Agent
+fn share_visitor(req: &HttpRequest, collection: &Collection) -> String {
+    match collection.sessions.token(req) {
+        Some(token) => format!("session:{}", token),
+        None => format!(
+            "client:{} {}",
+            client_addr(req, collection).unwrap_or_default(),
+            req.headers().get("User-Agent").and_then(|v| v.to_str().ok()).unwrap_or(""),
+        ),
+    }
+}
+
+/// 権限の判定に使うリクエストしたユーザーとその権限グループ
+/// 共有リンクがあれば使うので、リクエストごとに一度だけ作って使い回す
+/// 使えない共有リンクは無いものとして扱う 所有者などはリンクが無くても操作できるため
+pub async fn principal(req: &HttpRequest, collection: &Collection) -> Result<Principal, Error> {
+    let cached = req.extensions().get::<Principal>().cloned();
+    if let Some(principal) = cached {
+        return Ok(principal);
+    }
+
+    let mut principal = collection.file_system.principal(requester(req).as_deref()).await?;
+    if let Some(token) = share_token(req) {
+        let password = req.headers().get("X-Share-Password").and_then(|v| v.to_str().ok());
+        match collection.share_links.redeem(&token, password, &share_visitor(req, collection)).await {
+            Ok(grant) => principal.link = Some(grant),
+            Err(e) => debug!("share link is ignored: {}", e),
+        }
+    }
+    req.extensions_mut().insert(principal.clone());
+    Ok(principal)
+}
+
+/// `<@user | RUID>/<path>` を解決し、領域と perm から op ができるか確かめる
+/// 全てのエンドポイントはこれを通してパスを扱う
+pub async fn target(req: &HttpRequest, collection: &Collection, op: Operation) -> Result<Resolved, Error> {
+    let resolved = resolve_target(req, collection).await?;
+    authorize(req, collection, &resolved, op).await?;
+    Ok(resolved)
+}
+
+/// ゴミ箱などユーザー全体に関わる操作は所有者のみ
+pub async fn owner_target(req: &HttpRequest, collection: &Collection) -> Result<Resolved, Error> {
+    let resolved = resolve_target(req, collection).await?;
+    if requester(req).as_deref() != Some(resolved.user.as_str()) {
+        return Err(Error::new(ErrorKind::PermissionDenied, "only for the owner"));
+    }
+    Ok(resolved)
+}
+
+/// 権限を確かめずに `<@user | RUID>/<path>` を解決する
+/// 解決したパスを加工する場合は、その後で authorize を呼ぶ
+pub async fn resolve_target(req: &HttpRequest, collection: &Collection) -> Result<Resolved, Error> {
+    let user = req.match_info().get("user").unwrap_or("");
+    if user.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "user is not specified"));
+    }
+    collection.file_system.resolve(user, req.match_info().get("path").unwrap_or("")).await
+}
+
+/// 解決済みのパスに op ができるか確かめる
+pub async fn authorize(req: &HttpRequest, collection: &Collection, resolved: &Resolved, op: Operation) -> Result<(), Error> {
+    let principal = principal(req, collection).await?;
+    resolved.check(principal.user.as_deref())?;
+    collection.file_system.authorize(&resolved.user, &resolved.path, &principal, op).await
+}
+
+/// io::Error をレスポンスに変換する
+/// 権限エラーはファイルの存在を秘匿するため 404 にする
+pub fn err_response(e: &Error) -> HttpResponse {
+    let status = match e.kind() {
+        ErrorKind::NotFound | ErrorKind::PermissionDenied => StatusCode::NOT_FOUND,
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => StatusCode::BAD_REQUEST,
+        ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        ErrorKind::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+        ErrorKind::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        ErrorKind::ResourceBusy => StatusCode::TOO_MANY_REQUESTS,
+        _ => {
+            error!("Internal error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    HttpResponse::build(status).finish()
+}
+
+/// HTTP-date (RFC 7231) 形式
+pub fn http_date(time_millis: i64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(time_millis)
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
@@ -0,0 +1,279 @@
+use std::{collections::HashMap, sync::Arc};
+
+use actix_web::{guard, http::StatusCode, web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use base64::{engine::general_purpose, Engine as _};
+use log::error;
+
+use crate::{file_system::{path, perm::Operation, resolve::Resolved, upload::{Appended, UploadError, UploadSession}}, share::collection::Collection};
+
+use super::{authorize, err_response, http_date, principal, resolve_target};
+
+/// tus 1.0.0 互換の再開可能アップロード
+/// https://tus.io/protocols/resumable-upload
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSION: &str = "creation,checksum,expiration,termination";
+const CHECKSUM_MISMATCH: u16 = 460;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(web::resource("/uploads").route(web::method(actix_web::http::Method::OPTIONS).to(options)))
+        // `/uploads/{user}` と同じ形なので先に登録し、POST は次の resource に回す
+        .service(
+            web::resource("/uploads/{id}")
+                .guard(guard::Not(guard::Post()))
+                .route(web::head().to(offset))
+                .route(web::get().to(offset))
+                .route(web::patch().to(append))
+                .route(web::put().to(append))
+                .route(web::delete().to(terminate)),
+        )
+        .service(web::resource(["/uploads/{user}", "/uploads/{user}/{path:.*}"]).route(web::post().to(create)));
+}
+
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header(("Tus-Resumable", TUS_VERSION));
+    builder.insert_header(("Cache-Control", "no-store"));
+    builder
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Tus-Resumable ヘッダーが指定されている場合はバージョンを確認する 違う場合は返すレスポンス
+fn version_mismatch(req: &HttpRequest) -> Option<HttpResponse> {
+    match header(req, "Tus-Resumable") {
+        Some(v) if v != TUS_VERSION => Some(tus_response(StatusCode::PRECONDITION_FAILED)
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .finish()),
+        _ => None,
+    }
+}
+
+fn upload_id(req: &HttpRequest) -> Option<u128> {
+    req.match_info().get("id").and_then(|id| u128::from_str_radix(id, 16).ok())
+}
+
+/// `Upload-Metadata: key base64,key base64`
+fn parse_metadata(value: &str) -> HashMap<String, String> {
+    value.split(',')
+        .filter_map(|pair| {
+            let mut it = pair.trim().splitn(2, ' ');
+            let key = it.next()?.to_string();
+            let value = match it.next() {
+                Some(v) => String::from_utf8(general_purpose::STANDARD.decode(v.trim()).ok()?).ok()?,
+                None => String::new(),
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn upload_err_response(e: UploadError) -> HttpResponse {
+    match e {
+        UploadError::NotFound => tus_response(StatusCode::NOT_FOUND).finish(),
+        UploadError::Locked => tus_response(StatusCode::LOCKED).finish(),
+        UploadError::OffsetMismatch(offset) => tus_response(StatusCode::CONFLICT)
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .finish(),
+        UploadError::TooLarge => tus_response(StatusCode::PAYLOAD_TOO_LARGE).finish(),
+        UploadError::ChecksumMismatch => tus_response(StatusCode::from_u16(CHECKSUM_MISMATCH).unwrap()).finish(),
+        UploadError::Io(e) => err_response(&e),
+    }
+}
+
+fn session_headers(builder: &mut HttpResponseBuilder, session: &UploadSession) {
+    builder.insert_header(("Upload-Offset", session.offset.to_string()));
+    builder.insert_header(("Upload-Length", session.length.to_string()));
+    builder.insert_header(("Upload-Expires", http_date(session.expire_time)));
+}
+
+pub async fn options(collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSION))
+        .insert_header(("Tus-Max-Size", collection.upload.max_size.to_string()))
+        .insert_header(("Tus-Checksum-Algorithm", "sha256"))
+        .finish()
+}
+
+/// `POST /uploads/<@user | RUID>/<path>`
+/// Upload-Metadata の `sha256` に全体のチェックサム (hex) を指定できる
+/// パスが `/` で終わる場合は `filename` を付け足す
+pub async fn create(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    if let Some(res) = version_mismatch(&req) {
+        return res;
+    }
+    let mut resolved = match resolve_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let length = match header(&req, "Upload-Length").and_then(|v| v.parse::<u64>().ok()) {
+        Some(length) => length,
+        None => return tus_response(StatusCode::BAD_REQUEST).finish(),
+    };
+    let metadata = header(&req, "Upload-Metadata").map(parse_metadata).unwrap_or_default();
+
+    if req.path().ends_with('/') {
+        match metadata.get("filename").map(|name| path::normalize_name(name)) {
+            Some(Ok(name)) => resolved.path = path::join(&resolved.path, &name),
+            _ => return tus_response(StatusCode::BAD_REQUEST).finish(),
+        }
+    }
+    if path::parent(&resolved.path).is_none() {
+        return tus_response(StatusCode::BAD_REQUEST).finish();
+    }
+    // filename を付け足した後のパスで判定する
+    if let Err(e) = authorize(&req, &collection, &resolved, Operation::Create).await {
+        return err_response(&e);
+    }
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+    let Resolved { user, path: file_path, .. } = resolved;
+
+    let session = match collection.upload.create(&user, &file_path, length, metadata.get("sha256").cloned(), &principal).await {
+        Ok(session) => session,
+        Err(e) => return upload_err_response(e),
+    };
+
+    let mut builder = tus_response(StatusCode::CREATED);
+    builder.insert_header(("Location", format!("/uploads/{:032x}", session.id)));
+    session_headers(&mut builder, &session);
+    builder.finish()
+}
+
+/// `HEAD /uploads/<id>` 現在のオフセットを返す
+pub async fn offset(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    if let Some(res) = version_mismatch(&req) {
+        return res;
+    }
+    let id = match upload_id(&req) {
+        Some(id) => id,
+        None => return tus_response(StatusCode::NOT_FOUND).finish(),
+    };
+
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.upload.get(id, &principal).await {
+        Ok(session) => {
+            let mut builder = tus_response(StatusCode::OK);
+            session_headers(&mut builder, &session);
+            builder.finish()
+        }
+        Err(e) => upload_err_response(e),
+    }
+}
+
+/// `PATCH /uploads/<id>` (`PUT` も可) Upload-Offset からチャンクを追記する
+pub async fn append(req: HttpRequest, payload: web::Payload, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    if let Some(res) = version_mismatch(&req) {
+        return res;
+    }
+    let id = match upload_id(&req) {
+        Some(id) => id,
+        None => return tus_response(StatusCode::NOT_FOUND).finish(),
+    };
+    if req.method() == actix_web::http::Method::PATCH && header(&req, "Content-Type") != Some("application/offset+octet-stream") {
+        return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).finish();
+    }
+    let offset = match header(&req, "Upload-Offset").and_then(|v| v.parse::<u64>().ok()) {
+        Some(offset) => offset,
+        None => return tus_response(StatusCode::BAD_REQUEST).finish(),
+    };
+
+    // Upload-Checksum: sha256 <base64>
+    let chunk_checksum = match header(&req, "Upload-Checksum") {
+        Some(value) => match value.split_once(' ') {
+            Some(("sha256", digest)) => match general_purpose::STANDARD.decode(digest.trim()) {
+                Ok(digest) => Some(digest),
+                Err(_) => return tus_response(StatusCode::BAD_REQUEST).finish(),
+            },
+            _ => return tus_response(StatusCode::BAD_REQUEST).finish(),
+        },
+        None => None,
+    };
+
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.upload.append(id, &principal, offset, payload, chunk_checksum).await {
+        Ok(Appended::Partial(session)) => {
+            let mut builder = tus_response(StatusCode::NO_CONTENT);
+            session_headers(&mut builder, &session);
+            builder.finish()
+        }
+        Ok(Appended::Finished(session, meta)) => {
+            tus_response(StatusCode::NO_CONTENT)
+                .insert_header(("Upload-Offset", session.offset.to_string()))
+                .insert_header(("Content-Location", format!("/ls/@{}{}", meta.owner, meta.path)))
+                .finish()
+        }
+        Err(e) => {
+            error!("Upload {:032x} failed: {}", id, e);
+            upload_err_response(e)
+        }
+    }
+}
+
+/// `DELETE /uploads/<id>`
+pub async fn terminate(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    if let Some(res) = version_mismatch(&req) {
+        return res;
+    }
+    let id = match upload_id(&req) {
+        Some(id) => id,
+        None => return tus_response(StatusCode::NOT_FOUND).finish(),
+    };
+
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.upload.terminate(id, &principal).await {
+        Ok(()) => tus_response(StatusCode::NO_CONTENT).finish(),
+        Err(e) => upload_err_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::{Method, StatusCode}, test::{call_service, init_service, TestRequest}, App};
+
+    use super::{config, parse_metadata};
+
+    #[test]
+    fn metadata_values_are_base64() {
+        let metadata = parse_metadata("filename ZG9jcy9hLnR4dA==, sha256 YWJj,empty, broken !!!");
+        assert_eq!(metadata.get("filename").map(String::as_str), Some("docs/a.txt"));
+        assert_eq!(metadata.get("sha256").map(String::as_str), Some("abc"));
+        assert_eq!(metadata.get("empty").map(String::as_str), Some(""));
+        assert!(!metadata.contains_key("broken"));
+    }
+
+    /// アップロードの id と作成先のユーザーが同じ形のパスでも、メソッドごとに正しい resource に届く
+    #[actix_web::test]
+    async fn routes_by_method() {
+        let app = init_service(App::new().configure(config)).await;
+        for (method, uri) in [
+            (Method::HEAD, "/uploads/0123456789abcdef0123456789abcdef"),
+            (Method::PATCH, "/uploads/0123456789abcdef0123456789abcdef"),
+            (Method::DELETE, "/uploads/0123456789abcdef0123456789abcdef"),
+            (Method::POST, "/uploads/@alice"),
+            (Method::POST, "/uploads/@alice/docs/a.txt"),
+        ] {
+            let req = TestRequest::default().method(method.clone()).uri(uri).to_request();
+            let res = call_service(&app, req).await;
+            assert_ne!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, uri);
+            assert_ne!(res.status(), StatusCode::NOT_FOUND, "{} {}", method, uri);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::{io::{Error, ErrorKind}, sync::Arc};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::channel::mpsc;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{file_system::{perm::Operation, resolve::Resolved}, share::collection::Collection};
+
+use super::{err_response, owner_target, principal, target};
+
+#[derive(Deserialize)]
+pub struct RmQuery {
+    #[serde(default)]
+    pub progress: bool,
+}
+
+fn trash_id(req: &HttpRequest) -> Option<u128> {
+    req.match_info().get("id").and_then(|id| u128::from_str_radix(id, 16).ok())
+}
+
+/// `/rm/<@user | RUID>/<path>` -> JSON
+/// ゴミ箱に移動して削除したメタデータを返す 中身も全て削除できる場合のみ
+/// `?progress=true` の場合は進捗を NDJSON で流し、最後の行に結果を返す
+pub async fn rm(req: HttpRequest, query: web::Query<RmQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Delete).await {
+        Ok(t) if t.is_area_root() => return err_response(&Error::new(ErrorKind::InvalidInput, "area root can't be removed")),
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+
+    if !query.progress {
+        return match collection.file_system.remove(&user, &path, &principal, |_| {}).await {
+            Ok((meta, trash)) => HttpResponse::Ok().json(json!({
+                "meta": meta,
+                "trash": trash,
+            })),
+            Err(e) => err_response(&e),
+        };
+    }
+
+    // 存在しない場合はストリームを始める前にエラーを返す
+    if let Err(e) = collection.file_system.get(&user, &path).await {
+        return err_response(&e);
+    }
+
+    let (tx, rx) = mpsc::unbounded::<Result<Bytes, actix_web::Error>>();
+    let file_system = Arc::clone(&collection.file_system);
+    actix_web::rt::spawn(async move {
+        let line = |value: serde_json::Value| Ok(Bytes::from(format!("{}\n", value)));
+        let progress_tx = tx.clone();
+        let result = file_system.remove(&user, &path, &principal, move |progress| {
+            let _ = progress_tx.unbounded_send(line(json!({ "progress": progress })));
+        }).await;
+        let last = match result {
+            Ok((meta, trash)) => json!({ "meta": meta, "trash": trash }),
+            Err(e) => json!({ "error": e.to_string() }),
+        };
+        let _ = tx.unbounded_send(line(last));
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(rx)
+}
+
+/// `/trash/<@user | RUID>` -> JSON
+pub async fn trash_list(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.trash_list(&user).await {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `/restore/<@user | RUID>/<trash id>` -> JSON
+pub async fn restore(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let id = match trash_id(&req) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match collection.file_system.restore(&user, id).await {
+        Ok(meta) => HttpResponse::Ok().json(meta),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `DELETE /trash/<@user | RUID>/<trash id>` -> JSON
+/// 保持期間を待たずに完全に削除する
+pub async fn purge(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let id = match trash_id(&req) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match collection.file_system.purge(Some(&user), id).await {
+        Ok(entry) => HttpResponse::Ok().json(entry),
+        Err(e) => err_response(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}};
+    use serde_json::Value;
+
+    use crate::{
+        file_system::test_support::{self as fs_support, Backend},
+        idis_server::{api::test_support, session::SessionUser},
+        share::collection::Collection,
+    };
+
+    fn as_user(collection: &Collection, user: &str, req: TestRequest) -> TestRequest {
+        let token = collection.sessions.login(None, SessionUser { ruid: 1, user: user.to_string() }).unwrap();
+        req.cookie(collection.sessions.cookie(&token))
+    }
+
+    #[actix_web::test]
+    async fn progress_trash_restore_and_purge() {
+        let (_dir, collection) = test_support::collection(Backend::Memory).await;
+        fs_support::write(&collection.file_system, "alice", "/home/docs/a.txt", b"alpha").await;
+        fs_support::write(&collection.file_system, "alice", "/home/docs/sub/b.txt", b"beta").await;
+
+        let res = test_support::call(&collection, as_user(&collection, "alice", TestRequest::post().uri("/rm/@alice/docs?progress=true"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "application/x-ndjson");
+        let body = test::read_body(res).await;
+        let lines: Vec<Value> = std::str::from_utf8(&body).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 5);
+        for (i, line) in lines[..4].iter().enumerate() {
+            assert_eq!((line["progress"]["done"].as_u64(), line["progress"]["total"].as_u64()), (Some(i as u64 + 1), Some(4)));
+        }
+        assert_eq!(lines[4]["trash"]["path"], "/home/docs");
+        let id = lines[4]["trash"]["id"].as_str().unwrap().to_string();
+
+        // ゴミ箱は所有者のみ
+        let res = test_support::call(&collection, as_user(&collection, "bob", TestRequest::get().uri("/trash/@alice"))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = test_support::call(&collection, as_user(&collection, "alice", TestRequest::get().uri("/trash/@alice"))).await;
+        let list: Value = test::read_body_json(res).await;
+        assert_eq!(list.as_array().unwrap().len(), 1);
+
+        let uri = format!("/restore/@alice/{}", id);
+        let res = test_support::call(&collection, as_user(&collection, "bob", TestRequest::post().uri(&uri))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = test_support::call(&collection, as_user(&collection, "alice", TestRequest::post().uri(&uri))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(collection.file_system.get("alice", "/home/docs/sub/b.txt").await.is_ok());
+
+        let res = test_support::call(&collection, as_user(&collection, "alice", TestRequest::delete().uri("/rm/@alice/docs"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let removed: Value = test::read_body_json(res).await;
+        let uri = format!("/trash/@alice/{}", removed["trash"]["id"].as_str().unwrap());
+        let res = test_support::call(&collection, as_user(&collection, "alice", TestRequest::delete().uri(&uri))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(collection.file_system.trash_list("alice").await.unwrap().is_empty());
+        let res = test_support::call(&collection, as_user(&collection, "alice", TestRequest::delete().uri(&uri))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::json;
+
+use crate::{file_system::{perm::Operation, resolve::Resolved, share_link::NewShareLink}, share::collection::Collection};
+
+use super::{err_response, owner_target, principal, target};
+
+/// URL のパスに使えない文字をエンコードする `/` はそのまま
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// `POST /share/<@user | RUID>/<path>` -> JSON
+/// `{ "ops": ["read"], "expire": <秒>, "password": "...", "max_uses": <回数> }`
+/// トークンは `?share=<token>` または `X-Share-Token` で使う
+pub async fn create(req: HttpRequest, body: web::Json<NewShareLink>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Share).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let meta = match collection.file_system.get(&user, &path).await {
+        Ok(meta) => meta,
+        Err(e) => return err_response(&e),
+    };
+
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.share_links.create(&user, &path, &principal, body.into_inner()).await {
+        Ok((link, token)) => {
+            let endpoint = if meta.is_folder() { "ls" } else { "get" };
+            HttpResponse::Created().json(json!({
+                "link": link.summary(),
+                "url": format!("/{}/@{}{}?share={}", endpoint, user, encode_path(&path), token),
+                "token": token,
+            }))
+        }
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `/links/<@user | RUID>` -> JSON
+pub async fn list(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.share_links.list(&user).await {
+        Ok(links) => HttpResponse::Ok().json(links),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `DELETE /links/<@user | RUID>/<id>` -> JSON
+/// 取り消したリンクのトークンは以後使えない
+pub async fn revoke(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, .. } = match owner_target(&req, &collection).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let id = match req.match_info().get("id").and_then(|id| u128::from_str_radix(id, 16).ok()) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match collection.share_links.revoke(&user, id).await {
+        Ok(link) => HttpResponse::Ok().json(link),
+        Err(e) => err_response(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}};
+
+    use crate::{
+        file_system::{perm::Operation, share_link::NewShareLink, test_support::{self as fs_support, Backend}},
+        idis_server::api::test_support,
+    };
+
+    #[test]
+    fn paths_are_encoded() {
+        assert_eq!(super::encode_path("/docs/a b/日.txt"), "/docs/a%20b/%E6%97%A5.txt");
+    }
+
+    /// トークンはクエリでもヘッダーでも使え、リンクの範囲の外は見えない
+    #[actix_web::test]
+    async fn token_grants_read() {
+        let (_dir, collection) = test_support::collection(Backend::Memory).await;
+        fs_support::write(&collection.file_system, "alice", "/home/docs/a.txt", b"alpha").await;
+        fs_support::write(&collection.file_system, "alice", "/home/b.txt", b"beta").await;
+        let new = NewShareLink { ops: [Operation::Read].into(), expire: None, password: None, max_uses: None };
+        let alice = collection.file_system.principal(Some("alice")).await.unwrap();
+        let (_, token) = collection.share_links.create("alice", "/home/docs", &alice, new).await.unwrap();
+
+        let res = test_support::call(&collection, TestRequest::get().uri("/get/@alice/docs/a.txt")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = test_support::call(&collection, TestRequest::get().uri(&format!("/get/@alice/docs/a.txt?share={}", token))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, &b"alpha"[..]);
+        let req = TestRequest::get().uri("/get/@alice/docs/a.txt").insert_header(("X-Share-Token", token.as_str()));
+        assert_eq!(test_support::call(&collection, req).await.status(), StatusCode::OK);
+        let res = test_support::call(&collection, TestRequest::get().uri(&format!("/get/@alice/b.txt?share={}", token))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,568 @@
+use std::{io::{Error, ErrorKind}, sync::Arc};
+
+use actix_web::{body::SizedStream, http::{header, Method, StatusCode}, HttpRequest, HttpResponse, HttpResponseBuilder};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::{stream::{self, BoxStream}, StreamExt};
+
+use crate::file_system::store::BlobStore;
+
+use super::http_date;
+
+/// 一度に返す範囲の上限 超える場合は Range を無視して全体を返す
+pub const MAX_RANGES: usize = 16;
+
+/// バイナリをストリームで返すレスポンスビルダー
+/// Range / If-Range (multipart/byteranges を含む) と条件付きリクエストに対応する
+pub struct FileStream {
+    source: Source,
+    size: u64,
+    chunk_size: usize,
+    file_name: Option<String>,
+    inline: bool,
+    content_type: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<i64>,
+    cache_control: Option<String>,
+}
+
+/// 本体の読み出し元
+#[derive(Clone)]
+enum Source {
+    Blob(Arc<dyn BlobStore>, String),
+    Inline(Bytes),
+}
+
+impl Source {
+    fn read(&self, start: u64, len: u64, chunk_size: usize) -> BoxStream<'static, Result<Bytes, Error>> {
+        match self {
+            Source::Blob(blobs, key) => blobs.read(key, start, len, chunk_size),
+            Source::Inline(data) => {
+                if start + len > data.len() as u64 {
+                    return stream::once(async { Err(Error::new(ErrorKind::UnexpectedEof, "data is shorter than expected")) }).boxed();
+                }
+                let data = data.slice(start as usize..(start + len) as usize);
+                let chunks: Vec<Result<Bytes, Error>> = (0..data.len())
+                    .step_by(chunk_size)
+                    .map(|i| Ok(data.slice(i..(i + chunk_size).min(data.len()))))
+                    .collect();
+                stream::iter(chunks).boxed()
+            }
+        }
+    }
+}
+
+/// 両端を含むバイト範囲
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+pub enum RangeRequest {
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+impl FileStream {
+    pub fn new(blobs: Arc<dyn BlobStore>, key: &str, size: u64, chunk_size: usize) -> Self {
+        Self::with_source(Source::Blob(blobs, key.to_string()), size, chunk_size)
+    }
+
+    /// メタデータに直接保存された本体
+    pub fn from_bytes(data: Bytes, chunk_size: usize) -> Self {
+        let size = data.len() as u64;
+        Self::with_source(Source::Inline(data), size, chunk_size)
+    }
+
+    fn with_source(source: Source, size: u64, chunk_size: usize) -> Self {
+        Self {
+            source,
+            size,
+            chunk_size: chunk_size.max(1),
+            file_name: None,
+            inline: false,
+            content_type: None,
+            etag: None,
+            last_modified: None,
+            cache_control: None,
+        }
+    }
+
+    pub fn file_name(mut self, file_name: &str) -> Self {
+        self.file_name = Some(file_name.to_string());
+        self
+    }
+
+    pub fn inline(mut self, inline: bool) -> Self {
+        self.inline = inline;
+        self
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// 強い ETag の値 (クォートなし)
+    pub fn etag(mut self, tag: &str) -> Self {
+        self.etag = Some(format!("\"{}\"", tag));
+        self
+    }
+
+    pub fn last_modified(mut self, time_millis: i64) -> Self {
+        self.last_modified = Some(time_millis);
+        self
+    }
+
+    pub fn cache_control(mut self, value: &str) -> Self {
+        self.cache_control = Some(value.to_string());
+        self
+    }
+
+    fn content_type_value(&self) -> String {
+        match (&self.content_type, &self.file_name) {
+            (Some(content_type), _) => content_type.clone(),
+            (None, Some(file_name)) => mime_guess::from_path(file_name).first_or_octet_stream().to_string(),
+            (None, None) => "application/octet-stream".to_string(),
+        }
+    }
+
+    fn base_response(&self, status: StatusCode) -> HttpResponseBuilder {
+        let mut builder = HttpResponse::build(status);
+        builder.insert_header((header::ACCEPT_RANGES, "bytes"));
+        builder.insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+        if self.inline {
+            // 利用者のファイルをサイトのオリジンでスクリプトとして動かさない
+            builder.insert_header((header::CONTENT_SECURITY_POLICY, "sandbox"));
+        }
+        if let Some(etag) = &self.etag {
+            builder.insert_header((header::ETAG, etag.as_str()));
+        }
+        if let Some(last_modified) = self.last_modified {
+            builder.insert_header((header::LAST_MODIFIED, http_date(last_modified)));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            builder.insert_header((header::CACHE_CONTROL, cache_control.as_str()));
+        }
+        builder
+    }
+
+    /// If-Match / If-Unmodified-Since / If-None-Match / If-Modified-Since を評価する
+    /// RFC 7232 Section 6 の順序
+    fn precondition(&self, req: &HttpRequest) -> Option<StatusCode> {
+        if let Some(if_match) = header_str(req, header::IF_MATCH) {
+            if !etag_matches(if_match, self.etag.as_deref(), false) {
+                return Some(StatusCode::PRECONDITION_FAILED);
+            }
+        } else if let (Some(since), Some(modified)) = (header_str(req, header::IF_UNMODIFIED_SINCE).and_then(parse_http_date), self.last_modified) {
+            if modified / 1000 > since / 1000 {
+                return Some(StatusCode::PRECONDITION_FAILED);
+            }
+        }
+
+        let safe = req.method() == Method::GET || req.method() == Method::HEAD;
+        if let Some(if_none_match) = header_str(req, header::IF_NONE_MATCH) {
+            if etag_matches(if_none_match, self.etag.as_deref(), true) {
+                return Some(if safe { StatusCode::NOT_MODIFIED } else { StatusCode::PRECONDITION_FAILED });
+            }
+        } else if let (true, Some(since), Some(modified)) = (safe, header_str(req, header::IF_MODIFIED_SINCE).and_then(parse_http_date), self.last_modified) {
+            if modified / 1000 <= since / 1000 {
+                return Some(StatusCode::NOT_MODIFIED);
+            }
+        }
+        None
+    }
+
+    /// If-Range が一致しない場合は Range を無視する
+    fn if_range(&self, req: &HttpRequest) -> bool {
+        match header_str(req, header::IF_RANGE) {
+            None => true,
+            Some(value) if value.starts_with('"') || value.starts_with("W/") => {
+                // If-Range は強い比較
+                !value.starts_with("W/") && self.etag.as_deref() == Some(value)
+            }
+            Some(value) => match (parse_http_date(value), self.last_modified) {
+                (Some(date), Some(modified)) => date / 1000 == modified / 1000,
+                _ => false,
+            },
+        }
+    }
+
+    pub async fn send(self, req: &HttpRequest) -> HttpResponse {
+        if let Some(status) = self.precondition(req) {
+            return self.base_response(status).finish();
+        }
+
+        let range = match (req.method() == Method::GET || req.method() == Method::HEAD, header_str(req, header::RANGE)) {
+            (true, Some(range)) if self.if_range(req) => parse_range(range, self.size),
+            _ => RangeRequest::Full,
+        };
+
+        let content_type = self.content_type_value();
+        let disposition = content_disposition(
+            if self.inline && !is_active(&content_type) { "inline" } else { "attachment" },
+            self.file_name.as_deref().unwrap_or("file"),
+        );
+
+        match range {
+            RangeRequest::Unsatisfiable => self.base_response(StatusCode::RANGE_NOT_SATISFIABLE)
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", self.size)))
+                .finish(),
+            RangeRequest::Full => {
+                let mut builder = self.base_response(StatusCode::OK);
+                builder.insert_header((header::CONTENT_TYPE, content_type));
+                builder.insert_header((header::CONTENT_DISPOSITION, disposition));
+                let body = self.source.read(0, self.size, self.chunk_size);
+                builder.body(SizedStream::new(self.size, Box::pin(body)))
+            }
+            RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+                let range = ranges[0];
+                let mut builder = self.base_response(StatusCode::PARTIAL_CONTENT);
+                builder.insert_header((header::CONTENT_TYPE, content_type));
+                builder.insert_header((header::CONTENT_DISPOSITION, disposition));
+                builder.insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end, self.size)));
+                let body = self.source.read(range.start, range.len(), self.chunk_size);
+                builder.body(SizedStream::new(range.len(), Box::pin(body)))
+            }
+            RangeRequest::Partial(ranges) => {
+                let boundary = format!("{:032x}", rand::random::<u128>());
+                let mut parts = Vec::new();
+                let mut length = 0;
+                for (i, range) in ranges.iter().enumerate() {
+                    let head = format!(
+                        "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        if i == 0 { "" } else { "\r\n" }, boundary, content_type, range.start, range.end, self.size
+                    );
+                    length += head.len() as u64 + range.len();
+                    parts.push((Bytes::from(head), *range));
+                }
+                let tail = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+                length += tail.len() as u64;
+
+                let source = self.source.clone();
+                let chunk_size = self.chunk_size;
+                let body = stream::iter(parts)
+                    .flat_map(move |(head, range)| {
+                        stream::once(async move { Ok(head) })
+                            .chain(source.read(range.start, range.len(), chunk_size))
+                    })
+                    .chain(stream::once(async move { Ok(tail) }));
+
+                let mut builder = self.base_response(StatusCode::PARTIAL_CONTENT);
+                builder.insert_header((header::CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary)));
+                builder.insert_header((header::CONTENT_DISPOSITION, disposition));
+                builder.body(SizedStream::new(length, Box::pin(body)))
+            }
+        }
+    }
+}
+
+fn header_str(req: &HttpRequest, name: header::HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// ETag リストとの比較 weak が true の場合は W/ を無視する
+fn etag_matches(list: &str, etag: Option<&str>, weak: bool) -> bool {
+    let etag = match etag {
+        Some(etag) => etag,
+        None => return false,
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',').map(|t| t.trim()).any(|t| {
+        match t.strip_prefix("W/") {
+            Some(t) => weak && t == etag,
+            None => t == etag,
+        }
+    })
+}
+
+/// ブラウザが文書やスクリプトとして実行する型 inline でも添付にする
+fn is_active(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    matches!(
+        essence.as_str(),
+        "text/html" | "text/xml" | "application/xml" | "text/javascript" | "application/javascript"
+            | "application/x-javascript" | "application/ecmascript" | "text/ecmascript"
+    ) || essence.ends_with("+xml")
+}
+
+/// HTTP-date を UTC ミリ秒に変換する
+pub fn parse_http_date(value: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(value.trim(), "%a, %d %b %Y %H:%M:%S GMT")
+        .ok()
+        .map(|t| DateTime::<Utc>::from_naive_utc_and_offset(t, Utc).timestamp_millis())
+}
+
+/// `bytes=0-499, 500-, -200` を解析する
+/// 構文が不正な場合とまとめた後も MAX_RANGES を超える場合は Range を無視して全体を返す
+pub fn parse_range(value: &str, size: u64) -> RangeRequest {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) => spec,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for part in spec.split(',') {
+        let (start, end) = match part.trim().split_once('-') {
+            Some(p) => p,
+            None => return RangeRequest::Full,
+        };
+        let range = match (start.trim(), end.trim()) {
+            ("", "") => return RangeRequest::Full,
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => continue,
+                Ok(n) if size > 0 => ByteRange { start: size.saturating_sub(n), end: size - 1 },
+                Ok(_) => continue,
+                Err(_) => return RangeRequest::Full,
+            },
+            (start, end) => {
+                let start = match start.parse::<u64>() {
+                    Ok(s) => s,
+                    Err(_) => return RangeRequest::Full,
+                };
+                let end = match end {
+                    "" => size.saturating_sub(1),
+                    end => match end.parse::<u64>() {
+                        Ok(e) if e >= start => e.min(size.saturating_sub(1)),
+                        _ => return RangeRequest::Full,
+                    },
+                };
+                if start >= size {
+                    continue;
+                }
+                ByteRange { start, end }
+            }
+        };
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    // 重なっている範囲はまとめる
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end + 1 => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    if merged.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    RangeRequest::Partial(merged)
+}
+
+/// RFC 6266 / RFC 5987 の Content-Disposition
+pub fn content_disposition(disposition: &str, file_name: &str) -> String {
+    let fallback: String = file_name.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+
+    let mut encoded = String::new();
+    for b in file_name.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+            | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", disposition, fallback, encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{body::{self, BodySize, MessageBody}, http::{header, StatusCode}, test::TestRequest};
+    use bytes::Bytes;
+
+    use crate::file_system::store::{memory::MemoryBlobStore, BlobStore};
+
+    use super::{parse_range, ByteRange, FileStream, RangeRequest, MAX_RANGES};
+
+    const BODY: &[u8] = b"0123456789abcdefghij";
+    const MODIFIED: i64 = 1_700_000_000_000;
+
+    fn ranges(value: &str, size: u64) -> Vec<(u64, u64)> {
+        match parse_range(value, size) {
+            RangeRequest::Partial(ranges) => ranges.iter().map(|r| (r.start, r.end)).collect(),
+            RangeRequest::Full => panic!("{} was ignored", value),
+            RangeRequest::Unsatisfiable => panic!("{} was unsatisfiable", value),
+        }
+    }
+
+    async fn stream() -> FileStream {
+        let blobs = Arc::new(MemoryBlobStore::new());
+        blobs.put("body", Bytes::from_static(BODY)).await.unwrap();
+        FileStream::new(blobs, "body", BODY.len() as u64, 4)
+            .content_type("text/plain")
+            .etag("abc")
+            .last_modified(MODIFIED)
+    }
+
+    #[test]
+    fn range_forms() {
+        assert_eq!(ranges("bytes=0-4", 20), [(0, 4)]);
+        assert_eq!(ranges("bytes=15-", 20), [(15, 19)]);
+        assert_eq!(ranges("bytes=-5", 20), [(15, 19)]);
+        assert_eq!(ranges("bytes=-50", 20), [(0, 19)]);
+        assert_eq!(ranges("bytes=10-99", 20), [(10, 19)]);
+        assert_eq!(ranges("bytes=0-1, 30-40, 5-6", 20), [(0, 1), (5, 6)]);
+        assert_eq!(ByteRange { start: 3, end: 3 }.len(), 1);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(ranges("bytes=0-4,3-8,9-10", 20), [(0, 10)]);
+        assert_eq!(ranges("bytes=0-,0-,0-", 20), [(0, 19)]);
+        assert_eq!(ranges("bytes=10-12,0-2", 20), [(0, 2), (10, 12)]);
+    }
+
+    #[test]
+    fn too_many_ranges_are_ignored() {
+        let spec = |count: u64| format!("bytes={}", (0..count).map(|i| format!("{}-{}", i * 2, i * 2)).collect::<Vec<_>>().join(","));
+        assert_eq!(ranges(&spec(MAX_RANGES as u64), 100).len(), MAX_RANGES);
+        assert!(matches!(parse_range(&spec(MAX_RANGES as u64 + 1), 100), RangeRequest::Full));
+        // まとめた後の数で数える
+        assert_eq!(ranges(&format!("bytes={}", vec!["0-5"; 100].join(",")), 100), [(0, 5)]);
+    }
+
+    #[test]
+    fn invalid_range_is_ignored() {
+        for value in ["items=0-1", "bytes=5-1", "bytes=a-b", "bytes=-", "bytes=1"] {
+            assert!(matches!(parse_range(value, 20), RangeRequest::Full), "{}", value);
+        }
+        assert!(matches!(parse_range("bytes=20-", 20), RangeRequest::Unsatisfiable));
+        assert!(matches!(parse_range("bytes=-0", 20), RangeRequest::Unsatisfiable));
+        assert!(matches!(parse_range("bytes=-5", 0), RangeRequest::Unsatisfiable));
+    }
+
+    #[actix_web::test]
+    async fn preconditions() {
+        let stream = stream().await;
+        let cases = [
+            (header::IF_MATCH, "\"abc\"", None),
+            (header::IF_MATCH, "\"other\"", Some(StatusCode::PRECONDITION_FAILED)),
+            (header::IF_MATCH, "W/\"abc\"", Some(StatusCode::PRECONDITION_FAILED)),
+            (header::IF_NONE_MATCH, "W/\"abc\"", Some(StatusCode::NOT_MODIFIED)),
+            (header::IF_NONE_MATCH, "*", Some(StatusCode::NOT_MODIFIED)),
+            (header::IF_NONE_MATCH, "\"other\"", None),
+            (header::IF_MODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:20 GMT", Some(StatusCode::NOT_MODIFIED)),
+            (header::IF_MODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:19 GMT", None),
+            (header::IF_UNMODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:19 GMT", Some(StatusCode::PRECONDITION_FAILED)),
+        ];
+        for (name, value, expected) in cases {
+            let req = TestRequest::get().insert_header((name.clone(), value)).to_http_request();
+            assert_eq!(stream.precondition(&req), expected, "{}: {}", name, value);
+        }
+
+        // If-None-Match がある場合は If-Modified-Since を見ない
+        let req = TestRequest::get()
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .insert_header((header::IF_MODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:20 GMT"))
+            .to_http_request();
+        assert_eq!(stream.precondition(&req), None);
+        let req = TestRequest::put().insert_header((header::IF_NONE_MATCH, "\"abc\"")).to_http_request();
+        assert_eq!(stream.precondition(&req), Some(StatusCode::PRECONDITION_FAILED));
+    }
+
+    #[actix_web::test]
+    async fn if_range_needs_a_strong_match() {
+        let stream = stream().await;
+        for (value, expected) in [
+            ("\"abc\"", true),
+            ("W/\"abc\"", false),
+            ("\"other\"", false),
+            ("Tue, 14 Nov 2023 22:13:20 GMT", true),
+            ("Tue, 14 Nov 2023 22:13:19 GMT", false),
+        ] {
+            let req = TestRequest::get().insert_header((header::IF_RANGE, value)).to_http_request();
+            assert_eq!(stream.if_range(&req), expected, "{}", value);
+        }
+
+        let req = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=0-1"))
+            .insert_header((header::IF_RANGE, "\"other\""))
+            .to_http_request();
+        let res = stream.send(&req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body::to_bytes(res.into_body()).await.unwrap(), BODY);
+    }
+
+    #[actix_web::test]
+    async fn single_range() {
+        let stream = stream().await;
+        let req = TestRequest::get().insert_header((header::RANGE, "bytes=3-9")).to_http_request();
+        let res = stream.send(&req).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers().get(header::CONTENT_RANGE).unwrap(), "bytes 3-9/20");
+        assert_eq!(body::to_bytes(res.into_body()).await.unwrap(), &BODY[3..10]);
+
+        let stream = self::stream().await;
+        let req = TestRequest::get().insert_header((header::RANGE, "bytes=30-")).to_http_request();
+        let res = stream.send(&req).await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers().get(header::CONTENT_RANGE).unwrap(), "bytes */20");
+    }
+
+    /// メタデータに直接持つ本体も範囲で読み出せる
+    #[actix_web::test]
+    async fn inline_source() {
+        let stream = FileStream::from_bytes(Bytes::from_static(BODY), 4).etag("abc");
+        let req = TestRequest::get().insert_header((header::RANGE, "bytes=2-12")).to_http_request();
+        let res = stream.send(&req).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body::to_bytes(res.into_body()).await.unwrap(), &BODY[2..13]);
+    }
+
+    #[actix_web::test]
+    async fn multipart_ranges() {
+        let stream = stream().await;
+        let req = TestRequest::get().insert_header((header::RANGE, "bytes=0-1,-3")).to_http_request();
+        let res = stream.send(&req).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = res.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/20\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 17-19/20\r\n\r\nhij\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(res.body().size(), BodySize::Sized(expected.len() as u64));
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap(), expected);
+    }
+
+    #[actix_web::test]
+    async fn active_types_are_not_inline() {
+        for (name, disposition) in [("a.txt", "inline"), ("a.png", "inline"), ("a.html", "attachment"), ("a.svg", "attachment"), ("a.xml", "attachment"), ("a.js", "attachment")] {
+            let req = TestRequest::get().to_http_request();
+            let res = FileStream::from_bytes(Bytes::from_static(BODY), 4).file_name(name).inline(true).send(&req).await;
+            let value = res.headers().get(header::CONTENT_DISPOSITION).unwrap().to_str().unwrap();
+            assert!(value.starts_with(disposition), "{}: {}", name, value);
+            assert_eq!(res.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+            assert_eq!(res.headers().get(header::CONTENT_SECURITY_POLICY).unwrap(), "sandbox");
+        }
+
+        let req = TestRequest::get().to_http_request();
+        let res = FileStream::from_bytes(Bytes::from_static(BODY), 4).file_name("a.html").send(&req).await;
+        assert_eq!(res.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert!(res.headers().get(header::CONTENT_SECURITY_POLICY).is_none());
+    }
+}
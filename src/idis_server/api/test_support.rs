@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use actix_web::{body::BoxBody, dev::ServiceResponse, test::{self, TestRequest}, web, App};
+use serde_json::json;
+use tempfile::TempDir;
+
+use crate::{config::Configuration, file_system::test_support::{self, Backend}, share::collection::Collection};
+
+/// 一時ディレクトリに作った Collection
+pub async fn collection(backend: Backend) -> (TempDir, Arc<Collection>) {
+    let dir = tempfile::tempdir().expect("temp dir");
+    std::fs::write(dir.path().join("status.json"), "{}").expect("status json");
+    std::fs::write(dir.path().join("status.html"), "{{ code }}").expect("status page");
+
+    let config = Configuration {
+        idis_server: serde_json::from_value(json!({
+            "enable": true, "server_bind": "127.0.0.1:0", "server_workers": 1, "server_backlog": 1,
+            "restart_on_panic": false, "max_failures": 0, "failure_count_period_time": 0, "restart_interval": 0,
+            "service_config": {
+                "session": { "cookie_name": "idis_session", "life_time": 60, "secure": false, "sweep_interval": 0, "max_users": 4 },
+            },
+        })).expect("server config"),
+        logger_mode: "info".to_string(),
+        middleware_config: serde_json::from_value(json!({
+            "status_page": {
+                "status_mes_json_path": dir.path().join("status.json").display().to_string(),
+                "status_page_template_path": dir.path().join("status.html").display().to_string(),
+            },
+        })).expect("middleware config"),
+        server_id: 1,
+        file_system: test_support::config(dir.path(), backend),
+    };
+    let collection = Collection::new(config).await;
+    (dir, collection)
+}
+
+/// api::config のルートにリクエストを送る
+pub async fn call(collection: &Arc<Collection>, req: TestRequest) -> ServiceResponse<BoxBody> {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::clone(collection)))
+            .configure(super::config),
+    ).await;
+    test::call_service(&app, req.to_request()).await
+}
@@ -0,0 +1,81 @@
+use std::{io::{Error, ErrorKind}, sync::Arc};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::{file_system::{path, perm::Operation, resolve::Resolved}, share::collection::Collection};
+
+use super::{authorize, err_response, principal, target};
+
+#[derive(Deserialize)]
+pub struct TransferQuery {
+    /// 移動先/複製先のパス `@<user>/<path>` の場合は別のユーザーへ
+    pub to: Option<String>,
+    /// 同じフォルダ内でのリネーム
+    pub name: Option<String>,
+}
+
+/// 移動先/複製先を解決して書き込めるか確かめる
+async fn destination(req: &HttpRequest, collection: &Collection, from: &Resolved, query: &TransferQuery) -> Result<Resolved, Error> {
+    let to = match (&query.to, &query.name) {
+        (Some(to), None) => match to.split_once('/') {
+            Some((user, path)) if user.starts_with('@') => collection.file_system.resolve(user, path).await?,
+            None if to.starts_with('@') => collection.file_system.resolve(to, "").await?,
+            _ => collection.file_system.resolve(&format!("@{}", from.user), to).await?,
+        },
+        (None, Some(name)) => {
+            let name = path::normalize_name(name)?;
+            let parent = path::parent(&from.path).ok_or_else(|| Error::new(ErrorKind::InvalidInput, "root can't be renamed"))?;
+            Resolved {
+                user: from.user.clone(),
+                path: path::join(parent, &name),
+                area: from.area,
+            }
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "either to or name is required")),
+    };
+    authorize(req, collection, &to, Operation::Create).await?;
+    Ok(to)
+}
+
+/// `/mv/<@user | RUID>/<path>?to=<path>` または `?name=<name>` -> JSON
+pub async fn mv(req: HttpRequest, query: web::Query<TransferQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let from = match target(&req, &collection, Operation::Delete).await {
+        Ok(t) if t.is_area_root() => return err_response(&Error::new(ErrorKind::InvalidInput, "area root can't be moved")),
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let to = match destination(&req, &collection, &from, &query).await {
+        Ok(d) => d,
+        Err(e) => return err_response(&e),
+    };
+    if to.user != from.user {
+        return err_response(&Error::new(ErrorKind::InvalidInput, "move between users is not supported, use copy"));
+    }
+
+    match collection.file_system.move_to(&from.user, &from.path, &to.path).await {
+        Ok(meta) => HttpResponse::Ok().json(meta),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `/cp/<@user | RUID>/<path>?to=[@<user>/]<path>` -> JSON
+pub async fn cp(req: HttpRequest, query: web::Query<TransferQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let from = match target(&req, &collection, Operation::Read).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+    let to = match destination(&req, &collection, &from, &query).await {
+        Ok(d) => d,
+        Err(e) => return err_response(&e),
+    };
+
+    let principal = match principal(&req, &collection).await {
+        Ok(p) => p,
+        Err(e) => return err_response(&e),
+    };
+    match collection.file_system.copy_to(&from.user, &from.path, &to.user, &to.path, &principal).await {
+        Ok(meta) => HttpResponse::Created().json(meta),
+        Err(e) => err_response(&e),
+    }
+}
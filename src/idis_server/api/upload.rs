@@ -0,0 +1,71 @@
+use std::{io::{Error, ErrorKind}, sync::Arc};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::{file_system::{archive::ArchiveFormat, perm::Operation, resolve::Resolved}, share::collection::Collection};
+
+use super::{err_response, principal, target};
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    /// `zip` `tar` `tar.gz` のアーカイブを path のフォルダに展開する
+    pub extract: Option<String>,
+}
+
+/// `/upload/<@user | RUID>/<path>` -> JSON
+/// パスが `/` で終わる場合はフォルダを作成する
+/// `?extract=<format>` の場合はアーカイブを展開してエントリごとの結果を返す
+pub async fn upload(req: HttpRequest, query: web::Query<UploadQuery>, payload: web::Payload, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Create).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    if let Some(format) = query.extract.as_deref() {
+        let format = match ArchiveFormat::parse(format) {
+            Some(format) => format,
+            None => return err_response(&Error::new(ErrorKind::InvalidInput, "extract must be zip, tar or tar.gz")),
+        };
+        let principal = match principal(&req, &collection).await {
+            Ok(p) => p,
+            Err(e) => return err_response(&e),
+        };
+        let (temp_path, _, _) = match collection.file_system.save_temp(payload).await {
+            Ok(saved) => saved,
+            Err(e) => return err_response(&e),
+        };
+        return match collection.file_system.extract(&user, &path, &principal, temp_path, format).await {
+            Ok(report) => HttpResponse::Ok().json(report),
+            Err(e) => err_response(&e),
+        };
+    }
+
+    if req.path().ends_with('/') {
+        return match collection.file_system.create_folder(&user, &path).await {
+            Ok(meta) => HttpResponse::Created().json(meta),
+            Err(e) => err_response(&e),
+        };
+    }
+
+    // Content-Length がある場合は受け取る前に上限を確かめる
+    let length = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(length) = length {
+        if let Err(e) = collection.file_system.check_quota(&user, length).await {
+            return err_response(&e);
+        }
+    }
+
+    let (content, size, checksum) = match collection.file_system.save_binary(&user, payload).await {
+        Ok(saved) => saved,
+        Err(e) => return err_response(&e),
+    };
+
+    // 失敗した場合のバイナリは create_file が解放する
+    match collection.file_system.create_file(&user, &path, content, size, checksum).await {
+        Ok(meta) => HttpResponse::Created().json(meta),
+        Err(e) => err_response(&e),
+    }
+}
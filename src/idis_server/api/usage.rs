@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::json;
+
+use crate::{file_system::resolve::Resolved, share::collection::Collection};
+
+use super::{err_response, owner_target, requester, resolve_target};
+
+/// `/usage/<@user | RUID>` -> JSON
+/// 使用量と上限、RUID のファイルの種類ごとの内訳
+/// 所有者と `perm.admins` のユーザーのみ
+pub async fn usage(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let target = match collection.file_system.config.perm.is_admin(requester(&req).as_deref()) {
+        true => resolve_target(&req, &collection).await,
+        false => owner_target(&req, &collection).await,
+    };
+    let Resolved { user, .. } = match target {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.usage(&user).await {
+        Ok(usage) => HttpResponse::Ok().json(json!({
+            "quota": collection.file_system.config.quota.limit(&usage),
+            "usage": usage,
+        })),
+        Err(e) => err_response(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}};
+    use serde_json::Value;
+
+    use crate::{
+        file_system::{perm::EVERYONE, test_support::{self as fs_support, Backend}},
+        idis_server::{api::test_support, session::SessionUser},
+    };
+
+    /// 読む権限があっても所有者と管理者以外には見せない
+    #[actix_web::test]
+    async fn only_owner_and_admins() {
+        let (_dir, collection) = test_support::collection(Backend::Memory).await;
+        fs_support::write(&collection.file_system, "alice", "/home/a.txt", b"alpha").await;
+        let mut root = collection.file_system.get("alice", "/").await.unwrap();
+        root.perm.read.allow.insert(EVERYONE);
+        collection.file_system.metas.put(&root).await.unwrap();
+
+        for (user, status) in [(None, StatusCode::NOT_FOUND), (Some("bob"), StatusCode::NOT_FOUND), (Some("alice"), StatusCode::OK), (Some("root"), StatusCode::OK)] {
+            let mut req = TestRequest::get().uri("/usage/@alice");
+            if let Some(user) = user {
+                let token = collection.sessions.login(None, SessionUser { ruid: 1, user: user.to_string() }).unwrap();
+                req = req.cookie(collection.sessions.cookie(&token));
+            }
+            let res = test_support::call(&collection, req).await;
+            assert_eq!(res.status(), status, "{:?}", user);
+            if status == StatusCode::OK {
+                let body: Value = test::read_body_json(res).await;
+                assert_eq!(body["usage"]["used"], 5);
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{io::{Error, ErrorKind}, sync::Arc};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{file_system::{path, perm::Operation, resolve::Resolved, version::FileVersion}, share::collection::Collection};
+
+use super::{err_response, stream::FileStream, target};
+
+#[derive(Deserialize)]
+pub struct VersionQuery {
+    pub v: u64,
+}
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    pub from: u64,
+    /// 省略した場合は現在の版
+    pub to: Option<u64>,
+}
+
+/// `/history/<@user | RUID>/<path>` -> JSON
+/// 現在の版と古い版の一覧 (新しい順)
+pub async fn history(req: HttpRequest, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Read).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.versions(&user, &path).await {
+        Ok((meta, versions)) => HttpResponse::Ok().json(json!({
+            "current": FileVersion::from_meta(&meta, meta.update_time).summary(),
+            "versions": versions.iter().map(FileVersion::summary).collect::<Vec<_>>(),
+        })),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `/version/<@user | RUID>/<path>?v=<version>` -> BinaryStream
+pub async fn get(req: HttpRequest, query: web::Query<VersionQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Read).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    let version = match collection.file_system.version(&user, &path, query.v).await {
+        Ok(version) => version,
+        Err(e) => return err_response(&e),
+    };
+    let chunk_size = collection.file_system.config.streaming_chunk_size;
+    let stream = match (&version.inline, &version.blob) {
+        (Some(data), _) => FileStream::from_bytes(Bytes::from(data.clone()), chunk_size),
+        (None, Some(blob)) => FileStream::new(Arc::clone(&collection.file_system.blobs), blob, version.size, chunk_size),
+        (None, None) => return err_response(&Error::new(ErrorKind::NotFound, "version has no content")),
+    };
+
+    let mut stream = stream
+        .file_name(path::name(&path))
+        .content_type(&version.data_type)
+        .inline(false)
+        .last_modified(version.update_time)
+        .cache_control("private, no-cache");
+    if let Some(checksum) = &version.checksum {
+        stream = stream.etag(checksum);
+    }
+    stream.send(&req).await
+}
+
+/// `/version/<@user | RUID>/<path>?v=<version>` -> JSON
+/// 古い版の内容で上書きする 上書き前の内容は新しい版として残る
+pub async fn restore(req: HttpRequest, query: web::Query<VersionQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Edit).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    match collection.file_system.restore_version(&user, &path, query.v).await {
+        Ok(meta) => HttpResponse::Ok().json(meta),
+        Err(e) => err_response(&e),
+    }
+}
+
+/// `/diff/<@user | RUID>/<path>?from=<version>&to=<version>` -> JSON Patch
+/// JSON のファイルのみ
+pub async fn diff(req: HttpRequest, query: web::Query<DiffQuery>, collection: web::Data<Arc<Collection>>) -> HttpResponse {
+    let Resolved { user, path, .. } = match target(&req, &collection, Operation::Read).await {
+        Ok(t) => t,
+        Err(e) => return err_response(&e),
+    };
+
+    let to = match query.to {
+        Some(to) => to,
+        None => match collection.file_system.get(&user, &path).await {
+            Ok(meta) => meta.version,
+            Err(e) => return err_response(&e),
+        },
+    };
+    match collection.file_system.diff_versions(&user, &path, query.from, to).await {
+        Ok(patch) => HttpResponse::Ok().content_type("application/json-patch+json").json(patch),
+        Err(e) => err_response(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::{self, TestRequest}};
+    use serde_json::{json, Value};
+
+    use crate::{file_system::{perm::EVERYONE, test_support::{self as fs_support, Backend}}, idis_server::api::test_support};
+
+    #[actix_web::test]
+    async fn history_get_and_diff() {
+        let (_dir, collection) = test_support::collection(Backend::Memory).await;
+        let fs = &collection.file_system;
+        fs_support::write(fs, "alice", "/home/a.json", br#"{ "a": 1 }"#).await;
+        fs_support::write(fs, "alice", "/home/a.json", br#"{ "a": 2 }"#).await;
+
+        // 読む権限の無い相手には履歴があることも見せない
+        let res = test_support::call(&collection, TestRequest::get().uri("/history/@alice/a.json")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let mut home = fs.get("alice", "/home").await.unwrap();
+        home.perm.read.allow.insert(EVERYONE);
+        fs.metas.put(&home).await.unwrap();
+
+        let res = test_support::call(&collection, TestRequest::get().uri("/history/@alice/a.json")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let history: Value = test::read_body_json(res).await;
+        assert_eq!(history["current"]["version"], 2);
+        assert_eq!(history["versions"].as_array().unwrap().len(), 1);
+        assert!(history["versions"][0].get("inline").is_none());
+
+        let res = test_support::call(&collection, TestRequest::get().uri("/version/@alice/a.json?v=1")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, &br#"{ "a": 1 }"#[..]);
+        let res = test_support::call(&collection, TestRequest::get().uri("/version/@alice/a.json?v=5")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = test_support::call(&collection, TestRequest::get().uri("/diff/@alice/a.json?from=1")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let patch: Value = test::read_body_json(res).await;
+        assert_eq!(patch, json!([{ "op": "replace", "path": "/a", "value": 2 }]));
+    }
+}
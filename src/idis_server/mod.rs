@@ -1,2 +1,4 @@
 pub mod actix_server;
-pub mod actix_server_config;
\ No newline at end of file
+pub mod actix_server_config;
+pub mod api;
+pub mod session;
\ No newline at end of file
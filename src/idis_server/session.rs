@@ -0,0 +1,320 @@
+use std::{collections::HashMap, io::{Error, ErrorKind}, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}, time::Duration};
+
+use actix_web::{cookie::{self, Cookie, SameSite}, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use log::info;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::utils::custom_serializers_adapters::Hex;
+
+const TOKEN_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionConfig {
+    pub cookie_name: String,
+    /// 最後のアクセスからの秒数
+    pub life_time: u64,
+    /// HTTPS でのみ cookie を送る
+    pub secure: bool,
+    pub sweep_interval: u64,
+    /// 一つのセッションにログインできるユーザーの数
+    pub max_users: usize,
+}
+
+/// セッションにログインしているユーザー
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionUser {
+    #[serde_as(as = "Hex")]
+    pub ruid: u128,
+    pub user: String,
+}
+
+impl SessionUser {
+    /// `@<user>` または RUID (hex) に当てはまるか
+    pub fn matches(&self, spec: &str) -> bool {
+        match spec.strip_prefix('@') {
+            Some(name) => self.user == name,
+            None => u128::from_str_radix(spec.trim_start_matches("0x"), 16) == Ok(self.ruid),
+        }
+    }
+}
+
+/// 一つのブラウザに複数のユーザーでログインできる active が今のユーザー
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionData {
+    pub generated_time: i64,
+    pub last_access_time: i64,
+    pub users: Vec<SessionUser>,
+    #[serde_as(as = "Option<Hex>")]
+    pub active: Option<u128>,
+}
+
+impl SessionData {
+    pub fn active_user(&self) -> Option<&SessionUser> {
+        self.active.and_then(|ruid| self.users.iter().find(|u| u.ruid == ruid))
+    }
+}
+
+/// cookie のトークン -> セッション 全てメモリに置く
+pub struct Sessions {
+    config: SessionConfig,
+    sessions: RwLock<HashMap<String, SessionData>>,
+}
+
+impl Sessions {
+    pub fn new(config: SessionConfig) -> Self {
+        Self {
+            config,
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, SessionData>> {
+        self.sessions.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, SessionData>> {
+        self.sessions.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn generate() -> String {
+        let mut token = [0u8; TOKEN_LENGTH];
+        OsRng.fill_bytes(&mut token);
+        URL_SAFE_NO_PAD.encode(token)
+    }
+
+    fn is_expired(&self, data: &SessionData, now: i64) -> bool {
+        now - data.last_access_time >= self.config.life_time.saturating_mul(1000) as i64
+    }
+
+    /// リクエストの cookie のトークン
+    pub fn token(&self, req: &HttpRequest) -> Option<String> {
+        req.cookie(&self.config.cookie_name).map(|c| c.value().to_string())
+    }
+
+    /// アクセス時刻を更新したセッション 期限切れのセッションは消して無いものとして扱う
+    fn touch<'a>(&self, sessions: &'a mut HashMap<String, SessionData>, token: &str) -> Option<&'a mut SessionData> {
+        let now = Utc::now().timestamp_millis();
+        if self.is_expired(sessions.get(token)?, now) {
+            sessions.remove(token);
+            return None;
+        }
+        let data = sessions.get_mut(token)?;
+        data.last_access_time = now;
+        Some(data)
+    }
+
+    /// アクセス時刻を更新して返す 期限切れのセッションは無いものとして扱う
+    pub fn get(&self, token: &str) -> Option<SessionData> {
+        self.touch(&mut self.write(), token).cloned()
+    }
+
+    /// 今のユーザー
+    pub fn current(&self, token: &str) -> Option<SessionUser> {
+        self.get(token)?.active_user().cloned()
+    }
+
+    /// セッションにログインしているユーザーのうち spec (`@<user>` または RUID) に当てはまるもの
+    /// リクエストごとにユーザーを選ぶ場合に使う セッションに無いユーザーにはなれない
+    pub fn impersonate(&self, token: &str, spec: &str) -> Option<SessionUser> {
+        self.get(token)?.users.into_iter().find(|u| u.matches(spec))
+    }
+
+    /// ログインしたユーザーをセッションに加えて今のユーザーにし、新しいトークンを返す
+    /// 固定化を防ぐため、既にあるセッションでもトークンは作り直す
+    pub fn login(&self, token: Option<&str>, user: SessionUser) -> Result<String, Error> {
+        let now = Utc::now().timestamp_millis();
+        let mut sessions = self.write();
+        let mut data = token.and_then(|t| sessions.get(t))
+            .filter(|data| !self.is_expired(data, now))
+            .cloned()
+            .unwrap_or(SessionData {
+                generated_time: now,
+                last_access_time: now,
+                users: Vec::new(),
+                active: None,
+            });
+        if !data.users.iter().any(|u| u.ruid == user.ruid) {
+            if data.users.len() >= self.config.max_users {
+                return Err(Error::new(ErrorKind::InvalidInput, "too many users in the session"));
+            }
+            data.users.push(user.clone());
+        }
+        data.last_access_time = now;
+        data.active = Some(user.ruid);
+        if let Some(token) = token {
+            sessions.remove(token);
+        }
+
+        let mut token = Self::generate();
+        while sessions.contains_key(&token) {
+            token = Self::generate();
+        }
+        sessions.insert(token.clone(), data);
+        Ok(token)
+    }
+
+    /// 今のユーザーを切り替える
+    pub fn switch(&self, token: &str, spec: &str) -> Result<SessionData, Error> {
+        let mut sessions = self.write();
+        let data = self.touch(&mut sessions, token).ok_or_else(|| Error::new(ErrorKind::NotFound, "session is not found"))?;
+        let user = data.users.iter().find(|u| u.matches(spec)).ok_or_else(|| Error::new(ErrorKind::NotFound, "user is not in the session"))?;
+        data.active = Some(user.ruid);
+        Ok(data.clone())
+    }
+
+    /// spec (省略した場合は今のユーザー) をセッションから外す
+    /// 今のユーザーを外した場合は残りの最初のユーザーにする 誰も残らなければセッションも消して None を返す
+    pub fn logout(&self, token: &str, spec: Option<&str>) -> Result<Option<SessionData>, Error> {
+        let mut sessions = self.write();
+        let data = self.touch(&mut sessions, token).ok_or_else(|| Error::new(ErrorKind::NotFound, "session is not found"))?;
+        let ruid = match spec {
+            Some(spec) => data.users.iter().find(|u| u.matches(spec)).map(|u| u.ruid),
+            None => data.active,
+        }.ok_or_else(|| Error::new(ErrorKind::NotFound, "user is not in the session"))?;
+
+        data.users.retain(|u| u.ruid != ruid);
+        if data.active == Some(ruid) {
+            data.active = data.users.first().map(|u| u.ruid);
+        }
+        if data.users.is_empty() {
+            sessions.remove(token);
+            return Ok(None);
+        }
+        Ok(Some(data.clone()))
+    }
+
+    /// 全てのユーザーをセッションから外す
+    pub fn logout_all(&self, token: &str) {
+        self.write().remove(token);
+    }
+
+    pub fn cookie(&self, token: &str) -> Cookie<'static> {
+        Cookie::build(self.config.cookie_name.clone(), token.to_string())
+            .path("/")
+            .http_only(true)
+            .secure(self.config.secure)
+            .same_site(SameSite::Lax)
+            .max_age(cookie::time::Duration::seconds(self.config.life_time as i64))
+            .finish()
+    }
+
+    /// cookie を消す
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.cookie("");
+        cookie.make_removal();
+        cookie
+    }
+
+    fn sweep(&self) -> usize {
+        let now = Utc::now().timestamp_millis();
+        let mut sessions = self.write();
+        let before = sessions.len();
+        sessions.retain(|_, data| !self.is_expired(data, now));
+        before - sessions.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub async fn run_sweeper(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let count = self.sweep();
+            if count > 0 {
+                info!("swept {} expired sessions, {} remain", count, self.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::{SessionConfig, SessionUser, Sessions};
+
+    fn sessions(life_time: u64) -> Sessions {
+        Sessions::new(SessionConfig { cookie_name: "idis_session".to_string(), life_time, secure: false, sweep_interval: 0, max_users: 2 })
+    }
+
+    fn user(ruid: u128, name: &str) -> SessionUser {
+        SessionUser { ruid, user: name.to_string() }
+    }
+
+    /// 固定化を防ぐため、ログインのたびに古いトークンは使えなくなる ユーザーは引き継ぐ
+    #[test]
+    fn login_regenerates_token() {
+        let sessions = sessions(60);
+        let first = sessions.login(None, user(1, "alice")).unwrap();
+        assert_eq!(sessions.current(&first), Some(user(1, "alice")));
+
+        let second = sessions.login(Some(&first), user(2, "bob")).unwrap();
+        assert_ne!(first, second);
+        assert!(sessions.get(&first).is_none());
+        let data = sessions.get(&second).unwrap();
+        assert_eq!((data.users, data.active), (vec![user(1, "alice"), user(2, "bob")], Some(2)));
+        assert_eq!(sessions.len(), 1);
+
+        // 同じユーザーは増えず、上限を超えるユーザーは断る 断った場合はトークンも変わらない
+        let third = sessions.login(Some(&second), user(1, "alice")).unwrap();
+        assert_eq!(sessions.get(&third).unwrap().users.len(), 2);
+        assert_eq!(sessions.login(Some(&third), user(3, "carol")).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(sessions.current(&third), Some(user(1, "alice")));
+
+        // 知らないトークンでも新しいセッションを作る
+        let other = sessions.login(Some("unknown"), user(3, "carol")).unwrap();
+        assert_eq!(sessions.current(&other), Some(user(3, "carol")));
+        assert_eq!(sessions.len(), 2);
+    }
+
+    /// 切り替えと一人のログアウトではトークンは変わらない
+    #[test]
+    fn switch_and_logout() {
+        let sessions = sessions(60);
+        let token = sessions.login(None, user(1, "alice")).unwrap();
+        let token = sessions.login(Some(&token), user(0xb0b, "bob")).unwrap();
+
+        assert_eq!(sessions.switch(&token, "@alice").unwrap().active, Some(1));
+        assert_eq!(sessions.current(&token), Some(user(1, "alice")));
+        assert_eq!(sessions.switch(&token, "@carol").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(sessions.switch("unknown", "@alice").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(sessions.impersonate(&token, "0xb0b"), Some(user(0xb0b, "bob")));
+        assert_eq!(sessions.impersonate(&token, "@carol"), None);
+
+        // 今のユーザーを外すと残りの最初のユーザーになる
+        let data = sessions.logout(&token, None).unwrap().unwrap();
+        assert_eq!((data.users, data.active), (vec![user(0xb0b, "bob")], Some(0xb0b)));
+        assert_eq!(sessions.logout(&token, Some("@alice")).unwrap_err().kind(), ErrorKind::NotFound);
+        assert!(sessions.logout(&token, Some("@bob")).unwrap().is_none());
+        assert!(sessions.get(&token).is_none());
+        assert_eq!(sessions.len(), 0);
+    }
+
+    #[test]
+    fn expired_sessions_are_ignored_and_swept() {
+        let sessions = sessions(0);
+        let token = sessions.login(None, user(1, "alice")).unwrap();
+        sessions.login(None, user(2, "bob")).unwrap();
+        assert_eq!(sessions.sweep(), 2);
+
+        let token2 = sessions.login(Some(&token), user(1, "alice")).unwrap();
+        assert_eq!(sessions.current(&token2), None);
+        assert_eq!(sessions.len(), 0);
+
+        // 切り替えとログアウトも期限切れのセッションには効かない
+        let token = sessions.login(None, user(1, "alice")).unwrap();
+        assert_eq!(sessions.switch(&token, "@alice").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(sessions.len(), 0);
+        let token = sessions.login(None, user(1, "alice")).unwrap();
+        assert_eq!(sessions.logout(&token, None).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(sessions.len(), 0);
+    }
+}
@@ -1,11 +1,13 @@
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::sync::Arc;
+use std::time::Duration;
 
 use config::Configuration;
 use server::server_trait::WkServer;
 use share::collection::{self, Collection};
 use tokio;
 use env_logger::Env;
+use file_system::fsck::FsckOptions;
 
 use log::{error, info};
 
@@ -15,6 +17,7 @@ mod share;
 mod actix_middleware;
 mod utils;
 mod server;
+mod file_system;
 
 async fn server_start(config: Configuration, collection: Arc<Collection>) -> Result<(), Error> {
     std::env::set_var("RUST_LOG", config.logger_mode);
@@ -23,6 +26,46 @@ async fn server_start(config: Configuration, collection: Arc<Collection>) -> Res
     let idis_server = idis_server::actix_server::IndexServer::new(config.idis_server, Arc::clone(&collection)).run_with_restart();
     // 追加していくの
 
+    // 間隔が 0 の処理は行わない (tokio::time::interval は 0 で panic する)
+    let fs_config = &config.file_system;
+    let every = |secs: u64| Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+    if let Some(interval) = every(fs_config.upload_sweep_interval) {
+        tokio::spawn(Arc::clone(&collection.upload).run_sweeper(interval));
+    }
+    if let Some(interval) = every(collection.config.idis_server.service_config.session.sweep_interval) {
+        tokio::spawn(Arc::clone(&collection.sessions).run_sweeper(interval));
+    }
+    if let Some(interval) = every(fs_config.trash_purge_interval) {
+        tokio::spawn(Arc::clone(&collection.file_system).run_purger(interval));
+    }
+    if let Some(interval) = every(fs_config.blob_gc_interval) {
+        tokio::spawn(Arc::clone(&collection.file_system).run_blob_gc(interval));
+    }
+    if let Some(interval) = every(fs_config.blob_verify_interval) {
+        tokio::spawn(Arc::clone(&collection.file_system).run_blob_verifier(interval));
+    }
+    if let Some(interval) = every(fs_config.quota.reconcile_interval) {
+        tokio::spawn(Arc::clone(&collection.file_system).run_usage_reconciler(interval));
+    }
+    tokio::spawn(Arc::clone(&collection.file_system).run_preview_worker());
+    if let Some(interval) = every(fs_config.encryption.rotation_check_interval) {
+        tokio::spawn(Arc::clone(&collection.file_system).run_key_rotation(interval));
+    }
+    if let Some(interval) = every(fs_config.lifecycle.interval) {
+        tokio::spawn(Arc::clone(&collection.file_system).run_lifecycle(interval));
+    }
+    if let Some(interval) = every(fs_config.fsck.interval) {
+        tokio::spawn(Arc::clone(&collection.file_system).run_fsck(interval, fs_config.fsck.repair));
+    }
+    if config.file_system.provision_on_start {
+        let provisioner = Arc::clone(&collection.provisioner);
+        tokio::spawn(async move {
+            if let Err(e) = provisioner.migrate_all().await {
+                error!("Failed to migrate user templates: {}", e);
+            }
+        });
+    }
+
     let result = tokio::join!(
         idis_server
     );
@@ -40,12 +83,67 @@ async fn server_start(config: Configuration, collection: Arc<Collection>) -> Res
 }
 
 
+/// `idis-system fsck [--quarantine] [--prune] [--recompute]`
+/// サーバーを起動せずに検査し、結果を JSON で標準出力に書く
+async fn fsck(collection: Arc<Collection>, args: &[String]) -> Result<(), Error> {
+    let mut options = FsckOptions::default();
+    for arg in args {
+        match arg.as_str() {
+            "--quarantine" => options.quarantine = true,
+            "--prune" => options.prune = true,
+            "--recompute" => options.recompute = true,
+            "--repair" => options = FsckOptions { quarantine: true, prune: true, recompute: true },
+            other => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown option: {}", other))),
+        }
+    }
+    let report = collection.file_system.fsck(options).await?;
+    let json = serde_json::to_string_pretty(&report).map_err(Error::other)?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// `idis-system lifecycle [--dry-run]`
+/// ライフサイクルの規則を一度評価し、結果を JSON で標準出力に書く
+async fn lifecycle(collection: Arc<Collection>, args: &[String]) -> Result<(), Error> {
+    let mut dry_run = false;
+    for arg in args {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            other => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown option: {}", other))),
+        }
+    }
+    let report = collection.file_system.run_lifecycle_rules(dry_run).await?;
+    let json = serde_json::to_string_pretty(&report).map_err(Error::other)?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// `idis-system account-level <user> <level>`
+/// ユーザーの記録の account_level を書き換え、使用量を JSON で標準出力に書く
+async fn account_level(collection: Arc<Collection>, args: &[String]) -> Result<(), Error> {
+    let (user, level) = match args {
+        [user, level] => (user, level.parse::<u64>().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "usage: account-level <user> <level>")),
+    };
+    let usage = collection.file_system.set_account_level(user.trim_start_matches('@'), level).await?;
+    let json = serde_json::to_string_pretty(&usage).map_err(Error::other)?;
+    println!("{}", json);
+    Ok(())
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
     let config = config::Configuration::loader("config.yaml");
-    let collection = collection::Collection::new(config.clone());
+    let collection = collection::Collection::new(config.clone()).await;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("fsck") => return fsck(collection, &args[1..]).await,
+        Some("lifecycle") => return lifecycle(collection, &args[1..]).await,
+        Some("account-level") => return account_level(collection, &args[1..]).await,
+        _ => {}
+    }
 
     server_start(config, collection).await?;
     Ok(())
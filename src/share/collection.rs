@@ -1,25 +1,66 @@
 use std::sync::Arc;
 
-use crate::{actix_middleware::{self, status_page::middleware}, config::{self, Configuration}};
+use crate::{actix_middleware::{self, status_page::middleware}, config::{self, Configuration}, file_system::{account::Accounts, file_system::FileSystem, provision::Provisioner, share_link::ShareLinks, upload::ResumableUpload}, idis_server::session::Sessions, utils::ruid::RuidGenerator};
 
 #[derive(Clone)]
 pub struct Collection {
     pub middleware: actix_middleware::handler::CustomMiddleware,
     pub config: config::Configuration,
+    pub file_system: Arc<FileSystem>,
+    pub upload: Arc<ResumableUpload>,
+    pub provisioner: Arc<Provisioner>,
+    pub share_links: Arc<ShareLinks>,
+    pub accounts: Arc<Accounts>,
+    pub sessions: Arc<Sessions>,
 }
 
 impl Collection {
-    pub fn new(config: Configuration) -> Arc<Self> {
+    pub async fn new(config: Configuration) -> Arc<Self> {
         let midware = match actix_middleware::handler::CustomMiddleware::new(&config) {
             Ok(m) => m,
             Err(e) => panic!("Error: {}", e),
         };
 
+        let ruid = Arc::new(RuidGenerator::new(config.server_id));
+
+        let file_system = match FileSystem::new(&config.file_system, Arc::clone(&ruid)).await {
+            Ok(f) => Arc::new(f),
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        let upload = match ResumableUpload::new(Arc::clone(&file_system), Arc::clone(&ruid)) {
+            Ok(u) => Arc::new(u),
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        let provisioner = match Provisioner::new(Arc::clone(&file_system), Arc::clone(&ruid)) {
+            Ok(p) => Arc::new(p),
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        let share_links = match ShareLinks::new(Arc::clone(&file_system), Arc::clone(&ruid)) {
+            Ok(s) => Arc::new(s),
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        let accounts = match Accounts::new(Arc::clone(&file_system), Arc::clone(&provisioner), Arc::clone(&ruid)) {
+            Ok(a) => Arc::new(a),
+            Err(e) => panic!("Error: {}", e),
+        };
+
+        let sessions = Arc::new(Sessions::new(config.idis_server.service_config.session.clone()));
+
         let collection = Self {
             middleware: midware,
             config: config,
+            file_system,
+            upload,
+            provisioner,
+            share_links,
+            accounts,
+            sessions,
         };
 
         Arc::new(collection)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,47 @@
+use base64::{engine::general_purpose, Engine};
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+use serde_with::{DeserializeAs, SerializeAs};
+
+/// u128 (RUID) を 16進数文字列として扱う
+pub struct Hex;
+
+impl SerializeAs<u128> for Hex {
+    fn serialize_as<S>(source: &u128, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{:032x}", source))
+    }
+}
+
+impl<'de> DeserializeAs<'de, u128> for Hex {
+    fn deserialize_as<D>(deserializer: D) -> Result<u128, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        u128::from_str_radix(s.trim_start_matches("0x"), 16).map_err(D::Error::custom)
+    }
+}
+
+/// バイト列を base64 文字列として扱う
+pub struct Base64;
+
+impl SerializeAs<Vec<u8>> for Base64 {
+    fn serialize_as<S>(source: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&general_purpose::STANDARD.encode(source))
+    }
+}
+
+impl<'de> DeserializeAs<'de, Vec<u8>> for Base64 {
+    fn deserialize_as<D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        general_purpose::STANDARD.decode(s).map_err(D::Error::custom)
+    }
+}
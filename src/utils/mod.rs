@@ -1,2 +1,5 @@
 pub mod fs;
-pub mod logger;
\ No newline at end of file
+pub mod logger;
+pub mod password;
+pub mod ruid;
+pub mod custom_serializers_adapters;
\ No newline at end of file
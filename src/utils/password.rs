@@ -0,0 +1,35 @@
+use std::io::{Error, ErrorKind};
+
+use argon2::{password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Algorithm, Argon2, Params, Version};
+
+/// Argon2id のパラメーターを指定したハッシュ関数
+pub fn argon2id(memory_cost: u32, time_cost: u32, parallelism: u32) -> Result<Argon2<'static>, Error> {
+    let params = Params::new(memory_cost, time_cost, parallelism, None)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+/// PHC 文字列にする 重いので spawn_blocking の中で呼ぶ
+pub fn hash(argon2: &Argon2, password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2.hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| Error::other(e.to_string()))
+}
+
+/// パラメーターは PHC 文字列のものを使う
+pub fn verify(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// argon2 と違うパラメーターで作られたハッシュか
+pub fn needs_rehash(argon2: &Argon2, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => parsed.algorithm.as_str() != Algorithm::Argon2id.as_str()
+            || Params::try_from(&parsed).map_or(true, |params| params != *argon2.params()),
+        Err(_) => true,
+    }
+}
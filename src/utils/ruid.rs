@@ -0,0 +1,167 @@
+use std::sync::Mutex;
+
+use chrono::Utc;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// RUID v1 のプレフィックス一覧
+/// docment/server/system/ruid.md を参照
+pub mod prefix {
+    pub const UNCATEGORIZED_FILE: u16 = 0x1000;
+    pub const TEXT_FILE: u16 = 0x1100;
+    pub const BINARY_FILE: u16 = 0x1200;
+    pub const CONFIG_FILE: u16 = 0x1300;
+    pub const CACHE_FILE: u16 = 0x1400;
+    pub const LOG_FILE: u16 = 0x1500;
+    pub const MEDIA_FILE: u16 = 0x1600;
+    pub const COMPRESSED_FILE: u16 = 0x1700;
+    pub const ENCRYPTED_FILE: u16 = 0x1800;
+    pub const FOLDER: u16 = 0x1E01;
+    pub const OTHER_FILE: u16 = 0x1F00;
+
+    pub const USER_EXAMPLE_ID: u16 = 0x2100;
+    pub const USER_ID: u16 = 0x2101;
+    pub const PERMISSION_ID: u16 = 0x2200;
+    pub const EVERYONE_PERMISSION: u16 = 0x2201;
+    pub const AUTHENTICATED_PERMISSION: u16 = 0x2202;
+    pub const GUEST_PERMISSION: u16 = 0x2203;
+    pub const OWNER_PERMISSION: u16 = 0x2204;
+    pub const NODE_PERMISSION: u16 = 0x2205;
+    pub const SHARE_LINK: u16 = 0x2300;
+}
+
+const VERSION: u128 = 0x0;
+
+pub struct RuidGenerator {
+    server_id: u16,
+    rng: Mutex<ChaCha20Rng>,
+}
+
+impl RuidGenerator {
+    pub fn new(server_id: u16) -> Self {
+        Self {
+            server_id,
+            rng: Mutex::new(ChaCha20Rng::from_entropy()),
+        }
+    }
+
+    pub fn server_id(&self) -> u16 {
+        self.server_id
+    }
+
+    /// | 16 bits prefix | 4 bits version | 16 bits server id | 48 bits timestamp(μs) | 44 bits random |
+    pub fn generate(&self, prefix: u16) -> u128 {
+        let time = (Utc::now().timestamp_micros() as u128) & ((1 << 48) - 1);
+        let random = {
+            let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+            (rng.next_u64() as u128) & ((1 << 44) - 1)
+        };
+
+        ((prefix as u128) << 112)
+            | (VERSION << 108)
+            | ((self.server_id as u128) << 92)
+            | (time << 44)
+            | random
+    }
+}
+
+pub fn prefix_of(ruid: u128) -> u16 {
+    (ruid >> 112) as u16
+}
+
+/// RUID を発行したサーバーの ID
+pub fn server_id_of(ruid: u128) -> u16 {
+    (ruid >> 92) as u16
+}
+
+/// ファイルの種類の名前 上位8bit (0x11 など) で分類する
+pub fn file_type_name(prefix: u16) -> &'static str {
+    match prefix & 0xFF00 {
+        prefix::TEXT_FILE => "text",
+        prefix::BINARY_FILE => "binary",
+        prefix::CONFIG_FILE => "config",
+        prefix::CACHE_FILE => "cache",
+        prefix::LOG_FILE => "log",
+        prefix::MEDIA_FILE => "media",
+        prefix::COMPRESSED_FILE => "compressed",
+        prefix::ENCRYPTED_FILE => "encrypted",
+        _ => "other",
+    }
+}
+
+/// 拡張子からファイルの RUID プレフィックスを決める
+pub fn prefix_from_extension(name: &str) -> u16 {
+    let ext = match name.rsplit_once('.') {
+        Some((_, ext)) => ext.to_ascii_lowercase(),
+        None => return prefix::UNCATEGORIZED_FILE,
+    };
+
+    match ext.as_str() {
+        "txt" => 0x1100,
+        "md" => 0x1101,
+        "rtf" => 0x1102,
+        "doc" | "docx" => 0x1103,
+        "pdf" => 0x1104,
+        "odt" => 0x1105,
+        "tex" => 0x1106,
+        "epub" => 0x1107,
+        "csv" => 0x1108,
+
+        "exe" => 0x1200,
+        "bin" => 0x1201,
+        "dll" => 0x1202,
+        "so" => 0x1203,
+        "dmg" => 0x1204,
+        "iso" => 0x1205,
+        "img" => 0x1206,
+
+        "cfg" | "conf" => 0x1300,
+        "ini" => 0x1301,
+        "json" => 0x1302,
+        "xml" => 0x1303,
+        "yaml" | "yml" => 0x1304,
+        "toml" => 0x1305,
+
+        "cache" => 0x1400,
+        "tmp" => 0x1401,
+        "swp" => 0x1402,
+
+        "log" => 0x1500,
+        "out" => 0x1501,
+
+        "jpg" | "jpeg" => 0x1600,
+        "png" => 0x1601,
+        "gif" => 0x1602,
+        "bmp" => 0x1603,
+        "tiff" => 0x1604,
+        "svg" => 0x1605,
+        "mp4" => 0x1606,
+        "mp3" => 0x1607,
+        "wav" => 0x1608,
+        "mkv" => 0x1609,
+        "avi" => 0x160A,
+        "mov" => 0x160B,
+        "flv" => 0x160C,
+        "wmv" => 0x160D,
+        "webm" => 0x160E,
+        "ogg" => 0x160F,
+        "flac" => 0x1610,
+        "aac" => 0x1611,
+        "m4a" => 0x1612,
+        "webp" => 0x1613,
+
+        "zip" => 0x1700,
+        "rar" => 0x1701,
+        "tar" => 0x1702,
+        "gz" => 0x1703,
+        "7z" => 0x1704,
+        "bz2" => 0x1705,
+        "xz" => 0x1706,
+
+        "enc" => 0x1800,
+        "gpg" => 0x1801,
+        "aes" => 0x1802,
+
+        _ => prefix::OTHER_FILE,
+    }
+}